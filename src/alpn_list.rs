@@ -1,4 +1,6 @@
 use std::alloc;
+use std::convert::TryFrom;
+use std::io;
 use std::mem;
 use std::ptr;
 use std::slice;
@@ -29,14 +31,29 @@ impl Drop for AlpnList {
 }
 
 impl AlpnList {
-    pub fn new(protos: &[Vec<u8>]) -> Self {
+    pub fn new(protos: &[Vec<u8>]) -> io::Result<Self> {
         // ALPN wire format is each ALPN preceded by its length as a byte.
         let mut alpn_wire_format =
             Vec::with_capacity(protos.iter().map(Vec::len).sum::<usize>() + protos.len());
         for alpn in protos {
-            alpn_wire_format.push(alpn.len() as u8);
+            let len = match u8::try_from(alpn.len()) {
+                Ok(0) | Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "application protocol names must be 1 to 255 bytes long",
+                    ))
+                }
+                Ok(len) => len,
+            };
+            alpn_wire_format.push(len);
             alpn_wire_format.extend(alpn);
         }
+        let list_size = u16::try_from(alpn_wire_format.len()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the list of application protocols is too long",
+            )
+        })?;
 
         let size = SEC_APPLICATION_PROTOCOL_HEADER_SIZE + alpn_wire_format.len();
         let layout = alloc::Layout::from_size_align(
@@ -60,14 +77,14 @@ impl AlpnList {
 
             let protocol = &mut *protocols.ProtocolLists.as_mut_ptr();
             protocol.ProtoNegoExt = Identity::SecApplicationProtocolNegotiationExt_ALPN;
-            protocol.ProtocolListSize = alpn_wire_format.len() as u16;
+            protocol.ProtocolListSize = list_size;
 
             let protocol_list_offset =
                 protocol.ProtocolList.as_ptr() as usize - buf.as_ptr() as usize;
             let protocol_list = &mut buf[protocol_list_offset..];
             protocol_list.copy_from_slice(&alpn_wire_format);
 
-            Self { layout, memory }
+            Ok(Self { layout, memory })
         }
     }
 }
@@ -0,0 +1,269 @@
+//! Schannel TLS streams for tokio.
+//!
+//! This module is available with the `tokio` feature. The streams are built
+//! on `TlsEngine`, so the wrapped stream is only ever polled and no call
+//! blocks the executor.
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::schannel_cred::{ClientCred, ServerCred};
+use crate::tls_stream::{Builder, SessionState, TlsEngine};
+
+/// The size of the buffer ciphertext is read into from the wrapped stream.
+const READ_SIZE: usize = 16 * 1024;
+
+/// The maximum amount of plaintext encrypted by a single `poll_write` call.
+const MAX_WRITE_SIZE: usize = 16 * 1024;
+
+macro_rules! ready {
+    ($e:expr) => {
+        match $e {
+            Poll::Ready(t) => t,
+            Poll::Pending => return Poll::Pending,
+        }
+    };
+}
+
+impl Builder {
+    /// Initialize a new TLS session where the stream provided will be
+    /// connecting to a remote TLS server.
+    ///
+    /// The returned future completes once the handshake has finished.
+    pub fn connect_async<C, S>(&mut self, cred: C, stream: S) -> Handshake<S>
+    where
+        C: Into<ClientCred>,
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        Handshake::new(self.connect_engine(cred), stream)
+    }
+
+    /// Initialize a new TLS session where the stream provided will be
+    /// accepting a connection.
+    ///
+    /// The returned future completes once the handshake has finished.
+    pub fn accept_async<C, S>(&mut self, cred: C, stream: S) -> Handshake<S>
+    where
+        C: Into<ServerCred>,
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        Handshake::new(self.accept_engine(cred), stream)
+    }
+}
+
+/// A future performing the handshake of an `AsyncTlsStream`.
+#[derive(Debug)]
+pub struct Handshake<S> {
+    inner: Option<io::Result<AsyncTlsStream<S>>>,
+}
+
+impl<S> Handshake<S> {
+    fn new(engine: io::Result<TlsEngine>, stream: S) -> Handshake<S> {
+        Handshake {
+            inner: Some(engine.map(|engine| AsyncTlsStream {
+                engine,
+                stream,
+                out_buf: vec![],
+                out_pos: 0,
+                plain_buf: vec![],
+                plain_pos: 0,
+                read_buf: vec![0; READ_SIZE].into_boxed_slice(),
+            })),
+        }
+    }
+}
+
+impl<S> Future for Handshake<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Output = io::Result<AsyncTlsStream<S>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let stream = match self.inner {
+            Some(Ok(ref mut stream)) => stream,
+            Some(Err(_)) => return Poll::Ready(self.inner.take().unwrap()),
+            None => panic!("Handshake polled after completion"),
+        };
+        ready!(stream.poll_handshake(cx))?;
+        Poll::Ready(self.inner.take().unwrap())
+    }
+}
+
+/// An Schannel TLS stream wrapping an asynchronous stream.
+pub struct AsyncTlsStream<S> {
+    engine: TlsEngine,
+    stream: S,
+    // ciphertext to write to the stream, valid from out_pos to len()
+    out_buf: Vec<u8>,
+    out_pos: usize,
+    // decrypted data, valid from plain_pos to len()
+    plain_buf: Vec<u8>,
+    plain_pos: usize,
+    read_buf: Box<[u8]>,
+}
+
+impl<S> std::fmt::Debug for AsyncTlsStream<S>
+where
+    S: std::fmt::Debug,
+{
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("AsyncTlsStream")
+            .field("engine", &self.engine)
+            .field("stream", &self.stream)
+            .finish()
+    }
+}
+
+impl<S> AsyncTlsStream<S> {
+    /// Returns a reference to the wrapped stream.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Returns a mutable reference to the wrapped stream.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Returns the engine holding the state of the TLS session.
+    ///
+    /// This gives access to the certificates and other details of the
+    /// session.
+    pub fn engine(&self) -> &TlsEngine {
+        &self.engine
+    }
+}
+
+impl<S> AsyncTlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_handshake(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        loop {
+            ready!(self.poll_flush_out(cx))?;
+            if self.engine.session_state() != SessionState::Handshaking {
+                return Poll::Ready(Ok(()));
+            }
+
+            let len = ready!(self.poll_read_in(cx))?;
+            if len == 0 {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "unexpected EOF during handshake",
+                )));
+            }
+            self.engine.handshake_input(&self.read_buf[..len])?;
+        }
+    }
+
+    /// Writes all pending output of the engine to the stream, then flushes it.
+    fn poll_flush_out(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_out(cx))?;
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    /// Writes all pending output of the engine to the stream.
+    fn poll_write_out(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.engine.take_output(&mut self.out_buf);
+        while self.out_pos < self.out_buf.len() {
+            let buf = &self.out_buf[self.out_pos..];
+            let len = ready!(Pin::new(&mut self.stream).poll_write(cx, buf))?;
+            if len == 0 {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write whole buffer",
+                )));
+            }
+            self.out_pos += len;
+        }
+        self.out_buf.clear();
+        self.out_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_read_in(&mut self, cx: &mut Context) -> Poll<io::Result<usize>> {
+        let mut buf = ReadBuf::new(&mut self.read_buf);
+        ready!(Pin::new(&mut self.stream).poll_read(cx, &mut buf))?;
+        Poll::Ready(Ok(buf.filled().len()))
+    }
+}
+
+impl<S> AsyncRead for AsyncTlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.plain_pos == this.plain_buf.len() {
+            this.plain_buf.clear();
+            this.plain_pos = 0;
+            // Records received along with earlier data, such as the end of
+            // the handshake, are decrypted first.
+            this.engine.unwrap(&[], &mut this.plain_buf)?;
+            if !this.plain_buf.is_empty() {
+                break;
+            }
+
+            // Responses to the peer's handshake messages or close_notify
+            // have to be sent before more data can be received.
+            ready!(this.poll_write_out(cx))?;
+            if this.engine.session_state() == SessionState::Shutdown {
+                return Poll::Ready(Ok(()));
+            }
+
+            let len = ready!(this.poll_read_in(cx))?;
+            if len == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            this.engine
+                .unwrap(&this.read_buf[..len], &mut this.plain_buf)?;
+        }
+
+        let len = std::cmp::min(buf.remaining(), this.plain_buf.len() - this.plain_pos);
+        buf.put_slice(&this.plain_buf[this.plain_pos..this.plain_pos + len]);
+        this.plain_pos += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for AsyncTlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // Pending output is written first so that it doesn't pile up.
+        ready!(this.poll_write_out(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let len = std::cmp::min(buf.len(), MAX_WRITE_SIZE);
+        this.engine.wrap(&buf[..len], &mut this.out_buf)?;
+        // The data has been accepted even if the stream isn't ready for it
+        // yet; the rest is written by the next call or `poll_flush`.
+        if let Poll::Ready(Err(e)) = this.poll_write_out(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().poll_flush_out(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.engine.shutdown()?;
+        ready!(this.poll_flush_out(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,125 @@
+//! Detection of the Schannel features supported by the running system.
+use std::mem;
+use std::sync::OnceLock;
+
+use windows_sys::Win32::System::{LibraryLoader, SystemInformation};
+
+use crate::schannel_cred::{Direction, Protocol, SchannelCred};
+use crate::security_context::SecurityContext;
+
+/// The Schannel features supported by the running system.
+///
+/// Most features are derived from the version of Windows, which is read with
+/// `RtlGetVersion` so that it isn't affected by the compatibility settings of
+/// the application's manifest. TLS 1.3 is probed by starting a handshake
+/// with only TLS 1.3 enabled, since it can also be disabled by policy.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct Capabilities {
+    /// The major version of Windows.
+    pub major_version: u32,
+    /// The minor version of Windows.
+    pub minor_version: u32,
+    /// The build number of Windows.
+    pub build_number: u32,
+    /// Whether TLS 1.3 can be negotiated, which requires Windows 11 or
+    /// Windows Server 2022.
+    pub tls13: bool,
+    /// Whether application protocols can be negotiated with ALPN, which
+    /// requires Windows 8.1 or Windows Server 2012 R2.
+    pub alpn: bool,
+    /// Whether servers staple OCSP responses, which requires Windows 7 or
+    /// Windows Server 2008 R2.
+    pub ocsp_stapling: bool,
+    /// Whether keying material can be exported from sessions with
+    /// `SECPKG_ATTR_KEYING_MATERIAL`, which requires Windows 10.
+    pub keying_material_export: bool,
+    /// Whether Schannel exports the traffic secrets needed by
+    /// `tls_stream::Builder::key_log`, which requires Windows 11.
+    pub traffic_secrets: bool,
+}
+
+impl Capabilities {
+    fn detect() -> Capabilities {
+        let (major_version, minor_version, build_number) = os_version().unwrap_or((0, 0, 0));
+        let at_least = |major: u32, minor: u32, build: u32| {
+            (major_version, minor_version, build_number) >= (major, minor, build)
+        };
+        Capabilities {
+            major_version,
+            minor_version,
+            build_number,
+            tls13: at_least(10, 0, 20348) && probe_tls13(),
+            alpn: at_least(6, 3, 9600),
+            ocsp_stapling: at_least(6, 1, 7600),
+            keying_material_export: at_least(10, 0, 10240),
+            traffic_secrets: at_least(10, 0, 22000),
+        }
+    }
+}
+
+/// Returns the features supported by the running system.
+///
+/// The system is only probed by the first call, later calls return the same
+/// result.
+pub fn capabilities() -> Capabilities {
+    static CAPABILITIES: OnceLock<Capabilities> = OnceLock::new();
+    *CAPABILITIES.get_or_init(Capabilities::detect)
+}
+
+type RtlGetVersion = unsafe extern "system" fn(*mut SystemInformation::OSVERSIONINFOW) -> i32;
+
+fn os_version() -> Option<(u32, u32, u32)> {
+    unsafe {
+        let ntdll = "ntdll.dll\0".encode_utf16().collect::<Vec<_>>();
+        let module = LibraryLoader::GetModuleHandleW(ntdll.as_ptr());
+        if module == 0 {
+            return None;
+        }
+        let function = LibraryLoader::GetProcAddress(module, b"RtlGetVersion\0".as_ptr())?;
+        let function =
+            mem::transmute::<unsafe extern "system" fn() -> isize, RtlGetVersion>(function);
+        let mut info: SystemInformation::OSVERSIONINFOW = mem::zeroed();
+        info.dwOSVersionInfoSize = mem::size_of_val(&info) as u32;
+        if function(&mut info) != 0 {
+            return None;
+        }
+        Some((info.dwMajorVersion, info.dwMinorVersion, info.dwBuildNumber))
+    }
+}
+
+fn probe_tls13() -> bool {
+    let mut cred = match SchannelCred::builder()
+        .enabled_protocols(&[Protocol::Tls13])
+        .acquire(Direction::Outbound)
+    {
+        Ok(cred) => cred,
+        Err(_) => return false,
+    };
+    let domain = "localhost\0".encode_utf16().collect::<Vec<_>>();
+    SecurityContext::initialize(&mut cred, false, Some(&domain[..]), &None).is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn consistent() {
+        let caps = capabilities();
+        assert!(caps.major_version >= 6, "{:?}", caps);
+        assert_eq!(caps, capabilities());
+        if caps.tls13 {
+            assert!(caps.alpn && caps.keying_material_export, "{:?}", caps);
+        }
+        if caps.traffic_secrets {
+            assert!(caps.keying_material_export, "{:?}", caps);
+        }
+        if caps.keying_material_export {
+            assert!(caps.alpn, "{:?}", caps);
+        }
+        if caps.alpn {
+            assert!(caps.ocsp_stapling, "{:?}", caps);
+        }
+    }
+}
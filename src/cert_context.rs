@@ -1,20 +1,27 @@
 //! Bindings to Windows `PCCERT_CONTEXT` APIs.
 
 use std::ffi::{c_void, CStr, OsString};
-use std::io;
 use std::mem;
+use std::net::IpAddr;
 use std::os::windows::prelude::*;
 use std::ptr;
 use std::slice;
 
 use windows_sys::Win32::Foundation;
 use windows_sys::Win32::Security::Cryptography;
+use windows_sys::Win32::System::Memory;
 
 use crate::cert_store::CertStore;
 use crate::crypt_prov::{CryptProv, ProviderType};
+use crate::error::{self, Win32Error};
 use crate::ncrypt_key::NcryptKey;
 use crate::Inner;
 
+static szOID_SUBJECT_ALT_NAME2: &[u8] = null_terminate!(Cryptography::szOID_SUBJECT_ALT_NAME2);
+
+// from wincrypt.h, only exposed by a windows-sys feature this crate lacks
+pub(crate) const CERT_ALT_NAME_IP_ADDRESS: u32 = 8;
+
 /// A supported hashing algorithm
 pub struct HashAlgorithm(u32, usize);
 
@@ -87,7 +94,7 @@ inner!(CertContext, *const Cryptography::CERT_CONTEXT);
 
 impl CertContext {
     /// Decodes a DER-formatted X509 certificate.
-    pub fn new(data: &[u8]) -> io::Result<CertContext> {
+    pub fn new(data: &[u8]) -> error::Result<CertContext> {
         let ret = unsafe {
             Cryptography::CertCreateCertificateContext(
                 Cryptography::X509_ASN_ENCODING | Cryptography::PKCS_7_ASN_ENCODING,
@@ -96,7 +103,7 @@ impl CertContext {
             )
         };
         if ret.is_null() {
-            Err(io::Error::last_os_error())
+            Err(Win32Error::last().into())
         } else {
             Ok(CertContext(ret))
         }
@@ -108,7 +115,7 @@ impl CertContext {
     }
 
     /// Certificate subject public key info
-    pub fn subject_public_key_info_der(&self) -> io::Result<Vec<u8>> {
+    pub fn subject_public_key_info_der(&self) -> error::Result<Vec<u8>> {
         unsafe {
             let mut len: u32 = 0;
             let ok = Cryptography::CryptEncodeObjectEx(
@@ -122,7 +129,7 @@ impl CertContext {
                 &mut len,
             );
             if ok == 0 {
-                return Err(io::Error::last_os_error());
+                return Err(Win32Error::last().into());
             }
             if len > 0 {
                 let mut buf = vec![0; len as usize];
@@ -137,16 +144,16 @@ impl CertContext {
                     &mut len,
                 );
                 if ok == 0 {
-                    return Err(io::Error::last_os_error());
+                    return Err(Win32Error::last().into());
                 }
                 return Ok(buf);
             }
         }
-        Err(io::Error::last_os_error())
+        Err(Win32Error::last().into())
     }
 
     /// Decodes a PEM-formatted X509 certificate.
-    pub fn from_pem(pem: &str) -> io::Result<CertContext> {
+    pub fn from_pem(pem: &str) -> error::Result<CertContext> {
         unsafe {
             assert!(pem.len() <= u32::max_value() as usize);
 
@@ -161,7 +168,7 @@ impl CertContext {
                 ptr::null_mut(),
             );
             if ok == 0 {
-                return Err(io::Error::last_os_error());
+                return Err(Win32Error::last().into());
             }
 
             let mut buf = vec![0; len as usize];
@@ -175,7 +182,7 @@ impl CertContext {
                 ptr::null_mut(),
             );
             if ok == 0 {
-                return Err(io::Error::last_os_error());
+                return Err(Win32Error::last().into());
             }
 
             CertContext::new(&buf)
@@ -183,7 +190,7 @@ impl CertContext {
     }
 
     /// Get certificate as PEM-formatted X509 certificate.
-    pub fn to_pem(&self) -> io::Result<String> {
+    pub fn to_pem(&self) -> error::Result<String> {
         unsafe {
             let mut len = 0;
             let ok = Cryptography::CryptBinaryToStringA(
@@ -194,7 +201,7 @@ impl CertContext {
                 &mut len,
             );
             if ok == 0 {
-                return Err(io::Error::last_os_error());
+                return Err(Win32Error::last().into());
             }
 
             let mut buf = vec![0; len as usize];
@@ -206,7 +213,7 @@ impl CertContext {
                 &mut len,
             );
             if ok == 0 {
-                return Err(io::Error::last_os_error());
+                return Err(Win32Error::last().into());
             }
 
             Ok(CStr::from_ptr(buf.as_ptr() as *const _)
@@ -216,7 +223,7 @@ impl CertContext {
     }
 
     /// Returns a hash of this certificate
-    pub fn fingerprint(&self, alg: HashAlgorithm) -> io::Result<Vec<u8>> {
+    pub fn fingerprint(&self, alg: HashAlgorithm) -> error::Result<Vec<u8>> {
         unsafe {
             let mut buf = vec![0u8; alg.1];
             let mut len = buf.len() as u32;
@@ -232,7 +239,7 @@ impl CertContext {
             );
 
             if ret == 0 {
-                return Err(io::Error::last_os_error());
+                return Err(Win32Error::last().into());
             }
             Ok(buf)
         }
@@ -243,7 +250,7 @@ impl CertContext {
     /// The sha1 is returned as a 20-byte array representing the bits of the
     /// sha1 hash.
     #[deprecated(note = "please use fingerprint instead")]
-    pub fn sha1(&self) -> io::Result<[u8; 20]> {
+    pub fn sha1(&self) -> error::Result<[u8; 20]> {
         let mut out = [0u8; 20];
         out.copy_from_slice(&self.fingerprint(HashAlgorithm::sha1())?);
         Ok(out)
@@ -260,40 +267,95 @@ impl CertContext {
     /// * `RSA/SHA1`
     /// * `RSA/SHA256`
     /// * `ECDSA/SHA256`
-    pub fn sign_hash_algorithms(&self) -> io::Result<String> {
+    pub fn sign_hash_algorithms(&self) -> error::Result<String> {
         self.get_string(Cryptography::CERT_SIGN_HASH_CNG_ALG_PROP_ID)
     }
 
     /// Returns the signature hash.
-    pub fn signature_hash(&self) -> io::Result<Vec<u8>> {
+    pub fn signature_hash(&self) -> error::Result<Vec<u8>> {
         self.get_bytes(Cryptography::CERT_SIGNATURE_HASH_PROP_ID)
     }
 
     /// Returns the property displayed by the certificate UI. This property
     /// allows the user to describe the certificate's use.
-    pub fn description(&self) -> io::Result<Vec<u8>> {
+    pub fn description(&self) -> error::Result<Vec<u8>> {
         self.get_bytes(Cryptography::CERT_DESCRIPTION_PROP_ID)
     }
 
     /// Returns a string that contains the display name for the certificate.
-    pub fn friendly_name(&self) -> io::Result<String> {
+    pub fn friendly_name(&self) -> error::Result<String> {
         self.get_string(Cryptography::CERT_FRIENDLY_NAME_PROP_ID)
     }
 
     /// Configures the string that contains the display name for this
     /// certificate.
-    pub fn set_friendly_name(&self, name: &str) -> io::Result<()> {
+    pub fn set_friendly_name(&self, name: &str) -> error::Result<()> {
         self.set_string(Cryptography::CERT_FRIENDLY_NAME_PROP_ID, name)
     }
 
+    /// Returns the DER-encoded OCSP response attached to this certificate.
+    ///
+    /// Schannel attaches the response stapled by a server to the certificate
+    /// returned by `TlsStream::peer_certificate`.
+    pub fn ocsp_response(&self) -> error::Result<Vec<u8>> {
+        self.get_bytes(Cryptography::CERT_OCSP_RESPONSE_PROP_ID)
+    }
+
+    /// Attaches a DER-encoded OCSP response to this certificate.
+    ///
+    /// Schannel servers staple the response attached to their certificate
+    /// for clients requesting the certificate status. The property is shared
+    /// by all contexts of the same certificate, so this also updates the
+    /// response sent by credentials which have already been acquired.
+    pub fn set_ocsp_response(&self, der: &[u8]) -> error::Result<()> {
+        self.set_bytes(Cryptography::CERT_OCSP_RESPONSE_PROP_ID, der)
+    }
+
     /// Verifies the time validity of this certificate relative to the system's
     /// current time.
-    pub fn is_time_valid(&self) -> io::Result<bool> {
+    pub fn is_time_valid(&self) -> error::Result<bool> {
         let ret =
             unsafe { Cryptography::CertVerifyTimeValidity(ptr::null_mut(), (*self.0).pCertInfo) };
         Ok(ret == 0)
     }
 
+    /// Returns true if a private key is linked to this certificate.
+    ///
+    /// This only checks that the certificate refers to a key container or
+    /// holds a key handle. Whether the key can be used is only known once it
+    /// is acquired with `private_key`.
+    pub fn has_private_key(&self) -> bool {
+        [
+            Cryptography::CERT_KEY_PROV_INFO_PROP_ID,
+            Cryptography::CERT_KEY_CONTEXT_PROP_ID,
+            Cryptography::CERT_NCRYPT_KEY_HANDLE_PROP_ID,
+        ]
+        .iter()
+        .any(|&prop| unsafe {
+            let mut len = 0;
+            Cryptography::CertGetCertificateContextProperty(self.0, prop, ptr::null_mut(), &mut len)
+                != 0
+        })
+    }
+
+    /// Acquires the private key linked to this certificate, returning `None`
+    /// if there is none.
+    ///
+    /// This catches certificates which can't identify a server or client
+    /// before credentials are acquired with them. If `silent` is set, keys
+    /// which need to ask the user for permission fail to be acquired rather
+    /// than showing a prompt. Use `private_key` for more options.
+    pub fn find_private_key(&self, silent: bool) -> error::Result<Option<PrivateKey>> {
+        if !self.has_private_key() {
+            return Ok(None);
+        }
+        match self.private_key().silent(silent).acquire() {
+            Ok(key) => Ok(Some(key)),
+            Err(ref e) if e.raw_os_error() == Some(Foundation::CRYPT_E_NO_KEY_PROPERTY) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
     /// Returns a builder used to acquire the private key corresponding to this certificate.
     pub fn private_key(&self) -> AcquirePrivateKeyOptions {
         AcquirePrivateKeyOptions {
@@ -303,14 +365,14 @@ impl CertContext {
     }
 
     /// Deletes this certificate from its certificate store.
-    pub fn delete(self) -> io::Result<()> {
+    pub fn delete(self) -> error::Result<()> {
         unsafe {
             let ret = Cryptography::CertDeleteCertificateFromStore(self.0);
             mem::forget(self);
             if ret != 0 {
                 Ok(())
             } else {
-                Err(io::Error::last_os_error())
+                Err(Win32Error::last().into())
             }
         }
     }
@@ -327,15 +389,106 @@ impl CertContext {
         }
     }
 
+    /// Returns the IP addresses listed in the subject alternative name
+    /// extension of this certificate.
+    pub fn ip_addresses(&self) -> error::Result<Vec<IpAddr>> {
+        unsafe {
+            let info = &*(*self.0).pCertInfo;
+            let extension = Cryptography::CertFindExtension(
+                szOID_SUBJECT_ALT_NAME2.as_ptr(),
+                info.cExtension,
+                info.rgExtension,
+            );
+            if extension.is_null() {
+                return Ok(vec![]);
+            }
+
+            let value = &(*extension).Value;
+            let mut names: *mut Cryptography::CERT_ALT_NAME_INFO = ptr::null_mut();
+            let mut len = 0;
+            let res = Cryptography::CryptDecodeObjectEx(
+                Cryptography::X509_ASN_ENCODING | Cryptography::PKCS_7_ASN_ENCODING,
+                Cryptography::X509_ALTERNATE_NAME,
+                value.pbData,
+                value.cbData,
+                Cryptography::CRYPT_DECODE_ALLOC_FLAG,
+                ptr::null(),
+                &mut names as *mut _ as *mut c_void,
+                &mut len,
+            );
+            if res == 0 {
+                return Err(Win32Error::last().into());
+            }
+
+            let entries = match (*names).cAltEntry {
+                0 => &[][..],
+                n => slice::from_raw_parts((*names).rgAltEntry, n as usize),
+            };
+            let addrs = entries
+                .iter()
+                .filter(|entry| entry.dwAltNameChoice == CERT_ALT_NAME_IP_ADDRESS)
+                .filter_map(|entry| {
+                    let blob = entry.Anonymous.IPAddress;
+                    match blob.cbData {
+                        4 => {
+                            let mut octets = [0; 4];
+                            octets.copy_from_slice(slice::from_raw_parts(blob.pbData, 4));
+                            Some(IpAddr::from(octets))
+                        }
+                        16 => {
+                            let mut octets = [0; 16];
+                            octets.copy_from_slice(slice::from_raw_parts(blob.pbData, 16));
+                            Some(IpAddr::from(octets))
+                        }
+                        _ => None,
+                    }
+                })
+                .collect();
+            Memory::LocalFree(names as isize);
+            Ok(addrs)
+        }
+    }
+
+    /// Returns the OID of the algorithm of the certificate's public key, such
+    /// as `szOID_RSA_RSA` or `szOID_ECC_PUBLIC_KEY`.
+    pub fn public_key_algorithm(&self) -> String {
+        unsafe {
+            let info = &(*(*self.0).pCertInfo).SubjectPublicKeyInfo;
+            let oid = info.Algorithm.pszObjId;
+            if oid.is_null() {
+                return String::new();
+            }
+            CStr::from_ptr(oid as *const _)
+                .to_string_lossy()
+                .into_owned()
+        }
+    }
+
+    /// Returns the length of the certificate's public key in bits, the
+    /// modulus for RSA keys and the size of the curve for ECC keys.
+    pub fn public_key_bits(&self) -> error::Result<u32> {
+        unsafe {
+            let bits = Cryptography::CertGetPublicKeyLength(
+                Cryptography::X509_ASN_ENCODING | Cryptography::PKCS_7_ASN_ENCODING,
+                &(*(*self.0).pCertInfo).SubjectPublicKeyInfo,
+            );
+            if bits == 0 {
+                Err(Win32Error::last().into())
+            } else {
+                Ok(bits)
+            }
+        }
+    }
+
     /// Returns the valid uses for this certificate
-    pub fn valid_uses(&self) -> io::Result<ValidUses> {
+    pub fn valid_uses(&self) -> error::Result<ValidUses> {
         unsafe {
             let mut buf_len = 0;
             let ok =
                 Cryptography::CertGetEnhancedKeyUsage(self.0, 0, ptr::null_mut(), &mut buf_len);
 
             if ok == 0 {
-                return Err(io::Error::last_os_error());
+                return Err(Win32Error::last().into());
             }
 
             let mut buf = vec![0u8; buf_len as usize];
@@ -344,16 +497,16 @@ impl CertContext {
             let ok =
                 Cryptography::CertGetEnhancedKeyUsage(self.0, 0, cert_enhkey_usage, &mut buf_len);
             if ok == 0 {
-                return Err(io::Error::last_os_error());
+                return Err(Win32Error::last().into());
             }
 
             let use_cnt = (*cert_enhkey_usage).cUsageIdentifier;
             if use_cnt == 0 {
-                let last_error = io::Error::last_os_error();
-                match last_error.raw_os_error() {
-                    Some(Foundation::CRYPT_E_NOT_FOUND) => return Ok(ValidUses::All),
-                    Some(Foundation::S_OK) => (),
-                    _ => return Err(last_error),
+                let last_error = Win32Error::last();
+                match last_error.code() as i32 {
+                    Foundation::CRYPT_E_NOT_FOUND => return Ok(ValidUses::All),
+                    Foundation::S_OK => (),
+                    _ => return Err(last_error.into()),
                 };
             }
 
@@ -392,7 +545,7 @@ impl CertContext {
         }
     }
 
-    fn get_bytes(&self, prop: u32) -> io::Result<Vec<u8>> {
+    fn get_bytes(&self, prop: u32) -> error::Result<Vec<u8>> {
         unsafe {
             let mut len = 0;
             let ret = Cryptography::CertGetCertificateContextProperty(
@@ -402,7 +555,7 @@ impl CertContext {
                 &mut len,
             );
             if ret == 0 {
-                return Err(io::Error::last_os_error());
+                return Err(Win32Error::last().into());
             }
 
             let mut buf = vec![0u8; len as usize];
@@ -413,13 +566,13 @@ impl CertContext {
                 &mut len,
             );
             if ret == 0 {
-                return Err(io::Error::last_os_error());
+                return Err(Win32Error::last().into());
             }
             Ok(buf)
         }
     }
 
-    fn get_string(&self, prop: u32) -> io::Result<String> {
+    fn get_string(&self, prop: u32) -> error::Result<String> {
         unsafe {
             let mut len = 0;
             let ret = Cryptography::CertGetCertificateContextProperty(
@@ -429,7 +582,7 @@ impl CertContext {
                 &mut len,
             );
             if ret == 0 {
-                return Err(io::Error::last_os_error());
+                return Err(Win32Error::last().into());
             }
 
             // Divide by 2 b/c `len` is the byte length, but we're allocating
@@ -443,7 +596,7 @@ impl CertContext {
                 &mut len,
             );
             if ret == 0 {
-                return Err(io::Error::last_os_error());
+                return Err(Win32Error::last().into());
             }
 
             // Chop off the trailing nul byte
@@ -451,7 +604,27 @@ impl CertContext {
         }
     }
 
-    fn set_string(&self, prop: u32, s: &str) -> io::Result<()> {
+    fn set_bytes(&self, prop: u32, data: &[u8]) -> error::Result<()> {
+        unsafe {
+            let data = Cryptography::CRYPTOAPI_BLOB {
+                cbData: data.len() as u32,
+                pbData: data.as_ptr() as *mut _,
+            };
+            let ret = Cryptography::CertSetCertificateContextProperty(
+                self.0,
+                prop,
+                0,
+                &data as *const _ as *const _,
+            );
+            if ret == 0 {
+                Err(Win32Error::last().into())
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn set_string(&self, prop: u32, s: &str) -> error::Result<()> {
         unsafe {
             let data = s.encode_utf16().chain(Some(0)).collect::<Vec<_>>();
             let data = Cryptography::CRYPTOAPI_BLOB {
@@ -465,7 +638,7 @@ impl CertContext {
                 &data as *const _ as *const _,
             );
             if ret == 0 {
-                Err(io::Error::last_os_error())
+                Err(Win32Error::last().into())
             } else {
                 Ok(())
             }
@@ -498,6 +671,12 @@ impl<'a> AcquirePrivateKeyOptions<'a> {
         self.flag(Cryptography::CRYPT_ACQUIRE_SILENT_FLAG, silent)
     }
 
+    /// If set, the key is acquired once and cached with the certificate, with
+    /// later lookups returning the same handle.
+    pub fn cache(&mut self, cache: bool) -> &mut AcquirePrivateKeyOptions<'a> {
+        self.flag(Cryptography::CRYPT_ACQUIRE_CACHE_FLAG, cache)
+    }
+
     fn flag(&mut self, flag: u32, set: bool) -> &mut AcquirePrivateKeyOptions<'a> {
         if set {
             self.flags |= flag;
@@ -508,7 +687,7 @@ impl<'a> AcquirePrivateKeyOptions<'a> {
     }
 
     /// Acquires the private key handle.
-    pub fn acquire(&self) -> io::Result<PrivateKey> {
+    pub fn acquire(&self) -> error::Result<PrivateKey> {
         unsafe {
             let flags = self.flags | Cryptography::CRYPT_ACQUIRE_ALLOW_NCRYPT_KEY_FLAG;
             let mut handle = Cryptography::HCRYPTPROV_OR_NCRYPT_KEY_HANDLE::default();
@@ -523,12 +702,23 @@ impl<'a> AcquirePrivateKeyOptions<'a> {
                 &mut free,
             );
             if res == 0 {
-                return Err(io::Error::last_os_error());
+                return Err(Win32Error::last().into());
             }
-            assert_ne!(free, 0);
+            // Handles cached with the certificate must not be freed by us.
+            let owned = free != 0;
             if spec & Cryptography::CERT_NCRYPT_KEY_SPEC != 0 {
-                Ok(PrivateKey::NcryptKey(NcryptKey::from_inner(handle)))
+                let key = if owned {
+                    NcryptKey::from_inner(handle)
+                } else {
+                    NcryptKey::borrowed(handle, self.cert)
+                };
+                Ok(PrivateKey::NcryptKey(key))
             } else {
+                // CryptoAPI providers are reference counted, so a cached one
+                // gets a reference of its own.
+                if !owned && Cryptography::CryptContextAddRef(handle, ptr::null_mut(), 0) == 0 {
+                    return Err(Win32Error::last().into());
+                }
                 Ok(PrivateKey::CryptProv(CryptProv::from_inner(handle)))
             }
         }
@@ -614,7 +804,7 @@ impl<'a> SetKeyProvInfo<'a> {
     }
 
     /// Sets the private key for this certificate.
-    pub fn set(&mut self) -> io::Result<()> {
+    pub fn set(&mut self) -> error::Result<()> {
         unsafe {
             let container = self
                 .container
@@ -646,7 +836,7 @@ impl<'a> SetKeyProvInfo<'a> {
             if res != 0 {
                 Ok(())
             } else {
-                Err(io::Error::last_os_error())
+                Err(Win32Error::last().into())
             }
         }
     }
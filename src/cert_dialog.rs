@@ -0,0 +1,190 @@
+//! Selection of client certificates by the user.
+//!
+//! This module is only available with the `cert-dialog` feature.
+use std::mem;
+use std::ptr;
+
+use windows_sys::Win32::Foundation;
+use windows_sys::Win32::Security::Cryptography::{self, UI};
+
+use crate::cert_chain::CertChainContext;
+use crate::cert_context::CertContext;
+use crate::cert_store::{CertAdd, CertStore, Memory};
+use crate::error::{self, Win32Error};
+use crate::Inner;
+
+static szOID_PKIX_KP_CLIENT_AUTH: &[u8] = null_terminate!(Cryptography::szOID_PKIX_KP_CLIENT_AUTH);
+
+/// The standard Windows dialog to select a certificate.
+///
+/// The dialog only lists the certificates of a store which can authenticate
+/// a client: those which have a private key, are valid for client
+/// authentication and haven't expired. If the issuers accepted by the server
+/// are set, certificates must also chain to one of them.
+///
+/// The dialog can be shown when a server asks for a certificate:
+///
+/// ```no_run
+/// use std::net::TcpStream;
+///
+/// use schannel::cert_dialog::CertificateDialog;
+/// use schannel::schannel_cred::SchannelCred;
+/// use schannel::tls_stream;
+///
+/// let cred = SchannelCred::builder().acquire_client().unwrap();
+/// let stream = TcpStream::connect("example.com:443").unwrap();
+/// let stream = tls_stream::Builder::new()
+///     .domain("example.com")
+///     .client_cert_selector(|issuers| {
+///         let cert = CertificateDialog::new()
+///             .title("Client certificate")
+///             .text("Select the certificate to log in to example.com with")
+///             .issuers(issuers)
+///             .select_from_user_store()
+///             .ok()??;
+///         SchannelCred::builder().cert(cert).acquire_client().ok()
+///     })
+///     .connect(cred, stream)
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CertificateDialog {
+    window: Foundation::HWND,
+    title: Option<Vec<u16>>,
+    text: Option<Vec<u16>>,
+    issuers: Vec<Vec<u8>>,
+}
+
+impl CertificateDialog {
+    /// Creates a dialog with the default title and text.
+    pub fn new() -> CertificateDialog {
+        CertificateDialog::default()
+    }
+
+    /// Sets the window owning the dialog.
+    ///
+    /// Defaults to none, which makes the dialog a top-level window.
+    pub fn window(&mut self, window: Foundation::HWND) -> &mut CertificateDialog {
+        self.window = window;
+        self
+    }
+
+    /// Sets the title of the dialog.
+    pub fn title(&mut self, title: &str) -> &mut CertificateDialog {
+        self.title = Some(wide(title));
+        self
+    }
+
+    /// Sets the text shown above the list of certificates.
+    pub fn text(&mut self, text: &str) -> &mut CertificateDialog {
+        self.text = Some(wide(text));
+        self
+    }
+
+    /// Sets the DER encoded names of the certificate authorities accepted by
+    /// the server, as passed to `tls_stream::Builder::client_cert_selector`.
+    ///
+    /// If the list is empty, which is the default, certificates of any issuer
+    /// are listed.
+    pub fn issuers(&mut self, issuers: &[Vec<u8>]) -> &mut CertificateDialog {
+        self.issuers = issuers.to_vec();
+        self
+    }
+
+    /// Returns a store with the certificates of `store` the dialog lists.
+    pub fn candidates(&self, store: &CertStore) -> error::Result<CertStore> {
+        unsafe {
+            let mut issuers = self
+                .issuers
+                .iter()
+                .map(|issuer| Cryptography::CRYPTOAPI_BLOB {
+                    cbData: issuer.len() as u32,
+                    pbData: issuer.as_ptr() as *mut u8,
+                })
+                .collect::<Vec<_>>();
+            let mut para: Cryptography::CERT_CHAIN_FIND_BY_ISSUER_PARA = mem::zeroed();
+            para.cbSize = mem::size_of_val(&para) as u32;
+            para.pszUsageIdentifier = szOID_PKIX_KP_CLIENT_AUTH.as_ptr();
+            para.cIssuer = issuers.len() as u32;
+            para.rgIssuer = issuers.as_mut_ptr();
+
+            let mut candidates = Memory::new()?.into_store();
+            let mut chain = ptr::null_mut();
+            loop {
+                // Each call frees the chain found by the previous one.
+                chain = Cryptography::CertFindChainInStore(
+                    store.as_inner(),
+                    Cryptography::X509_ASN_ENCODING | Cryptography::PKCS_7_ASN_ENCODING,
+                    0,
+                    Cryptography::CERT_CHAIN_FIND_BY_ISSUER,
+                    &para as *const _ as *const _,
+                    chain,
+                );
+                if chain.is_null() {
+                    break;
+                }
+                let context = CertChainContext(Cryptography::CertDuplicateCertificateChain(chain));
+                let leaf = match context.get_chain(0).and_then(|chain| chain.get(0)) {
+                    Some(leaf) => leaf,
+                    None => continue,
+                };
+                let added = match leaf.is_time_valid() {
+                    Ok(true) => candidates.add_cert(&leaf, CertAdd::UseExisting).map(|_| ()),
+                    Ok(false) => Ok(()),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = added {
+                    Cryptography::CertFreeCertificateChain(chain);
+                    return Err(e);
+                }
+            }
+
+            let err = Win32Error::last();
+            match err.code() as i32 {
+                Foundation::CRYPT_E_NOT_FOUND => Ok(candidates),
+                _ => Err(err.into()),
+            }
+        }
+    }
+
+    /// Shows the dialog with the candidates of `store`, returning the
+    /// certificate selected by the user.
+    ///
+    /// Returns `None` if the user canceled the dialog, as well as without
+    /// showing it if `store` has no candidates.
+    pub fn select(&self, store: &CertStore) -> error::Result<Option<CertContext>> {
+        let candidates = self.candidates(store)?;
+        if candidates.certs().next().is_none() {
+            return Ok(None);
+        }
+
+        let title = self.title.as_ref().map_or(ptr::null(), |t| t.as_ptr());
+        let text = self.text.as_ref().map_or(ptr::null(), |t| t.as_ptr());
+        unsafe {
+            let cert = UI::CryptUIDlgSelectCertificateFromStore(
+                candidates.as_inner(),
+                self.window,
+                title,
+                text,
+                0,
+                0,
+                ptr::null(),
+            );
+            if cert.is_null() {
+                Ok(None)
+            } else {
+                Ok(Some(CertContext::from_inner(cert)))
+            }
+        }
+    }
+
+    /// Shows the dialog with the candidates of the current user's personal
+    /// ("My") store, see `select`.
+    pub fn select_from_user_store(&self) -> error::Result<Option<CertContext>> {
+        self.select(&CertStore::open_current_user("My")?)
+    }
+}
+
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(Some(0)).collect()
+}
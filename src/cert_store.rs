@@ -3,7 +3,6 @@
 use std::cmp;
 use std::ffi::OsStr;
 use std::fmt;
-use std::io;
 use std::mem;
 use std::os::windows::prelude::*;
 use std::ptr;
@@ -12,6 +11,7 @@ use windows_sys::Win32::Security::Cryptography;
 
 use crate::cert_context::CertContext;
 use crate::ctl_context::CtlContext;
+use crate::error::{self, Win32Error};
 use crate::Inner;
 
 /// Representation of certificate store on Windows, wrapping a `HCERTSTORE`.
@@ -102,7 +102,7 @@ impl CertStore {
     ///
     /// Common valid values for `which` are "My", "Root", "Trust", "CA".
     /// Additonal MSDN docs https://docs.microsoft.com/en-us/windows/win32/api/wincrypt/nf-wincrypt-certopenstore#remarks
-    pub fn open_current_user(which: &str) -> io::Result<CertStore> {
+    pub fn open_current_user(which: &str) -> error::Result<CertStore> {
         unsafe {
             let data = OsStr::new(which)
                 .encode_wide()
@@ -119,7 +119,7 @@ impl CertStore {
             if !store.is_null() {
                 Ok(CertStore(store))
             } else {
-                Err(io::Error::last_os_error())
+                Err(Win32Error::last().into())
             }
         }
     }
@@ -128,7 +128,7 @@ impl CertStore {
     ///
     /// Common valid values for `which` are "My", "Root", "Trust", "CA".
     /// Additonal MSDN docs https://docs.microsoft.com/en-us/windows/win32/api/wincrypt/nf-wincrypt-certopenstore#remarks
-    pub fn open_local_machine(which: &str) -> io::Result<CertStore> {
+    pub fn open_local_machine(which: &str) -> error::Result<CertStore> {
         unsafe {
             let data = OsStr::new(which)
                 .encode_wide()
@@ -145,7 +145,7 @@ impl CertStore {
             if !store.is_null() {
                 Ok(CertStore(store))
             } else {
-                Err(io::Error::last_os_error())
+                Err(Win32Error::last().into())
             }
         }
     }
@@ -154,7 +154,7 @@ impl CertStore {
     /// `CertStore` instance.
     ///
     /// The password must also be provided to decrypt the encoded data.
-    pub fn import_pkcs12(data: &[u8], password: Option<&str>) -> io::Result<CertStore> {
+    pub fn import_pkcs12(data: &[u8], password: Option<&str>) -> error::Result<CertStore> {
         unsafe {
             let blob = Cryptography::CRYPTOAPI_BLOB {
                 cbData: data.len() as u32,
@@ -176,7 +176,42 @@ impl CertStore {
             if !res.is_null() {
                 Ok(CertStore(res))
             } else {
-                Err(io::Error::last_os_error())
+                Err(Win32Error::last().into())
+            }
+        }
+    }
+
+    /// Opens a read-only store with the certificates of a serialized store,
+    /// such as the `.sst` files exported by the certificate manager.
+    pub fn open_serialized(data: &[u8]) -> error::Result<CertStore> {
+        CertStore::open_encoded(Cryptography::CERT_STORE_PROV_SERIALIZED, data)
+    }
+
+    /// Opens a read-only store with the certificates of a DER-encoded PKCS#7
+    /// message, such as the `.p7b` files exported by the certificate manager.
+    pub fn open_pkcs7(data: &[u8]) -> error::Result<CertStore> {
+        CertStore::open_encoded(Cryptography::CERT_STORE_PROV_PKCS7, data)
+    }
+
+    fn open_encoded(provider: windows_sys::core::PCSTR, data: &[u8]) -> error::Result<CertStore> {
+        unsafe {
+            let blob = Cryptography::CRYPTOAPI_BLOB {
+                cbData: data.len() as u32,
+                pbData: data.as_ptr() as *mut u8,
+            };
+            // The certificates are copied into the store, which doesn't
+            // refer to `data` once opened.
+            let store = Cryptography::CertOpenStore(
+                provider,
+                Cryptography::X509_ASN_ENCODING | Cryptography::PKCS_7_ASN_ENCODING,
+                Cryptography::HCRYPTPROV_LEGACY::default(),
+                Cryptography::CERT_STORE_READONLY_FLAG,
+                &blob as *const _ as *const _,
+            );
+            if !store.is_null() {
+                Ok(CertStore(store))
+            } else {
+                Err(Win32Error::last().into())
             }
         }
     }
@@ -193,7 +228,7 @@ impl CertStore {
     ///
     /// This function will add the certificate specified in `cx` to this store.
     /// A copy of the added certificate is returned.
-    pub fn add_cert(&mut self, cx: &CertContext, how: CertAdd) -> io::Result<CertContext> {
+    pub fn add_cert(&mut self, cx: &CertContext, how: CertAdd) -> error::Result<CertContext> {
         unsafe {
             let how = how as u32;
             let mut ret = ptr::null_mut();
@@ -204,7 +239,7 @@ impl CertStore {
                 &mut ret,
             );
             if res == 0 {
-                Err(io::Error::last_os_error())
+                Err(Win32Error::last().into())
             } else {
                 Ok(CertContext::from_inner(ret))
             }
@@ -215,7 +250,7 @@ impl CertStore {
     ///
     /// The password specified will be the password used to unlock the returned
     /// data.
-    pub fn export_pkcs12(&self, password: &str) -> io::Result<Vec<u8>> {
+    pub fn export_pkcs12(&self, password: &str) -> error::Result<Vec<u8>> {
         unsafe {
             let password = password.encode_utf16().chain(Some(0)).collect::<Vec<_>>();
             let mut blob = mem::zeroed();
@@ -226,7 +261,7 @@ impl CertStore {
                 Cryptography::EXPORT_PRIVATE_KEYS,
             );
             if res == 0 {
-                return Err(io::Error::last_os_error());
+                return Err(Win32Error::last().into());
             }
             let mut ret = Vec::with_capacity(blob.cbData as usize);
             blob.pbData = ret.as_mut_ptr();
@@ -237,7 +272,7 @@ impl CertStore {
                 Cryptography::EXPORT_PRIVATE_KEYS,
             );
             if res == 0 {
-                return Err(io::Error::last_os_error());
+                return Err(Win32Error::last().into());
             }
             ret.set_len(blob.cbData as usize);
             Ok(ret)
@@ -324,7 +359,7 @@ impl PfxImportOptions {
     }
 
     /// Imports certificates from a PKCS #12 archive, returning a `CertStore` containing them.
-    pub fn import(&self, data: &[u8]) -> io::Result<CertStore> {
+    pub fn import(&self, data: &[u8]) -> error::Result<CertStore> {
         unsafe {
             let blob = Cryptography::CRYPTOAPI_BLOB {
                 cbData: cmp::min(data.len(), u32::max_value() as usize) as u32,
@@ -336,7 +371,7 @@ impl PfxImportOptions {
             if !store.is_null() {
                 Ok(CertStore(store))
             } else {
-                Err(io::Error::last_os_error())
+                Err(Win32Error::last().into())
             }
         }
     }
@@ -352,7 +387,7 @@ impl Memory {
     /// can be added to.
     ///
     /// Initially the returned certificate store contains no certificates.
-    pub fn new() -> io::Result<Memory> {
+    pub fn new() -> error::Result<Memory> {
         unsafe {
             let store = Cryptography::CertOpenStore(
                 Cryptography::CERT_STORE_PROV_MEMORY,
@@ -364,7 +399,7 @@ impl Memory {
             if !store.is_null() {
                 Ok(Memory(CertStore(store)))
             } else {
-                Err(io::Error::last_os_error())
+                Err(Win32Error::last().into())
             }
         }
     }
@@ -372,7 +407,7 @@ impl Memory {
     /// Adds a new certificate to this memory store.
     ///
     /// For example the bytes could be a DER-encoded certificate.
-    pub fn add_encoded_certificate(&mut self, cert: &[u8]) -> io::Result<CertContext> {
+    pub fn add_encoded_certificate(&mut self, cert: &[u8]) -> error::Result<CertContext> {
         unsafe {
             let mut cert_context = ptr::null_mut();
 
@@ -387,7 +422,7 @@ impl Memory {
             if res != 0 {
                 Ok(CertContext::from_inner(cert_context))
             } else {
-                Err(io::Error::last_os_error())
+                Err(Win32Error::last().into())
             }
         }
     }
@@ -395,7 +430,7 @@ impl Memory {
     /// Adds a new CTL to this memory store, in its encoded form.
     ///
     /// This can be created through the `ctl_context::Builder` type.
-    pub fn add_encoded_ctl(&mut self, ctl: &[u8]) -> io::Result<CtlContext> {
+    pub fn add_encoded_ctl(&mut self, ctl: &[u8]) -> error::Result<CtlContext> {
         unsafe {
             let mut ctl_context = ptr::null_mut();
 
@@ -410,7 +445,7 @@ impl Memory {
             if res != 0 {
                 Ok(CtlContext::from_inner(ctl_context))
             } else {
-                Err(io::Error::last_os_error())
+                Err(Win32Error::last().into())
             }
         }
     }
@@ -447,6 +482,23 @@ mod test {
             .unwrap();
     }
 
+    #[test]
+    fn open_encoded_stores() {
+        let store = CertStore::open_serialized(include_bytes!("../test/certs.sst")).unwrap();
+        assert_eq!(store.certs().count(), 2);
+        let store = CertStore::open_pkcs7(include_bytes!("../test/certs.p7b")).unwrap();
+        assert_eq!(store.certs().count(), 2);
+
+        let cert = store.certs().next().unwrap();
+        let mut store = store;
+        assert!(store.add_cert(&cert, CertAdd::Always).is_err());
+
+        let cert = include_bytes!("../test/cert.der");
+        assert!(CertStore::open_serialized(cert).is_err());
+        assert!(CertStore::open_pkcs7(cert).is_err());
+        assert!(CertStore::open_pkcs7(&[]).is_err());
+    }
+
     #[test]
     fn pfx_import() {
         let pfx = include_bytes!("../test/identity.p12");
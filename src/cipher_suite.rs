@@ -0,0 +1,448 @@
+//! Enumeration of the cipher suites supported by the running system.
+use std::ffi::c_void;
+use std::io;
+use std::mem;
+use std::ptr;
+use std::slice;
+use std::sync::OnceLock;
+
+use windows_sys::Win32::Foundation;
+use windows_sys::Win32::Security::Authentication::Identity;
+use windows_sys::Win32::Security::Cryptography;
+use windows_sys::Win32::System::LibraryLoader;
+
+use crate::error::{self, CrateError, Error};
+use crate::schannel_cred::{Algorithm, Protocol};
+
+/// A cipher suite and the algorithms it is made of.
+///
+/// This describes the cipher suites enabled on the running system, as
+/// returned by `schannel::enumerate_cipher_suites`, as well as the cipher
+/// suite negotiated by a session, as returned by `TlsStream::cipher_suite`.
+///
+/// Algorithms are named by their CNG identifiers, such as `AES`, `SHA1` or
+/// `ECDH`. Names are empty for the parts a cipher suite doesn't specify: AEAD
+/// suites have no hash, and TLS 1.3 suites have neither a key exchange nor a
+/// certificate type.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct CipherSuiteInfo {
+    /// The name of the cipher suite, such as
+    /// `TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384`.
+    pub name: String,
+    /// The IANA identifier of the cipher suite, such as `0xc030`.
+    pub id: u32,
+    /// The protocols the cipher suite can be negotiated with.
+    ///
+    /// For a session, this is the negotiated protocol.
+    pub protocols: Vec<Protocol>,
+    /// The bulk encryption algorithm.
+    pub cipher: String,
+    /// The length of the cipher's key in bits.
+    pub cipher_bits: u32,
+    /// The block size of the cipher in bytes.
+    pub cipher_block_len: u32,
+    /// The hash algorithm of the MAC.
+    pub hash: String,
+    /// The length of the hash in bits.
+    pub hash_bits: u32,
+    /// The key exchange algorithm.
+    pub exchange: String,
+    /// The minimum length of the key exchange keys in bits.
+    pub min_exchange_bits: u32,
+    /// The maximum length of the key exchange keys in bits.
+    pub max_exchange_bits: u32,
+    /// The public key algorithm of the server's certificate.
+    pub certificate: String,
+}
+
+/// The algorithms which allow the ciphers of cipher suites, by CNG name and
+/// key length, 0 matching any length.
+const CIPHERS: &[(&str, u32, &[Algorithm])] = &[
+    ("AES", 128, &[Algorithm::Aes128, Algorithm::Aes]),
+    ("AES", 192, &[Algorithm::Aes192, Algorithm::Aes]),
+    ("AES", 256, &[Algorithm::Aes256, Algorithm::Aes]),
+    ("3DES", 0, &[Algorithm::TripleDes]),
+    ("3DES_112", 0, &[Algorithm::TripleDes112]),
+    ("DES", 0, &[Algorithm::Des]),
+    ("DESX", 0, &[Algorithm::Desx]),
+    ("RC2", 0, &[Algorithm::Rc2]),
+    ("RC4", 0, &[Algorithm::Rc4]),
+];
+
+/// The algorithms which allow the hashes of cipher suites.
+const HASHES: &[(&str, &[Algorithm])] = &[
+    ("MD5", &[Algorithm::Md5]),
+    ("SHA1", &[Algorithm::Sha1]),
+    ("SHA256", &[Algorithm::Sha256]),
+    ("SHA384", &[Algorithm::Sha384]),
+    ("SHA512", &[Algorithm::Sha512]),
+];
+
+/// The algorithms which allow the key exchanges of cipher suites.
+const EXCHANGES: &[(&str, &[Algorithm])] = &[
+    ("ECDH", &[Algorithm::EcdhEphem, Algorithm::Ecdh]),
+    ("DH", &[Algorithm::DhEphem]),
+    ("RSA", &[Algorithm::RsaKeyx]),
+];
+
+/// The algorithms which allow the certificate types of cipher suites.
+const CERTIFICATES: &[(&str, &[Algorithm])] = &[
+    ("ECDSA", &[Algorithm::Ecdsa]),
+    ("RSA", &[Algorithm::RsaSign]),
+    ("DSA", &[Algorithm::DssSign]),
+    ("DSS", &[Algorithm::DssSign]),
+];
+
+impl CipherSuiteInfo {
+    fn from_raw(raw: &SslCipherSuite) -> CipherSuiteInfo {
+        CipherSuiteInfo {
+            name: string(&raw.szCipherSuite),
+            id: raw.dwCipherSuite,
+            protocols: protocol(raw.dwProtocol).into_iter().collect(),
+            cipher: string(&raw.szCipher),
+            cipher_bits: raw.dwCipherLen,
+            cipher_block_len: raw.dwCipherBlockLen,
+            hash: string(&raw.szHash),
+            hash_bits: raw.dwHashLen,
+            exchange: string(&raw.szExchange),
+            min_exchange_bits: raw.dwMinExchangeLen,
+            max_exchange_bits: raw.dwMaxExchangeLen,
+            certificate: string(&raw.szCertificate),
+        }
+    }
+
+    pub(crate) fn from_cipher_info(info: &Identity::SecPkgContext_CipherInfo) -> CipherSuiteInfo {
+        CipherSuiteInfo::from_raw(&SslCipherSuite {
+            dwProtocol: info.dwProtocol,
+            dwCipherSuite: info.dwCipherSuite,
+            dwBaseCipherSuite: info.dwBaseCipherSuite,
+            szCipherSuite: info.szCipherSuite,
+            szCipher: info.szCipher,
+            dwCipherLen: info.dwCipherLen,
+            dwCipherBlockLen: info.dwCipherBlockLen,
+            szHash: info.szHash,
+            dwHashLen: info.dwHashLen,
+            szExchange: info.szExchange,
+            dwMinExchangeLen: info.dwMinExchangeLen,
+            dwMaxExchangeLen: info.dwMaxExchangeLen,
+            szCertificate: info.szCertificate,
+            dwKeyType: info.dwKeyType,
+        })
+    }
+
+    /// Returns, for each part of the cipher suite with a known algorithm,
+    /// the `Algorithm`s which allow it in `schannel_cred::Builder::supported_algorithms`.
+    fn components(&self) -> Vec<&'static [Algorithm]> {
+        let mut components = Vec::with_capacity(4);
+        let cipher = CIPHERS.iter().find(|&&(name, bits, _)| {
+            name.eq_ignore_ascii_case(&self.cipher) && (bits == 0 || bits == self.cipher_bits)
+        });
+        components.extend(cipher.map(|&(_, _, algorithms)| algorithms));
+        // AEAD suites have no MAC, but end with the hash of their PRF
+        let hash = match &*self.hash {
+            "" => self.name.rsplit('_').next().unwrap_or(""),
+            hash => hash,
+        };
+        for &(table, name) in &[
+            (HASHES, hash),
+            (EXCHANGES, &*self.exchange),
+            (CERTIFICATES, &*self.certificate),
+        ] {
+            let component = table.iter().find(|&&(n, _)| n.eq_ignore_ascii_case(name));
+            components.extend(component.map(|&(_, algorithms)| algorithms));
+        }
+        components
+    }
+
+    /// Returns true if every part of the cipher suite is allowed by one of
+    /// `algorithms`.
+    pub(crate) fn allowed_by(&self, algorithms: &[Algorithm]) -> bool {
+        self.components()
+            .iter()
+            .all(|component| component.iter().any(|a| algorithms.contains(a)))
+    }
+
+    /// Returns true if `algorithm` allows a part of the cipher suite.
+    pub(crate) fn uses(&self, algorithm: Algorithm) -> bool {
+        self.components()
+            .iter()
+            .any(|component| component.contains(&algorithm))
+    }
+}
+
+/// Returns true if `algorithm` can allow a part of a cipher suite, so that
+/// `CipherSuiteInfo::uses` can tell whether any cipher suite needs it.
+pub(crate) fn names_component(algorithm: Algorithm) -> bool {
+    CIPHERS.iter().any(|&(_, _, a)| a.contains(&algorithm))
+        || [HASHES, EXCHANGES, CERTIFICATES]
+            .iter()
+            .any(|table| table.iter().any(|&(_, a)| a.contains(&algorithm)))
+}
+
+/// Returns the cipher suites enabled on the running system, in the order
+/// Schannel prefers them.
+///
+/// The cipher suites are listed with `BCryptEnumContextFunctions`, which
+/// reflects the system configuration and group policy, and are described by
+/// the Microsoft SSL Protocol Provider. Use
+/// `schannel_cred::Builder::unsatisfiable` to find the settings of a builder
+/// which none of them can satisfy.
+pub fn enumerate_cipher_suites() -> error::Result<Vec<CipherSuiteInfo>> {
+    let names = enabled_cipher_suites()?;
+    let described = provider_cipher_suites()?;
+    let suites = names
+        .into_iter()
+        .map(|name| {
+            let mut matching = described.iter().filter(|suite| suite.name == name);
+            let mut info = match matching.next() {
+                Some(suite) => suite.clone(),
+                None => {
+                    return CipherSuiteInfo {
+                        name,
+                        ..CipherSuiteInfo::default()
+                    }
+                }
+            };
+            // the provider describes a cipher suite once for each protocol
+            for suite in matching {
+                for &protocol in &suite.protocols {
+                    if !info.protocols.contains(&protocol) {
+                        info.protocols.push(protocol);
+                    }
+                }
+            }
+            info
+        })
+        .collect();
+    Ok(suites)
+}
+
+/// Returns the names of the cipher suites enabled in the SSL context.
+fn enabled_cipher_suites() -> error::Result<Vec<String>> {
+    let context = "SSL\0".encode_utf16().collect::<Vec<_>>();
+    unsafe {
+        let mut len = 0;
+        let mut functions = ptr::null_mut();
+        match Cryptography::BCryptEnumContextFunctions(
+            Cryptography::CRYPT_LOCAL,
+            context.as_ptr(),
+            Cryptography::NCRYPT_SCHANNEL_INTERFACE,
+            &mut len,
+            &mut functions,
+        ) {
+            0 => {}
+            Foundation::STATUS_NOT_FOUND => return Ok(Vec::new()),
+            // an NTSTATUS, which is neither a SECURITY_STATUS nor a Win32 error
+            status => return Err(CrateError::Io(io::Error::from_raw_os_error(status))),
+        }
+        let names = slice::from_raw_parts(
+            (*functions).rgpszFunctions,
+            (*functions).cFunctions as usize,
+        )
+        .iter()
+        .map(|&name| {
+            let len = (0..).take_while(|&i| *name.add(i) != 0).count();
+            String::from_utf16_lossy(slice::from_raw_parts(name, len))
+        })
+        .collect();
+        Cryptography::BCryptFreeBuffer(functions as *const _);
+        Ok(names)
+    }
+}
+
+/// Returns the cipher suites described by the Microsoft SSL Protocol
+/// Provider, once for each protocol they can be negotiated with.
+fn provider_cipher_suites() -> error::Result<Vec<CipherSuiteInfo>> {
+    let functions = ssl_functions().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the SSL protocol provider is not available",
+        )
+    })?;
+    let provider_name = "Microsoft SSL Protocol Provider\0"
+        .encode_utf16()
+        .collect::<Vec<_>>();
+    unsafe {
+        let mut provider = 0;
+        let status = (functions.open_provider)(&mut provider, provider_name.as_ptr(), 0);
+        if status != 0 {
+            return Err(Error::from_code(status).into());
+        }
+
+        let mut suites = Vec::new();
+        let mut state = ptr::null_mut();
+        let result = loop {
+            let mut suite = ptr::null_mut();
+            match (functions.enum_cipher_suites)(provider, 0, &mut suite, &mut state, 0) {
+                0 => {
+                    suites.push(CipherSuiteInfo::from_raw(&*suite));
+                    (functions.free_buffer)(suite as *mut _);
+                }
+                Foundation::NTE_NO_MORE_ITEMS => break Ok(suites),
+                status => break Err(Error::from_code(status).into()),
+            }
+        };
+        if !state.is_null() {
+            (functions.free_buffer)(state);
+        }
+        (functions.free_object)(provider, 0);
+        result
+    }
+}
+
+/// Returns the protocol of an SSL protocol version number.
+fn protocol(version: u32) -> Option<Protocol> {
+    match version {
+        0x0300 => Some(Protocol::Ssl3),
+        0x0301 => Some(Protocol::Tls10),
+        0x0302 => Some(Protocol::Tls11),
+        0x0303 => Some(Protocol::Tls12),
+        0x0304 => Some(Protocol::Tls13),
+        _ => None,
+    }
+}
+
+fn string(wide: &[u16]) -> String {
+    let len = wide.iter().position(|&c| c == 0).unwrap_or(wide.len());
+    String::from_utf16_lossy(&wide[..len])
+}
+
+/// `NCRYPT_SSL_CIPHER_SUITE`, which windows-sys doesn't define.
+#[repr(C)]
+#[allow(non_snake_case)]
+struct SslCipherSuite {
+    dwProtocol: u32,
+    dwCipherSuite: u32,
+    dwBaseCipherSuite: u32,
+    szCipherSuite: [u16; 64],
+    szCipher: [u16; 64],
+    dwCipherLen: u32,
+    dwCipherBlockLen: u32,
+    szHash: [u16; 64],
+    dwHashLen: u32,
+    szExchange: [u16; 64],
+    dwMinExchangeLen: u32,
+    dwMaxExchangeLen: u32,
+    szCertificate: [u16; 64],
+    dwKeyType: u32,
+}
+
+type Function = unsafe extern "system" fn() -> isize;
+type SslOpenProvider = unsafe extern "system" fn(*mut usize, *const u16, u32) -> i32;
+type SslEnumCipherSuites =
+    unsafe extern "system" fn(usize, usize, *mut *mut SslCipherSuite, *mut *mut c_void, u32) -> i32;
+type SslFreeBuffer = unsafe extern "system" fn(*mut c_void) -> i32;
+type SslFreeObject = unsafe extern "system" fn(usize, u32) -> i32;
+
+struct SslFunctions {
+    open_provider: SslOpenProvider,
+    enum_cipher_suites: SslEnumCipherSuites,
+    free_buffer: SslFreeBuffer,
+    free_object: SslFreeObject,
+}
+
+/// Returns the functions of the SSL protocol provider, which windows-sys
+/// doesn't bind, loading them from `ncrypt.dll` on first use.
+///
+/// The module is never released.
+fn ssl_functions() -> Option<&'static SslFunctions> {
+    static FUNCTIONS: OnceLock<Option<SslFunctions>> = OnceLock::new();
+    FUNCTIONS
+        .get_or_init(|| unsafe {
+            let module = LibraryLoader::LoadLibraryExA(
+                b"ncrypt.dll\0".as_ptr(),
+                0,
+                LibraryLoader::LOAD_LIBRARY_SEARCH_SYSTEM32,
+            );
+            if module == 0 {
+                return None;
+            }
+            let function = |name: &[u8]| LibraryLoader::GetProcAddress(module, name.as_ptr());
+            let open_provider = function(b"SslOpenProvider\0")?;
+            let enum_cipher_suites = function(b"SslEnumCipherSuites\0")?;
+            let free_buffer = function(b"SslFreeBuffer\0")?;
+            let free_object = function(b"SslFreeObject\0")?;
+            Some(SslFunctions {
+                open_provider: mem::transmute::<Function, SslOpenProvider>(open_provider),
+                enum_cipher_suites: mem::transmute::<Function, SslEnumCipherSuites>(
+                    enum_cipher_suites,
+                ),
+                free_buffer: mem::transmute::<Function, SslFreeBuffer>(free_buffer),
+                free_object: mem::transmute::<Function, SslFreeObject>(free_object),
+            })
+        })
+        .as_ref()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn enumerate() {
+        let suites = enumerate_cipher_suites().unwrap();
+        assert!(!suites.is_empty());
+        assert!(
+            suites
+                .iter()
+                .any(|suite| suite.cipher == "AES" && suite.name.contains("_GCM_")),
+            "{:#?}",
+            suites
+        );
+        for suite in &suites {
+            assert!(suite.name.starts_with("TLS_"), "{:?}", suite);
+        }
+
+        let suite = suites
+            .iter()
+            .find(|suite| suite.name == "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384");
+        if let Some(suite) = suite {
+            assert_eq!(suite.id, 0xc030);
+            assert_eq!(suite.cipher_bits, 256);
+            assert_eq!(suite.exchange, "ECDH");
+            assert_eq!(suite.certificate, "RSA");
+            assert!(suite.protocols.contains(&Protocol::Tls12), "{:?}", suite);
+        }
+    }
+
+    #[test]
+    fn components() {
+        let suite = CipherSuiteInfo {
+            name: "TLS_ECDHE_RSA_WITH_AES_128_CBC_SHA".to_string(),
+            cipher: "AES".to_string(),
+            cipher_bits: 128,
+            hash: "SHA1".to_string(),
+            exchange: "ECDH".to_string(),
+            certificate: "RSA".to_string(),
+            ..CipherSuiteInfo::default()
+        };
+        assert!(suite.uses(Algorithm::Aes128));
+        assert!(suite.uses(Algorithm::Aes));
+        assert!(!suite.uses(Algorithm::Aes256));
+        assert!(suite.uses(Algorithm::RsaSign));
+        assert!(!suite.uses(Algorithm::RsaKeyx));
+
+        let algorithms = [
+            Algorithm::Aes,
+            Algorithm::Sha1,
+            Algorithm::EcdhEphem,
+            Algorithm::RsaSign,
+        ];
+        assert!(suite.allowed_by(&algorithms));
+        assert!(!suite.allowed_by(&algorithms[1..]));
+        assert!(!suite.allowed_by(&algorithms[..3]));
+
+        let suite = CipherSuiteInfo {
+            name: "TLS_AES_256_GCM_SHA384".to_string(),
+            cipher: "AES".to_string(),
+            cipher_bits: 256,
+            ..CipherSuiteInfo::default()
+        };
+        assert!(suite.allowed_by(&[Algorithm::Aes256, Algorithm::Sha384]));
+        assert!(!suite.allowed_by(&[Algorithm::Aes256, Algorithm::Sha256]));
+
+        assert!(names_component(Algorithm::Sha384));
+        assert!(!names_component(Algorithm::Hmac));
+    }
+}
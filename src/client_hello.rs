@@ -0,0 +1,191 @@
+//! Parsing of the server name in a ClientHello.
+//!
+//! Servers use this to report the name a client asked for on systems where
+//! Schannel doesn't expose it, see `TlsEngine::sni_hostname`. Only a
+//! ClientHello which fits in its first record is understood.
+use std::str;
+
+struct Parser<'a>(&'a [u8]);
+
+impl<'a> Parser<'a> {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<usize> {
+        self.bytes(2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.bytes(3)
+            .map(|b| u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize)
+    }
+
+    /// Reads a vector with a one byte length.
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.bytes(len)
+    }
+
+    /// Reads a vector with a two byte length.
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()?;
+        self.bytes(len)
+    }
+}
+
+const HANDSHAKE: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+const SERVER_NAME: usize = 0;
+const HOST_NAME: u8 = 0;
+
+/// Returns the host name of the server_name extension of the ClientHello
+/// at the start of `data`, which holds the records received from a client.
+///
+/// Returns `None` if the data doesn't start with a complete record with a
+/// ClientHello, or if the ClientHello has no host name.
+pub(crate) fn server_name(data: &[u8]) -> Option<String> {
+    let mut record = Parser(data);
+    if record.u8()? != HANDSHAKE {
+        return None;
+    }
+    record.bytes(2)?;
+    let mut handshake = Parser(record.vec16()?);
+    if handshake.u8()? != CLIENT_HELLO {
+        return None;
+    }
+    let len = handshake.u24()?;
+    let mut hello = Parser(handshake.bytes(len)?);
+    // the legacy version and the random
+    hello.bytes(2 + 32)?;
+    hello.vec8()?; // session id
+    hello.vec16()?; // cipher suites
+    hello.vec8()?; // compression methods
+
+    let mut extensions = Parser(hello.vec16()?);
+    while !extensions.is_empty() {
+        let extension = extensions.u16()?;
+        let data = extensions.vec16()?;
+        if extension != SERVER_NAME {
+            continue;
+        }
+        let mut names = Parser(Parser(data).vec16()?);
+        while !names.is_empty() {
+            let name_type = names.u8()?;
+            let name = names.vec16()?;
+            if name_type == HOST_NAME && !name.is_empty() {
+                return str::from_utf8(name).ok().map(str::to_owned);
+            }
+        }
+        return None;
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Builds a record with a ClientHello with the given extensions.
+    fn client_hello(extensions: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0xaa; 32]);
+        hello.push(0); // session id
+        hello.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]);
+        hello.extend_from_slice(&[0x01, 0x00]);
+        let mut data = Vec::new();
+        for &(extension, ref body) in extensions {
+            data.extend_from_slice(&extension.to_be_bytes());
+            data.extend_from_slice(&(body.len() as u16).to_be_bytes());
+            data.extend_from_slice(body);
+        }
+        hello.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&data);
+
+        let mut record = vec![HANDSHAKE, 0x03, 0x01];
+        record.extend_from_slice(&(hello.len() as u16 + 4).to_be_bytes());
+        record.push(CLIENT_HELLO);
+        record.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        record.extend_from_slice(&hello);
+        record
+    }
+
+    fn server_name_extension(names: &[(u8, &[u8])]) -> (u16, Vec<u8>) {
+        let mut list = Vec::new();
+        for &(name_type, name) in names {
+            list.push(name_type);
+            list.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            list.extend_from_slice(name);
+        }
+        let mut body = (list.len() as u16).to_be_bytes().to_vec();
+        body.extend_from_slice(&list);
+        (SERVER_NAME as u16, body)
+    }
+
+    #[test]
+    fn host_name() {
+        let supported_versions = (43, vec![0x02, 0x03, 0x04]);
+        let hello = client_hello(&[
+            supported_versions.clone(),
+            server_name_extension(&[(HOST_NAME, b"alpha.test")]),
+            supported_versions,
+        ]);
+        assert_eq!(server_name(&hello).as_deref(), Some("alpha.test"));
+
+        // records following the ClientHello are ignored
+        let mut data = hello.clone();
+        data.extend_from_slice(&[HANDSHAKE, 0x03, 0x03, 0x00, 0x00]);
+        assert_eq!(server_name(&data).as_deref(), Some("alpha.test"));
+
+        let hello = client_hello(&[server_name_extension(&[
+            (1, b"other"),
+            (HOST_NAME, b"beta.test"),
+        ])]);
+        assert_eq!(server_name(&hello).as_deref(), Some("beta.test"));
+    }
+
+    #[test]
+    fn no_host_name() {
+        assert_eq!(server_name(&client_hello(&[])), None);
+        assert_eq!(server_name(&client_hello(&[(43, vec![0x00])])), None);
+        let hello = client_hello(&[server_name_extension(&[(1, b"other")])]);
+        assert_eq!(server_name(&hello), None);
+        let hello = client_hello(&[server_name_extension(&[(HOST_NAME, b"\xff")])]);
+        assert_eq!(server_name(&hello), None);
+    }
+
+    #[test]
+    fn malformed() {
+        let hello = client_hello(&[server_name_extension(&[(HOST_NAME, b"alpha.test")])]);
+        for len in 0..hello.len() {
+            assert_eq!(server_name(&hello[..len]), None, "{}", len);
+        }
+
+        let mut server_hello = hello.clone();
+        server_hello[5] = 0x02;
+        assert_eq!(server_name(&server_hello), None);
+        let mut alert = hello.clone();
+        alert[0] = 0x15;
+        assert_eq!(server_name(&alert), None);
+
+        // an extension running past the end of the extensions
+        let mut hello = hello;
+        let len = hello.len();
+        hello[len - 16] += 1;
+        assert_eq!(server_name(&hello), None);
+    }
+}
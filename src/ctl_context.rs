@@ -2,13 +2,13 @@
 
 #![allow(dead_code)]
 
-use std::io;
 use std::mem;
 use std::ptr;
 
 use windows_sys::Win32::Security::Cryptography;
 
 use crate::cert_context::CertContext;
+use crate::error::{self, Win32Error};
 use crate::Inner;
 
 static szOID_OIWSEC_sha1: &[u8] = null_terminate!(Cryptography::szOID_OIWSEC_sha1);
@@ -79,7 +79,7 @@ impl Builder {
     /// into a CTL.
     ///
     /// This can later be passed to `Memory::add_encoded_ctl`.
-    pub fn encode_and_sign(&self) -> io::Result<Vec<u8>> {
+    pub fn encode_and_sign(&self) -> error::Result<Vec<u8>> {
         unsafe {
             let encoding = Cryptography::X509_ASN_ENCODING | Cryptography::PKCS_7_ASN_ENCODING;
 
@@ -131,7 +131,7 @@ impl Builder {
                 &mut size,
             );
             if res == 0 {
-                return Err(io::Error::last_os_error());
+                return Err(Win32Error::last().into());
             }
 
             let mut encoded = vec![0; size as usize];
@@ -145,7 +145,7 @@ impl Builder {
                 &mut size,
             );
             if res == 0 {
-                return Err(io::Error::last_os_error());
+                return Err(Win32Error::last().into());
             }
 
             Ok(encoded)
@@ -153,7 +153,7 @@ impl Builder {
     }
 }
 
-fn cert_entry(cert: &CertContext) -> io::Result<Vec<u8>> {
+fn cert_entry(cert: &CertContext) -> error::Result<Vec<u8>> {
     unsafe {
         let mut size: u32 = 0;
 
@@ -167,7 +167,7 @@ fn cert_entry(cert: &CertContext) -> io::Result<Vec<u8>> {
             &mut size,
         );
         if res == 0 {
-            return Err(io::Error::last_os_error());
+            return Err(Win32Error::last().into());
         }
 
         let mut entry = vec![0u8; size as usize];
@@ -181,7 +181,7 @@ fn cert_entry(cert: &CertContext) -> io::Result<Vec<u8>> {
             &mut size,
         );
         if res == 0 {
-            Err(io::Error::last_os_error())
+            Err(Win32Error::last().into())
         } else {
             Ok(entry)
         }
@@ -0,0 +1,81 @@
+//! Transports made of a separate reader and writer.
+use std::io::{self, Read, Write};
+
+/// A stream reading from one object and writing to another.
+///
+/// This allows a `TlsStream` to run over transports which are split into a
+/// reading and a writing half, such as the two ends of a pair of pipes:
+///
+/// ```no_run
+/// use std::process::{Command, Stdio};
+///
+/// use schannel::duplex::Duplex;
+/// use schannel::schannel_cred::SchannelCred;
+/// use schannel::tls_stream;
+///
+/// let mut child = Command::new("proxy")
+///     .stdin(Stdio::piped())
+///     .stdout(Stdio::piped())
+///     .spawn()
+///     .unwrap();
+/// let stream = Duplex::new(child.stdout.take().unwrap(), child.stdin.take().unwrap());
+/// let cred = SchannelCred::builder().acquire_client().unwrap();
+/// let stream = tls_stream::Builder::new()
+///     .domain("example.com")
+///     .connect(cred, stream)
+///     .unwrap();
+/// let (reader, writer) = stream.into_inner().unwrap().0.into_inner();
+/// ```
+#[derive(Debug)]
+pub struct Duplex<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R, W> Duplex<R, W> {
+    /// Creates a stream reading from `reader` and writing to `writer`.
+    pub fn new(reader: R, writer: W) -> Duplex<R, W> {
+        Duplex { reader, writer }
+    }
+
+    /// Returns a reference to the reader.
+    pub fn reader(&self) -> &R {
+        &self.reader
+    }
+
+    /// Returns a mutable reference to the reader.
+    pub fn reader_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Returns a reference to the writer.
+    pub fn writer(&self) -> &W {
+        &self.writer
+    }
+
+    /// Returns a mutable reference to the writer.
+    pub fn writer_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// Consumes the stream, returning the reader and the writer.
+    pub fn into_inner(self) -> (R, W) {
+        (self.reader, self.writer)
+    }
+}
+
+impl<R: Read, W> Read for Duplex<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl<R, W: Write> Write for Duplex<R, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
@@ -0,0 +1,849 @@
+//! Errors reported by Schannel, certificate validation and CryptoAPI.
+//!
+//! Functions of this crate fail with codes from two spaces which overlap:
+//! Schannel and certificate validation report `SECURITY_STATUS` codes,
+//! represented by `Error`, while the certificate, store and key functions of
+//! CryptoAPI report Win32 codes through `GetLastError`, represented by
+//! `Win32Error`. Functions outside of TLS streams return a `CrateError`,
+//! which tells them apart from each other and from other I/O errors. Streams
+//! and their builders keep returning `io::Error`s, as `Read` and `Write` do,
+//! which `CrateError::from` classifies the same way.
+use std::error;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::ptr;
+use std::sync::atomic::{AtomicIsize, Ordering};
+use std::sync::Once;
+
+use windows_sys::Win32::Foundation;
+use windows_sys::Win32::System::Diagnostics::Debug;
+use windows_sys::Win32::System::LibraryLoader;
+
+const FACILITY_SECURITY: u32 = 9;
+const FACILITY_CERT: u32 = 11;
+
+/// The libraries whose message tables describe the codes missing from the
+/// system table.
+const MESSAGE_MODULES: [&[u8]; 3] = [b"secur32.dll\0", b"ntdll.dll\0", b"crypt32.dll\0"];
+
+/// Descriptions of common codes, used if no message table describes them.
+const DESCRIPTIONS: &[(i32, &str)] = &[
+    (
+        Foundation::SEC_E_UNTRUSTED_ROOT,
+        "The certificate chain was issued by an authority that is not trusted.",
+    ),
+    (
+        Foundation::SEC_E_CERT_EXPIRED,
+        "The received certificate has expired.",
+    ),
+    (
+        Foundation::SEC_E_WRONG_PRINCIPAL,
+        "The target principal name is incorrect.",
+    ),
+    (
+        Foundation::SEC_E_ALGORITHM_MISMATCH,
+        "The client and server cannot communicate, because they do not possess a common algorithm.",
+    ),
+    (
+        Foundation::SEC_E_UNSUPPORTED_FUNCTION,
+        "The function requested is not supported.",
+    ),
+    (
+        Foundation::SEC_E_ILLEGAL_MESSAGE,
+        "The message received was unexpected or badly formatted.",
+    ),
+    (
+        Foundation::SEC_E_INCOMPLETE_MESSAGE,
+        "The supplied message is incomplete.",
+    ),
+    (
+        Foundation::SEC_E_INCOMPLETE_CREDENTIALS,
+        "The credentials supplied were not complete, and could not be verified.",
+    ),
+    (
+        Foundation::SEC_E_CONTEXT_EXPIRED,
+        "The context has expired and can no longer be used.",
+    ),
+    (
+        Foundation::SEC_E_MESSAGE_ALTERED,
+        "The message or signature supplied for verification has been altered.",
+    ),
+    (
+        Foundation::SEC_E_DECRYPT_FAILURE,
+        "The specified data could not be decrypted.",
+    ),
+    (
+        Foundation::SEC_E_INTERNAL_ERROR,
+        "The Local Security Authority cannot be contacted.",
+    ),
+    (
+        Foundation::SEC_E_NO_CREDENTIALS,
+        "No credentials are available in the security package.",
+    ),
+    (
+        Foundation::NTE_SILENT_CONTEXT,
+        "Provider could not perform the action since the context was acquired as silent.",
+    ),
+    (
+        Foundation::CERT_E_EXPIRED,
+        "A required certificate is not within its validity period.",
+    ),
+    (
+        Foundation::CERT_E_UNTRUSTEDROOT,
+        "A certificate chain processed, but terminated in a root certificate which is not trusted.",
+    ),
+    (
+        Foundation::CERT_E_CHAINING,
+        "A certificate chain could not be built to a trusted root authority.",
+    ),
+    (
+        Foundation::CERT_E_CN_NO_MATCH,
+        "The certificate's CN name does not match the passed value.",
+    ),
+    (
+        Foundation::CERT_E_WRONG_USAGE,
+        "The certificate is not valid for the requested usage.",
+    ),
+    (
+        Foundation::CERTSRV_E_WEAK_SIGNATURE_OR_KEY,
+        "A signature algorithm or public key length does not meet the system's minimum required strength.",
+    ),
+    (
+        Foundation::CERT_E_REVOKED,
+        "A certificate was explicitly revoked by its issuer.",
+    ),
+    (
+        Foundation::CRYPT_E_REVOKED,
+        "The certificate is revoked.",
+    ),
+    (
+        Foundation::CRYPT_E_NO_REVOCATION_CHECK,
+        "The revocation function was unable to check revocation for the certificate.",
+    ),
+    (
+        Foundation::CRYPT_E_REVOCATION_OFFLINE,
+        "The revocation function was unable to check revocation because the revocation server was offline.",
+    ),
+];
+
+/// A `SECURITY_STATUS` error code reported by Schannel or by the validation
+/// of a peer's certificate.
+///
+/// Errors reported by a call into Schannel during a TLS session also record
+/// the name of the failing function and the phase of the session it was
+/// made in, and are wrapped in the `io::Error`s returned by this crate.
+/// Other errors carry their code as the raw OS error. Use
+/// `Error::from_io_error` to recover them in either case.
+///
+/// Errors are compared and hashed by their code only.
+#[derive(Copy, Clone)]
+pub struct Error {
+    code: i32,
+    operation: Option<&'static str>,
+    phase: Option<&'static str>,
+}
+
+impl Error {
+    /// Creates an error from a `SECURITY_STATUS` code such as
+    /// `SEC_E_UNTRUSTED_ROOT` or `CERT_E_EXPIRED`.
+    pub fn from_code(code: i32) -> Error {
+        Error {
+            code,
+            operation: None,
+            phase: None,
+        }
+    }
+
+    /// Records the name of the function which failed with this error and the
+    /// phase of the TLS session it was called in.
+    pub(crate) fn with_context(mut self, operation: &'static str, phase: &'static str) -> Error {
+        self.operation = Some(operation);
+        self.phase = Some(phase);
+        self
+    }
+
+    /// Returns the `SECURITY_STATUS` code of this error.
+    pub fn code(&self) -> i32 {
+        self.code
+    }
+
+    /// Returns the name of the Schannel or CryptoAPI function which failed,
+    /// such as `"InitializeSecurityContextW"` or `"DecryptMessage"`, if known.
+    pub fn operation(&self) -> Option<&'static str> {
+        self.operation
+    }
+
+    /// Returns the phase of the TLS session the error occurred in, if known.
+    ///
+    /// This is one of `"handshake"`, `"renegotiation"`, `"data transfer"` or
+    /// `"shutdown"`.
+    pub fn phase(&self) -> Option<&'static str> {
+        self.phase
+    }
+
+    /// Returns the kind of failure this error represents.
+    pub fn kind(&self) -> ErrorKind {
+        match self.code {
+            Foundation::SEC_E_UNTRUSTED_ROOT
+            | Foundation::SEC_E_ISSUING_CA_UNTRUSTED
+            | Foundation::CERT_E_UNTRUSTEDROOT
+            | Foundation::CERT_E_UNTRUSTEDCA
+            | Foundation::CERT_E_UNTRUSTEDTESTROOT
+            | Foundation::CERT_E_CHAINING => ErrorKind::UntrustedRoot,
+            Foundation::SEC_E_CERT_EXPIRED
+            | Foundation::SEC_E_KDC_CERT_EXPIRED
+            | Foundation::SEC_E_SMARTCARD_CERT_EXPIRED
+            | Foundation::CERT_E_EXPIRED
+            | Foundation::CERT_E_VALIDITYPERIODNESTING => ErrorKind::CertExpired,
+            Foundation::SEC_E_WRONG_PRINCIPAL | Foundation::CERT_E_CN_NO_MATCH => {
+                ErrorKind::CertNameMismatch
+            }
+            Foundation::SEC_E_KDC_CERT_REVOKED
+            | Foundation::SEC_E_SMARTCARD_CERT_REVOKED
+            | Foundation::CERT_E_REVOKED
+            | Foundation::CRYPT_E_REVOKED => ErrorKind::CertRevoked,
+            Foundation::CERT_E_WRONG_USAGE => ErrorKind::CertWrongUsage,
+            Foundation::CERTSRV_E_WEAK_SIGNATURE_OR_KEY => ErrorKind::WeakKey,
+            Foundation::SEC_E_ALGORITHM_MISMATCH => ErrorKind::AlgorithmMismatch,
+            // Schannel reports a lack of common protocol versions as an
+            // unsupported function.
+            Foundation::SEC_E_UNSUPPORTED_FUNCTION | Foundation::SEC_E_DOWNGRADE_DETECTED => {
+                ErrorKind::ProtocolVersion
+            }
+            Foundation::SEC_E_INCOMPLETE_MESSAGE => ErrorKind::IncompleteMessage,
+            Foundation::SEC_E_CONTEXT_EXPIRED | Foundation::SEC_I_CONTEXT_EXPIRED => {
+                ErrorKind::ContextExpired
+            }
+            Foundation::NTE_SILENT_CONTEXT => ErrorKind::InteractionRequired,
+            _ => ErrorKind::Other,
+        }
+    }
+
+    /// Returns a description of this error.
+    ///
+    /// Many Schannel codes are missing from the system message table, so
+    /// the tables of the libraries implementing Schannel and certificate
+    /// validation are searched as well.
+    pub fn message(&self) -> String {
+        if let Some(message) = lookup_message(self.code) {
+            return message;
+        }
+        match DESCRIPTIONS.iter().find(|&&(code, _)| code == self.code) {
+            Some(&(_, description)) => description.to_string(),
+            None => format!("unknown error {:#010x}", self.code),
+        }
+    }
+
+    /// Extracts the Schannel error from an I/O error returned by this crate.
+    ///
+    /// Returns `None` if the error didn't originate from Schannel, for
+    /// example if it was returned by the underlying stream.
+    pub fn from_io_error(err: &io::Error) -> Option<Error> {
+        if let Some(err) = err.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+            return Some(*err);
+        }
+        match err.raw_os_error() {
+            Some(code) if is_security_status(code) => Some(Error::from_code(code)),
+            _ => None,
+        }
+    }
+}
+
+/// The kind of failure an `Error` represents.
+///
+/// This groups the codes of failures which are commonly handled differently
+/// from others, so that they can be matched on without the Windows
+/// constants.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The peer's certificate isn't issued by a trusted certificate
+    /// authority.
+    UntrustedRoot,
+    /// The peer's certificate, or a certificate in its chain, has expired or
+    /// isn't valid yet.
+    CertExpired,
+    /// The peer's certificate isn't valid for the requested host name.
+    CertNameMismatch,
+    /// The peer's certificate, or a certificate in its chain, has been
+    /// revoked.
+    CertRevoked,
+    /// The peer's certificate isn't valid for the usages it is required to
+    /// have, see `tls_stream::Builder::required_eku`.
+    CertWrongUsage,
+    /// A key of the peer's certificate chain is shorter than it is required
+    /// to be, see `tls_stream::Builder::min_rsa_key_bits`.
+    WeakKey,
+    /// The peers don't support a common cipher suite or algorithm.
+    AlgorithmMismatch,
+    /// The peers don't support a common protocol version.
+    ProtocolVersion,
+    /// A message from the peer was incomplete.
+    IncompleteMessage,
+    /// The TLS session has been closed.
+    ContextExpired,
+    /// A private key needs to ask the user, for example for the PIN of a
+    /// smart card, which silent credentials don't allow.
+    InteractionRequired,
+    /// Any other failure.
+    Other,
+}
+
+/// Returns true if `code` is a failure `HRESULT` of the security or
+/// certificate facilities.
+fn is_security_status(code: i32) -> bool {
+    let facility = (code as u32 >> 16) & 0x1fff;
+    code < 0 && (facility == FACILITY_SECURITY || facility == FACILITY_CERT)
+}
+
+/// Returns the handles of `MESSAGE_MODULES`, loading them on first use.
+///
+/// The handles are never released. Modules which fail to load are left as
+/// null handles.
+fn message_modules() -> &'static [AtomicIsize; 3] {
+    static MODULES: [AtomicIsize; 3] = [
+        AtomicIsize::new(0),
+        AtomicIsize::new(0),
+        AtomicIsize::new(0),
+    ];
+    static INIT: Once = Once::new();
+
+    INIT.call_once(|| {
+        for (module, name) in MODULES.iter().zip(MESSAGE_MODULES.iter()) {
+            let handle = unsafe {
+                LibraryLoader::LoadLibraryExA(
+                    name.as_ptr(),
+                    0,
+                    LibraryLoader::LOAD_LIBRARY_SEARCH_SYSTEM32,
+                )
+            };
+            module.store(handle, Ordering::Relaxed);
+        }
+    });
+    &MODULES
+}
+
+/// Looks up the message of `code` in the system message table, then in those
+/// of `MESSAGE_MODULES`.
+fn lookup_message(code: i32) -> Option<String> {
+    if let Some(message) = format_message(Debug::FORMAT_MESSAGE_FROM_SYSTEM, 0, code) {
+        return Some(message);
+    }
+    message_modules().iter().find_map(|module| {
+        let module = module.load(Ordering::Relaxed);
+        if module == 0 {
+            return None;
+        }
+        format_message(Debug::FORMAT_MESSAGE_FROM_HMODULE, module, code)
+    })
+}
+
+/// Looks up the message of `code` in the system message table or in that of
+/// `module`.
+fn format_message(flags: u32, module: isize, code: i32) -> Option<String> {
+    let mut buf = [0u16; 512];
+    let len = unsafe {
+        Debug::FormatMessageW(
+            flags | Debug::FORMAT_MESSAGE_IGNORE_INSERTS,
+            module as *const _,
+            code as u32,
+            0,
+            buf.as_mut_ptr(),
+            buf.len() as u32,
+            ptr::null(),
+        )
+    };
+    if len == 0 {
+        return None;
+    }
+    let message = String::from_utf16_lossy(&buf[..len as usize]);
+    let message = message.trim_end();
+    if message.is_empty() {
+        None
+    } else {
+        Some(message.to_string())
+    }
+}
+
+impl fmt::Debug for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let mut debug = fmt.debug_struct("Error");
+        debug.field("code", &format_args!("{:#010x}", self.code));
+        if let Some(operation) = self.operation {
+            debug.field("operation", &operation);
+        }
+        if let Some(phase) = self.phase {
+            debug.field("phase", &phase);
+        }
+        debug.finish()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{} ({:#010x})", self.message(), self.code)?;
+        if let Some(operation) = self.operation {
+            write!(fmt, " in {}", operation)?;
+        }
+        if let Some(phase) = self.phase {
+            write!(fmt, " during {}", phase)?;
+        }
+        Ok(())
+    }
+}
+
+impl PartialEq for Error {
+    fn eq(&self, other: &Error) -> bool {
+        self.code == other.code
+    }
+}
+
+impl Eq for Error {}
+
+impl Hash for Error {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.code.hash(state);
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<Error> for io::Error {
+    /// Wraps the error in an `io::Error` whose `ErrorKind` matches its
+    /// `Error::kind`.
+    ///
+    /// The error can be recovered with `Error::from_io_error` or by
+    /// downcasting `io::Error::get_ref`.
+    fn from(err: Error) -> io::Error {
+        let kind = match err.kind() {
+            ErrorKind::UntrustedRoot
+            | ErrorKind::CertExpired
+            | ErrorKind::CertNameMismatch
+            | ErrorKind::CertRevoked
+            | ErrorKind::CertWrongUsage
+            | ErrorKind::WeakKey => io::ErrorKind::InvalidData,
+            ErrorKind::AlgorithmMismatch | ErrorKind::ProtocolVersion => io::ErrorKind::Unsupported,
+            ErrorKind::IncompleteMessage => io::ErrorKind::UnexpectedEof,
+            ErrorKind::ContextExpired => io::ErrorKind::NotConnected,
+            ErrorKind::InteractionRequired => io::ErrorKind::PermissionDenied,
+            ErrorKind::Other => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+/// A Win32 error code reported by a CryptoAPI function through
+/// `GetLastError`.
+///
+/// Some of these functions report failures with `HRESULT`s, such as
+/// `CRYPT_E_NOT_FOUND`, which are kept as they are: the code is that
+/// returned by `GetLastError`, whatever its space.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Win32Error {
+    code: u32,
+}
+
+impl Win32Error {
+    /// Creates an error from a Win32 error code such as
+    /// `ERROR_ACCESS_DENIED`.
+    pub fn from_code(code: u32) -> Win32Error {
+        Win32Error { code }
+    }
+
+    /// Returns the error of the last failed function of the calling thread.
+    pub fn last() -> Win32Error {
+        Win32Error::from_code(unsafe { Foundation::GetLastError() })
+    }
+
+    /// Returns the Win32 error code of this error.
+    pub fn code(&self) -> u32 {
+        self.code
+    }
+
+    /// Returns the `io::ErrorKind` the standard library gives this code.
+    pub fn kind(&self) -> io::ErrorKind {
+        io::Error::from_raw_os_error(self.code as i32).kind()
+    }
+
+    /// Returns a description of this error.
+    pub fn message(&self) -> String {
+        lookup_message(self.code as i32).unwrap_or_else(|| format!("unknown error {}", self.code))
+    }
+
+    /// Returns the code formatted as `GetLastError` codes usually are: in
+    /// decimal, in hexadecimal for `HRESULT`s.
+    fn display_code(&self) -> String {
+        if (self.code as i32) < 0 {
+            format!("{:#010x}", self.code)
+        } else {
+            self.code.to_string()
+        }
+    }
+}
+
+impl fmt::Debug for Win32Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Win32Error")
+            .field("code", &format_args!("{}", self.display_code()))
+            .finish()
+    }
+}
+
+impl fmt::Display for Win32Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{} (os error {})", self.message(), self.display_code())
+    }
+}
+
+impl error::Error for Win32Error {}
+
+impl From<Win32Error> for io::Error {
+    /// Converts the error to a raw OS error with the same code.
+    fn from(err: Win32Error) -> io::Error {
+        io::Error::from_raw_os_error(err.code as i32)
+    }
+}
+
+/// An error returned by this crate: a `SECURITY_STATUS`, a Win32 error or
+/// another I/O error.
+///
+/// Certificate, certificate store and key functions return this error, see
+/// `error::Result`. It converts to and from `io::Error`, so that it can be
+/// propagated with `?` from functions returning `io::Result`, and the errors
+/// of streams can be classified with `CrateError::from`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum CrateError {
+    /// A `SECURITY_STATUS` reported by Schannel or certificate validation.
+    Security(Error),
+    /// A Win32 error reported by a CryptoAPI function.
+    Win32(Win32Error),
+    /// Any other I/O error, such as an error of the underlying stream or an
+    /// invalid argument.
+    Io(io::Error),
+}
+
+/// The result of the functions of this crate failing with a `CrateError`.
+pub type Result<T> = std::result::Result<T, CrateError>;
+
+impl CrateError {
+    /// Returns the raw code of this error, as `io::Error::raw_os_error`
+    /// does: the code of a `SECURITY_STATUS` or Win32 error, or the raw OS
+    /// error of an I/O error.
+    pub fn raw_os_error(&self) -> Option<i32> {
+        match *self {
+            CrateError::Security(ref err) => Some(err.code()),
+            CrateError::Win32(ref err) => Some(err.code() as i32),
+            CrateError::Io(ref err) => err.raw_os_error(),
+        }
+    }
+
+    /// Returns the `io::ErrorKind` of the `io::Error` this error converts
+    /// to.
+    pub fn kind(&self) -> io::ErrorKind {
+        match *self {
+            CrateError::Security(err) => io::Error::from(err).kind(),
+            CrateError::Win32(ref err) => err.kind(),
+            CrateError::Io(ref err) => err.kind(),
+        }
+    }
+}
+
+impl fmt::Display for CrateError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CrateError::Security(ref err) => err.fmt(fmt),
+            CrateError::Win32(ref err) => err.fmt(fmt),
+            CrateError::Io(ref err) => err.fmt(fmt),
+        }
+    }
+}
+
+impl error::Error for CrateError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            CrateError::Security(ref err) => Some(err),
+            CrateError::Win32(ref err) => Some(err),
+            CrateError::Io(ref err) => Some(err),
+        }
+    }
+}
+
+impl From<Error> for CrateError {
+    fn from(err: Error) -> CrateError {
+        CrateError::Security(err)
+    }
+}
+
+impl From<Win32Error> for CrateError {
+    fn from(err: Win32Error) -> CrateError {
+        CrateError::Win32(err)
+    }
+}
+
+impl From<io::Error> for CrateError {
+    /// Classifies an I/O error.
+    ///
+    /// Errors recognized by `Error::from_io_error`, which wrap an `Error` or
+    /// carry the code of a failed `SECURITY_STATUS`, are
+    /// `CrateError::Security`. Other raw OS errors are `CrateError::Win32`.
+    fn from(err: io::Error) -> CrateError {
+        if let Some(security) = Error::from_io_error(&err) {
+            return CrateError::Security(security);
+        }
+        match err.raw_os_error() {
+            Some(code) => CrateError::Win32(Win32Error::from_code(code as u32)),
+            None => CrateError::Io(err),
+        }
+    }
+}
+
+impl From<CrateError> for io::Error {
+    /// Converts the error back into an `io::Error`, see `From<Error>` and
+    /// `From<Win32Error>`.
+    fn from(err: CrateError) -> io::Error {
+        match err {
+            CrateError::Security(err) => err.into(),
+            CrateError::Win32(err) => err.into(),
+            CrateError::Io(err) => err,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn kind() {
+        let kinds = [
+            (Foundation::SEC_E_UNTRUSTED_ROOT, ErrorKind::UntrustedRoot),
+            (
+                Foundation::SEC_E_ISSUING_CA_UNTRUSTED,
+                ErrorKind::UntrustedRoot,
+            ),
+            (Foundation::CERT_E_UNTRUSTEDROOT, ErrorKind::UntrustedRoot),
+            (Foundation::CERT_E_UNTRUSTEDCA, ErrorKind::UntrustedRoot),
+            (
+                Foundation::CERT_E_UNTRUSTEDTESTROOT,
+                ErrorKind::UntrustedRoot,
+            ),
+            (Foundation::CERT_E_CHAINING, ErrorKind::UntrustedRoot),
+            (Foundation::SEC_E_CERT_EXPIRED, ErrorKind::CertExpired),
+            (Foundation::SEC_E_KDC_CERT_EXPIRED, ErrorKind::CertExpired),
+            (
+                Foundation::SEC_E_SMARTCARD_CERT_EXPIRED,
+                ErrorKind::CertExpired,
+            ),
+            (Foundation::CERT_E_EXPIRED, ErrorKind::CertExpired),
+            (
+                Foundation::CERT_E_VALIDITYPERIODNESTING,
+                ErrorKind::CertExpired,
+            ),
+            (
+                Foundation::SEC_E_WRONG_PRINCIPAL,
+                ErrorKind::CertNameMismatch,
+            ),
+            (Foundation::CERT_E_CN_NO_MATCH, ErrorKind::CertNameMismatch),
+            (Foundation::SEC_E_KDC_CERT_REVOKED, ErrorKind::CertRevoked),
+            (
+                Foundation::SEC_E_SMARTCARD_CERT_REVOKED,
+                ErrorKind::CertRevoked,
+            ),
+            (Foundation::CERT_E_REVOKED, ErrorKind::CertRevoked),
+            (Foundation::CRYPT_E_REVOKED, ErrorKind::CertRevoked),
+            (Foundation::CERT_E_WRONG_USAGE, ErrorKind::CertWrongUsage),
+            (
+                Foundation::CERTSRV_E_WEAK_SIGNATURE_OR_KEY,
+                ErrorKind::WeakKey,
+            ),
+            (
+                Foundation::SEC_E_ALGORITHM_MISMATCH,
+                ErrorKind::AlgorithmMismatch,
+            ),
+            (
+                Foundation::SEC_E_UNSUPPORTED_FUNCTION,
+                ErrorKind::ProtocolVersion,
+            ),
+            (
+                Foundation::SEC_E_DOWNGRADE_DETECTED,
+                ErrorKind::ProtocolVersion,
+            ),
+            (
+                Foundation::SEC_E_INCOMPLETE_MESSAGE,
+                ErrorKind::IncompleteMessage,
+            ),
+            (Foundation::SEC_E_CONTEXT_EXPIRED, ErrorKind::ContextExpired),
+            (Foundation::SEC_I_CONTEXT_EXPIRED, ErrorKind::ContextExpired),
+            (
+                Foundation::NTE_SILENT_CONTEXT,
+                ErrorKind::InteractionRequired,
+            ),
+            (Foundation::SEC_E_INTERNAL_ERROR, ErrorKind::Other),
+            (Foundation::SEC_E_ILLEGAL_MESSAGE, ErrorKind::Other),
+            (Foundation::CRYPT_E_REVOCATION_OFFLINE, ErrorKind::Other),
+        ];
+        for &(code, kind) in kinds.iter() {
+            assert_eq!(Error::from_code(code).kind(), kind, "{:#010x}", code);
+        }
+    }
+
+    #[test]
+    fn message() {
+        for &code in [
+            Foundation::SEC_E_UNTRUSTED_ROOT,
+            Foundation::SEC_E_ALGORITHM_MISMATCH,
+            Foundation::CERT_E_CN_NO_MATCH,
+        ]
+        .iter()
+        {
+            let message = Error::from_code(code).message();
+            assert!(!message.is_empty());
+            assert!(!message.starts_with("unknown error"), "{}", message);
+        }
+
+        let display = Error::from_code(Foundation::SEC_E_UNTRUSTED_ROOT).to_string();
+        assert!(display.ends_with("(0x80090325)"), "{}", display);
+    }
+
+    #[test]
+    fn context() {
+        let err = Error::from_code(Foundation::SEC_E_ILLEGAL_MESSAGE);
+        assert_eq!(err.operation(), None);
+        assert_eq!(err.phase(), None);
+
+        let err = err.with_context("DecryptMessage", "data transfer");
+        assert_eq!(err.operation(), Some("DecryptMessage"));
+        assert_eq!(err.phase(), Some("data transfer"));
+        assert_eq!(err, Error::from_code(Foundation::SEC_E_ILLEGAL_MESSAGE));
+
+        let display = err.to_string();
+        assert!(
+            display.ends_with("(0x80090326) in DecryptMessage during data transfer"),
+            "{}",
+            display
+        );
+        let debug = format!("{:?}", err);
+        assert!(debug.contains("\"DecryptMessage\""), "{}", debug);
+        assert!(debug.contains("\"data transfer\""), "{}", debug);
+    }
+
+    #[test]
+    fn into_io_error() {
+        let err = io::Error::from(Error::from_code(Foundation::CERT_E_CN_NO_MATCH));
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.get_ref().and_then(|e| e.downcast_ref::<Error>()),
+            Some(&Error::from_code(Foundation::CERT_E_CN_NO_MATCH))
+        );
+        assert_eq!(
+            err.to_string(),
+            Error::from_code(Foundation::CERT_E_CN_NO_MATCH).to_string()
+        );
+
+        let err = io::Error::from(Error::from_code(Foundation::SEC_E_ALGORITHM_MISMATCH));
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        let err = io::Error::from(Error::from_code(Foundation::SEC_E_CONTEXT_EXPIRED));
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+        let err = io::Error::from(Error::from_code(Foundation::NTE_SILENT_CONTEXT));
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn from_io_error() {
+        let err = io::Error::from_raw_os_error(Foundation::SEC_E_UNTRUSTED_ROOT);
+        assert_eq!(
+            Error::from_io_error(&err),
+            Some(Error::from_code(Foundation::SEC_E_UNTRUSTED_ROOT))
+        );
+        let err = io::Error::from_raw_os_error(Foundation::CERT_E_EXPIRED);
+        assert_eq!(
+            Error::from_io_error(&err).map(|e| e.code()),
+            Some(Foundation::CERT_E_EXPIRED)
+        );
+
+        let err = io::Error::from(Error::from_code(Foundation::SEC_E_INTERNAL_ERROR));
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert_eq!(
+            Error::from_io_error(&err),
+            Some(Error::from_code(Foundation::SEC_E_INTERNAL_ERROR))
+        );
+
+        let err = io::Error::from_raw_os_error(Foundation::ERROR_ACCESS_DENIED as i32);
+        assert_eq!(Error::from_io_error(&err), None);
+        let err = io::Error::new(io::ErrorKind::ConnectionReset, "reset");
+        assert_eq!(Error::from_io_error(&err), None);
+    }
+
+    #[test]
+    fn win32_error() {
+        let err = Win32Error::from_code(Foundation::ERROR_ACCESS_DENIED);
+        assert_eq!(err.code(), 5);
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(!err.message().starts_with("unknown error"), "{}", err);
+        let display = err.to_string();
+        assert!(display.ends_with("(os error 5)"), "{}", display);
+
+        let display = Win32Error::from_code(Foundation::CRYPT_E_NOT_FOUND as u32).to_string();
+        assert!(display.ends_with("(os error 0x80092004)"), "{}", display);
+
+        let err = io::Error::from(err);
+        assert_eq!(err.raw_os_error(), Some(5));
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn crate_error() {
+        let err = CrateError::from(Error::from_code(Foundation::SEC_E_WRONG_PRINCIPAL));
+        assert_eq!(err.raw_os_error(), Some(Foundation::SEC_E_WRONG_PRINCIPAL));
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            Error::from_code(Foundation::SEC_E_WRONG_PRINCIPAL).to_string()
+        );
+
+        let err = CrateError::from(Win32Error::from_code(Foundation::ERROR_FILE_NOT_FOUND));
+        assert_eq!(err.raw_os_error(), Some(2));
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().ends_with("(os error 2)"), "{}", err);
+    }
+
+    #[test]
+    fn crate_error_from_io_error() {
+        let err = io::Error::from(Error::from_code(Foundation::SEC_E_CERT_EXPIRED));
+        match CrateError::from(err) {
+            CrateError::Security(err) => assert_eq!(err.kind(), ErrorKind::CertExpired),
+            err => panic!("{:?}", err),
+        }
+        let err = io::Error::from_raw_os_error(Foundation::SEC_E_UNTRUSTED_ROOT);
+        match CrateError::from(err) {
+            CrateError::Security(err) => assert_eq!(err.kind(), ErrorKind::UntrustedRoot),
+            err => panic!("{:?}", err),
+        }
+
+        let err = io::Error::from_raw_os_error(Foundation::ERROR_ACCESS_DENIED as i32);
+        match CrateError::from(err) {
+            CrateError::Win32(err) => assert_eq!(err.code(), Foundation::ERROR_ACCESS_DENIED),
+            err => panic!("{:?}", err),
+        }
+
+        let err = io::Error::new(io::ErrorKind::InvalidInput, "bad input");
+        match CrateError::from(err) {
+            CrateError::Io(ref err) => assert_eq!(err.to_string(), "bad input"),
+            err => panic!("{:?}", err),
+        }
+
+        // converting back keeps the code and kind
+        let err = io::Error::from(CrateError::from(io::Error::from_raw_os_error(5)));
+        assert_eq!(err.raw_os_error(), Some(5));
+        let err = io::Error::from(CrateError::from(Error::from_code(
+            Foundation::SEC_E_CERT_EXPIRED,
+        )));
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            Error::from_io_error(&err),
+            Some(Error::from_code(Foundation::SEC_E_CERT_EXPIRED))
+        );
+    }
+}
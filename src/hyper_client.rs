@@ -0,0 +1,135 @@
+//! Schannel TLS for the hyper HTTP client.
+//!
+//! This module is available with the `hyper` feature.
+//!
+//! ```no_run
+//! use hyper::net::HttpsConnector;
+//! use hyper::Client;
+//! use schannel::hyper_client::SchannelClient;
+//!
+//! let ssl = SchannelClient::new().unwrap();
+//! let client = Client::with_connector(HttpsConnector::new(ssl));
+//! let response = client.get("https://www.rust-lang.org/").send().unwrap();
+//! ```
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use hyper::net::{HttpStream, NetworkStream, SslClient};
+
+use crate::tls_connector::TlsConnector;
+use crate::tls_stream::{self, HandshakeError};
+
+/// An `SslClient` performing the TLS handshake of hyper's connections.
+///
+/// The requested host is used for Server Name Indication and certificate
+/// validation.
+#[derive(Debug, Clone)]
+pub struct SchannelClient(TlsConnector);
+
+impl SchannelClient {
+    /// Returns a new `SchannelClient` with the default settings.
+    pub fn new() -> io::Result<SchannelClient> {
+        TlsConnector::new().map(SchannelClient)
+    }
+
+    /// Returns the `TlsConnector` used for connections.
+    pub fn connector(&self) -> &TlsConnector {
+        &self.0
+    }
+}
+
+impl From<TlsConnector> for SchannelClient {
+    fn from(connector: TlsConnector) -> SchannelClient {
+        SchannelClient(connector)
+    }
+}
+
+impl<T> SslClient<T> for SchannelClient
+where
+    T: NetworkStream + Send + Clone + Read + Write,
+{
+    type Stream = TlsStream<T>;
+
+    fn wrap_client(&self, stream: T, host: &str) -> hyper::Result<TlsStream<T>> {
+        match self.0.connect(host, stream) {
+            Ok(stream) => Ok(TlsStream(Arc::new(Mutex::new(stream)))),
+            Err(HandshakeError::Failure(e)) => Err(io::Error::from(e).into()),
+            Err(HandshakeError::Io(e)) => Err(e.into()),
+            Err(HandshakeError::Interrupted(_)) => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "the handshake would block",
+            )
+            .into()),
+        }
+    }
+}
+
+/// A TLS stream used by hyper.
+///
+/// hyper requires its streams to be cloneable, so clones share the
+/// underlying `TlsStream`.
+#[derive(Debug)]
+pub struct TlsStream<S = HttpStream>(Arc<Mutex<tls_stream::TlsStream<S>>>);
+
+impl<S> Clone for TlsStream<S> {
+    fn clone(&self) -> TlsStream<S> {
+        TlsStream(self.0.clone())
+    }
+}
+
+impl<S> TlsStream<S> {
+    /// Locks the stream for access to the underlying `TlsStream`.
+    pub fn lock(&self) -> MutexGuard<'_, tls_stream::TlsStream<S>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<S> Read for TlsStream<S>
+where
+    S: Read + Write,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.lock().read(buf)
+    }
+}
+
+impl<S> Write for TlsStream<S>
+where
+    S: Read + Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock().flush()
+    }
+}
+
+impl<S> NetworkStream for TlsStream<S>
+where
+    S: NetworkStream + Send + Read + Write,
+{
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.lock().get_mut().peer_addr()
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.lock().get_ref().set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.lock().get_ref().set_write_timeout(dur)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        let mut stream = self.lock();
+        if how != Shutdown::Read {
+            // The peer may already have closed the connection.
+            let _ = stream.shutdown();
+        }
+        stream.get_mut().close(how)
+    }
+}
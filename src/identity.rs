@@ -0,0 +1,67 @@
+//! Certificates with private keys identifying one side of a TLS session.
+use std::io;
+
+use crate::cert_context::CertContext;
+use crate::cert_store::PfxImportOptions;
+use crate::error;
+
+/// A certificate along with its private key and any intermediate
+/// certificates.
+///
+/// An `Identity` is usually imported from a PKCS #12 archive and passed to a
+/// `TlsAcceptor`, or to a `TlsConnector` to authenticate the client.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    cert: CertContext,
+}
+
+impl Identity {
+    /// Imports an identity from a DER-encoded PKCS #12 archive.
+    ///
+    /// The archive must contain exactly one certificate with a private key.
+    /// The other certificates in the archive remain available through the
+    /// certificate's store, which Schannel uses to send the chain to the peer.
+    pub fn from_pkcs12(der: &[u8], password: &str) -> error::Result<Identity> {
+        let store = PfxImportOptions::new().password(password).import(der)?;
+        let mut identities = store.certs().filter(|cert| {
+            cert.private_key()
+                .compare_key(true)
+                .silent(true)
+                .acquire()
+                .is_ok()
+        });
+        let cert = match (identities.next(), identities.next()) {
+            (Some(cert), None) => cert,
+            (Some(_), Some(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "multiple certificates with private keys found",
+                )
+                .into())
+            }
+            (None, _) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "no certificate with a private key found",
+                )
+                .into())
+            }
+        };
+        Ok(Identity { cert })
+    }
+
+    /// Creates an identity from a certificate with an associated private key.
+    pub fn from_cert(cert: CertContext) -> Identity {
+        Identity { cert }
+    }
+
+    /// Returns the certificate of this identity.
+    pub fn cert(&self) -> &CertContext {
+        &self.cert
+    }
+
+    /// Consumes the identity, returning its certificate.
+    pub fn into_cert(self) -> CertContext {
+        self.cert
+    }
+}
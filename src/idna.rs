@@ -0,0 +1,112 @@
+//! Conversion of internationalized domain names to their ASCII form.
+//!
+//! Labels containing non-ASCII characters are lowercased and encoded with
+//! Punycode (RFC 3492). The full UTS #46 mapping is not applied, so names
+//! are expected to already be in their normalized form.
+
+const BASE: u64 = 36;
+const TMIN: u64 = 1;
+const TMAX: u64 = 26;
+const SKEW: u64 = 38;
+const DAMP: u64 = 700;
+const INITIAL_BIAS: u64 = 72;
+const INITIAL_N: u64 = 0x80;
+
+/// Converts the Unicode labels of `domain` to A-labels.
+pub fn to_ascii(domain: &str) -> String {
+    domain
+        .split('.')
+        .map(|label| {
+            if label.is_ascii() {
+                label.to_string()
+            } else {
+                format!("xn--{}", punycode(&label.to_lowercase()))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+fn adapt(delta: u64, num_points: u64, first_time: bool) -> u64 {
+    let mut delta = if first_time { delta / DAMP } else { delta / 2 };
+    delta += delta / num_points;
+    let mut k = 0;
+    while delta > ((BASE - TMIN) * TMAX) / 2 {
+        delta /= BASE - TMIN;
+        k += BASE;
+    }
+    k + (BASE - TMIN + 1) * delta / (delta + SKEW)
+}
+
+fn encode_digit(digit: u64) -> char {
+    if digit < 26 {
+        (b'a' + digit as u8) as char
+    } else {
+        (b'0' + (digit - 26) as u8) as char
+    }
+}
+
+fn punycode(label: &str) -> String {
+    let input = label.chars().map(|c| c as u64).collect::<Vec<_>>();
+    let mut output = label.chars().filter(char::is_ascii).collect::<String>();
+    let basic = output.len() as u64;
+    if basic > 0 {
+        output.push('-');
+    }
+
+    let mut n = INITIAL_N;
+    let mut delta = 0;
+    let mut bias = INITIAL_BIAS;
+    let mut handled = basic;
+    while (handled as usize) < input.len() {
+        let m = input.iter().cloned().filter(|&c| c >= n).min().unwrap();
+        delta += (m - n) * (handled + 1);
+        n = m;
+        for &c in &input {
+            if c < n {
+                delta += 1;
+            }
+            if c == n {
+                let mut q = delta;
+                let mut k = BASE;
+                loop {
+                    let t = if k <= bias {
+                        TMIN
+                    } else if k >= bias + TMAX {
+                        TMAX
+                    } else {
+                        k - bias
+                    };
+                    if q < t {
+                        break;
+                    }
+                    output.push(encode_digit(t + (q - t) % (BASE - t)));
+                    q = (q - t) / (BASE - t);
+                    k += BASE;
+                }
+                output.push(encode_digit(q));
+                bias = adapt(delta, handled + 1, handled == basic);
+                delta = 0;
+                handled += 1;
+            }
+        }
+        delta += 1;
+        n += 1;
+    }
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn to_ascii_labels() {
+        assert_eq!(to_ascii("bücher.example"), "xn--bcher-kva.example");
+        assert_eq!(to_ascii("Bücher.example"), "xn--bcher-kva.example");
+        assert_eq!(to_ascii("www.münchen.de"), "www.xn--mnchen-3ya.de");
+        assert_eq!(to_ascii("例え.中国"), "xn--r8jz45g.xn--fiqs8s");
+        assert_eq!(to_ascii("テスト"), "xn--zckzah");
+        assert_eq!(to_ascii("Example.com"), "Example.com");
+    }
+}
@@ -0,0 +1,343 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::ops::Range;
+
+use crate::zeroize;
+
+/// The buffer of data received from the wrapped stream of a TLS session.
+///
+/// Records are decrypted in place, so the buffer holds the plaintext which
+/// has not been read yet followed by the ciphertext which has not been
+/// decrypted yet:
+///
+/// ```text
+/// [ consumed | plaintext | consumed | ciphertext | free space ]
+/// ```
+///
+/// If requested, plaintext is wiped as it is consumed and the whole buffer is
+/// wiped before its memory is released.
+pub struct InputBuffer {
+    buf: Vec<u8>,
+    // decrypted data, valid from plain_start to plain_end
+    plain_start: usize,
+    plain_end: usize,
+    // the lengths of the records making up the plaintext, less what has been
+    // consumed of the first one
+    record_lens: VecDeque<usize>,
+    // encrypted data, valid from enc_start to enc_end
+    enc_start: usize,
+    enc_end: usize,
+    zeroize: bool,
+}
+
+impl Drop for InputBuffer {
+    fn drop(&mut self) {
+        if self.zeroize {
+            zeroize(&mut self.buf);
+        }
+    }
+}
+
+impl InputBuffer {
+    pub fn with_capacity(capacity: usize, zeroize: bool) -> InputBuffer {
+        InputBuffer {
+            buf: Vec::with_capacity(capacity),
+            plain_start: 0,
+            plain_end: 0,
+            record_lens: VecDeque::new(),
+            enc_start: 0,
+            enc_end: 0,
+            zeroize,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.capacity()
+    }
+
+    /// Returns true if there is neither plaintext nor ciphertext buffered.
+    pub fn is_empty(&self) -> bool {
+        self.plaintext().is_empty() && self.ciphertext().is_empty()
+    }
+
+    pub fn plaintext(&self) -> &[u8] {
+        &self.buf[self.plain_start..self.plain_end]
+    }
+
+    /// Consumes up to `amt` bytes of plaintext.
+    pub fn consume_plaintext(&mut self, amt: usize) {
+        debug_assert!(amt <= self.plain_end - self.plain_start);
+        let amt = cmp::min(amt, self.plain_end - self.plain_start);
+        if self.zeroize {
+            zeroize(&mut self.buf[self.plain_start..self.plain_start + amt]);
+        }
+        self.plain_start += amt;
+
+        let mut amt = amt;
+        while amt > 0 {
+            let len = match self.record_lens.front_mut() {
+                Some(len) => len,
+                None => break,
+            };
+            if *len > amt {
+                *len -= amt;
+                break;
+            }
+            amt -= *len;
+            self.record_lens.pop_front();
+        }
+    }
+
+    /// Returns the part of the plaintext which belongs to the first record
+    /// decrypted into it.
+    pub fn first_record(&self) -> &[u8] {
+        let len = self.record_lens.front().copied().unwrap_or(0);
+        &self.plaintext()[..len]
+    }
+
+    pub fn into_plaintext(self) -> Vec<u8> {
+        self.plaintext().to_vec()
+    }
+
+    pub fn ciphertext(&self) -> &[u8] {
+        &self.buf[self.enc_start..self.enc_end]
+    }
+
+    pub fn ciphertext_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[self.enc_start..self.enc_end]
+    }
+
+    /// Consumes up to `amt` bytes of ciphertext.
+    pub fn consume_ciphertext(&mut self, amt: usize) {
+        debug_assert!(amt <= self.enc_end - self.enc_start);
+        let amt = cmp::min(amt, self.enc_end - self.enc_start);
+        self.enc_start += amt;
+    }
+
+    /// Returns true if the ciphertext starts with a complete TLS record.
+    pub fn has_complete_record(&self) -> bool {
+        let ciphertext = self.ciphertext();
+        if ciphertext.len() < 5 {
+            return false;
+        }
+        let len = u16::from_be_bytes([ciphertext[3], ciphertext[4]]) as usize;
+        ciphertext.len() >= 5 + len
+    }
+
+    /// Discards all ciphertext which has not been decrypted yet.
+    pub fn clear_ciphertext(&mut self) {
+        self.enc_start = self.enc_end;
+    }
+
+    /// Records that the first `consumed` bytes of the ciphertext have been
+    /// decrypted in place into `data`, which is relative to the start of the
+    /// ciphertext.
+    ///
+    /// The new plaintext is handed out where it is unless older plaintext is
+    /// still waiting to be read, in which case it is moved to follow it.
+    pub fn decrypted(&mut self, data: Range<usize>, consumed: usize) -> io::Result<()> {
+        if data.start > data.end || data.end > consumed || consumed > self.ciphertext().len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Schannel returned a buffer outside of the input",
+            ));
+        }
+        let start = self.enc_start + data.start;
+        let end = self.enc_start + data.end;
+        self.consume_ciphertext(consumed);
+        if start == end {
+            return Ok(());
+        }
+        self.record_lens.push_back(end - start);
+
+        if self.plain_start == self.plain_end {
+            self.plain_start = start;
+            self.plain_end = end;
+        } else {
+            self.buf.copy_within(start..end, self.plain_end);
+            self.plain_end += end - start;
+        }
+        Ok(())
+    }
+
+    /// Moves the buffered data to the start of the buffer to make room for
+    /// more ciphertext.
+    fn compact(&mut self) {
+        let start = if self.plain_start == self.plain_end {
+            self.enc_start
+        } else {
+            self.plain_start
+        };
+        if start == 0 {
+            return;
+        }
+
+        self.buf.copy_within(start..self.enc_end, 0);
+        if self.plain_start == self.plain_end {
+            self.plain_start = 0;
+            self.plain_end = 0;
+        } else {
+            self.plain_start -= start;
+            self.plain_end -= start;
+        }
+        self.enc_start -= start;
+        self.enc_end -= start;
+    }
+
+    /// Grows the buffer to a length of at least `min_len`.
+    fn grow(&mut self, min_len: usize) {
+        // The length of `buf` never decreases, so memory is only zeroed the
+        // first time the buffer grows to a given size rather than before
+        // every read.
+        if self.buf.len() < min_len {
+            if self.zeroize && self.buf.capacity() < min_len {
+                // Reallocating would leave a copy of the data behind in the
+                // old allocation.
+                let mut buf = Vec::with_capacity(min_len);
+                buf.extend_from_slice(&self.buf);
+                zeroize(&mut self.buf);
+                self.buf = buf;
+            }
+            self.buf.resize(min_len, 0);
+        }
+    }
+
+    /// Appends `data` to the ciphertext.
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        if self.is_empty() || self.buf.len() < self.enc_end + data.len() {
+            self.compact();
+        }
+        self.grow(self.enc_end + data.len());
+        self.buf[self.enc_end..self.enc_end + data.len()].copy_from_slice(data);
+        self.enc_end += data.len();
+    }
+
+    /// Reads ciphertext from `stream` until at least `needs_read` bytes have
+    /// been received or the stream reaches EOF.
+    ///
+    /// The buffer is grown to hold at least `read_size` bytes, so that a whole
+    /// record can usually be received with a single read call. Buffered data
+    /// is only moved to the front when the buffer would otherwise have to
+    /// grow, so processing many small records doesn't copy the data behind
+    /// them over and over again.
+    ///
+    /// The ciphertext is only read into when the message at its start is
+    /// incomplete, so an error is returned if completing it would require
+    /// buffering more than `max_message_size` bytes.
+    pub fn read_from<R: Read + ?Sized>(
+        &mut self,
+        stream: &mut R,
+        needs_read: &mut usize,
+        read_size: usize,
+        max_message_size: usize,
+    ) -> io::Result<usize> {
+        let mut sum_nread = 0;
+
+        if self.is_empty() {
+            self.compact();
+        }
+        while *needs_read > 0 {
+            if self.ciphertext().len() + *needs_read > max_message_size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "message from the peer exceeds the maximum size",
+                ));
+            }
+
+            let min_len = |existing_len: usize| {
+                cmp::max(
+                    cmp::max(read_size, 2 * existing_len),
+                    existing_len + *needs_read,
+                )
+            };
+            if self.buf.len() < min_len(self.enc_end) {
+                self.compact();
+            }
+            let min_len = min_len(self.enc_end);
+            self.grow(min_len);
+            let nread = stream.read(&mut self.buf[self.enc_end..])?;
+            self.enc_end += nread;
+            *needs_read = needs_read.saturating_sub(nread);
+            if nread == 0 {
+                break;
+            }
+            sum_nread += nread;
+        }
+
+        Ok(sum_nread)
+    }
+
+    /// Releases the memory of the buffer if it is empty and has grown beyond
+    /// `baseline`.
+    pub fn shrink(&mut self, baseline: usize) {
+        if self.is_empty() && self.capacity() > baseline {
+            *self = InputBuffer::with_capacity(baseline, self.zeroize);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn zeroize_consumed_plaintext() {
+        let mut input = InputBuffer::with_capacity(0, true);
+        let mut data = &b"headerplaintexttrailer"[..];
+        let mut needs_read = data.len();
+        input
+            .read_from(&mut data, &mut needs_read, 0, 1024)
+            .unwrap();
+        assert_eq!(input.ciphertext(), b"headerplaintexttrailer");
+
+        // pretend the record was decrypted in place
+        input.decrypted(6..15, 22).unwrap();
+        assert_eq!(input.plaintext(), b"plaintext");
+        assert!(input.ciphertext().is_empty());
+
+        input.consume_plaintext(5);
+        assert_eq!(input.plaintext(), b"text");
+        assert_eq!(&input.buf[..15], b"header\0\0\0\0\0text");
+        input.consume_plaintext(4);
+        assert_eq!(&input.buf[6..15], &[0; 9]);
+    }
+
+    #[test]
+    fn keep_unread_plaintext() {
+        let mut input = InputBuffer::with_capacity(0, false);
+        let mut data = &b"[one][two]"[..];
+        let mut needs_read = data.len();
+        input
+            .read_from(&mut data, &mut needs_read, 0, 1024)
+            .unwrap();
+
+        input.decrypted(1..4, 5).unwrap();
+        input.consume_plaintext(1);
+        input.decrypted(1..4, 5).unwrap();
+        assert_eq!(input.plaintext(), b"netwo");
+        assert!(input.ciphertext().is_empty());
+        assert_eq!(input.first_record(), b"ne");
+        input.consume_plaintext(1);
+        assert_eq!(input.first_record(), b"e");
+        input.consume_plaintext(1);
+        assert_eq!(input.first_record(), b"two");
+        assert_eq!(input.into_plaintext(), b"two");
+    }
+
+    #[test]
+    fn invalid_decrypted_range() {
+        let mut input = InputBuffer::with_capacity(0, false);
+        let mut data = &b"[one]"[..];
+        let mut needs_read = data.len();
+        input
+            .read_from(&mut data, &mut needs_read, 0, 1024)
+            .unwrap();
+
+        assert!(input.decrypted(1..4, 6).is_err());
+        assert!(input.decrypted(1..6, 5).is_err());
+        assert_eq!(input.ciphertext(), b"[one]");
+        input.decrypted(1..4, 5).unwrap();
+        assert_eq!(input.plaintext(), b"one");
+    }
+}
@@ -0,0 +1,192 @@
+//! Logging of TLS secrets in the NSS key log format.
+//!
+//! Each line names a secret, the client random of the session it belongs to
+//! and the secret itself, which is what Wireshark and similar tools need to
+//! decrypt captured traffic.
+use std::env;
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::mem;
+use std::ptr;
+use std::slice;
+use std::sync::Arc;
+
+use windows_sys::Win32::Security::Authentication::Identity;
+
+pub(crate) type KeyLogCallback = Arc<dyn Fn(&str) + Sync + Send>;
+
+/// Returns a callback appending lines to the file named by `SSLKEYLOGFILE`,
+/// if it is set.
+pub(crate) fn file_from_env() -> Option<KeyLogCallback> {
+    let path = env::var_os("SSLKEYLOGFILE")?;
+    Some(Arc::new(move |line| {
+        let file = OpenOptions::new().create(true).append(true).open(&path);
+        if let Ok(mut file) = file {
+            let _ = writeln!(file, "{}", line);
+        }
+    }))
+}
+
+/// Returns the random of the ClientHello at the start of `data`.
+fn client_random(data: &[u8]) -> Option<[u8; 32]> {
+    // the record header, handshake header and legacy version precede it
+    if data.len() < 43 || data[0] != 0x16 || data[5] != 0x01 {
+        return None;
+    }
+    let mut random = [0; 32];
+    random.copy_from_slice(&data[11..43]);
+    Some(random)
+}
+
+fn line(label: &str, client_random: &[u8; 32], secret: &[u8]) -> String {
+    let mut line = String::with_capacity(label.len() + 2 + 2 * (32 + secret.len()));
+    line.push_str(label);
+    line.push(' ');
+    for byte in client_random {
+        write!(line, "{:02x}", byte).unwrap();
+    }
+    line.push(' ');
+    for byte in secret {
+        write!(line, "{:02x}", byte).unwrap();
+    }
+    line
+}
+
+/// Passes the secrets of a session to a callback as they become available.
+///
+/// Schannel hands out TLS 1.3 traffic secrets in order, the handshake
+/// secret of each side first, followed by its application secrets.
+pub(crate) struct KeyLogger {
+    callback: KeyLogCallback,
+    client_random: Option<[u8; 32]>,
+    client_secrets: u32,
+    server_secrets: u32,
+}
+
+impl KeyLogger {
+    pub(crate) fn new(callback: KeyLogCallback) -> KeyLogger {
+        KeyLogger {
+            callback,
+            client_random: None,
+            client_secrets: 0,
+            server_secrets: 0,
+        }
+    }
+
+    /// Picks up the client random from the ClientHello, which is the first
+    /// handshake message sent by clients and received by servers.
+    pub(crate) fn observe_hello(&mut self, data: &[u8]) {
+        if self.client_random.is_none() {
+            self.client_random = client_random(data);
+        }
+    }
+
+    /// Logs the secret in a `SECBUFFER_TRAFFIC_SECRETS` buffer.
+    ///
+    /// # Safety
+    ///
+    /// `buf` must be a buffer returned by Schannel.
+    pub(crate) unsafe fn traffic_secrets(&mut self, buf: &Identity::SecBuffer) {
+        let secrets = buf.pvBuffer as *const Identity::SEC_TRAFFIC_SECRETS;
+        if secrets.is_null() || (buf.cbBuffer as usize) < mem::size_of_val(&*secrets) {
+            return;
+        }
+        let data = ptr::addr_of!((*secrets).TrafficSecret) as *const u8;
+        let offset = data as usize - secrets as usize;
+        let len = (*secrets).TrafficSecretSize as usize;
+        if buf.cbBuffer as usize - offset < len {
+            return;
+        }
+        let secret = slice::from_raw_parts(data, len);
+        match (*secrets).TrafficSecretType {
+            Identity::SecTrafficSecret_Client => self.secret(false, secret),
+            Identity::SecTrafficSecret_Server => self.secret(true, secret),
+            _ => {}
+        }
+    }
+
+    fn secret(&mut self, server: bool, secret: &[u8]) {
+        let client_random = match self.client_random {
+            Some(ref client_random) => client_random,
+            None => return,
+        };
+        let (side, count) = if server {
+            ("SERVER", &mut self.server_secrets)
+        } else {
+            ("CLIENT", &mut self.client_secrets)
+        };
+        let label = match *count {
+            0 => format!("{}_HANDSHAKE_TRAFFIC_SECRET", side),
+            n => format!("{}_TRAFFIC_SECRET_{}", side, n - 1),
+        };
+        *count += 1;
+        (self.callback)(&line(&label, client_random, secret));
+    }
+
+    /// Returns true once the secrets protecting application data in both
+    /// directions have been logged.
+    pub(crate) fn is_complete(&self) -> bool {
+        self.client_secrets >= 2 && self.server_secrets >= 2
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    // a handshake record with a ClientHello, up to the random
+    const HELLO_HEADER: &[u8] = &[
+        0x16, 0x03, 0x01, 0x00, 0x40, 0x01, 0x00, 0x00, 0x3c, 0x03, 0x03,
+    ];
+
+    #[test]
+    fn hello_random() {
+        let mut hello = HELLO_HEADER.to_vec();
+        hello.extend(0..32);
+        hello.extend_from_slice(&[0x00, 0x00]);
+        let random = client_random(&hello).unwrap();
+        assert_eq!(random[0], 0);
+        assert_eq!(random[31], 31);
+
+        assert!(client_random(&hello[..42]).is_none());
+        hello[5] = 0x02;
+        assert!(client_random(&hello).is_none());
+    }
+
+    #[test]
+    fn nss_lines() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let lines2 = lines.clone();
+        let mut logger = KeyLogger::new(Arc::new(move |line| {
+            lines2.lock().unwrap().push(line.to_string())
+        }));
+
+        // nothing can be logged without a client random
+        logger.secret(false, &[0xff]);
+        assert!(lines.lock().unwrap().is_empty());
+
+        let mut hello = HELLO_HEADER.to_vec();
+        hello.extend(&[0xab; 32]);
+        logger.observe_hello(&hello);
+        logger.secret(false, &[0x01, 0x02]);
+        logger.secret(true, &[0x03]);
+        assert!(!logger.is_complete());
+        logger.secret(false, &[0x04]);
+        logger.secret(true, &[0x05]);
+        assert!(logger.is_complete());
+
+        let random = "ab".repeat(32);
+        assert_eq!(
+            *lines.lock().unwrap(),
+            [
+                format!("CLIENT_HANDSHAKE_TRAFFIC_SECRET {} 0102", random),
+                format!("SERVER_HANDSHAKE_TRAFFIC_SECRET {} 03", random),
+                format!("CLIENT_TRAFFIC_SECRET_0 {} 04", random),
+                format!("SERVER_TRAFFIC_SECRET_0 {} 05", random),
+            ]
+        );
+    }
+}
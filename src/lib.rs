@@ -3,8 +3,11 @@
 #![warn(missing_docs)]
 #![allow(non_upper_case_globals)]
 
+use std::convert::TryFrom;
 use std::ffi::c_void;
+use std::io;
 use std::ptr;
+use std::sync;
 
 use windows_sys::Win32::Security::Authentication::Identity;
 
@@ -57,6 +60,26 @@ macro_rules! null_terminate {
     }};
 }
 
+#[cfg(feature = "log")]
+macro_rules! trace {
+    ($($arg:tt)*) => { log::trace!($($arg)*) };
+}
+
+#[cfg(not(feature = "log"))]
+macro_rules! trace {
+    ($($arg:tt)*) => {};
+}
+
+#[cfg(feature = "log")]
+macro_rules! debug {
+    ($($arg:tt)*) => { log::debug!($($arg)*) };
+}
+
+#[cfg(not(feature = "log"))]
+macro_rules! debug {
+    ($($arg:tt)*) => {};
+}
+
 /// Allows access to the underlying schannel API representation of a wrapped data type
 ///
 /// Performing actions with internal handles might lead to the violation of internal assumptions
@@ -73,19 +96,40 @@ pub trait RawPointer {
     unsafe fn as_ptr(&self) -> *mut ::std::os::raw::c_void;
 }
 
+#[cfg(feature = "tokio")]
+pub mod async_tls_stream;
+pub mod capabilities;
 pub mod cert_chain;
 pub mod cert_context;
+#[cfg(feature = "cert-dialog")]
+pub mod cert_dialog;
 pub mod cert_store;
+pub mod cipher_suite;
 pub mod crypt_key;
 pub mod crypt_prov;
 /* pub */ mod ctl_context;
+pub mod duplex;
+pub mod error;
+#[cfg(feature = "hyper")]
+pub mod hyper_client;
+pub mod identity;
 pub mod key_handle;
 pub mod ncrypt_key;
 pub mod schannel_cred;
+pub mod tls_connector;
 pub mod tls_stream;
 
+pub use crate::capabilities::{capabilities, Capabilities};
+pub use crate::cipher_suite::{enumerate_cipher_suites, CipherSuiteInfo};
+
 mod alpn_list;
+mod client_hello;
 mod context_buffer;
+mod idna;
+mod input_buffer;
+mod key_log;
+#[cfg(test)]
+mod mock_stream;
 mod security_context;
 
 #[cfg(test)]
@@ -106,6 +150,62 @@ const INIT_REQUESTS: u32 = Identity::ISC_REQ_CONFIDENTIALITY
     | Identity::ISC_REQ_STREAM
     | Identity::ISC_REQ_USE_SUPPLIED_CREDS;
 
+/// Connects to the TLS server `domain` over `stream` with default settings.
+///
+/// This is a shortcut for `tls_stream::Builder::connect_default`, which
+/// should be used to change any settings, such as the application protocols
+/// to request.
+///
+/// ```no_run
+/// use std::io::{Read, Write};
+/// use std::net::TcpStream;
+///
+/// let stream = TcpStream::connect("example.com:443").unwrap();
+/// let mut stream = schannel::connect("example.com", stream).unwrap();
+/// stream.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+/// let mut response = vec![];
+/// stream.read_to_end(&mut response).unwrap();
+/// ```
+pub fn connect<S>(domain: &str, stream: S) -> io::Result<tls_stream::TlsStream<S>>
+where
+    S: io::Read + io::Write,
+{
+    tls_stream::Builder::new()
+        .domain(domain)
+        .connect_default(stream)
+}
+
+/// Removes sessions from Schannel's session cache, so that the following
+/// connections perform full handshakes.
+///
+/// If `server_name` is given only sessions with that target name are
+/// removed, otherwise the cache is emptied. The cache is maintained by the
+/// LSA and shared by every Schannel user in the process, so this also affects
+/// connections not made by this crate. This is useful after rotating
+/// credentials, as cached sessions otherwise keep being resumed until they
+/// expire, by default after 10 hours.
+pub fn purge_session_cache(server_name: Option<&str>) -> error::Result<()> {
+    purge_session_cache_with_flags(server_name, 0)
+}
+
+/// Like `purge_session_cache`, but passes `flags` to `SslEmptyCacheW`.
+///
+/// No flags are currently defined, so `flags` should be 0.
+pub fn purge_session_cache_with_flags(
+    server_name: Option<&str>,
+    flags: u32,
+) -> error::Result<()> {
+    let wide = server_name.map(|name| name.encode_utf16().chain(Some(0)).collect::<Vec<u16>>());
+    let ptr = wide.as_ref().map_or(ptr::null(), |wide| wide.as_ptr());
+    unsafe {
+        if Identity::SslEmptyCacheW(ptr, flags) != 0 {
+            Ok(())
+        } else {
+            Err(error::Win32Error::last().into())
+        }
+    }
+}
+
 trait Inner<T> {
     unsafe fn from_inner(t: T) -> Self;
 
@@ -114,16 +214,30 @@ trait Inner<T> {
     fn get_mut(&mut self) -> &mut T;
 }
 
-unsafe fn secbuf(buftype: u32, bytes: Option<&mut [u8]>) -> Identity::SecBuffer {
+/// The most input passed to Schannel in one call, as buffer lengths are 32
+/// bits wide.
+const MAX_SECBUFFER_LEN: usize = u32::MAX as usize;
+
+/// Converts a buffer length to the 32 bit length Schannel expects.
+fn secbuf_len(len: usize) -> io::Result<u32> {
+    u32::try_from(len).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("a buffer of {} bytes is too large for Schannel", len),
+        )
+    })
+}
+
+unsafe fn secbuf(buftype: u32, bytes: Option<&mut [u8]>) -> io::Result<Identity::SecBuffer> {
     let (ptr, len) = match bytes {
-        Some(bytes) => (bytes.as_mut_ptr(), bytes.len() as u32),
+        Some(bytes) => (bytes.as_mut_ptr(), secbuf_len(bytes.len())?),
         None => (ptr::null_mut(), 0),
     };
-    Identity::SecBuffer {
+    Ok(Identity::SecBuffer {
         BufferType: buftype,
         cbBuffer: len,
         pvBuffer: ptr as *mut c_void,
-    }
+    })
 }
 
 unsafe fn secbuf_desc(bufs: &mut [Identity::SecBuffer]) -> Identity::SecBufferDesc {
@@ -133,3 +247,11 @@ unsafe fn secbuf_desc(bufs: &mut [Identity::SecBuffer]) -> Identity::SecBufferDe
         pBuffers: bufs.as_mut_ptr(),
     }
 }
+
+/// Overwrites `buf` with zeros in a way which can't be optimized away.
+fn zeroize(buf: &mut [u8]) {
+    for byte in buf.iter_mut() {
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+    sync::atomic::compiler_fence(sync::atomic::Ordering::SeqCst);
+}
@@ -0,0 +1,342 @@
+//! In-memory transports for driving TLS sessions in tests without a network.
+//!
+//! `MockStream` plays back a script of reads and records everything written
+//! to it, while `pipe` connects two endpoints so that a client and a server
+//! can run against each other on a single thread.
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+/// A step in the script of a `MockStream`.
+#[derive(Debug)]
+enum Event {
+    Data(Vec<u8>),
+    WouldBlock,
+    Eof,
+    Error(io::ErrorKind),
+}
+
+impl Event {
+    fn into_error(self) -> io::Error {
+        match self {
+            Event::WouldBlock => io::Error::new(io::ErrorKind::WouldBlock, "injected WouldBlock"),
+            Event::Error(kind) => io::Error::new(kind, "injected failure"),
+            Event::Data(_) | Event::Eof => unreachable!(),
+        }
+    }
+}
+
+/// A stream which plays back scripted reads and records writes.
+///
+/// Reads return the scripted data in chunks of at most `read_chunk` bytes,
+/// fail with the scripted errors when they come up and return `Ok(0)` for
+/// scripted EOFs as well as once the script has been played back. Writes
+/// accept at most `write_chunk` bytes at a time, unless a scripted write
+/// error is pending.
+#[derive(Debug)]
+pub struct MockStream {
+    reads: VecDeque<Event>,
+    writes: VecDeque<Event>,
+    written: Vec<u8>,
+    read_chunk: usize,
+    write_chunk: usize,
+}
+
+impl MockStream {
+    pub fn new() -> MockStream {
+        MockStream {
+            reads: VecDeque::new(),
+            writes: VecDeque::new(),
+            written: Vec::new(),
+            read_chunk: usize::MAX,
+            write_chunk: usize::MAX,
+        }
+    }
+
+    /// Appends `data` to the bytes handed out by reads.
+    pub fn data(&mut self, data: &[u8]) -> &mut MockStream {
+        self.reads.push_back(Event::Data(data.to_vec()));
+        self
+    }
+
+    /// Makes the next read past the scripted data fail with `WouldBlock`.
+    pub fn would_block(&mut self) -> &mut MockStream {
+        self.reads.push_back(Event::WouldBlock);
+        self
+    }
+
+    /// Makes the next read past the scripted data return `Ok(0)`.
+    pub fn eof(&mut self) -> &mut MockStream {
+        self.reads.push_back(Event::Eof);
+        self
+    }
+
+    /// Makes the next read past the scripted data fail with an error of
+    /// `kind`.
+    pub fn error(&mut self, kind: io::ErrorKind) -> &mut MockStream {
+        self.reads.push_back(Event::Error(kind));
+        self
+    }
+
+    /// Makes the next write fail with `WouldBlock`.
+    pub fn write_would_block(&mut self) -> &mut MockStream {
+        self.writes.push_back(Event::WouldBlock);
+        self
+    }
+
+    /// Makes the next write fail with an error of `kind`.
+    pub fn write_error(&mut self, kind: io::ErrorKind) -> &mut MockStream {
+        self.writes.push_back(Event::Error(kind));
+        self
+    }
+
+    /// Limits the number of bytes returned by a single read.
+    pub fn read_chunk(&mut self, len: usize) -> &mut MockStream {
+        self.read_chunk = len;
+        self
+    }
+
+    /// Limits the number of bytes accepted by a single write.
+    pub fn write_chunk(&mut self, len: usize) -> &mut MockStream {
+        self.write_chunk = len;
+        self
+    }
+
+    /// Returns the bytes written so far.
+    pub fn written(&self) -> &[u8] {
+        &self.written
+    }
+
+    /// Returns true if the whole script has been played back.
+    pub fn is_done(&self) -> bool {
+        self.reads.is_empty()
+    }
+}
+
+impl Read for MockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.reads.pop_front() {
+            None | Some(Event::Eof) => Ok(0),
+            Some(Event::Data(mut data)) => {
+                let len = cmp::min(cmp::min(buf.len(), data.len()), self.read_chunk);
+                buf[..len].copy_from_slice(&data[..len]);
+                if len < data.len() {
+                    data.drain(..len);
+                    self.reads.push_front(Event::Data(data));
+                }
+                Ok(len)
+            }
+            Some(event) => Err(event.into_error()),
+        }
+    }
+}
+
+impl Write for MockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(event) = self.writes.pop_front() {
+            return Err(event.into_error());
+        }
+        let len = cmp::min(buf.len(), self.write_chunk);
+        self.written.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Channel {
+    data: VecDeque<u8>,
+    closed: bool,
+}
+
+/// One end of an in-memory connection created by `pipe`.
+///
+/// Reads fail with `WouldBlock` while the peer hasn't written anything, and
+/// return `Ok(0)` once the peer has been closed or dropped. Writes only
+/// block once a limit set with `write_limit` has been used up, and fail once
+/// the peer has been dropped.
+#[derive(Debug)]
+pub struct PipeStream {
+    incoming: Arc<Mutex<Channel>>,
+    outgoing: Arc<Mutex<Channel>>,
+    read_chunk: usize,
+    write_chunk: usize,
+    write_limit: Option<usize>,
+    read_error: Option<io::ErrorKind>,
+}
+
+/// Creates a pair of connected in-memory streams.
+pub fn pipe() -> (PipeStream, PipeStream) {
+    let a = Arc::new(Mutex::new(Channel::default()));
+    let b = Arc::new(Mutex::new(Channel::default()));
+    let end = |incoming: &Arc<Mutex<Channel>>, outgoing: &Arc<Mutex<Channel>>| PipeStream {
+        incoming: incoming.clone(),
+        outgoing: outgoing.clone(),
+        read_chunk: usize::MAX,
+        write_chunk: usize::MAX,
+        write_limit: None,
+        read_error: None,
+    };
+    (end(&a, &b), end(&b, &a))
+}
+
+impl PipeStream {
+    /// Limits the number of bytes returned by a single read.
+    pub fn read_chunk(&mut self, len: usize) -> &mut PipeStream {
+        self.read_chunk = len;
+        self
+    }
+
+    /// Limits the number of bytes accepted by a single write.
+    pub fn write_chunk(&mut self, len: usize) -> &mut PipeStream {
+        self.write_chunk = len;
+        self
+    }
+
+    /// Limits the number of bytes all further writes accept in total, after
+    /// which writes fail with `WouldBlock`. `None` removes the limit.
+    pub fn write_limit(&mut self, limit: Option<usize>) -> &mut PipeStream {
+        self.write_limit = limit;
+        self
+    }
+
+    /// Makes the next read fail with an error of `kind`.
+    pub fn fail_next_read(&mut self, kind: io::ErrorKind) -> &mut PipeStream {
+        self.read_error = Some(kind);
+        self
+    }
+
+    /// Closes the stream for writing, so that the peer reads EOF once it has
+    /// read everything written before.
+    pub fn close(&self) {
+        self.outgoing.lock().unwrap().closed = true;
+    }
+
+    /// Returns the number of bytes written by the peer which haven't been
+    /// read yet.
+    pub fn pending(&self) -> usize {
+        self.incoming.lock().unwrap().data.len()
+    }
+}
+
+impl Drop for PipeStream {
+    fn drop(&mut self) {
+        self.close();
+        self.incoming.lock().unwrap().closed = true;
+    }
+}
+
+impl Read for PipeStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(kind) = self.read_error.take() {
+            return Err(io::Error::new(kind, "injected failure"));
+        }
+        let mut incoming = self.incoming.lock().unwrap();
+        if incoming.data.is_empty() {
+            return if incoming.closed || buf.is_empty() {
+                Ok(0)
+            } else {
+                Err(io::Error::new(io::ErrorKind::WouldBlock, "pipe is empty"))
+            };
+        }
+        let len = cmp::min(cmp::min(buf.len(), incoming.data.len()), self.read_chunk);
+        for (dst, src) in buf.iter_mut().zip(incoming.data.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for PipeStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut outgoing = self.outgoing.lock().unwrap();
+        if outgoing.closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "pipe is closed"));
+        }
+        let limit = self.write_limit.unwrap_or(usize::MAX);
+        if limit == 0 && !buf.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "pipe is full"));
+        }
+        let len = cmp::min(cmp::min(buf.len(), self.write_chunk), limit);
+        outgoing.data.extend(&buf[..len]);
+        if let Some(ref mut limit) = self.write_limit {
+            *limit -= len;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mock_stream_script() {
+        let mut stream = MockStream::new();
+        stream
+            .data(b"hello")
+            .would_block()
+            .data(b"!")
+            .error(io::ErrorKind::ConnectionReset)
+            .eof()
+            .read_chunk(2);
+
+        let mut buf = [0; 8];
+        assert_eq!(stream.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"he");
+        assert_eq!(stream.read(&mut buf).unwrap(), 2);
+        assert_eq!(stream.read(&mut buf).unwrap(), 1);
+        assert_eq!(&buf[..1], b"o");
+        let err = stream.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(stream.read(&mut buf).unwrap(), 1);
+        let err = stream.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        assert!(stream.is_done());
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+
+        stream.write_would_block().write_chunk(3);
+        let err = stream.write(b"abcd").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(stream.write(b"abcd").unwrap(), 3);
+        assert_eq!(stream.write(b"d").unwrap(), 1);
+        assert_eq!(stream.written(), b"abcd");
+    }
+
+    #[test]
+    fn pipe_ends() {
+        let (mut a, mut b) = pipe();
+        let mut buf = [0; 8];
+        let err = b.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        a.write_all(b"ping").unwrap();
+        assert_eq!(b.pending(), 4);
+        b.read_chunk(1);
+        assert_eq!(b.read(&mut buf).unwrap(), 1);
+        assert_eq!(&buf[..1], b"p");
+        b.read_chunk(usize::MAX);
+        assert_eq!(b.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"ing");
+
+        b.fail_next_read(io::ErrorKind::ConnectionReset);
+        let err = b.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+
+        b.write_all(b"pong").unwrap();
+        drop(b);
+        assert_eq!(a.read(&mut buf).unwrap(), 4);
+        assert_eq!(a.read(&mut buf).unwrap(), 0);
+        let err = a.write(b"x").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+}
@@ -2,15 +2,63 @@
 
 use windows_sys::Win32::Security::Cryptography;
 
+use crate::cert_context::CertContext;
+
 /// A CNG handle to a key.
-pub struct NcryptKey(Cryptography::NCRYPT_KEY_HANDLE);
+pub struct NcryptKey {
+    handle: Cryptography::NCRYPT_KEY_HANDLE,
+    // the certificate owning the handle, which is only freed by the
+    // certificate if this is set
+    owner: Option<CertContext>,
+}
 
 impl Drop for NcryptKey {
     fn drop(&mut self) {
-        unsafe {
-            Cryptography::NCryptFreeObject(self.0);
+        if self.owner.is_none() {
+            unsafe {
+                Cryptography::NCryptFreeObject(self.handle);
+            }
+        }
+    }
+}
+
+impl NcryptKey {
+    /// Wraps a handle cached by `cert`, which stays alive as long as the
+    /// returned key does.
+    pub(crate) unsafe fn borrowed(
+        handle: Cryptography::NCRYPT_KEY_HANDLE,
+        cert: &CertContext,
+    ) -> NcryptKey {
+        NcryptKey {
+            handle,
+            owner: Some(cert.clone()),
+        }
+    }
+}
+
+impl crate::Inner<Cryptography::NCRYPT_KEY_HANDLE> for NcryptKey {
+    unsafe fn from_inner(handle: Cryptography::NCRYPT_KEY_HANDLE) -> NcryptKey {
+        NcryptKey {
+            handle,
+            owner: None,
         }
     }
+
+    fn as_inner(&self) -> Cryptography::NCRYPT_KEY_HANDLE {
+        self.handle
+    }
+
+    fn get_mut(&mut self) -> &mut Cryptography::NCRYPT_KEY_HANDLE {
+        &mut self.handle
+    }
 }
 
-inner!(NcryptKey, Cryptography::NCRYPT_KEY_HANDLE);
+impl crate::RawPointer for NcryptKey {
+    unsafe fn from_ptr(t: *mut ::std::os::raw::c_void) -> NcryptKey {
+        crate::Inner::from_inner(t as _)
+    }
+
+    unsafe fn as_ptr(&self) -> *mut ::std::os::raw::c_void {
+        self.handle as *mut _
+    }
+}
@@ -1,5 +1,47 @@
 //! Schannel credentials.
+//!
+//! With the `serde` feature, `Algorithm`, `Protocol`, `Direction` and
+//! `CipherPreset` are serialized as the names accepted by their `FromStr`
+//! implementations, so policies can be read from configuration files:
+//!
+//! ```
+//! # #[cfg(feature = "serde")] {
+//! use schannel::schannel_cred::{Algorithm, CipherPreset, Direction, Protocol, SchannelCred};
+//! use serde::Deserialize;
+//!
+//! #[derive(Deserialize)]
+//! struct Policy {
+//!     direction: Direction,
+//!     preset: CipherPreset,
+//!     protocols: Vec<Protocol>,
+//!     algorithms: Vec<Algorithm>,
+//! }
+//!
+//! let policy: Policy = serde_json::from_str(
+//!     r#"{
+//!         "direction": "outbound",
+//!         "preset": "compatible",
+//!         "protocols": ["tls1.2", "tls1.3"],
+//!         "algorithms": ["aes256", "ecdhe", "rsa-sign", "sha384"]
+//!     }"#,
+//! )
+//! .unwrap();
+//! assert_eq!(policy.protocols, [Protocol::Tls12, Protocol::Tls13]);
+//!
+//! let cred = SchannelCred::builder()
+//!     .preset(policy.preset)
+//!     .enabled_protocols(&policy.protocols)
+//!     .supported_algorithms(&policy.algorithms)
+//!     .acquire(policy.direction)
+//!     .unwrap();
+//! # }
+//! ```
+use std::error;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem::MaybeUninit;
 use std::ptr;
+use std::str::FromStr;
 use std::sync::Arc;
 use std::{io, mem};
 
@@ -7,147 +49,300 @@ use windows_sys::Win32::Foundation;
 use windows_sys::Win32::Security::Authentication::Identity;
 use windows_sys::Win32::Security::{Credentials, Cryptography};
 
+use crate::capabilities;
 use crate::cert_context::CertContext;
+use crate::cert_store::{CertAdd, Memory};
+use crate::cipher_suite::{self, CipherSuiteInfo};
+use crate::error::CrateError;
 use crate::Inner;
 
 static UNISP_NAME: &[u8] = null_terminate!(Identity::UNISP_NAME);
 
 /// The communication direction that an `SchannelCred` will support.
-#[derive(Copy, Debug, Clone, PartialEq, Eq)]
+#[derive(Copy, Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Direction {
     /// Server-side, inbound connections.
     Inbound,
     /// Client-side, outbound connections.
     Outbound,
+    /// Both inbound and outbound connections.
+    ///
+    /// Credentials for both directions can only be acquired as an untyped
+    /// `SchannelCred`.
+    Both,
 }
 
-/// Algorithms supported by Schannel.
-// https://msdn.microsoft.com/en-us/library/windows/desktop/aa375549(v=vs.85).aspx
-#[derive(Debug, Copy, Clone)]
-#[repr(u32)]
-#[non_exhaustive]
-pub enum Algorithm {
+/// The names accepted by `Direction::from_str`.
+const DIRECTION_NAMES: &[(&str, Direction)] = &[
+    ("inbound", Direction::Inbound),
+    ("outbound", Direction::Outbound),
+    ("both", Direction::Both),
+];
+
+impl fmt::Display for Direction {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Direction::Inbound => "inbound",
+            Direction::Outbound => "outbound",
+            Direction::Both => "both",
+        };
+        fmt.write_str(name)
+    }
+}
+
+impl FromStr for Direction {
+    type Err = ParseError;
+
+    /// Parses `inbound`, `outbound` or `both`, ignoring case.
+    fn from_str(s: &str) -> Result<Direction, ParseError> {
+        DIRECTION_NAMES
+            .iter()
+            .find(|&&(name, _)| names_match(name, s))
+            .map(|&(_, direction)| direction)
+            .ok_or_else(|| ParseError {
+                what: "direction",
+                input: s.to_string(),
+                valid: DIRECTION_NAMES.iter().map(|&(name, _)| name).collect(),
+            })
+    }
+}
+
+macro_rules! algorithms {
+    ($($(#[$attr:meta])* $name:ident($display:literal $(, $alias:literal)*) = $value:expr,)*) => {
+        /// Algorithms supported by Schannel.
+        ///
+        /// Algorithms without a variant of their own are represented by
+        /// `Algorithm::Other`. Algorithms are compared by their `ALG_ID`, so
+        /// `Algorithm::Other` is equal to the variant with the same `ALG_ID`.
+        // https://msdn.microsoft.com/en-us/library/windows/desktop/aa375549(v=vs.85).aspx
+        #[derive(Debug, Copy, Clone)]
+        #[non_exhaustive]
+        pub enum Algorithm {
+            $($(#[$attr])* $name,)*
+            /// An algorithm identified by its `ALG_ID`.
+            Other(u32),
+        }
+
+        impl Algorithm {
+            /// Returns the algorithm identified by an `ALG_ID`.
+            ///
+            /// `Algorithm::Other` is only returned for values without a
+            /// variant of their own.
+            pub fn from_raw(alg_id: u32) -> Algorithm {
+                $(
+                    if alg_id == $value {
+                        return Algorithm::$name;
+                    }
+                )*
+                Algorithm::Other(alg_id)
+            }
+
+            /// Returns the `ALG_ID` of this algorithm.
+            pub fn as_raw(&self) -> u32 {
+                match *self {
+                    $(Algorithm::$name => $value,)*
+                    Algorithm::Other(alg_id) => alg_id,
+                }
+            }
+
+            fn name(&self) -> Option<&'static str> {
+                match *self {
+                    $(Algorithm::$name => Some($display),)*
+                    Algorithm::Other(_) => None,
+                }
+            }
+        }
+
+        /// The names accepted by `Algorithm::from_str`, aliases included.
+        const ALGORITHM_NAMES: &[(&str, Algorithm)] = &[
+            $(
+                ($display, Algorithm::$name),
+                $(($alias, Algorithm::$name),)*
+            )*
+        ];
+    };
+}
+
+algorithms! {
     /// Advanced Encryption Standard (AES).
-    Aes = Cryptography::ALG_CLASS_DATA_ENCRYPT
+    Aes("aes") = Cryptography::ALG_CLASS_DATA_ENCRYPT
         | Cryptography::ALG_TYPE_BLOCK
         | Cryptography::ALG_SID_AES,
     /// 128 bit AES.
-    Aes128 = Cryptography::ALG_CLASS_DATA_ENCRYPT
+    Aes128("aes128") = Cryptography::ALG_CLASS_DATA_ENCRYPT
         | Cryptography::ALG_TYPE_BLOCK
         | Cryptography::ALG_SID_AES_128,
     /// 192 bit AES.
-    Aes192 = Cryptography::ALG_CLASS_DATA_ENCRYPT
+    Aes192("aes192") = Cryptography::ALG_CLASS_DATA_ENCRYPT
         | Cryptography::ALG_TYPE_BLOCK
         | Cryptography::ALG_SID_AES_192,
     /// 256 bit AES.
-    Aes256 = Cryptography::ALG_CLASS_DATA_ENCRYPT
+    Aes256("aes256") = Cryptography::ALG_CLASS_DATA_ENCRYPT
         | Cryptography::ALG_TYPE_BLOCK
         | Cryptography::ALG_SID_AES_256,
     /// Temporary algorithm identifier for handles of Diffie-Hellman–agreed keys.
-    AgreedkeyAny = Cryptography::ALG_CLASS_KEY_EXCHANGE
+    AgreedkeyAny("agreedkey-any") = Cryptography::ALG_CLASS_KEY_EXCHANGE
         | Cryptography::ALG_TYPE_DH
         | Cryptography::ALG_SID_AGREED_KEY_ANY,
     /// An algorithm to create a 40-bit DES key that has parity bits and zeroed key bits to make
     /// its key length 64 bits.
-    CylinkMek = Cryptography::ALG_CLASS_DATA_ENCRYPT
+    CylinkMek("cylink-mek") = Cryptography::ALG_CLASS_DATA_ENCRYPT
         | Cryptography::ALG_TYPE_BLOCK
         | Cryptography::ALG_SID_CYLINK_MEK,
     /// DES encryption algorithm.
-    Des = Cryptography::ALG_CLASS_DATA_ENCRYPT
+    Des("des") = Cryptography::ALG_CLASS_DATA_ENCRYPT
         | Cryptography::ALG_TYPE_BLOCK
         | Cryptography::ALG_SID_DES,
     /// DESX encryption algorithm.
-    Desx = Cryptography::ALG_CLASS_DATA_ENCRYPT
+    Desx("desx") = Cryptography::ALG_CLASS_DATA_ENCRYPT
         | Cryptography::ALG_TYPE_BLOCK
         | Cryptography::ALG_SID_DESX,
     /// Diffie-Hellman ephemeral key exchange algorithm.
-    DhEphem = Cryptography::ALG_CLASS_KEY_EXCHANGE
+    DhEphem("dh-ephem", "dhe") = Cryptography::ALG_CLASS_KEY_EXCHANGE
         | Cryptography::ALG_TYPE_DH
         | Cryptography::ALG_SID_DH_EPHEM,
     /// Diffie-Hellman store and forward key exchange algorithm.
-    DhSf = Cryptography::ALG_CLASS_KEY_EXCHANGE
+    DhSf("dh-sf") = Cryptography::ALG_CLASS_KEY_EXCHANGE
         | Cryptography::ALG_TYPE_DH
         | Cryptography::ALG_SID_DH_SANDF,
     /// DSA public key signature algorithm.
-    DssSign = Cryptography::ALG_CLASS_SIGNATURE
+    DssSign("dss-sign", "dsa") = Cryptography::ALG_CLASS_SIGNATURE
         | Cryptography::ALG_TYPE_DSS
         | Cryptography::ALG_SID_DSS_ANY,
     /// Elliptic curve Diffie-Hellman key exchange algorithm.
-    Ecdh = Cryptography::ALG_CLASS_KEY_EXCHANGE
+    Ecdh("ecdh") = Cryptography::ALG_CLASS_KEY_EXCHANGE
         | Cryptography::ALG_TYPE_DH
         | Cryptography::ALG_SID_ECDH,
     /// Ephemeral elliptic curve Diffie-Hellman key exchange algorithm.
-    EcdhEphem = Cryptography::ALG_CLASS_KEY_EXCHANGE
+    EcdhEphem("ecdh-ephem", "ecdhe") = Cryptography::ALG_CLASS_KEY_EXCHANGE
         | Cryptography::ALG_TYPE_ECDH
         | Cryptography::ALG_SID_ECDH_EPHEM,
     /// Elliptic curve digital signature algorithm.
-    Ecdsa = Cryptography::ALG_CLASS_SIGNATURE
+    Ecdsa("ecdsa") = Cryptography::ALG_CLASS_SIGNATURE
         | Cryptography::ALG_TYPE_DSS
         | Cryptography::ALG_SID_ECDSA,
+    /// Elliptic curve Menezes-Qu-Vanstone key exchange algorithm.
+    Ecmqv("ecmqv") = Cryptography::ALG_CLASS_KEY_EXCHANGE
+        | Cryptography::ALG_TYPE_ANY
+        | Cryptography::ALG_SID_ECMQV,
     /// One way function hashing algorithm.
-    HashReplaceOwf = Cryptography::ALG_CLASS_HASH
+    HashReplaceOwf("hash-replace-owf") = Cryptography::ALG_CLASS_HASH
         | Cryptography::ALG_TYPE_ANY
         | Cryptography::ALG_SID_HASH_REPLACE_OWF,
     /// Hughes MD5 hashing algorithm.
-    HughesMd5 = Cryptography::ALG_CLASS_KEY_EXCHANGE
+    HughesMd5("hughes-md5") = Cryptography::ALG_CLASS_KEY_EXCHANGE
         | Cryptography::ALG_TYPE_ANY
         | Cryptography::ALG_SID_MD5,
     /// HMAC keyed hash algorithm.
-    Hmac = Cryptography::ALG_CLASS_HASH | Cryptography::ALG_TYPE_ANY | Cryptography::ALG_SID_HMAC,
+    Hmac("hmac") = Cryptography::ALG_CLASS_HASH | Cryptography::ALG_TYPE_ANY | Cryptography::ALG_SID_HMAC,
     /// MAC keyed hash algorithm.
-    Mac = Cryptography::ALG_CLASS_HASH | Cryptography::ALG_TYPE_ANY | Cryptography::ALG_SID_MAC,
+    Mac("mac") = Cryptography::ALG_CLASS_HASH | Cryptography::ALG_TYPE_ANY | Cryptography::ALG_SID_MAC,
     /// MD2 hashing algorithm.
-    Md2 = Cryptography::ALG_CLASS_HASH | Cryptography::ALG_TYPE_ANY | Cryptography::ALG_SID_MD2,
+    Md2("md2") = Cryptography::ALG_CLASS_HASH | Cryptography::ALG_TYPE_ANY | Cryptography::ALG_SID_MD2,
     /// MD4 hashing algorithm.
-    Md4 = Cryptography::ALG_CLASS_HASH | Cryptography::ALG_TYPE_ANY | Cryptography::ALG_SID_MD4,
+    Md4("md4") = Cryptography::ALG_CLASS_HASH | Cryptography::ALG_TYPE_ANY | Cryptography::ALG_SID_MD4,
     /// MD5 hashing algorithm.
-    Md5 = Cryptography::ALG_CLASS_HASH | Cryptography::ALG_TYPE_ANY | Cryptography::ALG_SID_MD5,
+    Md5("md5") = Cryptography::ALG_CLASS_HASH | Cryptography::ALG_TYPE_ANY | Cryptography::ALG_SID_MD5,
     /// No signature algorithm..
-    NoSign =
+    NoSign("no-sign") =
         Cryptography::ALG_CLASS_SIGNATURE | Cryptography::ALG_TYPE_ANY | Cryptography::ALG_SID_ANY,
     /// RC2 block encryption algorithm.
-    Rc2 = Cryptography::ALG_CLASS_DATA_ENCRYPT
+    Rc2("rc2") = Cryptography::ALG_CLASS_DATA_ENCRYPT
         | Cryptography::ALG_TYPE_BLOCK
         | Cryptography::ALG_SID_RC2,
     /// RC4 stream encryption algorithm.
-    Rc4 = Cryptography::ALG_CLASS_DATA_ENCRYPT
+    Rc4("rc4") = Cryptography::ALG_CLASS_DATA_ENCRYPT
         | Cryptography::ALG_TYPE_STREAM
         | Cryptography::ALG_SID_RC4,
     /// RC5 block encryption algorithm.
-    Rc5 = Cryptography::ALG_CLASS_DATA_ENCRYPT
+    Rc5("rc5") = Cryptography::ALG_CLASS_DATA_ENCRYPT
         | Cryptography::ALG_TYPE_BLOCK
         | Cryptography::ALG_SID_RC5,
     /// RSA public key exchange algorithm.
-    RsaKeyx = Cryptography::ALG_CLASS_KEY_EXCHANGE
+    RsaKeyx("rsa-keyx", "rsa") = Cryptography::ALG_CLASS_KEY_EXCHANGE
         | Cryptography::ALG_TYPE_RSA
         | Cryptography::ALG_SID_RSA_ANY,
     /// RSA public key signature algorithm.
-    RsaSign = Cryptography::ALG_CLASS_SIGNATURE
+    RsaSign("rsa-sign") = Cryptography::ALG_CLASS_SIGNATURE
         | Cryptography::ALG_TYPE_RSA
         | Cryptography::ALG_SID_RSA_ANY,
-    /// SHA hashing algorithm.
-    Sha1 = Cryptography::ALG_CLASS_HASH | Cryptography::ALG_TYPE_ANY | Cryptography::ALG_SID_SHA1,
+    /// SEAL stream encryption algorithm.
+    Seal("seal") = Cryptography::ALG_CLASS_DATA_ENCRYPT
+        | Cryptography::ALG_TYPE_STREAM
+        | Cryptography::ALG_SID_SEAL,
+    /// SHA hashing algorithm, also known as `CALG_SHA`.
+    Sha1("sha1", "sha") = Cryptography::ALG_CLASS_HASH | Cryptography::ALG_TYPE_ANY | Cryptography::ALG_SID_SHA1,
     /// 256 bit SHA hashing algorithm.
-    Sha256 =
+    Sha256("sha256") =
         Cryptography::ALG_CLASS_HASH | Cryptography::ALG_TYPE_ANY | Cryptography::ALG_SID_SHA_256,
     /// 384 bit SHA hashing algorithm.
-    Sha384 =
+    Sha384("sha384") =
         Cryptography::ALG_CLASS_HASH | Cryptography::ALG_TYPE_ANY | Cryptography::ALG_SID_SHA_384,
     /// 512 bit SHA hashing algorithm.
-    Sha512 =
+    Sha512("sha512") =
         Cryptography::ALG_CLASS_HASH | Cryptography::ALG_TYPE_ANY | Cryptography::ALG_SID_SHA_512,
     /// Triple DES encryption algorithm.
-    TripleDes = Cryptography::ALG_CLASS_DATA_ENCRYPT
+    TripleDes("triple-des", "3des") = Cryptography::ALG_CLASS_DATA_ENCRYPT
         | Cryptography::ALG_TYPE_BLOCK
         | Cryptography::ALG_SID_3DES,
     /// Two-key triple DES encryption with effective key length equal to 112 bits.
-    TripleDes112 = Cryptography::ALG_CLASS_DATA_ENCRYPT
+    TripleDes112("triple-des-112", "3des-112") = Cryptography::ALG_CLASS_DATA_ENCRYPT
         | Cryptography::ALG_TYPE_BLOCK
         | Cryptography::ALG_SID_3DES_112,
 }
 
+impl fmt::Display for Algorithm {
+    /// Formats the algorithm by its name, or by its hexadecimal `ALG_ID` for
+    /// `Algorithm::Other`.
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.name() {
+            Some(name) => fmt.write_str(name),
+            None => write!(fmt, "{:#x}", self.as_raw()),
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = ParseError;
+
+    /// Parses an algorithm name such as `aes256`, `sha384` or `3des`.
+    ///
+    /// Names are case-insensitive and `-` and `_` separators are optional.
+    /// Hexadecimal `ALG_ID`s such as `0x6610` are accepted as well.
+    fn from_str(s: &str) -> Result<Algorithm, ParseError> {
+        if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            if let Ok(alg_id) = u32::from_str_radix(hex, 16) {
+                return Ok(Algorithm::from_raw(alg_id));
+            }
+        }
+        ALGORITHM_NAMES
+            .iter()
+            .find(|&&(name, _)| names_match(name, s))
+            .map(|&(_, algorithm)| algorithm)
+            .ok_or_else(|| ParseError {
+                what: "algorithm",
+                input: s.to_string(),
+                valid: ALGORITHM_NAMES.iter().map(|&(name, _)| name).collect(),
+            })
+    }
+}
+
+impl PartialEq for Algorithm {
+    fn eq(&self, other: &Algorithm) -> bool {
+        self.as_raw() == other.as_raw()
+    }
+}
+
+impl Eq for Algorithm {}
+
+impl Hash for Algorithm {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_raw().hash(state);
+    }
+}
+
 /// Protocols supported by Schannel.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 #[non_exhaustive]
 pub enum Protocol {
     /// Secure Sockets Layer 3.0
@@ -162,9 +357,102 @@ pub enum Protocol {
     Tls13,
 }
 
+/// The names accepted by `Protocol::from_str`, aliases included.
+const PROTOCOL_NAMES: &[(&str, Protocol)] = &[
+    ("ssl3", Protocol::Ssl3),
+    ("sslv3", Protocol::Ssl3),
+    ("tls1.0", Protocol::Tls10),
+    ("tls1", Protocol::Tls10),
+    ("tlsv1", Protocol::Tls10),
+    ("tlsv1.0", Protocol::Tls10),
+    ("tls1.1", Protocol::Tls11),
+    ("tlsv1.1", Protocol::Tls11),
+    ("tls1.2", Protocol::Tls12),
+    ("tlsv1.2", Protocol::Tls12),
+    ("tls1.3", Protocol::Tls13),
+    ("tlsv1.3", Protocol::Tls13),
+];
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Protocol::Ssl3 => "ssl3",
+            Protocol::Tls10 => "tls1.0",
+            Protocol::Tls11 => "tls1.1",
+            Protocol::Tls12 => "tls1.2",
+            Protocol::Tls13 => "tls1.3",
+        };
+        fmt.write_str(name)
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = ParseError;
+
+    /// Parses a protocol name such as `tls1.2`, `tls12` or `TLSv1.2`.
+    ///
+    /// Names are case-insensitive and `.`, `-` and `_` separators are
+    /// optional.
+    fn from_str(s: &str) -> Result<Protocol, ParseError> {
+        PROTOCOL_NAMES
+            .iter()
+            .find(|&&(name, _)| names_match(name, s))
+            .map(|&(_, protocol)| protocol)
+            .ok_or_else(|| ParseError {
+                what: "protocol",
+                input: s.to_string(),
+                valid: PROTOCOL_NAMES.iter().map(|&(name, _)| name).collect(),
+            })
+    }
+}
+
+/// Compares names ignoring case and separators.
+fn names_match(name: &str, input: &str) -> bool {
+    let normalize = |s: &str| {
+        s.chars()
+            .filter(|c| !matches!(c, '-' | '_' | '.'))
+            .map(|c| c.to_ascii_lowercase())
+            .collect::<String>()
+    };
+    normalize(name) == normalize(input)
+}
+
+/// An error returned when parsing an `Algorithm`, `Protocol`, `Direction` or
+/// `CipherPreset` fails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    what: &'static str,
+    input: String,
+    valid: Vec<&'static str>,
+}
+
+impl ParseError {
+    /// Returns the names which would have been accepted.
+    pub fn valid_names(&self) -> &[&'static str] {
+        &self.valid
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "unknown {} `{}`, expected one of: {}",
+            self.what,
+            self.input,
+            self.valid.join(", ")
+        )
+    }
+}
+
+impl error::Error for ParseError {}
+
 impl Protocol {
     fn dword(self, direction: Direction) -> u32 {
         match (self, direction) {
+            (protocol, Direction::Both) => {
+                protocol.dword(Direction::Inbound) | protocol.dword(Direction::Outbound)
+            }
             (Protocol::Ssl3, Direction::Inbound) => Identity::SP_PROT_SSL3_SERVER,
             (Protocol::Tls10, Direction::Inbound) => Identity::SP_PROT_TLS1_0_SERVER,
             (Protocol::Tls11, Direction::Inbound) => Identity::SP_PROT_TLS1_1_SERVER,
@@ -179,12 +467,221 @@ impl Protocol {
     }
 }
 
+/// Predefined sets of protocols and algorithms for `Builder::preset`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum CipherPreset {
+    /// TLS 1.2 and 1.3 with forward secret key exchanges, AES and SHA-2.
+    ///
+    /// This isn't limited to AEAD cipher suites: with TLS 1.2 the ECDHE
+    /// suites using AES in CBC mode with SHA-256 or SHA-384 MACs, such as
+    /// `TLS_ECDHE_RSA_WITH_AES_128_CBC_SHA256`, are allowed as well, since the
+    /// supported algorithms can't tell them apart from the GCM suites.
+    /// Enabling only TLS 1.3 leaves nothing but AEAD suites.
+    Modern,
+    /// Adds AES in CBC mode with SHA-1 MACs, finite field Diffie-Hellman and
+    /// RSA key exchanges, which are older but not known to be broken.
+    Compatible,
+    /// Adds SSL 3.0, TLS 1.0 and 1.1, 3DES and RC4, and no longer restricts
+    /// Schannel to strong cryptography.
+    ///
+    /// This is dangerous: these protocols and ciphers have practical attacks
+    /// and should only be enabled to talk to peers which can't be upgraded.
+    Legacy,
+}
+
+impl CipherPreset {
+    /// Returns the protocols enabled by this preset.
+    pub fn protocols(self) -> &'static [Protocol] {
+        match self {
+            CipherPreset::Modern | CipherPreset::Compatible => &[Protocol::Tls12, Protocol::Tls13],
+            CipherPreset::Legacy => &[
+                Protocol::Ssl3,
+                Protocol::Tls10,
+                Protocol::Tls11,
+                Protocol::Tls12,
+                Protocol::Tls13,
+            ],
+        }
+    }
+
+    /// Returns the algorithms supported by this preset.
+    pub fn algorithms(self) -> &'static [Algorithm] {
+        match self {
+            CipherPreset::Modern => &[
+                Algorithm::Aes128,
+                Algorithm::Aes256,
+                Algorithm::EcdhEphem,
+                Algorithm::Ecdsa,
+                Algorithm::RsaSign,
+                Algorithm::Sha256,
+                Algorithm::Sha384,
+            ],
+            CipherPreset::Compatible => &[
+                Algorithm::Aes128,
+                Algorithm::Aes256,
+                Algorithm::EcdhEphem,
+                Algorithm::DhEphem,
+                Algorithm::RsaKeyx,
+                Algorithm::Ecdsa,
+                Algorithm::RsaSign,
+                Algorithm::Sha1,
+                Algorithm::Sha256,
+                Algorithm::Sha384,
+            ],
+            CipherPreset::Legacy => &[
+                Algorithm::Aes128,
+                Algorithm::Aes256,
+                Algorithm::TripleDes,
+                Algorithm::Rc4,
+                Algorithm::EcdhEphem,
+                Algorithm::DhEphem,
+                Algorithm::RsaKeyx,
+                Algorithm::Ecdsa,
+                Algorithm::RsaSign,
+                Algorithm::DssSign,
+                Algorithm::Md5,
+                Algorithm::Sha1,
+                Algorithm::Sha256,
+                Algorithm::Sha384,
+            ],
+        }
+    }
+
+    /// Returns the minimum and maximum cipher strengths in bits allowed by
+    /// this preset.
+    pub fn cipher_strength(self) -> (u32, u32) {
+        match self {
+            CipherPreset::Modern | CipherPreset::Compatible => (128, 256),
+            CipherPreset::Legacy => (112, 256),
+        }
+    }
+}
+
+/// The names accepted by `CipherPreset::from_str`.
+const CIPHER_PRESET_NAMES: &[(&str, CipherPreset)] = &[
+    ("modern", CipherPreset::Modern),
+    ("compatible", CipherPreset::Compatible),
+    ("legacy", CipherPreset::Legacy),
+];
+
+impl fmt::Display for CipherPreset {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            CipherPreset::Modern => "modern",
+            CipherPreset::Compatible => "compatible",
+            CipherPreset::Legacy => "legacy",
+        };
+        fmt.write_str(name)
+    }
+}
+
+impl FromStr for CipherPreset {
+    type Err = ParseError;
+
+    /// Parses `modern`, `compatible` or `legacy`, ignoring case.
+    fn from_str(s: &str) -> Result<CipherPreset, ParseError> {
+        CIPHER_PRESET_NAMES
+            .iter()
+            .find(|&&(name, _)| names_match(name, s))
+            .map(|&(_, preset)| preset)
+            .ok_or_else(|| ParseError {
+                what: "cipher preset",
+                input: s.to_string(),
+                valid: CIPHER_PRESET_NAMES.iter().map(|&(name, _)| name).collect(),
+            })
+    }
+}
+
+/// Implements `Serialize` and `Deserialize` with `Display` and `FromStr`.
+#[cfg(feature = "serde")]
+macro_rules! serde_names {
+    ($($t:ident: $expecting:literal,)*) => {
+        $(
+            impl serde::Serialize for $t {
+                fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                    serializer.collect_str(self)
+                }
+            }
+
+            impl<'de> serde::Deserialize<'de> for $t {
+                fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<$t, D::Error> {
+                    struct Visitor;
+
+                    impl<'de> serde::de::Visitor<'de> for Visitor {
+                        type Value = $t;
+
+                        fn expecting(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+                            fmt.write_str($expecting)
+                        }
+
+                        fn visit_str<E: serde::de::Error>(self, s: &str) -> Result<$t, E> {
+                            s.parse().map_err(E::custom)
+                        }
+                    }
+
+                    deserializer.deserialize_str(Visitor)
+                }
+            }
+        )*
+    };
+}
+
+#[cfg(feature = "serde")]
+serde_names! {
+    Algorithm: "an algorithm name",
+    Protocol: "a protocol name",
+    Direction: "a direction name",
+    CipherPreset: "a cipher preset name",
+}
+
+/// A setting of a `Builder` which none of the available cipher suites can
+/// satisfy, as returned by `Builder::unsatisfiable`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Unsatisfiable {
+    /// An enabled protocol which none of the cipher suites allowed by the
+    /// other settings can be negotiated with.
+    Protocol(Protocol),
+    /// A supported algorithm which none of the cipher suites allowed by the
+    /// other settings use.
+    Algorithm(Algorithm),
+}
+
+impl fmt::Display for Unsatisfiable {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Unsatisfiable::Protocol(protocol) => {
+                write!(
+                    fmt,
+                    "no available cipher suite allows protocol {}",
+                    protocol
+                )
+            }
+            Unsatisfiable::Algorithm(algorithm) => {
+                write!(
+                    fmt,
+                    "no available cipher suite uses algorithm {}",
+                    algorithm
+                )
+            }
+        }
+    }
+}
+
 /// A builder type for `SchannelCred`s.
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
 pub struct Builder {
     supported_algorithms: Option<Vec<Algorithm>>,
     enabled_protocols: Option<Vec<Protocol>>,
+    cipher_strength: Option<(u32, u32)>,
+    weak_crypto: bool,
+    check_capabilities: bool,
+    silent: bool,
     certs: Vec<CertContext>,
+    // the intermediate certificates sent along with each of `certs`
+    chains: Vec<Vec<CertContext>>,
+    ocsp_response: Option<Vec<u8>>,
 }
 
 impl Builder {
@@ -205,6 +702,60 @@ impl Builder {
         self
     }
 
+    /// Sets the minimum and maximum strength in bits of the ciphers used by
+    /// credentials created from this builder.
+    ///
+    /// A value of 0 uses Schannel's default for that bound.
+    pub fn cipher_strength(&mut self, min: u32, max: u32) -> &mut Builder {
+        self.cipher_strength = Some((min, max));
+        self
+    }
+
+    /// Sets the protocols, algorithms and cipher strengths of a preset.
+    ///
+    /// Options set after this override the values of the preset.
+    pub fn preset(&mut self, preset: CipherPreset) -> &mut Builder {
+        self.enabled_protocols(preset.protocols());
+        self.supported_algorithms(preset.algorithms());
+        let (min, max) = preset.cipher_strength();
+        self.cipher_strength(min, max);
+        self.weak_crypto = preset == CipherPreset::Legacy;
+        self
+    }
+
+    /// Determines if `acquire` checks that the running system supports the
+    /// requested protocols, failing with `io::ErrorKind::Unsupported` if only
+    /// TLS 1.3 is enabled and it isn't supported.
+    ///
+    /// Without the check, such credentials fail later with a handshake
+    /// error. See `schannel::capabilities`. Defaults to `false`.
+    pub fn check_capabilities(&mut self, check: bool) -> &mut Builder {
+        self.check_capabilities = check;
+        self
+    }
+
+    /// Determines if the private keys of the certificates passed to `cert`
+    /// may ask the user for permission or a PIN, as keys on smart cards and
+    /// in some key storage providers do.
+    ///
+    /// If set, `acquire` acquires each key without any user interface and
+    /// caches it with its certificate, so that Schannel signs with the
+    /// silent handle during handshakes. `acquire`, or a handshake with
+    /// providers which only ask when signing, then fails with an error of
+    /// kind `io::ErrorKind::PermissionDenied` and
+    /// `schannel::error::ErrorKind::InteractionRequired` instead of waiting for a
+    /// prompt, which services can never answer. Schannel is never allowed to
+    /// pick a certificate on its own, whether or not this is set.
+    ///
+    /// The credentials returned by a `tls_stream::Builder::client_cert_selector`
+    /// callback are acquired by the callback, so it should set this itself:
+    /// it isn't inherited from the credentials the connection started with.
+    /// Defaults to `false`.
+    pub fn silent(&mut self, silent: bool) -> &mut Builder {
+        self.silent = silent;
+        self
+    }
+
     /// Add a certificate to get passed down when the credentials are acquired.
     ///
     /// Certificates passed here may specify a certificate that contains a
@@ -214,22 +765,191 @@ impl Builder {
     ///
     /// Clients often do not call this function and either depend on Schannel to
     /// find an appropriate certificate or create a certificate later if needed.
+    ///
+    /// Servers may supply several certificates with different key types,
+    /// such as an ECDSA and an RSA certificate, and Schannel selects one
+    /// based on the cipher suites offered by each client. The certificate
+    /// selected for a session is returned by `TlsStream::certificate`.
+    ///
+    /// Every certificate must have a linked private key, otherwise `acquire`
+    /// fails.
     pub fn cert(&mut self, cx: CertContext) -> &mut Builder {
+        self.cert_with_chain(cx, Vec::new())
+    }
+
+    /// Adds a certificate like `cert`, along with the intermediate
+    /// certificates between it and its root.
+    ///
+    /// Schannel only sends the intermediates it can find when building the
+    /// certificate's chain, in the system's stores or the store the
+    /// certificate is in. A certificate imported on its own, in a memory
+    /// store or from a PEM file for example, would reach peers without them
+    /// and fail to chain to their trusted roots. The certificate and its
+    /// intermediates are copied into a memory store when the credentials are
+    /// acquired, so that the peer receives the full chain.
+    pub fn cert_with_chain(
+        &mut self,
+        cx: CertContext,
+        intermediates: Vec<CertContext>,
+    ) -> &mut Builder {
         self.certs.push(cx);
+        self.chains.push(intermediates);
+        self
+    }
+
+    /// Sets a DER-encoded OCSP response to staple for clients requesting the
+    /// status of the server's certificate.
+    ///
+    /// The response is attached to every certificate passed to `cert` when
+    /// the credentials are acquired. Responses expire, so long running
+    /// servers should periodically replace it with
+    /// `CertContext::set_ocsp_response`, which takes effect for new sessions
+    /// without acquiring the credentials again.
+    pub fn ocsp_response(&mut self, der: &[u8]) -> &mut Builder {
+        self.ocsp_response = Some(der.to_owned());
         self
     }
 
-    /// Creates a new `SchannelCred`.
-    pub fn acquire(&self, direction: Direction) -> io::Result<SchannelCred> {
+    /// Checks the protocols, algorithms and cipher strengths set on this
+    /// builder against `suites`, typically the cipher suites returned by
+    /// `schannel::enumerate_cipher_suites`, returning the settings which
+    /// none of them can satisfy.
+    ///
+    /// A cipher suite is allowed if it can be negotiated with one of the
+    /// enabled protocols, its cipher strength is within the bounds, and each
+    /// of its cipher, hash, key exchange and certificate type is allowed by
+    /// one of the supported algorithms. The hash of AEAD suites, which have
+    /// no MAC, is the hash their name ends with. Algorithms which are never part of
+    /// a cipher suite's description, such as `Algorithm::Hmac`, aren't
+    /// reported. Settings left at their defaults aren't checked.
+    pub fn unsatisfiable(&self, suites: &[CipherSuiteInfo]) -> Vec<Unsatisfiable> {
+        let allowed = suites
+            .iter()
+            .filter(|suite| match self.enabled_protocols {
+                Some(ref protocols) => suite.protocols.iter().any(|p| protocols.contains(p)),
+                None => true,
+            })
+            .filter(|suite| match self.supported_algorithms {
+                Some(ref algorithms) => suite.allowed_by(algorithms),
+                None => true,
+            })
+            .filter(|suite| match self.cipher_strength {
+                Some((min, max)) => {
+                    (min == 0 || suite.cipher_bits >= min) && (max == 0 || suite.cipher_bits <= max)
+                }
+                None => true,
+            })
+            .collect::<Vec<_>>();
+
+        let mut unsatisfiable = Vec::new();
+        for &protocol in self.enabled_protocols.iter().flatten() {
+            if !allowed
+                .iter()
+                .any(|suite| suite.protocols.contains(&protocol))
+            {
+                unsatisfiable.push(Unsatisfiable::Protocol(protocol));
+            }
+        }
+        for &algorithm in self.supported_algorithms.iter().flatten() {
+            if cipher_suite::names_component(algorithm)
+                && !allowed.iter().any(|suite| suite.uses(algorithm))
+            {
+                unsatisfiable.push(Unsatisfiable::Algorithm(algorithm));
+            }
+        }
+        unsatisfiable
+    }
+
+    /// Returns the certificates passed to `cert`.
+    pub(crate) fn certs(&self) -> &[CertContext] {
+        &self.certs
+    }
+
+    /// Creates a new `ClientCred` for outbound connections.
+    pub fn acquire_client(&self) -> Result<ClientCred, CrateError> {
+        self.acquire(Direction::Outbound).map(ClientCred)
+    }
+
+    /// Creates a new `ServerCred` for inbound connections.
+    pub fn acquire_server(&self) -> Result<ServerCred, CrateError> {
+        self.acquire(Direction::Inbound).map(ServerCred)
+    }
+
+    /// Creates a new `SchannelCred` for connections in `direction`.
+    ///
+    /// Prefer `acquire_client` or `acquire_server`, whose credentials can't
+    /// be passed to the wrong side of a TLS session.
+    pub fn acquire(&self, direction: Direction) -> Result<SchannelCred, CrateError> {
+        if !self.certs.iter().all(|cert| cert.has_private_key()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "certificate has no private key",
+            )
+            .into());
+        }
+
+        if self.check_capabilities {
+            if let Some(ref protocols) = self.enabled_protocols {
+                let tls13_only =
+                    !protocols.is_empty() && protocols.iter().all(|&p| p == Protocol::Tls13);
+                if tls13_only && !capabilities::capabilities().tls13 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "TLS 1.3 requested but not supported on this OS",
+                    )
+                    .into());
+                }
+            }
+        }
+
+        if let Some(ref der) = self.ocsp_response {
+            for cert in &self.certs {
+                cert.set_ocsp_response(der)?;
+            }
+        }
+
+        let mut certs = Vec::with_capacity(self.certs.len());
+        for (cert, intermediates) in self.certs.iter().zip(&self.chains) {
+            if intermediates.is_empty() {
+                certs.push(cert.clone());
+                continue;
+            }
+            // the copy of the certificate keeps the store alive
+            let mut store = Memory::new()?.into_store();
+            for intermediate in intermediates {
+                store.add_cert(intermediate, CertAdd::UseExisting)?;
+            }
+            certs.push(store.add_cert(cert, CertAdd::UseExisting)?);
+        }
+
+        if self.silent {
+            for cert in &certs {
+                cert.private_key()
+                    .silent(true)
+                    .cache(true)
+                    .acquire()
+                    .map_err(silent_key_error)?;
+            }
+        }
+
         unsafe {
-            let mut handle: Credentials::SecHandle = mem::zeroed();
+            let mut handle = MaybeUninit::<Credentials::SecHandle>::uninit();
             let mut cred_data: Identity::SCHANNEL_CRED = mem::zeroed();
             cred_data.dwVersion = Identity::SCHANNEL_CRED_VERSION;
-            cred_data.dwFlags =
-                Identity::SCH_USE_STRONG_CRYPTO | Identity::SCH_CRED_NO_DEFAULT_CREDS;
-            if let Some(ref supported_algorithms) = self.supported_algorithms {
+            cred_data.dwFlags = Identity::SCH_CRED_NO_DEFAULT_CREDS;
+            if !self.weak_crypto {
+                cred_data.dwFlags |= Identity::SCH_USE_STRONG_CRYPTO;
+            }
+            if self.ocsp_response.is_some() {
+                cred_data.dwFlags |= Identity::SCH_CRED_SNI_ENABLE_OCSP;
+            }
+            let mut supported_algorithms = self
+                .supported_algorithms
+                .as_ref()
+                .map(|algs| algs.iter().map(|a| a.as_raw()).collect::<Vec<_>>());
+            if let Some(ref mut supported_algorithms) = supported_algorithms {
                 cred_data.cSupportedAlgs = supported_algorithms.len() as u32;
-                cred_data.palgSupportedAlgs = supported_algorithms.as_ptr() as *mut _;
+                cred_data.palgSupportedAlgs = supported_algorithms.as_mut_ptr();
             }
             if let Some(ref enabled_protocols) = self.enabled_protocols {
                 cred_data.grbitEnabledProtocols = enabled_protocols
@@ -237,13 +957,18 @@ impl Builder {
                     .map(|p| p.dword(direction))
                     .fold(0, |acc, p| acc | p);
             }
-            let mut certs = self.certs.iter().map(|c| c.as_inner()).collect::<Vec<_>>();
+            if let Some((min, max)) = self.cipher_strength {
+                cred_data.dwMinimumCipherStrength = min;
+                cred_data.dwMaximumCipherStrength = max;
+            }
+            let mut certs = certs.iter().map(|c| c.as_inner()).collect::<Vec<_>>();
             cred_data.cCreds = certs.len() as u32;
             cred_data.paCred = certs.as_mut_ptr() as _;
 
             let direction = match direction {
                 Direction::Inbound => Identity::SECPKG_CRED_INBOUND,
                 Direction::Outbound => Identity::SECPKG_CRED_OUTBOUND,
+                Direction::Both => Identity::SECPKG_CRED_BOTH,
             };
 
             match Identity::AcquireCredentialsHandleA(
@@ -254,22 +979,117 @@ impl Builder {
                 &mut cred_data as *const _ as *const _,
                 None,
                 ptr::null_mut(),
-                &mut handle,
+                handle.as_mut_ptr(),
                 ptr::null_mut(),
             ) {
-                Foundation::SEC_E_OK => Ok(SchannelCred::from_inner(handle)),
-                err => Err(io::Error::from_raw_os_error(err)),
+                Foundation::SEC_E_OK => Ok(SchannelCred::from_inner(handle.assume_init())),
+                err => Err(crate::error::Error::from_code(err).into()),
             }
         }
     }
 }
 
+/// Turns the failure to acquire a key which needs to ask the user into an
+/// `error::Error`, giving it a description and an `error::ErrorKind`.
+fn silent_key_error(err: CrateError) -> CrateError {
+    match err.raw_os_error() {
+        Some(code @ Foundation::NTE_SILENT_CONTEXT) => crate::error::Error::from_code(code).into(),
+        _ => err,
+    }
+}
+
 /// An SChannel credential.
 #[derive(Clone)]
 pub struct SchannelCred(Arc<RawCredHandle>);
 
 struct RawCredHandle(Credentials::SecHandle);
 
+impl fmt::Debug for SchannelCred {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("SchannelCred").finish()
+    }
+}
+
+/// An SChannel credential for the client side of TLS sessions.
+///
+/// Only client credentials can be passed to `tls_stream::Builder::connect`,
+/// so credentials acquired for servers are rejected at compile time:
+///
+/// ```compile_fail
+/// use std::net::TcpStream;
+///
+/// use schannel::schannel_cred::SchannelCred;
+/// use schannel::tls_stream;
+///
+/// let cred = SchannelCred::builder().acquire_server().unwrap();
+/// let stream = TcpStream::connect("google.com:443").unwrap();
+/// let _ = tls_stream::Builder::new().connect(cred, stream);
+/// ```
+///
+/// An untyped `SchannelCred` converts into a `ClientCred` without checking
+/// the direction it was acquired for.
+#[derive(Clone, Debug)]
+pub struct ClientCred(SchannelCred);
+
+/// An SChannel credential for the server side of TLS sessions.
+///
+/// Only server credentials can be passed to `tls_stream::Builder::accept`,
+/// so credentials acquired for clients are rejected at compile time:
+///
+/// ```compile_fail
+/// use std::net::TcpStream;
+///
+/// use schannel::schannel_cred::SchannelCred;
+/// use schannel::tls_stream;
+///
+/// let cred = SchannelCred::builder().acquire_client().unwrap();
+/// let stream = TcpStream::connect("google.com:443").unwrap();
+/// let _ = tls_stream::Builder::new().accept(cred, stream);
+/// ```
+///
+/// An untyped `SchannelCred` converts into a `ServerCred` without checking
+/// the direction it was acquired for.
+#[derive(Clone, Debug)]
+pub struct ServerCred(SchannelCred);
+
+impl ClientCred {
+    /// Returns the untyped credential.
+    pub fn into_inner(self) -> SchannelCred {
+        self.0
+    }
+}
+
+impl ServerCred {
+    /// Returns the untyped credential.
+    pub fn into_inner(self) -> SchannelCred {
+        self.0
+    }
+}
+
+impl From<SchannelCred> for ClientCred {
+    fn from(cred: SchannelCred) -> ClientCred {
+        ClientCred(cred)
+    }
+}
+
+impl From<SchannelCred> for ServerCred {
+    fn from(cred: SchannelCred) -> ServerCred {
+        ServerCred(cred)
+    }
+}
+
+/// ensures that a SchannelCred is always Sync/Send
+///
+/// Credential handles may be used concurrently by any number of security
+/// contexts, and the handle is only freed once the last clone is dropped.
+fn _is_sync() {
+    fn sync<T: Sync + Send>() {}
+    sync::<SchannelCred>();
+    sync::<ClientCred>();
+    sync::<ServerCred>();
+    sync::<Builder>();
+}
+
 impl Drop for RawCredHandle {
     fn drop(&mut self) {
         unsafe {
@@ -292,3 +1112,390 @@ impl SchannelCred {
         self.0.as_ref().0
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::error::Win32Error;
+
+    #[test]
+    fn algorithm_traits() {
+        assert_eq!(Algorithm::Aes256.as_raw(), 0x6610);
+        assert_eq!(Algorithm::Sha384.as_raw(), 0x800d);
+        assert_eq!(Algorithm::EcdhEphem.as_raw(), 0xae06);
+        assert_eq!(format!("{:?}", Algorithm::Aes256), "Aes256");
+
+        let algorithm = Algorithm::Sha256;
+        let copy = algorithm;
+        assert_eq!(algorithm, copy);
+        assert_ne!(algorithm, Algorithm::Sha384);
+
+        let set = [Algorithm::Aes128, Algorithm::Aes256, Algorithm::Aes128]
+            .iter()
+            .cloned()
+            .collect::<HashSet<_>>();
+        assert_eq!(set.len(), 2);
+        assert!(set.contains(&Algorithm::Aes256));
+    }
+
+    #[test]
+    fn algorithm_raw_values() {
+        for &alg_id in [0x6610, 0x800c, 0xae06, 0xa001, 0x6802, 0x8004].iter() {
+            let algorithm = Algorithm::from_raw(alg_id);
+            assert!(!matches!(algorithm, Algorithm::Other(_)), "{:#x}", alg_id);
+            assert_eq!(algorithm.as_raw(), alg_id);
+        }
+        assert_eq!(Algorithm::from_raw(0x6802), Algorithm::Seal);
+        assert_eq!(Algorithm::from_raw(0xa001), Algorithm::Ecmqv);
+
+        let unknown = Algorithm::from_raw(0x1234);
+        assert!(matches!(unknown, Algorithm::Other(0x1234)));
+        assert_eq!(unknown.as_raw(), 0x1234);
+        assert_eq!(Algorithm::Other(0x6610), Algorithm::Aes256);
+        assert_ne!(Algorithm::Other(0x6610), Algorithm::Other(0x660e));
+    }
+
+    #[test]
+    fn algorithm_names() {
+        for &(name, algorithm) in ALGORITHM_NAMES.iter() {
+            assert_eq!(name.parse::<Algorithm>().unwrap(), algorithm);
+            assert_eq!(
+                algorithm.to_string().parse::<Algorithm>().unwrap(),
+                algorithm
+            );
+        }
+        let other = Algorithm::Other(0x1234);
+        assert_eq!(other.to_string(), "0x1234");
+        assert!(matches!(
+            other.to_string().parse::<Algorithm>().unwrap(),
+            Algorithm::Other(0x1234)
+        ));
+
+        assert_eq!(Algorithm::Aes256.to_string(), "aes256");
+        assert_eq!("AES-256".parse::<Algorithm>().unwrap(), Algorithm::Aes256);
+        assert_eq!("3des".parse::<Algorithm>().unwrap(), Algorithm::TripleDes);
+        assert_eq!("0x6610".parse::<Algorithm>().unwrap(), Algorithm::Aes256);
+
+        let err = "aes512".parse::<Algorithm>().unwrap_err();
+        assert!(err.valid_names().contains(&"aes256"));
+        assert!(err
+            .to_string()
+            .starts_with("unknown algorithm `aes512`, expected one of: aes, "));
+    }
+
+    #[test]
+    fn protocol_names() {
+        for &(name, protocol) in PROTOCOL_NAMES.iter() {
+            assert_eq!(name.parse::<Protocol>().unwrap(), protocol);
+            assert_eq!(protocol.to_string().parse::<Protocol>().unwrap(), protocol);
+        }
+        assert_eq!(Protocol::Tls12.to_string(), "tls1.2");
+        assert_eq!("tls12".parse::<Protocol>().unwrap(), Protocol::Tls12);
+        assert_eq!("TLSv1.3".parse::<Protocol>().unwrap(), Protocol::Tls13);
+
+        let err = "tls2".parse::<Protocol>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown protocol `tls2`, expected one of: ssl3, sslv3, tls1.0, tls1, tlsv1, \
+             tlsv1.0, tls1.1, tlsv1.1, tls1.2, tlsv1.2, tls1.3, tlsv1.3"
+        );
+    }
+
+    #[test]
+    fn direction_traits() {
+        let set = [
+            Direction::Inbound,
+            Direction::Outbound,
+            Direction::Both,
+            Direction::Inbound,
+        ]
+        .iter()
+        .cloned()
+        .collect::<HashSet<_>>();
+        assert_eq!(set.len(), 3);
+        assert_eq!(format!("{:?}", Direction::Outbound), "Outbound");
+
+        for &(name, direction) in DIRECTION_NAMES.iter() {
+            assert_eq!(name.parse::<Direction>().unwrap(), direction);
+            assert_eq!(direction.to_string(), name);
+        }
+        assert_eq!("Inbound".parse::<Direction>().unwrap(), Direction::Inbound);
+        assert_eq!(
+            "sideways".parse::<Direction>().unwrap_err().to_string(),
+            "unknown direction `sideways`, expected one of: inbound, outbound, both"
+        );
+    }
+
+    #[test]
+    fn silent_key_errors() {
+        let err =
+            silent_key_error(Win32Error::from_code(Foundation::NTE_SILENT_CONTEXT as u32).into());
+        assert!(matches!(err, CrateError::Security(_)), "{:?}", err);
+        let err = io::Error::from(err);
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(
+            crate::error::Error::from_io_error(&err).map(|e| e.kind()),
+            Some(crate::error::ErrorKind::InteractionRequired)
+        );
+
+        let code = Foundation::CRYPT_E_NO_KEY_PROPERTY;
+        let err = silent_key_error(Win32Error::from_code(code as u32).into());
+        assert_eq!(err.raw_os_error(), Some(code));
+        assert!(matches!(err, CrateError::Win32(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn preset_names() {
+        for &(name, preset) in CIPHER_PRESET_NAMES.iter() {
+            assert_eq!(name.parse::<CipherPreset>().unwrap(), preset);
+            assert_eq!(preset.to_string(), name);
+        }
+        assert_eq!(
+            "strict".parse::<CipherPreset>().unwrap_err().to_string(),
+            "unknown cipher preset `strict`, expected one of: modern, compatible, legacy"
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_names() {
+        fn round_trip<T>(value: T, name: &str)
+        where
+            T: serde::Serialize + serde::de::DeserializeOwned + PartialEq + fmt::Debug,
+        {
+            let json = serde_json::to_string(&value).unwrap();
+            assert_eq!(json, format!("\"{}\"", name));
+            assert_eq!(serde_json::from_str::<T>(&json).unwrap(), value);
+        }
+
+        for &(name, algorithm) in ALGORITHM_NAMES.iter() {
+            round_trip(algorithm, &algorithm.to_string());
+            let json = format!("\"{}\"", name);
+            assert_eq!(serde_json::from_str::<Algorithm>(&json).unwrap(), algorithm);
+        }
+        round_trip(Algorithm::Other(0x1234), "0x1234");
+        for &(name, protocol) in PROTOCOL_NAMES.iter() {
+            round_trip(protocol, &protocol.to_string());
+            let json = format!("\"{}\"", name);
+            assert_eq!(serde_json::from_str::<Protocol>(&json).unwrap(), protocol);
+        }
+        for &(name, direction) in DIRECTION_NAMES.iter() {
+            round_trip(direction, name);
+        }
+        for &(name, preset) in CIPHER_PRESET_NAMES.iter() {
+            round_trip(preset, name);
+        }
+
+        let err = serde_json::from_str::<Protocol>("\"tls2\"").unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("unknown protocol `tls2`, expected one of: ssl3, "));
+        let err = serde_json::from_str::<CipherPreset>("\"strict\"").unwrap_err();
+        assert!(err.to_string().starts_with(
+            "unknown cipher preset `strict`, expected one of: modern, compatible, legacy"
+        ));
+        let err = serde_json::from_str::<Direction>("1").unwrap_err();
+        assert!(err.to_string().contains("expected a direction name"));
+    }
+
+    #[test]
+    fn presets() {
+        let mut builder = Builder::new();
+        builder.preset(CipherPreset::Modern);
+        assert_eq!(
+            builder.enabled_protocols.as_deref().unwrap(),
+            &[Protocol::Tls12, Protocol::Tls13]
+        );
+        assert_eq!(
+            builder.supported_algorithms.as_deref().unwrap(),
+            &[
+                Algorithm::Aes128,
+                Algorithm::Aes256,
+                Algorithm::EcdhEphem,
+                Algorithm::Ecdsa,
+                Algorithm::RsaSign,
+                Algorithm::Sha256,
+                Algorithm::Sha384,
+            ]
+        );
+        assert_eq!(builder.cipher_strength, Some((128, 256)));
+        assert!(!builder.weak_crypto);
+
+        builder.preset(CipherPreset::Compatible);
+        assert_eq!(
+            builder.enabled_protocols.as_deref().unwrap(),
+            &[Protocol::Tls12, Protocol::Tls13]
+        );
+        assert_eq!(
+            builder.supported_algorithms.as_deref().unwrap(),
+            &[
+                Algorithm::Aes128,
+                Algorithm::Aes256,
+                Algorithm::EcdhEphem,
+                Algorithm::DhEphem,
+                Algorithm::RsaKeyx,
+                Algorithm::Ecdsa,
+                Algorithm::RsaSign,
+                Algorithm::Sha1,
+                Algorithm::Sha256,
+                Algorithm::Sha384,
+            ]
+        );
+        assert_eq!(builder.cipher_strength, Some((128, 256)));
+        assert!(!builder.weak_crypto);
+
+        builder.preset(CipherPreset::Legacy);
+        assert_eq!(
+            builder.enabled_protocols.as_deref().unwrap(),
+            &[
+                Protocol::Ssl3,
+                Protocol::Tls10,
+                Protocol::Tls11,
+                Protocol::Tls12,
+                Protocol::Tls13,
+            ]
+        );
+        assert_eq!(
+            builder.supported_algorithms.as_deref().unwrap(),
+            &[
+                Algorithm::Aes128,
+                Algorithm::Aes256,
+                Algorithm::TripleDes,
+                Algorithm::Rc4,
+                Algorithm::EcdhEphem,
+                Algorithm::DhEphem,
+                Algorithm::RsaKeyx,
+                Algorithm::Ecdsa,
+                Algorithm::RsaSign,
+                Algorithm::DssSign,
+                Algorithm::Md5,
+                Algorithm::Sha1,
+                Algorithm::Sha256,
+                Algorithm::Sha384,
+            ]
+        );
+        assert_eq!(builder.cipher_strength, Some((112, 256)));
+        assert!(builder.weak_crypto);
+    }
+
+    #[test]
+    fn preset_overrides() {
+        let mut builder = Builder::new();
+        builder
+            .preset(CipherPreset::Modern)
+            .enabled_protocols(&[Protocol::Tls13])
+            .cipher_strength(256, 0);
+        assert_eq!(
+            builder.enabled_protocols.as_deref().unwrap(),
+            &[Protocol::Tls13]
+        );
+        assert_eq!(
+            builder.supported_algorithms.as_deref().unwrap(),
+            CipherPreset::Modern.algorithms()
+        );
+        assert_eq!(builder.cipher_strength, Some((256, 0)));
+
+        builder
+            .preset(CipherPreset::Legacy)
+            .preset(CipherPreset::Compatible);
+        assert!(!builder.weak_crypto);
+    }
+
+    #[test]
+    fn unsatisfiable() {
+        let suite =
+            |name: &str, protocol, bits, exchange: &str, certificate: &str| CipherSuiteInfo {
+                name: name.to_string(),
+                protocols: vec![protocol],
+                cipher: "AES".to_string(),
+                cipher_bits: bits,
+                exchange: exchange.to_string(),
+                certificate: certificate.to_string(),
+                ..CipherSuiteInfo::default()
+            };
+        let suites = [
+            suite("TLS_AES_256_GCM_SHA384", Protocol::Tls13, 256, "", ""),
+            suite("TLS_AES_128_GCM_SHA256", Protocol::Tls13, 128, "", ""),
+            suite(
+                "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256",
+                Protocol::Tls12,
+                128,
+                "ECDH",
+                "RSA",
+            ),
+            suite(
+                "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384",
+                Protocol::Tls12,
+                256,
+                "ECDH",
+                "ECDSA",
+            ),
+        ];
+
+        let mut builder = Builder::new();
+        assert_eq!(builder.unsatisfiable(&suites), []);
+        builder.preset(CipherPreset::Modern);
+        assert_eq!(builder.unsatisfiable(&suites), []);
+
+        builder.cipher_strength(256, 0);
+        assert_eq!(
+            builder.unsatisfiable(&suites),
+            [
+                Unsatisfiable::Algorithm(Algorithm::Aes128),
+                Unsatisfiable::Algorithm(Algorithm::RsaSign),
+                Unsatisfiable::Algorithm(Algorithm::Sha256),
+            ]
+        );
+
+        let mut builder = Builder::new();
+        builder
+            .enabled_protocols(&[Protocol::Tls11, Protocol::Tls12])
+            .supported_algorithms(&[
+                Algorithm::Aes,
+                Algorithm::TripleDes,
+                Algorithm::EcdhEphem,
+                Algorithm::RsaSign,
+                Algorithm::Sha256,
+                Algorithm::Hmac,
+            ]);
+        assert_eq!(
+            builder.unsatisfiable(&suites),
+            [
+                Unsatisfiable::Protocol(Protocol::Tls11),
+                Unsatisfiable::Algorithm(Algorithm::TripleDes),
+            ]
+        );
+        assert_eq!(
+            Unsatisfiable::Algorithm(Algorithm::TripleDes).to_string(),
+            "no available cipher suite uses algorithm triple-des"
+        );
+    }
+
+    #[test]
+    fn modern_preset_allows_cbc_with_sha2() {
+        let suite = |name: &str, hash: &str| CipherSuiteInfo {
+            name: name.to_string(),
+            protocols: vec![Protocol::Tls12],
+            cipher: "AES".to_string(),
+            cipher_bits: 128,
+            hash: hash.to_string(),
+            exchange: "ECDH".to_string(),
+            certificate: "RSA".to_string(),
+            ..CipherSuiteInfo::default()
+        };
+        let modern = CipherPreset::Modern.algorithms();
+        assert!(suite("TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256", "").allowed_by(modern));
+        assert!(suite("TLS_ECDHE_RSA_WITH_AES_128_CBC_SHA256", "SHA256").allowed_by(modern));
+        assert!(!suite("TLS_ECDHE_RSA_WITH_AES_128_CBC_SHA", "SHA1").allowed_by(modern));
+    }
+
+    #[test]
+    fn protocols_for_both_directions() {
+        assert_eq!(
+            Protocol::Tls12.dword(Direction::Both),
+            Identity::SP_PROT_TLS1_2_SERVER | Identity::SP_PROT_TLS1_2_CLIENT
+        );
+    }
+}
@@ -1,6 +1,8 @@
 use std::io;
-use std::mem;
+use std::mem::{self, MaybeUninit};
+use std::os::windows::io::{FromRawHandle, OwnedHandle};
 use std::ptr;
+use std::slice;
 
 use windows_sys::Win32::Foundation;
 use windows_sys::Win32::Security::Authentication::Identity;
@@ -10,7 +12,7 @@ use crate::alpn_list::AlpnList;
 use crate::cert_context::CertContext;
 use crate::context_buffer::ContextBuffer;
 use crate::schannel_cred::SchannelCred;
-use crate::{secbuf, secbuf_desc, Inner, INIT_REQUESTS};
+use crate::{secbuf, secbuf_desc, secbuf_len, zeroize, Inner, INIT_REQUESTS};
 
 pub struct SecurityContext(Credentials::SecHandle);
 
@@ -37,19 +39,25 @@ impl Inner<Credentials::SecHandle> for SecurityContext {
 }
 
 impl SecurityContext {
+    /// Creates a context, making the first call to
+    /// `InitializeSecurityContextW` for clients.
+    ///
+    /// Returns the token to send to the server, if any, and whether more
+    /// calls are needed, which is only not the case if Schannel completed the
+    /// handshake right away.
     pub fn initialize(
         cred: &mut SchannelCred,
         accept: bool,
         domain: Option<&[u16]>,
         requested_application_protocols: &Option<Vec<Vec<u8>>>,
-    ) -> io::Result<(SecurityContext, Option<ContextBuffer>)> {
+    ) -> io::Result<(SecurityContext, Option<ContextBuffer>, bool)> {
         unsafe {
-            let mut ctxt = mem::zeroed();
-
             if accept {
                 // If we're performing an accept then we need to wait to call
                 // `AcceptSecurityContext` until we've actually read some data.
-                return Ok((SecurityContext(ctxt), None));
+                // Until then the handle is zeroed, as it doesn't refer to a
+                // context.
+                return Ok((SecurityContext(mem::zeroed()), None, true));
             }
 
             let domain = domain.map(|b| b.as_ptr()).unwrap_or(ptr::null_mut());
@@ -59,20 +67,22 @@ impl SecurityContext {
             // Make sure `AlpnList` is kept alive for the duration of this function.
             let mut alpns = requested_application_protocols
                 .as_ref()
-                .map(|alpn| AlpnList::new(alpn));
+                .map(|alpn| AlpnList::new(alpn))
+                .transpose()?;
             if let Some(ref mut alpns) = alpns {
                 inbufs.push(secbuf(
                     Identity::SECBUFFER_APPLICATION_PROTOCOLS,
                     Some(&mut alpns[..]),
-                ));
+                )?);
             };
 
             let inbuf_desc = secbuf_desc(&mut inbufs[..]);
 
-            let mut outbuf = [secbuf(Identity::SECBUFFER_EMPTY, None)];
+            let mut outbuf = [secbuf(Identity::SECBUFFER_EMPTY, None)?];
             let mut outbuf_desc = secbuf_desc(&mut outbuf);
 
             let mut attributes = 0;
+            let mut ctxt = MaybeUninit::<Credentials::SecHandle>::uninit();
 
             match Identity::InitializeSecurityContextW(
                 &cred.as_inner(),
@@ -83,25 +93,34 @@ impl SecurityContext {
                 0,
                 &inbuf_desc,
                 0,
-                &mut ctxt,
+                ctxt.as_mut_ptr(),
                 &mut outbuf_desc,
                 &mut attributes,
                 ptr::null_mut(),
             ) {
-                Foundation::SEC_I_CONTINUE_NEEDED => {
-                    Ok((SecurityContext(ctxt), Some(ContextBuffer(outbuf[0]))))
+                Foundation::SEC_I_CONTINUE_NEEDED => Ok((
+                    SecurityContext(ctxt.assume_init()),
+                    Some(ContextBuffer(outbuf[0])),
+                    true,
+                )),
+                Foundation::SEC_E_OK => {
+                    let token = if outbuf[0].pvBuffer.is_null() {
+                        None
+                    } else {
+                        Some(ContextBuffer(outbuf[0]))
+                    };
+                    Ok((SecurityContext(ctxt.assume_init()), token, false))
                 }
                 err => Err(io::Error::from_raw_os_error(err)),
             }
         }
     }
 
-    unsafe fn attribute<T>(&self, attr: Identity::SECPKG_ATTR) -> io::Result<T> {
-        let mut value = mem::zeroed();
-        let status =
-            Identity::QueryContextAttributesW(&self.0, attr, &mut value as *mut _ as *mut _);
+    pub unsafe fn attribute<T>(&self, attr: Identity::SECPKG_ATTR) -> io::Result<T> {
+        let mut value = MaybeUninit::<T>::uninit();
+        let status = Identity::QueryContextAttributesW(&self.0, attr, value.as_mut_ptr() as *mut _);
         match status {
-            Foundation::SEC_E_OK => Ok(value),
+            Foundation::SEC_E_OK => Ok(value.assume_init()),
             err => Err(io::Error::from_raw_os_error(err)),
         }
     }
@@ -114,8 +133,16 @@ impl SecurityContext {
         unsafe { self.attribute(Identity::SECPKG_ATTR_SESSION_INFO) }
     }
 
+    pub fn connection_info(&self) -> io::Result<Identity::SecPkgContext_ConnectionInfo> {
+        unsafe { self.attribute(Identity::SECPKG_ATTR_CONNECTION_INFO) }
+    }
+
+    pub fn cipher_info(&self) -> io::Result<Identity::SecPkgContext_CipherInfo> {
+        unsafe { self.attribute(Identity::SECPKG_ATTR_CIPHER_INFO) }
+    }
+
     pub fn stream_sizes(&self) -> io::Result<Identity::SecPkgContext_StreamSizes> {
-        unsafe { self.attribute(Identity::SECPKG_ATTR_STREAM_SIZES) }
+        unsafe { check_stream_sizes(self.attribute(Identity::SECPKG_ATTR_STREAM_SIZES)?) }
     }
 
     pub fn remote_cert(&self) -> io::Result<CertContext> {
@@ -131,4 +158,152 @@ impl SecurityContext {
                 .map(|p| CertContext::from_inner(p))
         }
     }
+
+    /// Returns the DER encoded names of the issuers the server accepts for
+    /// client certificates.
+    pub fn issuer_list(&self) -> io::Result<Vec<Vec<u8>>> {
+        unsafe {
+            let list: Identity::SecPkgContext_IssuerListInfoEx =
+                self.attribute(Identity::SECPKG_ATTR_ISSUER_LIST_EX)?;
+            if list.aIssuers.is_null() {
+                return Ok(Vec::new());
+            }
+            let issuers = slice::from_raw_parts(list.aIssuers, list.cIssuers as usize)
+                .iter()
+                .map(|blob| match blob.cbData {
+                    0 => Vec::new(),
+                    len => slice::from_raw_parts(blob.pbData, len as usize).to_vec(),
+                })
+                .collect();
+            Identity::FreeContextBuffer(list.aIssuers as *mut _);
+            Ok(issuers)
+        }
+    }
+
+    /// Returns the server name the client asked for, or `None` if it didn't
+    /// specify one.
+    pub fn client_specified_target(&self) -> io::Result<Option<String>> {
+        unsafe {
+            let target: Identity::SecPkgContext_ClientSpecifiedTarget =
+                match self.attribute(Identity::SECPKG_ATTR_CLIENT_SPECIFIED_TARGET) {
+                    Ok(target) => target,
+                    Err(ref e) if e.raw_os_error() == Some(Foundation::SEC_E_TARGET_UNKNOWN) => {
+                        return Ok(None)
+                    }
+                    Err(e) => return Err(e),
+                };
+            if target.sTargetName.is_null() {
+                return Ok(None);
+            }
+            let mut len = 0;
+            while *target.sTargetName.add(len) != 0 {
+                len += 1;
+            }
+            let name = String::from_utf16_lossy(slice::from_raw_parts(target.sTargetName, len));
+            Identity::FreeContextBuffer(target.sTargetName as *mut _);
+            Ok(if name.is_empty() { None } else { Some(name) })
+        }
+    }
+
+    /// Serializes the context with `ExportSecurityContext`.
+    ///
+    /// The serialized context holds the session keys, so Schannel's copy is
+    /// wiped before it is freed.
+    pub fn export(&self) -> io::Result<Vec<u8>> {
+        unsafe {
+            let mut packed: Identity::SecBuffer = mem::zeroed();
+            match Identity::ExportSecurityContext(&self.0, 0, &mut packed, ptr::null_mut()) {
+                Foundation::SEC_E_OK => {}
+                err => return Err(io::Error::from_raw_os_error(err)),
+            }
+            let packed = ContextBuffer(packed);
+            let data = packed.to_vec();
+            if !packed.0.pvBuffer.is_null() {
+                zeroize(slice::from_raw_parts_mut(
+                    packed.0.pvBuffer as *mut u8,
+                    packed.0.cbBuffer as usize,
+                ));
+            }
+            Ok(data)
+        }
+    }
+
+    /// Creates a context from one serialized by `export`, with
+    /// `ImportSecurityContextW`.
+    pub fn import(packed: &[u8]) -> io::Result<SecurityContext> {
+        let package = Identity::UNISP_NAME_W
+            .encode_utf16()
+            .chain(Some(0))
+            .collect::<Vec<_>>();
+        unsafe {
+            let packed = Identity::SecBuffer {
+                cbBuffer: secbuf_len(packed.len())?,
+                BufferType: Identity::SECBUFFER_EMPTY,
+                pvBuffer: packed.as_ptr() as *mut _,
+            };
+            let mut context = MaybeUninit::<Credentials::SecHandle>::uninit();
+            match Identity::ImportSecurityContextW(
+                package.as_ptr(),
+                &packed,
+                ptr::null(),
+                context.as_mut_ptr(),
+            ) {
+                Foundation::SEC_E_OK => Ok(SecurityContext(context.assume_init())),
+                err => Err(io::Error::from_raw_os_error(err)),
+            }
+        }
+    }
+
+    /// Returns the access token of the account the client was mapped to.
+    pub fn token(&self) -> io::Result<OwnedHandle> {
+        unsafe {
+            let mut token = ptr::null_mut();
+            match Identity::QuerySecurityContextToken(&self.0, &mut token) {
+                Foundation::SEC_E_OK => Ok(OwnedHandle::from_raw_handle(token as _)),
+                err => Err(io::Error::from_raw_os_error(err)),
+            }
+        }
+    }
+}
+
+/// The largest record Schannel may be trusted to report, well above the
+/// limits of the TLS specification.
+const MAX_RECORD_SIZE: u64 = 64 * 1024;
+
+/// Rejects stream sizes which can't describe a valid TLS record, so they can
+/// be used in arithmetic without further checks.
+fn check_stream_sizes(
+    sizes: Identity::SecPkgContext_StreamSizes,
+) -> io::Result<Identity::SecPkgContext_StreamSizes> {
+    let total = sizes.cbHeader as u64 + sizes.cbMaximumMessage as u64 + sizes.cbTrailer as u64;
+    if sizes.cbMaximumMessage == 0 || total > MAX_RECORD_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Schannel reported invalid stream sizes",
+        ));
+    }
+    Ok(sizes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sizes(header: u32, max_message: u32, trailer: u32) -> Identity::SecPkgContext_StreamSizes {
+        Identity::SecPkgContext_StreamSizes {
+            cbHeader: header,
+            cbTrailer: trailer,
+            cbMaximumMessage: max_message,
+            cBuffers: 4,
+            cbBlockSize: 16,
+        }
+    }
+
+    #[test]
+    fn invalid_stream_sizes() {
+        assert!(check_stream_sizes(sizes(5, 16384, 36)).is_ok());
+        assert!(check_stream_sizes(sizes(5, 0, 36)).is_err());
+        assert!(check_stream_sizes(sizes(5, u32::MAX, 36)).is_err());
+        assert!(check_stream_sizes(sizes(u32::MAX, 16384, u32::MAX)).is_err());
+    }
 }
@@ -1,21 +1,35 @@
 use std::env;
-use std::io::{self, Error, Read, Write};
+use std::ffi::c_void;
+use std::io::{self, BufRead, Error, Read, Write};
 use std::mem;
-use std::net::{TcpListener, TcpStream};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, TcpListener, TcpStream};
 use std::ptr;
-use std::sync::Once;
+use std::sync::{self, mpsc, Once};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use windows_sys::Win32::Foundation;
+use windows_sys::Win32::Security::Authentication::Identity;
 use windows_sys::Win32::Security::Cryptography;
 use windows_sys::Win32::System::{SystemInformation, Time};
 
 use crate::alpn_list::AlpnList;
-use crate::cert_context::{CertContext, HashAlgorithm, KeySpec};
+use crate::cert_context::{
+    CertContext, HashAlgorithm, KeySpec, PrivateKey, CERT_ALT_NAME_IP_ADDRESS,
+};
 use crate::cert_store::{CertAdd, CertStore, Memory};
+use crate::client_hello;
 use crate::crypt_prov::{AcquireOptions, ProviderType};
-use crate::schannel_cred::{Algorithm, Direction, Protocol, SchannelCred};
-use crate::tls_stream::{self, HandshakeError};
+use crate::duplex::Duplex;
+use crate::error;
+use crate::identity;
+use crate::mock_stream::{self, MockStream, PipeStream};
+use crate::schannel_cred::{self, Algorithm, CipherPreset, Direction, Protocol, SchannelCred};
+use crate::tls_connector::{TlsAcceptor, TlsConnector};
+use crate::tls_stream::{
+    self, AlertDescription, AlertLevel, ClientCertPolicy, CopyError, ExportedContext,
+    HandshakeError, RecordDirection, SessionState, TlsEngine, TlsStream,
+};
 use crate::Inner;
 
 #[test]
@@ -35,6 +49,36 @@ fn basic() {
     assert!(out.ends_with(b"</html>") || out.ends_with(b"</HTML>\r\n"));
 }
 
+#[cfg(feature = "tokio")]
+#[test]
+fn basic_async() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let creds = SchannelCred::builder()
+            .acquire(Direction::Outbound)
+            .unwrap();
+        let stream = tokio::net::TcpStream::connect("google.com:443")
+            .await
+            .unwrap();
+        let mut stream = tls_stream::Builder::new()
+            .domain("google.com")
+            .connect_async(creds, stream)
+            .await
+            .unwrap();
+        stream.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+        stream.flush().await.unwrap();
+        let mut out = vec![];
+        stream.read_to_end(&mut out).await.unwrap();
+        assert!(out.starts_with(b"HTTP/1.0 200 OK") || out.starts_with(b"HTTP/1.0 302 Found"));
+        assert!(out.ends_with(b"</html>") || out.ends_with(b"</HTML>\r\n"));
+    });
+}
+
 #[test]
 fn invalid_algorithms() {
     let creds = SchannelCred::builder()
@@ -66,7 +110,8 @@ fn valid_algorithms() {
 
 fn unwrap_handshake<S>(e: HandshakeError<S>) -> io::Error {
     match e {
-        HandshakeError::Failure(e) => e,
+        HandshakeError::Failure(e) => io::Error::from_raw_os_error(e.code()),
+        HandshakeError::Io(e) => e,
         HandshakeError::Interrupted(_) => panic!("not an I/O error"),
     }
 }
@@ -198,6 +243,47 @@ fn wrong_host_cert() {
     );
 }
 
+#[test]
+fn cert_error_code() {
+    let creds = SchannelCred::builder()
+        .acquire(Direction::Outbound)
+        .unwrap();
+    let stream = TcpStream::connect("expired.badssl.com:443").unwrap();
+    let err = tls_stream::Builder::new()
+        .domain("expired.badssl.com")
+        .connect(creds, stream)
+        .err()
+        .unwrap();
+    let err = match err {
+        HandshakeError::Failure(err) => err,
+        _ => panic!("expected a TLS failure"),
+    };
+    assert_eq!(err.code(), Foundation::CERT_E_EXPIRED);
+    assert_eq!(err, error::Error::from_code(Foundation::CERT_E_EXPIRED));
+    assert_eq!(err.kind(), error::ErrorKind::CertExpired);
+}
+
+#[test]
+fn handshake_error_source() {
+    let creds = SchannelCred::builder()
+        .acquire(Direction::Outbound)
+        .unwrap();
+    let stream = TcpStream::connect("wrong.host.badssl.com:443").unwrap();
+    let err = tls_stream::Builder::new()
+        .domain("wrong.host.badssl.com")
+        .connect(creds, stream)
+        .err()
+        .unwrap();
+    let source = std::error::Error::source(&err).unwrap();
+    let tls_err = *source.downcast_ref::<error::Error>().unwrap();
+    assert_eq!(tls_err.kind(), error::ErrorKind::CertNameMismatch);
+    assert!(err.to_string().ends_with(&tls_err.to_string()));
+
+    let err = io::Error::from(tls_err);
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(error::Error::from_io_error(&err), Some(tls_err));
+}
+
 #[test]
 fn wrong_host_cert_ignored() {
     let creds = SchannelCred::builder()
@@ -547,7 +633,7 @@ fn local_root_store() -> CertStore {
     }
 }
 
-fn localhost_cert() -> Option<CertContext> {
+pub(crate) fn localhost_cert() -> Option<CertContext> {
     if env::var("SCHANNEL_RS_SKIP_SERVER_TESTS").is_ok() {
         return None;
     }
@@ -624,7 +710,7 @@ fn accept_a_socket() {
     let listener = TcpListener::bind("127.0.0.1:0").unwrap();
     let addr = listener.local_addr().unwrap();
     let t = thread::spawn(move || {
-        let stream = TcpStream::connect(&addr).unwrap();
+        let stream = TcpStream::connect(addr).unwrap();
         let creds = SchannelCred::builder()
             .acquire(Direction::Outbound)
             .unwrap();
@@ -684,7 +770,7 @@ fn accept_one_byte_at_a_time() {
     let listener = TcpListener::bind("127.0.0.1:0").unwrap();
     let addr = listener.local_addr().unwrap();
     let t = thread::spawn(move || {
-        let stream = TcpStream::connect(&addr).unwrap();
+        let stream = TcpStream::connect(addr).unwrap();
         let creds = SchannelCred::builder()
             .acquire(Direction::Outbound)
             .unwrap();
@@ -716,69 +802,110 @@ fn accept_one_byte_at_a_time() {
 }
 
 #[test]
-fn split_cert_key() {
+fn into_inner_after_shutdown() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
     let listener = TcpListener::bind("127.0.0.1:0").unwrap();
     let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel();
     let t = thread::spawn(move || {
-        let cert = include_bytes!("../test/cert.der");
-        let mut store = Memory::new().unwrap();
-        store.add_encoded_certificate(cert).unwrap();
-        let store = store.into_store();
-
-        let stream = TcpStream::connect(&addr).unwrap();
+        let stream = TcpStream::connect(addr).unwrap();
         let creds = SchannelCred::builder()
             .acquire(Direction::Outbound)
             .unwrap();
         let mut stream = tls_stream::Builder::new()
-            .domain("foobar.com")
-            .cert_store(store)
+            .domain("localhost")
             .connect(creds, stream)
             .unwrap();
         stream.write_all(&[1, 2, 3, 4]).unwrap();
-        stream.flush().unwrap();
-        assert_eq!(stream.read(&mut [0; 1024]).unwrap(), 4);
         stream.shutdown().unwrap();
+        let (mut stream, buf) = stream.into_inner().unwrap();
+        assert!(buf.is_empty());
+
+        // wait for the server to see the close_notify before reusing the
+        // socket for plaintext
+        rx.recv().unwrap();
+        stream.write_all(b"plaintext").unwrap();
     });
 
-    let cert = include_bytes!("../test/cert.der");
-    let cert = CertContext::new(cert).unwrap();
+    let stream = listener.accept().unwrap().0;
+    let creds = SchannelCred::builder()
+        .cert(cert)
+        .acquire(Direction::Inbound)
+        .unwrap();
+    let mut stream = tls_stream::Builder::new().accept(creds, stream).unwrap();
+    let mut out = vec![];
+    stream.read_to_end(&mut out).unwrap();
+    assert_eq!(out, [1, 2, 3, 4]);
+    let (mut stream, buf) = stream.into_inner().unwrap();
+    assert!(buf.is_empty());
 
-    let mut options = AcquireOptions::new();
-    options.container("schannel-test");
-    let type_ = ProviderType::rsa_full();
+    tx.send(()).unwrap();
+    let mut out = vec![];
+    stream.read_to_end(&mut out).unwrap();
+    assert_eq!(out, b"plaintext");
 
-    let mut container = match options.acquire(type_) {
-        Ok(container) => container,
-        Err(_) => options.new_keyset(true).acquire(type_).unwrap(),
+    t.join().unwrap();
+}
+
+#[test]
+fn split_echo() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
     };
-    let key = include_bytes!("../test/key.key");
-    container.import().import(key).unwrap();
 
-    cert.set_key_prov_info()
-        .container("schannel-test")
-        .type_(type_)
-        .keep_open(true)
-        .key_spec(KeySpec::key_exchange())
-        .set()
-        .unwrap();
+    const LEN: usize = 1024 * 1024;
+    let data = (0..LEN).map(|i| i as u8).collect::<Vec<_>>();
 
-    let stream = listener.accept().unwrap().0;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let stream = listener.accept().unwrap().0;
+        let creds = SchannelCred::builder()
+            .cert(cert)
+            .acquire(Direction::Inbound)
+            .unwrap();
+        let mut stream = tls_stream::Builder::new().accept(creds, stream).unwrap();
+        let mut buf = [0; 4096];
+        let mut total = 0;
+        while total < LEN {
+            let nread = stream.read(&mut buf).unwrap();
+            assert!(nread > 0);
+            stream.write_all(&buf[..nread]).unwrap();
+            total += nread;
+        }
+        stream.flush().unwrap();
+    });
+
+    let stream = TcpStream::connect(addr).unwrap();
     let creds = SchannelCred::builder()
-        .cert(cert)
-        .acquire(Direction::Inbound)
+        .acquire(Direction::Outbound)
         .unwrap();
-    let mut stream = tls_stream::Builder::new().accept(creds, stream).unwrap();
-    assert_eq!(stream.read(&mut [0; 1024]).unwrap(), 4);
-    stream.write_all(&[1, 2, 3, 4]).unwrap();
-    stream.flush().unwrap();
-    let mut buf = [0; 1];
-    assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    let stream = tls_stream::Builder::new()
+        .domain("localhost")
+        .connect(creds, stream)
+        .unwrap();
+    let (mut reader, mut writer) = stream.split();
 
+    let expected = data.clone();
+    let r = thread::spawn(move || {
+        let mut out = vec![0; LEN];
+        reader.read_exact(&mut out).unwrap();
+        assert!(out == expected);
+    });
+    writer.write_all(&data).unwrap();
+    writer.flush().unwrap();
+
+    r.join().unwrap();
     t.join().unwrap();
 }
 
 #[test]
-fn test_loopback_alpn() {
+fn peer_close_notify() {
     let cert = match localhost_cert() {
         Some(cert) => cert,
         None => return,
@@ -787,23 +914,17 @@ fn test_loopback_alpn() {
     let listener = TcpListener::bind("127.0.0.1:0").unwrap();
     let addr = listener.local_addr().unwrap();
     let t = thread::spawn(move || {
-        let stream = TcpStream::connect(&addr).unwrap();
+        let stream = TcpStream::connect(addr).unwrap();
         let creds = SchannelCred::builder()
             .acquire(Direction::Outbound)
             .unwrap();
         let mut stream = tls_stream::Builder::new()
             .domain("localhost")
-            .request_application_protocols(&[b"h2"])
             .connect(creds, stream)
             .unwrap();
-        assert_eq!(
-            stream
-                .negotiated_application_protocol()
-                .expect("localhost unreachable"),
-            Some(b"h2".to_vec())
-        );
-
+        stream.write_all(&[1, 2, 3, 4]).unwrap();
         stream.shutdown().unwrap();
+        assert_eq!(stream.session_state(), SessionState::Shutdown);
     });
 
     let stream = listener.accept().unwrap().0;
@@ -811,22 +932,23 @@ fn test_loopback_alpn() {
         .cert(cert)
         .acquire(Direction::Inbound)
         .unwrap();
-    let stream = tls_stream::Builder::new()
-        .request_application_protocols(&[b"h2"])
-        .accept(creds, stream)
-        .unwrap();
+    let mut stream = tls_stream::Builder::new().accept(creds, stream).unwrap();
+    let mut out = vec![];
+    stream.read_to_end(&mut out).unwrap();
+    assert_eq!(out, [1, 2, 3, 4]);
+    assert_eq!(stream.session_state(), SessionState::Shutdown);
+    assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+    let err = stream.write(&[1]).unwrap_err();
     assert_eq!(
-        stream
-            .negotiated_application_protocol()
-            .expect("localhost unreachable"),
-        Some(b"h2".to_vec())
+        err.raw_os_error().unwrap(),
+        Foundation::SEC_E_CONTEXT_EXPIRED
     );
 
     t.join().unwrap();
 }
 
 #[test]
-fn test_loopback_alpn_mismatch() {
+fn truncated_stream() {
     let cert = match localhost_cert() {
         Some(cert) => cert,
         None => return,
@@ -835,7 +957,7 @@ fn test_loopback_alpn_mismatch() {
     let listener = TcpListener::bind("127.0.0.1:0").unwrap();
     let addr = listener.local_addr().unwrap();
     let t = thread::spawn(move || {
-        let stream = TcpStream::connect(&addr).unwrap();
+        let stream = TcpStream::connect(addr).unwrap();
         let creds = SchannelCred::builder()
             .acquire(Direction::Outbound)
             .unwrap();
@@ -843,14 +965,10 @@ fn test_loopback_alpn_mismatch() {
             .domain("localhost")
             .connect(creds, stream)
             .unwrap();
-        assert_eq!(
-            stream
-                .negotiated_application_protocol()
-                .expect("localhost unreachable"),
-            None
-        );
-
-        stream.shutdown().unwrap();
+        stream.write_all(&[1, 2, 3, 4]).unwrap();
+        // close the connection without sending a close_notify
+        let (stream, _) = stream.into_inner().unwrap();
+        stream.shutdown(Shutdown::Both).unwrap();
     });
 
     let stream = listener.accept().unwrap().0;
@@ -858,66 +976,4586 @@ fn test_loopback_alpn_mismatch() {
         .cert(cert)
         .acquire(Direction::Inbound)
         .unwrap();
-    let stream = tls_stream::Builder::new()
-        .request_application_protocols(&[b"h2"])
-        .accept(creds, stream)
-        .unwrap();
-    assert_eq!(
-        stream
-            .negotiated_application_protocol()
-            .expect("localhost unreachable"),
-        None
-    );
+    let mut stream = tls_stream::Builder::new().accept(creds, stream).unwrap();
+    let mut out = vec![];
+    stream.read_to_end(&mut out).unwrap();
+    assert_eq!(out, [1, 2, 3, 4]);
+    assert_eq!(stream.session_state(), SessionState::Streaming);
 
     t.join().unwrap();
 }
 
-#[test]
-fn test_external_alpn() {
+/// Completes the handshake of a client over a `MockStream` with a server
+/// engine, scripting what the server sends in response to each flight.
+///
+/// Returns the client, the server, and the number of bytes the client wrote
+/// during the handshake.
+fn mock_client(cert: CertContext) -> (TlsStream<MockStream>, TlsEngine, usize) {
     let creds = SchannelCred::builder()
-        .acquire(Direction::Outbound)
+        .cert(cert)
+        .acquire(Direction::Inbound)
         .unwrap();
-    let stream = TcpStream::connect("google.com:443").unwrap();
-    let stream = tls_stream::Builder::new()
-        .request_application_protocols(&[b"h2"])
-        .domain("google.com")
-        .connect(creds, stream)
+    let mut server = tls_stream::Builder::new().accept_engine(creds).unwrap();
+    let creds = SchannelCred::builder()
+        .acquire(Direction::Outbound)
         .unwrap();
-    assert_eq!(
-        stream
-            .negotiated_application_protocol()
-            .expect("google.com unreachable"),
-        Some(b"h2".to_vec())
-    );
+    let mut stream = MockStream::new();
+    stream.would_block();
+    let mut result = tls_stream::Builder::new()
+        .domain("localhost")
+        .connect(creds, stream);
+
+    let mut sent = 0;
+    for _ in 0..10 {
+        let mut stream = match result {
+            Err(HandshakeError::Interrupted(stream)) => stream,
+            Err(_) => panic!("the handshake failed"),
+            Ok(mut stream) => {
+                server
+                    .handshake_input(&stream.get_ref().written()[sent..])
+                    .unwrap();
+                assert_eq!(server.session_state(), SessionState::Streaming);
+                let sent = stream.get_ref().written().len();
+                // the handshake may have completed without the last read
+                if !stream.get_ref().is_done() {
+                    let err = stream.read(&mut [0; 1]).unwrap_err();
+                    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+                }
+                return (stream, server, sent);
+            }
+        };
+        let written = stream.get_ref().written()[sent..].to_vec();
+        sent += written.len();
+        server.handshake_input(&written).unwrap();
+        let mut to_client = vec![];
+        server.take_output(&mut to_client);
+        stream.get_mut().data(&to_client).would_block();
+        result = stream.handshake();
+    }
+    panic!("the handshake did not complete");
 }
 
 #[test]
-fn test_alpn_list() {
-    let raw_proto_alpn_list = b"\x02h2";
-    // Little-endian bit representation of the expected `SEC_APPLICATION_PROTOCOL_LIST`.
-    let proto_list = &[
-        // `sspi::SecApplicationProtocolNegotiationExt_ALPN` equals 2.
-        &[2, 0, 0, 0, raw_proto_alpn_list.len() as u8, 0] as &[u8],
-        raw_proto_alpn_list,
-    ]
-    .concat();
-    let full_alpn_list = [&[proto_list.len() as u8, 0, 0, 0] as &[u8], proto_list].concat();
-    assert_eq!(
-        &AlpnList::new(&[b"h2".to_vec()]) as &[u8],
-        &full_alpn_list as &[u8]
-    );
+fn mock_peer_close_notify() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
 
-    let raw_proto_alpn_list = b"\x02h2\x08http/1.1";
-    // Little-endian bit representation of the expected `SEC_APPLICATION_PROTOCOL_LIST`.
-    let proto_list = &[
-        // `sspi::SecApplicationProtocolNegotiationExt_ALPN` equals 2.
-        &[2, 0, 0, 0, raw_proto_alpn_list.len() as u8, 0] as &[u8],
-        raw_proto_alpn_list,
-    ]
-    .concat();
-    let full_alpn_list = [&[proto_list.len() as u8, 0, 0, 0] as &[u8], proto_list].concat();
+    let (mut stream, mut server, sent) = mock_client(cert);
+    let mut to_client = vec![];
+    server.wrap(&[1, 2, 3, 4], &mut to_client).unwrap();
+    server.shutdown().unwrap();
+    server.take_output(&mut to_client);
+    stream.get_mut().data(&to_client);
+
+    let mut out = vec![];
+    stream.read_to_end(&mut out).unwrap();
+    assert_eq!(out, [1, 2, 3, 4]);
+    assert_eq!(stream.session_state(), SessionState::Shutdown);
+    assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+    let err = stream.write(&[1]).unwrap_err();
     assert_eq!(
-        &AlpnList::new(&[b"h2".to_vec(), b"http/1.1".to_vec()]) as &[u8],
-        &full_alpn_list as &[u8]
+        err.raw_os_error().unwrap(),
+        Foundation::SEC_E_CONTEXT_EXPIRED
     );
+
+    // our close_notify has been sent in response
+    let reply = &stream.get_ref().written()[sent..];
+    assert_eq!(reply[0], 0x15);
+    server.unwrap(reply, &mut out).unwrap();
+    assert_eq!(out, [1, 2, 3, 4]);
+    assert_eq!(server.session_state(), SessionState::Shutdown);
+}
+
+#[test]
+fn mock_truncated_stream() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let (mut stream, mut server, sent) = mock_client(cert);
+    let mut to_client = vec![];
+    server.wrap(&[1, 2, 3, 4], &mut to_client).unwrap();
+    // the connection is closed without a close_notify
+    stream.get_mut().data(&to_client).eof();
+
+    let mut out = vec![];
+    stream.read_to_end(&mut out).unwrap();
+    assert_eq!(out, [1, 2, 3, 4]);
+    assert_eq!(stream.session_state(), SessionState::Streaming);
+    assert_eq!(stream.get_ref().written().len(), sent);
+}
+
+#[test]
+fn mock_write_after_interrupted_write_prepared() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let (mut stream, mut server, sent) = mock_client(cert);
+    let (mut buf, payload) = stream.prepare_buffer(5).unwrap();
+    buf[payload.clone()].copy_from_slice(b"first");
+    stream.get_mut().write_would_block();
+    stream.write_prepared(&mut buf, payload).unwrap();
+    assert!(stream.pending_write_len() > 0);
+
+    // the record left over is sent ahead of the next one
+    stream.write_all(b"second").unwrap();
+    assert_eq!(stream.pending_write_len(), 0);
+    let mut plaintext = vec![];
+    server
+        .unwrap(&stream.get_ref().written()[sent..], &mut plaintext)
+        .unwrap();
+    assert_eq!(plaintext, b"firstsecond");
+}
+
+#[test]
+fn mock_resume_interrupted_copy_from() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let (mut stream, mut server, sent) = mock_client(cert);
+    stream.get_mut().write_would_block();
+    let err = stream.copy_from(&mut &b"abcdef"[..]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    let copy_error = err.get_ref().unwrap().downcast_ref::<CopyError>().unwrap();
+    assert_eq!(copy_error.sent(), 0);
+    assert_eq!(copy_error.pending(), 6);
+
+    // the record is sent before anything written afterwards
+    assert!(stream.flush_pending().unwrap() > 0);
+    assert_eq!(stream.pending_write_len(), 0);
+    stream.write_all(b"ghi").unwrap();
+
+    // a write right away doesn't lose the record either
+    stream.get_mut().write_would_block();
+    let err = stream.copy_from(&mut &b"jkl"[..]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    stream.write_all(b"mno").unwrap();
+
+    let mut plaintext = vec![];
+    server
+        .unwrap(&stream.get_ref().written()[sent..], &mut plaintext)
+        .unwrap();
+    assert_eq!(plaintext, b"abcdefghijklmno");
+}
+
+#[test]
+fn mock_errors_during_shutdown() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    // failing to answer the peer's close_notify still reads as a clean close
+    let (mut stream, mut server, _) = mock_client(cert.clone());
+    let mut to_client = vec![];
+    server.shutdown().unwrap();
+    server.take_output(&mut to_client);
+    stream
+        .get_mut()
+        .data(&to_client)
+        .write_error(io::ErrorKind::BrokenPipe);
+    assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+    assert_eq!(stream.session_state(), SessionState::Shutdown);
+
+    // a shutdown started on this side reports the failure
+    let (mut stream, _, _) = mock_client(cert);
+    stream.get_mut().write_would_block();
+    let err = stream.shutdown().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    assert_eq!(stream.session_state(), SessionState::ShuttingDown);
+    stream.get_mut().write_error(io::ErrorKind::BrokenPipe);
+    let err = stream.read(&mut [0; 1]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+}
+
+#[test]
+fn peek() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let stream = TcpStream::connect(addr).unwrap();
+        let creds = SchannelCred::builder()
+            .acquire(Direction::Outbound)
+            .unwrap();
+        let mut stream = tls_stream::Builder::new()
+            .domain("localhost")
+            .connect(creds, stream)
+            .unwrap();
+        stream.write_all(b"PRI * HTTP/2.0").unwrap();
+        stream.shutdown().unwrap();
+    });
+
+    let stream = listener.accept().unwrap().0;
+    let creds = SchannelCred::builder()
+        .cert(cert)
+        .acquire(Direction::Inbound)
+        .unwrap();
+    let mut stream = tls_stream::Builder::new().accept(creds, stream).unwrap();
+    let mut peeked = [0; 3];
+    assert_eq!(stream.peek(&mut peeked).unwrap(), 3);
+    assert_eq!(&peeked, b"PRI");
+    let mut read = [0; 3];
+    assert_eq!(stream.read(&mut read).unwrap(), 3);
+    assert_eq!(peeked, read);
+
+    let mut out = vec![];
+    stream.read_to_end(&mut out).unwrap();
+    assert_eq!(out, b" * HTTP/2.0");
+    assert_eq!(stream.peek(&mut peeked).unwrap(), 0);
+
+    t.join().unwrap();
+}
+
+#[test]
+fn flush_after_would_block() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    #[derive(Debug)]
+    struct BlockNextWrite<S> {
+        inner: S,
+        block: bool,
+    }
+
+    impl<S: Read> Read for BlockNextWrite<S> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl<S: Write> Write for BlockNextWrite<S> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.block {
+                self.block = false;
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let stream = TcpStream::connect(addr).unwrap();
+        let creds = SchannelCred::builder()
+            .acquire(Direction::Outbound)
+            .unwrap();
+        let stream = BlockNextWrite {
+            inner: stream,
+            block: false,
+        };
+        let mut stream = tls_stream::Builder::new()
+            .domain("localhost")
+            .connect(creds, stream)
+            .unwrap();
+        stream.get_mut().block = true;
+        let err = stream.write(b"hello").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        // the record is delivered by flush, and repeating the write doesn't
+        // send it again
+        stream.flush().unwrap();
+        assert_eq!(stream.write(b"hello").unwrap(), 5);
+        stream.shutdown().unwrap();
+    });
+
+    let stream = listener.accept().unwrap().0;
+    let creds = SchannelCred::builder()
+        .cert(cert)
+        .acquire(Direction::Inbound)
+        .unwrap();
+    let mut stream = tls_stream::Builder::new().accept(creds, stream).unwrap();
+    let mut out = vec![];
+    stream.read_to_end(&mut out).unwrap();
+    assert_eq!(out, b"hello");
+
+    t.join().unwrap();
+}
+
+#[test]
+fn shrink_buffers() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    const BASELINE: usize = 4096;
+    const LEN: usize = 1024 * 1024;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel();
+    let t = thread::spawn(move || {
+        let stream = listener.accept().unwrap().0;
+        let creds = SchannelCred::builder()
+            .cert(cert)
+            .acquire(Direction::Inbound)
+            .unwrap();
+        let mut stream = tls_stream::Builder::new()
+            .shrink_buffers(Some(BASELINE))
+            .accept(creds, stream)
+            .unwrap();
+        stream.write_all(&vec![1; LEN]).unwrap();
+        stream.flush().unwrap();
+        assert!(stream.buffer_capacities().output <= BASELINE);
+        rx.recv().unwrap();
+    });
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let creds = SchannelCred::builder()
+        .acquire(Direction::Outbound)
+        .unwrap();
+    let mut stream = tls_stream::Builder::new()
+        .domain("localhost")
+        .shrink_buffers(Some(BASELINE))
+        .connect(creds, stream)
+        .unwrap();
+    let mut out = vec![0; LEN];
+    stream.read_exact(&mut out).unwrap();
+    assert!(out.iter().all(|&b| b == 1));
+    let capacities = stream.buffer_capacities();
+    assert!(capacities.input <= BASELINE);
+    assert!(capacities.output <= BASELINE);
+    tx.send(()).unwrap();
+
+    t.join().unwrap();
+}
+
+#[test]
+fn initial_buffer_sizes() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    const INITIAL: usize = 64 * 1024;
+    const LEN: usize = 1024 * 1024;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let stream = listener.accept().unwrap().0;
+        let creds = SchannelCred::builder()
+            .cert(cert)
+            .acquire(Direction::Inbound)
+            .unwrap();
+        // tiny buffers still grow as needed
+        let mut stream = tls_stream::Builder::new()
+            .initial_read_buffer_size(1)
+            .initial_write_buffer_size(1)
+            .accept(creds, stream)
+            .unwrap();
+        stream.write_all(&vec![1; LEN]).unwrap();
+        stream.flush().unwrap();
+    });
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let creds = SchannelCred::builder()
+        .acquire(Direction::Outbound)
+        .unwrap();
+    let mut stream = tls_stream::Builder::new()
+        .domain("localhost")
+        .initial_read_buffer_size(INITIAL)
+        .initial_write_buffer_size(INITIAL)
+        .shrink_buffers(Some(4096))
+        .connect(creds, stream)
+        .unwrap();
+    let capacities = stream.buffer_capacities();
+    assert!(capacities.input >= INITIAL);
+    assert!(capacities.output >= INITIAL);
+
+    let mut out = vec![0; LEN];
+    stream.read_exact(&mut out).unwrap();
+    assert!(out.iter().all(|&b| b == 1));
+    // shrinking stops at the initial size
+    let capacities = stream.buffer_capacities();
+    assert!(capacities.input >= INITIAL);
+    assert!(capacities.output >= INITIAL);
+
+    t.join().unwrap();
+}
+
+#[test]
+fn read_to_end_large() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    const LEN: usize = 8 * 1024 * 1024;
+    let data = (0..LEN).map(|i| (i * 7) as u8).collect::<Vec<_>>();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let expected = data.clone();
+    let t = thread::spawn(move || {
+        let stream = listener.accept().unwrap().0;
+        let creds = SchannelCred::builder()
+            .cert(cert)
+            .acquire(Direction::Inbound)
+            .unwrap();
+        let mut stream = tls_stream::Builder::new().accept(creds, stream).unwrap();
+        stream.write_all(&data).unwrap();
+        stream.shutdown().unwrap();
+    });
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let creds = SchannelCred::builder()
+        .acquire(Direction::Outbound)
+        .unwrap();
+    let mut stream = tls_stream::Builder::new()
+        .domain("localhost")
+        .connect(creds, stream)
+        .unwrap();
+    let mut out = vec![];
+    stream.read_to_end(&mut out).unwrap();
+    assert!(out == expected);
+    assert_eq!(stream.session_state(), SessionState::Shutdown);
+
+    t.join().unwrap();
+}
+
+#[test]
+fn read_to_end_appends() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let stream = listener.accept().unwrap().0;
+        let creds = SchannelCred::builder()
+            .cert(cert)
+            .acquire(Direction::Inbound)
+            .unwrap();
+        let mut stream = tls_stream::Builder::new().accept(creds, stream).unwrap();
+        for _ in 0..100 {
+            stream.write_all(&[1; 1000]).unwrap();
+        }
+        stream.shutdown().unwrap();
+    });
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let creds = SchannelCred::builder()
+        .acquire(Direction::Outbound)
+        .unwrap();
+    let mut stream = tls_stream::Builder::new()
+        .domain("localhost")
+        .connect(creds, stream)
+        .unwrap();
+    let mut first = [0; 10];
+    stream.read_exact(&mut first).unwrap();
+
+    let mut out = b"prefix".to_vec();
+    assert_eq!(stream.read_to_end(&mut out).unwrap(), 100 * 1000 - 10);
+    assert_eq!(&out[..6], b"prefix");
+    assert!(out[6..].iter().all(|&b| b == 1));
+    assert_eq!(stream.session_state(), SessionState::Shutdown);
+    assert_eq!(stream.read_to_end(&mut out).unwrap(), 0);
+    assert_eq!(out.len(), 6 + 100 * 1000 - 10);
+
+    t.join().unwrap();
+}
+
+#[test]
+fn write_prepared() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let stream = listener.accept().unwrap().0;
+        let creds = SchannelCred::builder()
+            .cert(cert)
+            .acquire(Direction::Inbound)
+            .unwrap();
+        let mut stream = tls_stream::Builder::new().accept(creds, stream).unwrap();
+        let mut buf = [0; 3000];
+        stream.read_exact(&mut buf).unwrap();
+        assert!(buf[..1000].iter().all(|&b| b == 1));
+        assert!(buf[1000..].iter().all(|&b| b == 2));
+    });
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let creds = SchannelCred::builder()
+        .acquire(Direction::Outbound)
+        .unwrap();
+    let mut stream = tls_stream::Builder::new()
+        .domain("localhost")
+        .connect(creds, stream)
+        .unwrap();
+
+    let (mut buf, payload) = stream.prepare_buffer(1000).unwrap();
+    for b in &mut buf[payload.clone()] {
+        *b = 1;
+    }
+    stream.write_prepared(&mut buf, payload).unwrap();
+    assert!(buf.is_empty());
+
+    let (mut buf, payload) = stream.prepare_buffer(2000).unwrap();
+    for b in &mut buf[payload.clone()] {
+        *b = 2;
+    }
+    let err = stream.write_prepared(&mut buf, 0..payload.end).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    let err = stream
+        .write_prepared(&mut buf, payload.start..payload.end + 1000)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    stream.write_prepared(&mut buf, payload).unwrap();
+    stream.flush().unwrap();
+
+    t.join().unwrap();
+}
+
+#[test]
+fn copy_from() {
+    struct Failing;
+
+    impl Read for Failing {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("reader failed"))
+        }
+    }
+
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let (client, server) = offline_pair(
+        tls_stream::Builder::new().domain("localhost"),
+        &mut SchannelCred::builder(),
+        cert,
+    );
+    let mut client = client.unwrap();
+    let mut server = server.unwrap();
+    let data = (0..300_000u32).map(|i| i as u8).collect::<Vec<_>>();
+
+    assert_eq!(client.copy_from(&mut &data[..]).unwrap(), data.len() as u64);
+    let mut buf = vec![0; data.len()];
+    server.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, data);
+    assert_eq!(client.copy_from(&mut io::empty()).unwrap(), 0);
+
+    let err = client
+        .copy_from(&mut (&data[..40_000]).chain(Failing))
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+    let copy_err = err.get_ref().unwrap().downcast_ref::<CopyError>().unwrap();
+    assert_eq!(copy_err.sent(), 40_000);
+    assert_eq!(copy_err.pending(), 0);
+    assert_eq!(copy_err.error().to_string(), "reader failed");
+    let mut buf = vec![0; 40_000];
+    server.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, &data[..40_000]);
+
+    // the record which doesn't fit is sent by flush
+    client.get_mut().write_limit(Some(20_000));
+    let err = client.copy_from(&mut &data[..]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    let copy_err = err.get_ref().unwrap().downcast_ref::<CopyError>().unwrap();
+    let copied = (copy_err.sent() + copy_err.pending()) as usize;
+    assert!(copy_err.sent() > 0, "{:?}", copy_err);
+    assert!(copy_err.pending() > 0, "{:?}", copy_err);
+    client.get_mut().write_limit(None);
+    client.flush().unwrap();
+    let mut buf = vec![0; copied];
+    server.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, &data[..copied]);
+}
+
+#[test]
+fn read_into_spare() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let (client, server) = offline_pair(
+        tls_stream::Builder::new().domain("localhost"),
+        &mut SchannelCred::builder(),
+        cert,
+    );
+    let mut client = client.unwrap();
+    let mut server = server.unwrap();
+    let data = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    client.write_all(&data).unwrap();
+    client.flush().unwrap();
+
+    let mut buf = Vec::with_capacity(1000);
+    let len = server.read_into_spare(&mut buf).unwrap();
+    assert!(len > 0 && len <= 1000, "{}", len);
+    assert_eq!(buf.capacity(), 1000);
+    while buf.len() < data.len() {
+        buf.reserve(5000);
+        server.read_into_spare(&mut buf).unwrap();
+    }
+    assert_eq!(buf, data);
+
+    // the same data through read
+    client.write_all(&data).unwrap();
+    client.flush().unwrap();
+    let mut read = vec![0; data.len()];
+    server.read_exact(&mut read).unwrap();
+    assert_eq!(read, buf);
+
+    // without spare capacity everything buffered is appended
+    client.write_all(&data[..3000]).unwrap();
+    client.flush().unwrap();
+    let mut buf = Vec::new();
+    while buf.len() < 3000 {
+        buf.shrink_to_fit();
+        assert!(server.read_into_spare(&mut buf).unwrap() > 0);
+    }
+    assert_eq!(buf, &data[..3000]);
+
+    drop(client);
+    let mut buf = Vec::with_capacity(10);
+    assert_eq!(server.read_into_spare(&mut buf).unwrap(), 0);
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn record_sized_reads() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    const RECORDS: usize = 4;
+    const LEN: usize = RECORDS * 16 * 1024;
+
+    #[derive(Debug)]
+    struct CountReads<S> {
+        inner: S,
+        reads: usize,
+    }
+
+    impl<S: Read> Read for CountReads<S> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            self.inner.read(buf)
+        }
+    }
+
+    impl<S: Write> Write for CountReads<S> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel();
+    let t = thread::spawn(move || {
+        let stream = listener.accept().unwrap().0;
+        let creds = SchannelCred::builder()
+            .cert(cert)
+            .acquire(Direction::Inbound)
+            .unwrap();
+        let mut stream = tls_stream::Builder::new().accept(creds, stream).unwrap();
+        stream.write_all(b"hello").unwrap();
+        stream.flush().unwrap();
+        stream.write_all(&vec![1; LEN]).unwrap();
+        stream.flush().unwrap();
+        tx.send(()).unwrap();
+        assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+    });
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let creds = SchannelCred::builder()
+        .acquire(Direction::Outbound)
+        .unwrap();
+    let stream = CountReads {
+        inner: stream,
+        reads: 0,
+    };
+    let mut stream = tls_stream::Builder::new()
+        .domain("localhost")
+        .connect(creds, stream)
+        .unwrap();
+    let mut out = [0; 5];
+    stream.read_exact(&mut out).unwrap();
+    assert_eq!(&out, b"hello");
+
+    // wait for all of the records to be sent, so that each read call can
+    // receive as much data as the buffer allows
+    rx.recv().unwrap();
+    stream.get_mut().reads = 0;
+    let mut out = vec![0; LEN];
+    stream.read_exact(&mut out).unwrap();
+    assert!(out.iter().all(|&b| b == 1));
+    assert!(stream.get_ref().reads <= RECORDS);
+    stream.shutdown().unwrap();
+
+    t.join().unwrap();
+}
+
+#[test]
+fn oversized_handshake_message() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let mut stream = listener.accept().unwrap().0;
+        assert!(stream.read(&mut [0; 1024]).unwrap() > 0);
+        // the header of a 16 KiB handshake record whose body never arrives
+        // in full
+        stream.write_all(&[0x16, 0x03, 0x03, 0x40, 0x00]).unwrap();
+        stream.write_all(&[0; 2048]).unwrap();
+        let _ = stream.read(&mut [0; 1]);
+    });
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let creds = SchannelCred::builder()
+        .acquire(Direction::Outbound)
+        .unwrap();
+    let err = tls_stream::Builder::new()
+        .domain("localhost")
+        .max_handshake_message_size(1024)
+        .connect(creds, stream)
+        .err()
+        .unwrap();
+    let err = unwrap_handshake(err);
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    t.join().unwrap();
+}
+
+#[test]
+fn buffered_read_data() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    const LEN: usize = 16 * 1024;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let stream = listener.accept().unwrap().0;
+        let creds = SchannelCred::builder()
+            .cert(cert)
+            .acquire(Direction::Inbound)
+            .unwrap();
+        let mut stream = tls_stream::Builder::new().accept(creds, stream).unwrap();
+        stream.write_all(&[1; LEN]).unwrap();
+        stream.flush().unwrap();
+        assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+    });
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let creds = SchannelCred::builder()
+        .acquire(Direction::Outbound)
+        .unwrap();
+    let mut stream = tls_stream::Builder::new()
+        .domain("localhost")
+        .connect(creds, stream)
+        .unwrap();
+    assert_eq!(stream.buffered_read_len(), 0);
+
+    let mut out = [0; 100];
+    stream.read_exact(&mut out).unwrap();
+    assert!(stream.has_buffered_read_data());
+    assert_eq!(stream.buffered_read_len(), LEN - 100);
+
+    let mut out = vec![0; LEN - 100];
+    stream.read_exact(&mut out).unwrap();
+    assert!(!stream.has_buffered_read_data());
+    assert_eq!(stream.buffered_read_len(), 0);
+    stream.shutdown().unwrap();
+
+    t.join().unwrap();
+}
+
+#[test]
+fn write_zero() {
+    struct WriteZero;
+
+    impl Read for WriteZero {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Ok(0)
+        }
+    }
+
+    impl Write for WriteZero {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Ok(0)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let creds = SchannelCred::builder()
+        .acquire(Direction::Outbound)
+        .unwrap();
+    let err = tls_stream::Builder::new()
+        .domain("localhost")
+        .connect(creds, WriteZero)
+        .err()
+        .unwrap();
+    let err = unwrap_handshake(err);
+    assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+}
+
+#[test]
+fn transport_errors_during_handshake() {
+    #[derive(Debug)]
+    struct Failing(io::ErrorKind);
+
+    impl Read for Failing {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(self.0, "transport failure"))
+        }
+    }
+
+    impl Write for Failing {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    for &kind in &[io::ErrorKind::TimedOut, io::ErrorKind::ConnectionReset] {
+        let creds = SchannelCred::builder()
+            .acquire(Direction::Outbound)
+            .unwrap();
+        let err = tls_stream::Builder::new()
+            .domain("localhost")
+            .connect(creds, Failing(kind))
+            .err()
+            .unwrap();
+        let source = std::error::Error::source(&err)
+            .and_then(|e| e.downcast_ref::<io::Error>())
+            .unwrap();
+        assert_eq!(source.kind(), kind);
+        let err = match err {
+            HandshakeError::Io(err) => err,
+            _ => panic!("expected an I/O error"),
+        };
+        assert_eq!(err.kind(), kind);
+        assert_eq!(err.to_string(), "transport failure");
+        assert!(err.raw_os_error().is_none());
+    }
+
+    let creds = SchannelCred::builder()
+        .acquire(Direction::Outbound)
+        .unwrap();
+    let stream = match tls_stream::Builder::new()
+        .domain("localhost")
+        .connect(creds, Failing(io::ErrorKind::WouldBlock))
+    {
+        Err(HandshakeError::Interrupted(stream)) => stream,
+        _ => panic!("expected an interrupted handshake"),
+    };
+    match stream.handshake() {
+        Err(HandshakeError::Interrupted(_)) => {}
+        _ => panic!("expected an interrupted handshake"),
+    }
+}
+
+#[test]
+fn resume_shutdown() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    #[derive(Debug)]
+    struct Interrupt<S> {
+        inner: S,
+        block_write: bool,
+        block_flush: bool,
+    }
+
+    impl<S: Read> Read for Interrupt<S> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl<S: Write> Write for Interrupt<S> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.block_write {
+                self.block_write = false;
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            if self.block_flush {
+                self.block_flush = false;
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.inner.flush()
+        }
+    }
+
+    for &(block_write, block_flush) in &[(true, false), (false, true), (true, true)] {
+        let cert = cert.clone();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let t = thread::spawn(move || {
+            let stream = listener.accept().unwrap().0;
+            let creds = SchannelCred::builder()
+                .cert(cert)
+                .acquire(Direction::Inbound)
+                .unwrap();
+            let mut stream = tls_stream::Builder::new().accept(creds, stream).unwrap();
+            let mut out = vec![];
+            stream.read_to_end(&mut out).unwrap();
+            assert_eq!(out, b"hello");
+            assert_eq!(stream.session_state(), SessionState::Shutdown);
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let creds = SchannelCred::builder()
+            .acquire(Direction::Outbound)
+            .unwrap();
+        let stream = Interrupt {
+            inner: stream,
+            block_write: false,
+            block_flush: false,
+        };
+        let mut stream = tls_stream::Builder::new()
+            .domain("localhost")
+            .connect(creds, stream)
+            .unwrap();
+        stream.write_all(b"hello").unwrap();
+        stream.get_mut().block_write = block_write;
+        stream.get_mut().block_flush = block_flush;
+
+        loop {
+            match stream.shutdown() {
+                Ok(()) => break,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    assert_eq!(stream.session_state(), SessionState::ShuttingDown);
+                }
+                Err(e) => panic!("{}", e),
+            }
+        }
+        assert_eq!(stream.session_state(), SessionState::Shutdown);
+        assert!(!stream.get_ref().block_write);
+        assert!(!stream.get_ref().block_flush);
+        stream.shutdown().unwrap();
+
+        t.join().unwrap();
+    }
+}
+
+#[test]
+fn tls13_post_handshake_messages() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    const LEN: usize = 4 * 1024 * 1024;
+
+    for &split in &[false, true] {
+        // TLS 1.3 isn't available on older versions of Windows.
+        let server_creds = match SchannelCred::builder()
+            .cert(cert.clone())
+            .enabled_protocols(&[Protocol::Tls13])
+            .acquire(Direction::Inbound)
+        {
+            Ok(creds) => creds,
+            Err(_) => return,
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let t = thread::spawn(move || {
+            let stream = listener.accept().unwrap().0;
+            let mut stream = tls_stream::Builder::new()
+                .accept(server_creds, stream)
+                .unwrap();
+            stream.write_all(&vec![1; LEN]).unwrap();
+            let mut response = [0; 4];
+            stream.read_exact(&mut response).unwrap();
+            assert_eq!(&response, b"done");
+            stream.write_all(&vec![2; LEN]).unwrap();
+            stream.shutdown().unwrap();
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let creds = SchannelCred::builder()
+            .enabled_protocols(&[Protocol::Tls13])
+            .acquire(Direction::Outbound)
+            .unwrap();
+        let stream = tls_stream::Builder::new()
+            .domain("localhost")
+            .connect(creds, stream)
+            .unwrap();
+        let mut buf = vec![0; LEN];
+        let mut out = vec![];
+
+        // The session tickets sent after the handshake arrive in front of
+        // the data and must not interrupt it.
+        if split {
+            let (mut reader, mut writer) = stream.split();
+            reader.read_exact(&mut buf).unwrap();
+            writer.write_all(b"done").unwrap();
+            writer.flush().unwrap();
+            reader.read_to_end(&mut out).unwrap();
+        } else {
+            let mut stream = stream;
+            stream.read_exact(&mut buf).unwrap();
+            stream.write_all(b"done").unwrap();
+            stream.read_to_end(&mut out).unwrap();
+            assert_eq!(stream.session_state(), SessionState::Shutdown);
+        }
+        assert!(buf.iter().all(|&b| b == 1), "split: {}", split);
+        assert_eq!(out.len(), LEN, "split: {}", split);
+        assert!(out.iter().all(|&b| b == 2), "split: {}", split);
+
+        t.join().unwrap();
+    }
+}
+
+#[test]
+fn stats() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let stream = listener.accept().unwrap().0;
+        let creds = SchannelCred::builder()
+            .cert(cert)
+            .enabled_protocols(&[Protocol::Tls12])
+            .acquire(Direction::Inbound)
+            .unwrap();
+        let mut stream = tls_stream::Builder::new().accept(creds, stream).unwrap();
+        let mut buf = [0; 10_000];
+        stream.read_exact(&mut buf).unwrap();
+        stream.write_all(&[0; 5000]).unwrap();
+
+        let stats = stream.stats();
+        assert_eq!(stats.bytes_read, 10_000);
+        assert_eq!(stats.records_received, 10);
+        assert_eq!(stats.bytes_written, 5000);
+        assert_eq!(stats.records_sent, 1);
+        assert_eq!(stats.renegotiations, 0);
+        assert!(stats.handshake_duration.is_some());
+    });
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let creds = SchannelCred::builder()
+        .enabled_protocols(&[Protocol::Tls12])
+        .acquire(Direction::Outbound)
+        .unwrap();
+    let mut stream = tls_stream::Builder::new()
+        .domain("localhost")
+        .connect(creds, stream)
+        .unwrap();
+    assert_eq!(stream.stats().records_sent, 0);
+    assert!(stream.stats().handshake_duration.is_some());
+    for _ in 0..10 {
+        stream.write_all(&[0; 1000]).unwrap();
+    }
+    let mut buf = [0; 5000];
+    stream.read_exact(&mut buf).unwrap();
+
+    let stats = stream.stats();
+    assert_eq!(stats.bytes_read, 5000);
+    assert_eq!(stats.records_received, 1);
+    assert_eq!(stats.bytes_written, 10_000);
+    assert_eq!(stats.records_sent, 10);
+    assert_eq!(stats.renegotiations, 0);
+
+    t.join().unwrap();
+}
+
+#[test]
+fn record_observer() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let stream = listener.accept().unwrap().0;
+        let creds = SchannelCred::builder()
+            .cert(cert)
+            .acquire(Direction::Inbound)
+            .unwrap();
+        let mut stream = tls_stream::Builder::new().accept(creds, stream).unwrap();
+        let mut buf = [0; 600];
+        stream.read_exact(&mut buf).unwrap();
+        stream.write_all(&buf).unwrap();
+    });
+
+    let records = sync::Arc::new(sync::Mutex::new(vec![]));
+    let observed = records.clone();
+    let stream = TcpStream::connect(addr).unwrap();
+    let creds = SchannelCred::builder()
+        .enabled_protocols(&[Protocol::Tls12])
+        .acquire(Direction::Outbound)
+        .unwrap();
+    let mut stream = tls_stream::Builder::new()
+        .domain("localhost")
+        .record_observer(move |record| {
+            observed.lock().unwrap().push((
+                record.direction(),
+                record.is_handshake(),
+                record.is_application_data(),
+                record.data().len(),
+            ));
+        })
+        .connect(creds, stream)
+        .unwrap();
+
+    {
+        let records = records.lock().unwrap();
+        assert!(records.iter().any(|r| r.0 == RecordDirection::Sent && r.1));
+        assert!(records
+            .iter()
+            .any(|r| r.0 == RecordDirection::Received && r.1));
+        assert!(!records.iter().any(|r| r.2));
+    }
+
+    for len in &[100, 200, 300] {
+        stream.write_all(&vec![0; *len]).unwrap();
+    }
+    let mut buf = [0; 600];
+    stream.read_exact(&mut buf).unwrap();
+    t.join().unwrap();
+
+    let records = records.lock().unwrap();
+    let sent = records
+        .iter()
+        .filter(|r| r.0 == RecordDirection::Sent && r.2)
+        .map(|r| r.3)
+        .collect::<Vec<_>>();
+    assert_eq!(sent.len(), 3);
+    assert!(sent[0] > 100 && sent[1] > 200 && sent[2] > 300);
+    let received = records
+        .iter()
+        .filter(|r| r.0 == RecordDirection::Received && r.2)
+        .count();
+    assert_eq!(received, 1);
+}
+
+#[test]
+fn shutdown_write() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let stream = listener.accept().unwrap().0;
+        let creds = SchannelCred::builder()
+            .cert(cert)
+            .acquire(Direction::Inbound)
+            .unwrap();
+        let mut stream = tls_stream::Builder::new().accept(creds, stream).unwrap();
+        let mut request = [0; 7];
+        stream.read_exact(&mut request).unwrap();
+        assert_eq!(&request, b"request");
+        stream.write_all(&vec![1; 64 * 1024]).unwrap();
+        stream.shutdown().unwrap();
+    });
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let creds = SchannelCred::builder()
+        .acquire(Direction::Outbound)
+        .unwrap();
+    let mut stream = tls_stream::Builder::new()
+        .domain("localhost")
+        .connect(creds, stream)
+        .unwrap();
+    stream.write_all(b"request").unwrap();
+    stream.shutdown_write().unwrap();
+    assert_eq!(stream.session_state(), SessionState::WriteShutdown);
+    assert!(stream.write(b"more").is_err());
+
+    let mut out = vec![];
+    stream.read_to_end(&mut out).unwrap();
+    assert_eq!(out.len(), 64 * 1024);
+    assert!(out.iter().all(|&b| b == 1));
+    assert_eq!(stream.session_state(), SessionState::Shutdown);
+
+    t.join().unwrap();
+}
+
+#[test]
+fn engine_loopback() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let creds = SchannelCred::builder()
+        .cert(cert)
+        .acquire(Direction::Inbound)
+        .unwrap();
+    let mut server = tls_stream::Builder::new().accept_engine(creds).unwrap();
+    let creds = SchannelCred::builder()
+        .acquire(Direction::Outbound)
+        .unwrap();
+    let mut client = tls_stream::Builder::new()
+        .domain("localhost")
+        .connect_engine(creds)
+        .unwrap();
+
+    assert!(client.wrap(b"early", &mut vec![]).is_err());
+
+    let mut to_server = vec![];
+    let mut to_client = vec![];
+    for _ in 0..10 {
+        client.take_output(&mut to_server);
+        server.handshake_input(&to_server).unwrap();
+        to_server.clear();
+        server.take_output(&mut to_client);
+        client.handshake_input(&to_client).unwrap();
+        to_client.clear();
+
+        if client.session_state() == SessionState::Streaming
+            && server.session_state() == SessionState::Streaming
+        {
+            break;
+        }
+    }
+    assert_eq!(client.session_state(), SessionState::Streaming);
+    assert_eq!(server.session_state(), SessionState::Streaming);
+
+    let mut plaintext = vec![];
+    client.wrap(b"hello", &mut to_server).unwrap();
+    server.unwrap(&to_server, &mut plaintext).unwrap();
+    to_server.clear();
+    assert_eq!(plaintext, b"hello");
+
+    // records may be split at arbitrary points
+    let data = (0..100_000).map(|i| i as u8).collect::<Vec<_>>();
+    server.wrap(&data, &mut to_client).unwrap();
+    let mut plaintext = vec![];
+    for byte in to_client.chunks(1) {
+        client.unwrap(byte, &mut plaintext).unwrap();
+    }
+    to_client.clear();
+    assert_eq!(plaintext, data);
+
+    client.shutdown().unwrap();
+    assert_eq!(client.session_state(), SessionState::Shutdown);
+    assert!(client.wrap(b"late", &mut vec![]).is_err());
+    client.take_output(&mut to_server);
+    let mut plaintext = vec![];
+    server.unwrap(&to_server, &mut plaintext).unwrap();
+    assert!(plaintext.is_empty());
+    assert_eq!(server.session_state(), SessionState::Shutdown);
+    server.take_output(&mut to_client);
+    assert!(!to_client.is_empty());
+}
+
+/// Completes the handshake of TLS 1.3 engines, holding back what the server
+/// sends once the client has completed its side, which includes its session
+/// tickets.
+///
+/// Returns `None` on systems without TLS 1.3.
+fn tls13_engine_pair() -> Option<(TlsEngine, TlsEngine, Vec<u8>)> {
+    let cert = localhost_cert()?;
+    let creds = SchannelCred::builder()
+        .cert(cert)
+        .enabled_protocols(&[Protocol::Tls13])
+        .acquire_server()
+        .ok()?;
+    let mut server = tls_stream::Builder::new().accept_engine(creds).unwrap();
+    let creds = SchannelCred::builder()
+        .enabled_protocols(&[Protocol::Tls13])
+        .acquire_client()
+        .unwrap();
+    let mut client = tls_stream::Builder::new()
+        .domain("localhost")
+        .connect_engine(creds)
+        .unwrap();
+
+    let mut to_server = vec![];
+    let mut to_client = vec![];
+    for _ in 0..10 {
+        client.take_output(&mut to_server);
+        server.handshake_input(&to_server).unwrap();
+        to_server.clear();
+        if client.session_state() == SessionState::Streaming {
+            if server.session_state() == SessionState::Streaming {
+                break;
+            }
+            continue;
+        }
+        server.take_output(&mut to_client);
+        client.handshake_input(&to_client).unwrap();
+        to_client.clear();
+    }
+    assert_eq!(client.session_state(), SessionState::Streaming);
+    assert_eq!(server.session_state(), SessionState::Streaming);
+    server.take_output(&mut to_client);
+    Some((client, server, to_client))
+}
+
+/// Splits `data` into the TLS records it consists of.
+fn records(mut data: &[u8]) -> Vec<&[u8]> {
+    let mut records = vec![];
+    while data.len() >= 5 {
+        let len = 5 + u16::from_be_bytes([data[3], data[4]]) as usize;
+        records.push(&data[..len]);
+        data = &data[len..];
+    }
+    assert!(data.is_empty());
+    records
+}
+
+#[test]
+fn engine_handshake_messages_before_data() {
+    let (mut client, mut server, mut to_client) = match tls13_engine_pair() {
+        Some(pair) => pair,
+        None => return,
+    };
+    let held = to_client.len();
+    server.wrap(b"first", &mut to_client).unwrap();
+    server.wrap(b"second", &mut to_client).unwrap();
+
+    // the messages and the data following them are handled in one call,
+    // with the plaintext of all records kept
+    let mut plaintext = vec![];
+    client.unwrap(&to_client, &mut plaintext).unwrap();
+    assert_eq!(plaintext, b"firstsecond");
+    assert_eq!(client.session_state(), SessionState::Streaming);
+    if held > 0 {
+        assert!(client.stats().renegotiations > 0);
+    }
+
+    let mut to_server = vec![];
+    client.wrap(b"reply", &mut to_server).unwrap();
+    let mut plaintext = vec![];
+    server.unwrap(&to_server, &mut plaintext).unwrap();
+    assert_eq!(plaintext, b"reply");
+}
+
+#[test]
+fn engine_handshake_messages_alone() {
+    let (mut client, mut server, to_client) = match tls13_engine_pair() {
+        Some(pair) => pair,
+        None => return,
+    };
+
+    // Each message is passed on its own, so no ciphertext is left over for
+    // InitializeSecurityContextW, and the session must go on streaming.
+    let mut plaintext = vec![];
+    for record in records(&to_client) {
+        client.unwrap(record, &mut plaintext).unwrap();
+        assert_eq!(client.session_state(), SessionState::Streaming);
+    }
+    assert!(plaintext.is_empty());
+
+    let mut to_client = vec![];
+    server.wrap(b"data", &mut to_client).unwrap();
+    for chunk in to_client.chunks(1) {
+        client.unwrap(chunk, &mut plaintext).unwrap();
+    }
+    assert_eq!(plaintext, b"data");
+    let mut to_server = vec![];
+    client.take_output(&mut to_server);
+    client.wrap(b"reply", &mut to_server).unwrap();
+    let mut plaintext = vec![];
+    server.unwrap(&to_server, &mut plaintext).unwrap();
+    assert_eq!(plaintext, b"reply");
+}
+
+#[test]
+fn many_small_records() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    const RECORDS: usize = 10_000;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let stream = listener.accept().unwrap().0;
+        let creds = SchannelCred::builder()
+            .cert(cert)
+            .acquire(Direction::Inbound)
+            .unwrap();
+        let mut stream = tls_stream::Builder::new().accept(creds, stream).unwrap();
+        for i in 0..RECORDS {
+            stream.write_all(&(i as u32).to_le_bytes()).unwrap();
+        }
+        stream.shutdown().unwrap();
+    });
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let creds = SchannelCred::builder()
+        .acquire(Direction::Outbound)
+        .unwrap();
+    let mut stream = tls_stream::Builder::new()
+        .domain("localhost")
+        .connect(creds, stream)
+        .unwrap();
+    let mut out = vec![];
+    stream.read_to_end(&mut out).unwrap();
+    assert_eq!(out.len(), RECORDS * 4);
+    for (i, chunk) in out.chunks(4).enumerate() {
+        assert_eq!(chunk, (i as u32).to_le_bytes());
+    }
+
+    t.join().unwrap();
+}
+
+#[test]
+fn varying_write_sizes() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    const SIZES: &[usize] = &[1, 16 * 1024, 1, 5000, 0, 100 * 1024, 17];
+    let data = SIZES
+        .iter()
+        .enumerate()
+        .map(|(i, &len)| vec![i as u8 + 1; len])
+        .collect::<Vec<_>>();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let expected = data.concat();
+    let t = thread::spawn(move || {
+        let stream = listener.accept().unwrap().0;
+        let creds = SchannelCred::builder()
+            .cert(cert)
+            .acquire(Direction::Inbound)
+            .unwrap();
+        let mut stream = tls_stream::Builder::new().accept(creds, stream).unwrap();
+        for chunk in &data {
+            stream.write_all(chunk).unwrap();
+        }
+        stream.shutdown().unwrap();
+    });
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let creds = SchannelCred::builder()
+        .acquire(Direction::Outbound)
+        .unwrap();
+    let mut stream = tls_stream::Builder::new()
+        .domain("localhost")
+        .connect(creds, stream)
+        .unwrap();
+    let mut out = vec![];
+    stream.read_to_end(&mut out).unwrap();
+    assert!(out == expected);
+
+    t.join().unwrap();
+}
+
+#[test]
+fn split_cert_key() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let cert = include_bytes!("../test/cert.der");
+        let mut store = Memory::new().unwrap();
+        store.add_encoded_certificate(cert).unwrap();
+        let store = store.into_store();
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let creds = SchannelCred::builder()
+            .acquire(Direction::Outbound)
+            .unwrap();
+        let mut stream = tls_stream::Builder::new()
+            .domain("foobar.com")
+            .cert_store(store)
+            .connect(creds, stream)
+            .unwrap();
+        stream.write_all(&[1, 2, 3, 4]).unwrap();
+        stream.flush().unwrap();
+        assert_eq!(stream.read(&mut [0; 1024]).unwrap(), 4);
+        stream.shutdown().unwrap();
+    });
+
+    let cert = include_bytes!("../test/cert.der");
+    let cert = CertContext::new(cert).unwrap();
+
+    let mut options = AcquireOptions::new();
+    options.container("schannel-test");
+    let type_ = ProviderType::rsa_full();
+
+    let mut container = match options.acquire(type_) {
+        Ok(container) => container,
+        Err(_) => options.new_keyset(true).acquire(type_).unwrap(),
+    };
+    let key = include_bytes!("../test/key.key");
+    container.import().import(key).unwrap();
+
+    cert.set_key_prov_info()
+        .container("schannel-test")
+        .type_(type_)
+        .keep_open(true)
+        .key_spec(KeySpec::key_exchange())
+        .set()
+        .unwrap();
+
+    let stream = listener.accept().unwrap().0;
+    let creds = SchannelCred::builder()
+        .cert(cert)
+        .acquire(Direction::Inbound)
+        .unwrap();
+    let mut stream = tls_stream::Builder::new().accept(creds, stream).unwrap();
+    assert_eq!(stream.read(&mut [0; 1024]).unwrap(), 4);
+    stream.write_all(&[1, 2, 3, 4]).unwrap();
+    stream.flush().unwrap();
+    let mut buf = [0; 1];
+    assert_eq!(stream.read(&mut buf).unwrap(), 0);
+
+    t.join().unwrap();
+}
+
+#[test]
+fn test_loopback_alpn() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let stream = TcpStream::connect(addr).unwrap();
+        let creds = SchannelCred::builder()
+            .acquire(Direction::Outbound)
+            .unwrap();
+        let mut stream = tls_stream::Builder::new()
+            .domain("localhost")
+            .request_application_protocols(&[b"h2"])
+            .connect(creds, stream)
+            .unwrap();
+        assert_eq!(
+            stream
+                .negotiated_application_protocol()
+                .expect("localhost unreachable"),
+            Some(b"h2".to_vec())
+        );
+
+        stream.shutdown().unwrap();
+    });
+
+    let stream = listener.accept().unwrap().0;
+    let creds = SchannelCred::builder()
+        .cert(cert)
+        .acquire(Direction::Inbound)
+        .unwrap();
+    let stream = tls_stream::Builder::new()
+        .request_application_protocols(&[b"h2"])
+        .accept(creds, stream)
+        .unwrap();
+    assert_eq!(
+        stream
+            .negotiated_application_protocol()
+            .expect("localhost unreachable"),
+        Some(b"h2".to_vec())
+    );
+
+    t.join().unwrap();
+}
+
+#[test]
+fn test_loopback_alpn_mismatch() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let stream = TcpStream::connect(addr).unwrap();
+        let creds = SchannelCred::builder()
+            .acquire(Direction::Outbound)
+            .unwrap();
+        let mut stream = tls_stream::Builder::new()
+            .domain("localhost")
+            .connect(creds, stream)
+            .unwrap();
+        assert_eq!(
+            stream
+                .negotiated_application_protocol()
+                .expect("localhost unreachable"),
+            None
+        );
+
+        stream.shutdown().unwrap();
+    });
+
+    let stream = listener.accept().unwrap().0;
+    let creds = SchannelCred::builder()
+        .cert(cert)
+        .acquire(Direction::Inbound)
+        .unwrap();
+    let stream = tls_stream::Builder::new()
+        .request_application_protocols(&[b"h2"])
+        .accept(creds, stream)
+        .unwrap();
+    assert_eq!(
+        stream
+            .negotiated_application_protocol()
+            .expect("localhost unreachable"),
+        None
+    );
+
+    t.join().unwrap();
+}
+
+#[test]
+fn test_external_alpn() {
+    let creds = SchannelCred::builder()
+        .acquire(Direction::Outbound)
+        .unwrap();
+    let stream = TcpStream::connect("google.com:443").unwrap();
+    let stream = tls_stream::Builder::new()
+        .request_application_protocols(&[b"h2"])
+        .domain("google.com")
+        .connect(creds, stream)
+        .unwrap();
+    assert_eq!(
+        stream
+            .negotiated_application_protocol()
+            .expect("google.com unreachable"),
+        Some(b"h2".to_vec())
+    );
+}
+
+#[test]
+fn test_alpn_list() {
+    let raw_proto_alpn_list = b"\x02h2";
+    // Little-endian bit representation of the expected `SEC_APPLICATION_PROTOCOL_LIST`.
+    let proto_list = &[
+        // `sspi::SecApplicationProtocolNegotiationExt_ALPN` equals 2.
+        &[2, 0, 0, 0, raw_proto_alpn_list.len() as u8, 0] as &[u8],
+        raw_proto_alpn_list,
+    ]
+    .concat();
+    let full_alpn_list = [&[proto_list.len() as u8, 0, 0, 0] as &[u8], proto_list].concat();
+    assert_eq!(
+        &AlpnList::new(&[b"h2".to_vec()]).unwrap() as &[u8],
+        &full_alpn_list as &[u8]
+    );
+
+    let raw_proto_alpn_list = b"\x02h2\x08http/1.1";
+    // Little-endian bit representation of the expected `SEC_APPLICATION_PROTOCOL_LIST`.
+    let proto_list = &[
+        // `sspi::SecApplicationProtocolNegotiationExt_ALPN` equals 2.
+        &[2, 0, 0, 0, raw_proto_alpn_list.len() as u8, 0] as &[u8],
+        raw_proto_alpn_list,
+    ]
+    .concat();
+    let full_alpn_list = [&[proto_list.len() as u8, 0, 0, 0] as &[u8], proto_list].concat();
+    assert_eq!(
+        &AlpnList::new(&[b"h2".to_vec(), b"http/1.1".to_vec()]).unwrap() as &[u8],
+        &full_alpn_list as &[u8]
+    );
+
+    let invalid = [
+        vec![vec![]],
+        vec![vec![b'a'; 256]],
+        vec![vec![b'a'; 255]; 257],
+    ];
+    for protos in invalid.iter() {
+        let err = AlpnList::new(protos).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+    assert!(AlpnList::new(&[vec![b'a'; 255]]).is_ok());
+}
+
+#[test]
+fn secbuf_lengths() {
+    assert_eq!(crate::secbuf_len(0).unwrap(), 0);
+    assert_eq!(crate::secbuf_len(16 * 1024).unwrap(), 16 * 1024);
+    assert_eq!(
+        crate::secbuf_len(crate::MAX_SECBUFFER_LEN).unwrap(),
+        u32::MAX
+    );
+    #[cfg(target_pointer_width = "64")]
+    {
+        let err = crate::secbuf_len(crate::MAX_SECBUFFER_LEN + 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            err.to_string(),
+            "a buffer of 4294967296 bytes is too large for Schannel"
+        );
+    }
+}
+
+#[test]
+fn debug_output() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let stream = TcpStream::connect(addr).unwrap();
+        let creds = SchannelCred::builder()
+            .acquire(Direction::Outbound)
+            .unwrap();
+        let mut builder = tls_stream::Builder::new();
+        builder.domain("localhost");
+        assert!(format!("{:?}", builder).contains("\"localhost\""));
+        let mut stream = builder.connect(creds, stream).unwrap();
+        stream.write_all(b"super secret data").unwrap();
+        stream.flush().unwrap();
+        let mut buf = [0; 1];
+        stream.read_exact(&mut buf).unwrap();
+    });
+
+    let stream = listener.accept().unwrap().0;
+    let creds = SchannelCred::builder()
+        .cert(cert)
+        .acquire(Direction::Inbound)
+        .unwrap();
+    assert_eq!(format!("{:?}", creds), "SchannelCred");
+    let mut stream = tls_stream::Builder::new().accept(creds, stream).unwrap();
+    let mut buf = [0; 1];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(stream.buffered_read_len(), 16);
+
+    let debug = format!("{:?}", stream);
+    assert!(debug.contains("Streaming"), "{}", debug);
+    assert!(debug.contains("buffered_plaintext: 16"), "{}", debug);
+    assert!(!debug.contains("secret"), "{}", debug);
+    assert!(
+        !debug.contains(&format!("{:?}", &b"uper secret data"[..])[1..20]),
+        "{}",
+        debug
+    );
+
+    stream.write_all(&[0]).unwrap();
+    t.join().unwrap();
+}
+
+#[test]
+fn cloned_builder() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let mut creds = SchannelCred::builder();
+        creds.cert(cert);
+        for _ in 0..2 {
+            let stream = listener.accept().unwrap().0;
+            let creds = creds.clone().acquire(Direction::Inbound).unwrap();
+            if let Ok(mut stream) = tls_stream::Builder::new().accept(creds, stream) {
+                stream.write_all(b"hello").unwrap();
+            }
+        }
+    });
+
+    let mut base = tls_stream::Builder::new();
+    base.use_sni(false).max_record_size(32 * 1024);
+    let mut good = base.clone();
+    good.domain("localhost");
+    let mut bad = base.clone();
+    bad.domain("not-localhost.example");
+
+    let creds = SchannelCred::builder()
+        .acquire(Direction::Outbound)
+        .unwrap();
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut stream = good.connect(creds.clone(), stream).unwrap();
+    let mut buf = [0; 5];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+
+    let stream = TcpStream::connect(addr).unwrap();
+    match bad.connect(creds, stream) {
+        Err(HandshakeError::Failure(e)) => {
+            assert_eq!(e.kind(), error::ErrorKind::CertNameMismatch)
+        }
+        _ => panic!("expected a TLS failure"),
+    }
+
+    t.join().unwrap();
+}
+
+#[test]
+fn invalid_domain() {
+    let creds = SchannelCred::builder()
+        .acquire(Direction::Outbound)
+        .unwrap();
+    let err = tls_stream::Builder::new()
+        .domain("example.com\0.evil.example")
+        .connect_engine(creds)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn idn_domain() {
+    let creds = SchannelCred::builder()
+        .acquire(Direction::Outbound)
+        .unwrap();
+    let mut client = tls_stream::Builder::new()
+        .domain("bücher.example")
+        .connect_engine(creds)
+        .unwrap();
+    let mut client_hello = vec![];
+    client.take_output(&mut client_hello);
+    let sni = b"xn--bcher-kva.example";
+    assert!(client_hello.windows(sni.len()).any(|w| w == sni));
+}
+
+#[test]
+fn sni_hostname() {
+    let creds = SchannelCred::builder().acquire_client().unwrap();
+    let mut client = tls_stream::Builder::new()
+        .domain("alpha.test")
+        .connect_engine(creds)
+        .unwrap();
+    let mut hello = vec![];
+    client.take_output(&mut hello);
+    assert_eq!(
+        client_hello::server_name(&hello).as_deref(),
+        Some("alpha.test")
+    );
+
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let (client, server) = offline_pair(
+        tls_stream::Builder::new()
+            .domain("alpha.test")
+            .accept_invalid_hostnames(true),
+        &mut SchannelCred::builder(),
+        cert.clone(),
+    );
+    let mut client = client.unwrap();
+    let mut server = server.unwrap();
+    assert_eq!(
+        server.sni_hostname().unwrap().as_deref(),
+        Some("alpha.test")
+    );
+    assert_eq!(client.sni_hostname().unwrap(), None);
+    exchange(&mut client, &mut server, b"ping");
+
+    let (client, server) = offline_pair(
+        tls_stream::Builder::new()
+            .domain("localhost")
+            .use_sni(false),
+        &mut SchannelCred::builder(),
+        cert,
+    );
+    client.unwrap();
+    assert_eq!(server.unwrap().sni_hostname().unwrap(), None);
+}
+
+#[test]
+fn read_timeout() {
+    use std::os::windows::io::AsRawSocket;
+    use std::time::Duration;
+
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (done_tx, done_rx) = mpsc::channel();
+    let t = thread::spawn(move || {
+        let stream = listener.accept().unwrap().0;
+        let creds = SchannelCred::builder()
+            .cert(cert)
+            .acquire(Direction::Inbound)
+            .unwrap();
+        let mut stream = tls_stream::Builder::new().accept(creds, stream).unwrap();
+        // stall until the client has timed out
+        done_rx.recv().unwrap();
+        stream.write_all(b"late").unwrap();
+        stream.flush().unwrap();
+    });
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let socket = stream.as_raw_socket();
+    let creds = SchannelCred::builder()
+        .acquire(Direction::Outbound)
+        .unwrap();
+    let mut stream = tls_stream::Builder::new()
+        .domain("localhost")
+        .connect(creds, stream)
+        .unwrap();
+    assert_eq!(stream.as_raw_socket(), socket);
+
+    stream
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    assert_eq!(
+        stream.read_timeout().unwrap(),
+        Some(Duration::from_millis(100))
+    );
+    let err = stream.read(&mut [0; 4]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+    // the session survives the timeout
+    stream.set_read_timeout(None).unwrap();
+    done_tx.send(()).unwrap();
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"late");
+
+    t.join().unwrap();
+}
+
+#[test]
+fn error_operation() {
+    let creds = SchannelCred::builder()
+        .acquire(Direction::Outbound)
+        .unwrap();
+    let mut client = tls_stream::Builder::new()
+        .domain("localhost")
+        .connect_engine(creds)
+        .unwrap();
+    client.take_output(&mut vec![]);
+
+    // a handshake record holding a message of an unknown type
+    let err = client
+        .handshake_input(b"\x16\x03\x03\x00\x04\xff\x00\x00\x00")
+        .unwrap_err();
+    let error = error::Error::from_io_error(&err).unwrap();
+    assert_eq!(error.operation(), Some("InitializeSecurityContextW"));
+    assert_eq!(error.phase(), Some("handshake"));
+    let display = err.to_string();
+    assert!(
+        display.contains("in InitializeSecurityContextW during handshake"),
+        "{}",
+        display
+    );
+    assert!(format!("{:?}", err).contains("InitializeSecurityContextW"));
+}
+
+#[test]
+fn query_context_attribute() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let stream = listener.accept().unwrap().0;
+        let creds = SchannelCred::builder()
+            .cert(cert)
+            .acquire(Direction::Inbound)
+            .unwrap();
+        let mut stream = tls_stream::Builder::new().accept(creds, stream).unwrap();
+        stream.read_exact(&mut [0; 1]).unwrap();
+    });
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let creds = SchannelCred::builder()
+        .acquire(Direction::Outbound)
+        .unwrap();
+    let mut stream = tls_stream::Builder::new()
+        .domain("localhost")
+        .connect(creds, stream)
+        .unwrap();
+
+    let sizes: Identity::SecPkgContext_StreamSizes = unsafe {
+        stream
+            .query_context_attribute(Identity::SECPKG_ATTR_STREAM_SIZES)
+            .unwrap()
+    };
+    let (_, payload) = stream
+        .prepare_buffer(sizes.cbMaximumMessage as usize)
+        .unwrap();
+    assert_eq!(payload.start, sizes.cbHeader as usize);
+    assert!(stream
+        .prepare_buffer(sizes.cbMaximumMessage as usize + 1)
+        .is_err());
+
+    let mut raw_sizes: Identity::SecPkgContext_StreamSizes = unsafe { mem::zeroed() };
+    let status = unsafe {
+        Identity::QueryContextAttributesW(
+            stream.context_handle(),
+            Identity::SECPKG_ATTR_STREAM_SIZES,
+            &mut raw_sizes as *mut _ as *mut _,
+        )
+    };
+    assert_eq!(status, Foundation::SEC_E_OK);
+    assert_eq!(raw_sizes.cbHeader, sizes.cbHeader);
+    assert_eq!(raw_sizes.cbTrailer, sizes.cbTrailer);
+
+    stream.write_all(&[0]).unwrap();
+    t.join().unwrap();
+}
+
+#[test]
+fn connector_acceptor() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let acceptor = TlsAcceptor::new(identity::Identity::from_cert(cert)).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let mut handlers = vec![];
+        for stream in listener.incoming().take(3) {
+            let acceptor = acceptor.clone();
+            let stream = stream.unwrap();
+            handlers.push(thread::spawn(move || {
+                let mut stream = acceptor.accept(stream).unwrap();
+                let mut buf = [0; 5];
+                stream.read_exact(&mut buf).unwrap();
+                stream.write_all(&buf).unwrap();
+            }));
+        }
+        for handler in handlers {
+            handler.join().unwrap();
+        }
+    });
+
+    let connector = TlsConnector::builder()
+        .enabled_protocols(&[Protocol::Tls12])
+        .build()
+        .unwrap();
+    let clients = (0..3u8)
+        .map(|i| {
+            let connector = connector.clone();
+            thread::spawn(move || {
+                let stream = TcpStream::connect(addr).unwrap();
+                let mut stream = connector.connect("localhost", stream).unwrap();
+                let msg = [i; 5];
+                stream.write_all(&msg).unwrap();
+                let mut buf = [0; 5];
+                stream.read_exact(&mut buf).unwrap();
+                assert_eq!(buf, msg);
+            })
+        })
+        .collect::<Vec<_>>();
+    for client in clients {
+        client.join().unwrap();
+    }
+    server.join().unwrap();
+}
+
+/// The shape of a native-tls backend, built from the public API only.
+mod native_tls_backend {
+    use std::fmt;
+    use std::io::{self, Read, Write};
+
+    use crate::{error, identity, tls_connector, tls_stream};
+
+    #[derive(Debug)]
+    pub struct Error(io::Error);
+
+    impl fmt::Display for Error {
+        fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+            fmt::Display::fmt(&self.0, fmt)
+        }
+    }
+
+    impl std::error::Error for Error {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            self.0.source()
+        }
+    }
+
+    impl From<io::Error> for Error {
+        fn from(err: io::Error) -> Error {
+            Error(err)
+        }
+    }
+
+    impl From<error::CrateError> for Error {
+        fn from(err: error::CrateError) -> Error {
+            Error(err.into())
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct Identity(identity::Identity);
+
+    impl Identity {
+        pub fn from_pkcs12(buf: &[u8], pass: &str) -> Result<Identity, Error> {
+            Ok(Identity(identity::Identity::from_pkcs12(buf, pass)?))
+        }
+    }
+
+    pub type TlsStream<S> = tls_stream::TlsStream<S>;
+    pub type MidHandshakeTlsStream<S> = tls_stream::MidHandshakeTlsStream<S>;
+
+    pub enum HandshakeError<S> {
+        Failure(Error),
+        WouldBlock(MidHandshakeTlsStream<S>),
+    }
+
+    impl<S> From<tls_stream::HandshakeError<S>> for HandshakeError<S> {
+        fn from(err: tls_stream::HandshakeError<S>) -> HandshakeError<S> {
+            match err {
+                tls_stream::HandshakeError::Failure(e) => HandshakeError::Failure(Error(e.into())),
+                tls_stream::HandshakeError::Io(e) => HandshakeError::Failure(Error(e)),
+                tls_stream::HandshakeError::Interrupted(s) => HandshakeError::WouldBlock(s),
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct TlsConnector(tls_connector::TlsConnector);
+
+    impl TlsConnector {
+        pub fn new(accept_invalid_certs: bool) -> Result<TlsConnector, Error> {
+            let mut builder = tls_connector::TlsConnector::builder();
+            if accept_invalid_certs {
+                builder.verify_callback(|_| Ok(()));
+            }
+            Ok(TlsConnector(builder.build()?))
+        }
+
+        pub fn connect<S: Read + Write>(
+            &self,
+            domain: &str,
+            stream: S,
+        ) -> Result<TlsStream<S>, HandshakeError<S>> {
+            Ok(self.0.connect(domain, stream)?)
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct TlsAcceptor(tls_connector::TlsAcceptor);
+
+    impl TlsAcceptor {
+        pub fn new(identity: Identity) -> Result<TlsAcceptor, Error> {
+            Ok(TlsAcceptor(tls_connector::TlsAcceptor::new(identity.0)?))
+        }
+
+        pub fn accept<S: Read + Write>(
+            &self,
+            stream: S,
+        ) -> Result<TlsStream<S>, HandshakeError<S>> {
+            Ok(self.0.accept(stream)?)
+        }
+    }
+
+    pub fn handshake<S: Read + Write>(
+        stream: MidHandshakeTlsStream<S>,
+    ) -> Result<TlsStream<S>, HandshakeError<S>> {
+        Ok(stream.handshake()?)
+    }
+}
+
+#[test]
+fn native_tls_backend() {
+    use self::native_tls_backend::{
+        handshake, HandshakeError, Identity, TlsAcceptor, TlsConnector,
+    };
+
+    fn complete<S: Read + Write>(
+        mut result: Result<native_tls_backend::TlsStream<S>, HandshakeError<S>>,
+    ) -> native_tls_backend::TlsStream<S> {
+        loop {
+            match result {
+                Ok(stream) => return stream,
+                Err(HandshakeError::WouldBlock(stream)) => result = handshake(stream),
+                Err(HandshakeError::Failure(e)) => panic!("handshake failed: {}", e),
+            }
+        }
+    }
+
+    let identity = include_bytes!("../test/identity.p12");
+    let identity = Identity::from_pkcs12(identity, "mypass").unwrap();
+    let acceptor = TlsAcceptor::new(identity).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let stream = listener.accept().unwrap().0;
+        let mut stream = complete(acceptor.accept(stream));
+        stream.write_all(b"hello").unwrap();
+    });
+
+    let connector = TlsConnector::new(true).unwrap();
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut stream = complete(connector.connect("foobar.com", stream));
+    let mut buf = [0; 5];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+
+    t.join().unwrap();
+}
+
+#[test]
+#[cfg(feature = "hyper")]
+fn hyper_client() {
+    use hyper::net::HttpsConnector;
+    use hyper::Client;
+
+    use crate::hyper_client::SchannelClient;
+
+    let ssl = SchannelClient::new().unwrap();
+    let client = Client::with_connector(HttpsConnector::new(ssl));
+    let mut response = client.get("https://www.google.com/").send().unwrap();
+    assert!(response.status.is_success() || response.status.is_redirection());
+    let mut body = vec![];
+    response.read_to_end(&mut body).unwrap();
+    assert!(!body.is_empty());
+}
+
+/// The result of a handshake which may still be in progress.
+type Handshake<S> = Result<TlsStream<S>, HandshakeError<S>>;
+
+/// Drives the handshakes of a client and a server connected over a
+/// `mock_stream::pipe` until neither is waiting for the other, or one of them
+/// fails.
+fn handshake_pair<S>(
+    mut client: Handshake<S>,
+    mut server: Handshake<S>,
+) -> (Handshake<S>, Handshake<S>)
+where
+    S: Read + Write,
+{
+    fn resume<S: Read + Write>(result: Handshake<S>) -> Handshake<S> {
+        match result {
+            Err(HandshakeError::Interrupted(stream)) => stream.handshake(),
+            result => result,
+        }
+    }
+
+    // `None` if the handshake failed
+    fn waiting<S>(result: &Handshake<S>) -> Option<bool> {
+        match result {
+            Ok(_) => Some(false),
+            Err(HandshakeError::Interrupted(_)) => Some(true),
+            Err(_) => None,
+        }
+    }
+
+    for _ in 0..100 {
+        match (waiting(&client), waiting(&server)) {
+            (Some(false), Some(false)) | (None, _) | (_, None) => return (client, server),
+            _ => {}
+        }
+        client = resume(client);
+        server = resume(server);
+    }
+    panic!("the handshakes made no progress");
+}
+
+/// Connects a client configured by `client` and `client_cred` to a server
+/// identifying itself with `server_cert` over a pipe.
+fn offline_pair(
+    client: &mut tls_stream::Builder,
+    client_cred: &mut schannel_cred::Builder,
+    server_cert: CertContext,
+) -> (Handshake<PipeStream>, Handshake<PipeStream>) {
+    let (client_stream, server_stream) = mock_stream::pipe();
+    let creds = client_cred.acquire(Direction::Outbound).unwrap();
+    let client = client.connect(creds, client_stream);
+    let creds = SchannelCred::builder()
+        .cert(server_cert)
+        .acquire(Direction::Inbound)
+        .unwrap();
+    let server = tls_stream::Builder::new().accept(creds, server_stream);
+    handshake_pair(client, server)
+}
+
+/// Sends `data` from `writer` to `reader` and reads it back.
+fn exchange(writer: &mut TlsStream<PipeStream>, reader: &mut TlsStream<PipeStream>, data: &[u8]) {
+    writer.write_all(data).unwrap();
+    writer.flush().unwrap();
+    let mut buf = vec![0; data.len()];
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, data);
+}
+
+fn test_identity_cert() -> CertContext {
+    let identity = include_bytes!("../test/identity.p12");
+    identity::Identity::from_pkcs12(identity, "mypass")
+        .unwrap()
+        .into_cert()
+}
+
+#[test]
+fn offline_basic() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let (client, server) = offline_pair(
+        tls_stream::Builder::new().domain("localhost"),
+        &mut SchannelCred::builder(),
+        cert,
+    );
+    let mut client = client.unwrap();
+    let mut server = server.unwrap();
+    assert_eq!(client.session_state(), SessionState::Streaming);
+    assert_eq!(server.session_state(), SessionState::Streaming);
+
+    exchange(&mut client, &mut server, b"GET / HTTP/1.0\r\n\r\n");
+    exchange(&mut server, &mut client, b"HTTP/1.0 200 OK\r\n\r\n");
+
+    client.shutdown().unwrap();
+    assert_eq!(server.read(&mut [0; 1]).unwrap(), 0);
+    assert_eq!(server.session_state(), SessionState::Shutdown);
+    assert_eq!(client.read(&mut [0; 1]).unwrap(), 0);
+    assert_eq!(client.session_state(), SessionState::Shutdown);
+}
+
+#[test]
+fn offline_modern_preset() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let (client, server) = offline_pair(
+        tls_stream::Builder::new().domain("localhost"),
+        SchannelCred::builder().preset(CipherPreset::Modern),
+        cert,
+    );
+    let mut client = client.unwrap();
+    let mut server = server.unwrap();
+    exchange(&mut client, &mut server, b"ping");
+}
+
+#[test]
+fn key_log() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let lines = sync::Arc::new(sync::Mutex::new(Vec::new()));
+    let lines2 = lines.clone();
+    let (client, server) = offline_pair(
+        tls_stream::Builder::new()
+            .domain("localhost")
+            .key_log(move |line| lines2.lock().unwrap().push(line.to_string())),
+        SchannelCred::builder().enabled_protocols(&[Protocol::Tls13]),
+        cert,
+    );
+    let mut client = match client {
+        Ok(client) => client,
+        // Schannel doesn't support TLS 1.3 or exporting its secrets here
+        Err(HandshakeError::Io(ref e)) if e.kind() == io::ErrorKind::Unsupported => return,
+        Err(HandshakeError::Failure(_)) => return,
+        Err(e) => panic!("{:?}", e),
+    };
+    let mut server = server.unwrap();
+    exchange(&mut client, &mut server, b"ping");
+
+    let lines = lines.lock().unwrap();
+    let labels = lines
+        .iter()
+        .map(|line| line.split(' ').next().unwrap())
+        .collect::<Vec<_>>();
+    assert!(labels.contains(&"CLIENT_HANDSHAKE_TRAFFIC_SECRET"));
+    assert!(labels.contains(&"SERVER_HANDSHAKE_TRAFFIC_SECRET"));
+    assert!(labels.contains(&"CLIENT_TRAFFIC_SECRET_0"));
+    assert!(labels.contains(&"SERVER_TRAFFIC_SECRET_0"));
+    let random = lines[0].split(' ').nth(1).unwrap();
+    assert_eq!(random.len(), 64);
+    for line in lines.iter() {
+        let fields = line.split(' ').collect::<Vec<_>>();
+        assert_eq!(fields.len(), 3, "{}", line);
+        assert_eq!(fields[1], random);
+        assert!(fields[2].len() >= 64, "{}", line);
+        assert!(fields[2].chars().all(|c| c.is_ascii_hexdigit()));
+    }
+}
+
+#[test]
+fn check_capabilities() {
+    let caps = crate::capabilities();
+    let result = SchannelCred::builder()
+        .enabled_protocols(&[Protocol::Tls13])
+        .check_capabilities(true)
+        .acquire_client();
+    match result {
+        Ok(_) => assert!(caps.tls13),
+        Err(e) => {
+            assert!(!caps.tls13);
+            assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+        }
+    }
+
+    let creds = SchannelCred::builder().acquire_client().unwrap();
+    let result = tls_stream::Builder::new()
+        .domain("localhost")
+        .key_log(|_| ())
+        .check_capabilities(true)
+        .connect_engine(creds);
+    match result {
+        Ok(_) => assert!(caps.traffic_secrets),
+        Err(e) => {
+            assert!(!caps.traffic_secrets);
+            assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+        }
+    }
+}
+
+#[test]
+fn offline_valid_protocol() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let (client, server) = offline_pair(
+        tls_stream::Builder::new().domain("localhost"),
+        SchannelCred::builder().enabled_protocols(&[Protocol::Tls12]),
+        cert,
+    );
+    let mut client = client.unwrap();
+    let mut server = server.unwrap();
+    exchange(&mut client, &mut server, b"hello");
+}
+
+#[test]
+fn typed_credentials() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let client_cred = SchannelCred::builder().acquire_client().unwrap();
+    let server_cred = SchannelCred::builder().cert(cert).acquire_server().unwrap();
+    for _ in 0..2 {
+        let (client_stream, server_stream) = mock_stream::pipe();
+        let client = tls_stream::Builder::new()
+            .domain("localhost")
+            .connect(client_cred.clone(), client_stream);
+        let server = tls_stream::Builder::new().accept(server_cred.clone(), server_stream);
+        let (client, server) = handshake_pair(client, server);
+        let mut client = client.unwrap();
+        let mut server = server.unwrap();
+        exchange(&mut client, &mut server, b"hello");
+    }
+
+    // untyped credentials for both directions work on the client side too
+    let cred = SchannelCred::builder().acquire(Direction::Both).unwrap();
+    let (client_stream, server_stream) = mock_stream::pipe();
+    let client = tls_stream::Builder::new()
+        .domain("localhost")
+        .connect(cred, client_stream);
+    let server = tls_stream::Builder::new().accept(server_cred, server_stream);
+    let (client, server) = handshake_pair(client, server);
+    client.unwrap();
+    server.unwrap();
+}
+
+#[test]
+fn client_token_unmapped() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let (client, server) = offline_pair(
+        tls_stream::Builder::new().domain("localhost"),
+        &mut SchannelCred::builder(),
+        cert,
+    );
+    let client = client.unwrap();
+    let server = server.unwrap();
+
+    // no client certificate was requested, so there's nothing to map
+    let err = server.client_token().unwrap_err();
+    let error = error::Error::from_io_error(&err).unwrap();
+    assert_eq!(error.operation(), Some("QuerySecurityContextToken"));
+
+    let err = client.client_token().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn client_cert_policies() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let server_cred = SchannelCred::builder()
+        .cert(cert.clone())
+        .acquire_server()
+        .unwrap();
+    let policies = [
+        ClientCertPolicy::Ignore,
+        ClientCertPolicy::Request,
+        ClientCertPolicy::Require,
+    ];
+    for &policy in policies.iter() {
+        for &with_cert in &[false, true] {
+            let mut client_cred = SchannelCred::builder();
+            if with_cert {
+                client_cred.cert(cert.clone());
+            }
+            let client_cred = client_cred.acquire_client().unwrap();
+            let (client_stream, server_stream) = mock_stream::pipe();
+            let client = tls_stream::Builder::new()
+                .domain("localhost")
+                .connect(client_cred, client_stream);
+            let server = tls_stream::Builder::new()
+                .client_cert_policy(policy)
+                .accept(server_cred.clone(), server_stream);
+            let (client, server) = handshake_pair(client, server);
+
+            if policy == ClientCertPolicy::Require && !with_cert {
+                match server {
+                    Err(HandshakeError::Io(err)) => {
+                        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied)
+                    }
+                    _ => panic!("expected the handshake to fail"),
+                }
+                continue;
+            }
+
+            let mut client = client.unwrap();
+            let mut server = server.unwrap();
+            let presented = with_cert && policy != ClientCertPolicy::Ignore;
+            let peer = server.peer_certificate().unwrap();
+            assert_eq!(
+                peer.map(|cert| cert.fingerprint(HashAlgorithm::sha256()).unwrap()),
+                if presented {
+                    Some(cert.fingerprint(HashAlgorithm::sha256()).unwrap())
+                } else {
+                    None
+                },
+                "{:?} with a client certificate: {}",
+                policy,
+                with_cert
+            );
+            assert!(client.peer_certificate().unwrap().is_some());
+            exchange(&mut client, &mut server, b"hello");
+        }
+    }
+}
+
+#[test]
+fn dual_certificates() {
+    let ecdsa = include_bytes!("../test/localhost-ecdsa.p12");
+    let ecdsa = identity::Identity::from_pkcs12(ecdsa, "mypass")
+        .unwrap()
+        .into_cert();
+    let rsa = include_bytes!("../test/localhost-rsa.p12");
+    let rsa = identity::Identity::from_pkcs12(rsa, "mypass")
+        .unwrap()
+        .into_cert();
+    let server_cred = SchannelCred::builder()
+        .cert(ecdsa.clone())
+        .cert(rsa.clone())
+        .acquire_server()
+        .unwrap();
+
+    for &(signature, expected) in &[(Algorithm::Ecdsa, &ecdsa), (Algorithm::RsaSign, &rsa)] {
+        let client_cred = SchannelCred::builder()
+            .enabled_protocols(&[Protocol::Tls12])
+            .supported_algorithms(&[Algorithm::Aes128, Algorithm::Aes256, signature])
+            .acquire_client()
+            .unwrap();
+        let (client_stream, server_stream) = mock_stream::pipe();
+        let client = tls_stream::Builder::new()
+            .domain("localhost")
+            .verify_callback(|_| Ok(()))
+            .connect(client_cred, client_stream);
+        let server = tls_stream::Builder::new().accept(server_cred.clone(), server_stream);
+        let (client, server) = handshake_pair(client, server);
+        let client = client.unwrap();
+        let server = server.unwrap();
+
+        assert_eq!(server.certificate().unwrap(), *expected, "{:?}", signature);
+        assert_eq!(
+            client.peer_certificate().unwrap().unwrap(),
+            *expected,
+            "{:?}",
+            signature
+        );
+    }
+}
+
+#[test]
+fn ocsp_stapling() {
+    let identity = include_bytes!("../test/localhost-rsa.p12");
+    let identity = identity::Identity::from_pkcs12(identity, "mypass").unwrap();
+    let cert = identity.cert().clone();
+    let acceptor = TlsAcceptor::builder(identity)
+        .ocsp_response(b"first response")
+        .build()
+        .unwrap();
+    assert_eq!(cert.ocsp_response().unwrap(), b"first response");
+    let connector = TlsConnector::builder()
+        .verify_callback(|_| Ok(()))
+        .build()
+        .unwrap();
+
+    for &response in &[&b"first response"[..], b"second response"] {
+        acceptor.set_ocsp_response(response).unwrap();
+        assert_eq!(cert.ocsp_response().unwrap(), response);
+
+        let (client_stream, server_stream) = mock_stream::pipe();
+        let client = connector.connect("localhost", client_stream);
+        let server = acceptor.accept(server_stream);
+        let (client, server) = handshake_pair(client, server);
+        let mut client = client.unwrap();
+        let mut server = server.unwrap();
+        exchange(&mut client, &mut server, b"ping");
+        if let Ok(stapled) = client.peer_certificate().unwrap().unwrap().ocsp_response() {
+            assert_eq!(stapled, response);
+        }
+    }
+}
+
+#[test]
+fn session_resumption_disabled() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let client_cred = SchannelCred::builder()
+        .enabled_protocols(&[Protocol::Tls12])
+        .acquire_client()
+        .unwrap();
+    let server_cred = SchannelCred::builder().cert(cert).acquire_server().unwrap();
+    let connect = || {
+        let (client_stream, server_stream) = mock_stream::pipe();
+        let client = tls_stream::Builder::new()
+            .domain("localhost")
+            .connect(client_cred.clone(), client_stream);
+        let server = tls_stream::Builder::new().accept(server_cred.clone(), server_stream);
+        let (client, server) = handshake_pair(client, server);
+        (client.unwrap(), server.unwrap())
+    };
+
+    let (mut client, mut server) = connect();
+    client.set_session_resumption(false).unwrap();
+    exchange(&mut client, &mut server, b"ping");
+    client.shutdown().unwrap();
+
+    let (mut client, mut server) = connect();
+    assert!(!client.session_resumed().unwrap());
+    assert!(!server.session_resumed().unwrap());
+    server.set_session_resumption(true).unwrap();
+    exchange(&mut server, &mut client, b"pong");
+}
+
+#[test]
+fn cache_target_name() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let client_cred = SchannelCred::builder()
+        .enabled_protocols(&[Protocol::Tls12])
+        .acquire_client()
+        .unwrap();
+    let server_cred = SchannelCred::builder().cert(cert).acquire_server().unwrap();
+    let connect = |name: &str| {
+        let (client_stream, server_stream) = mock_stream::pipe();
+        let client = tls_stream::Builder::new()
+            .domain("localhost")
+            .cache_target_name(name)
+            .connect(client_cred.clone(), client_stream);
+        let server = tls_stream::Builder::new().accept(server_cred.clone(), server_stream);
+        let (client, server) = handshake_pair(client, server);
+        let mut client = client.unwrap();
+        let mut server = server.unwrap();
+        assert_eq!(server.sni_hostname().unwrap().as_deref(), Some(name));
+        exchange(&mut client, &mut server, b"ping");
+        client.session_resumed().unwrap()
+    };
+
+    assert!(!connect("alpha.localhost"));
+    assert!(!connect("beta.localhost"));
+    assert!(connect("alpha.localhost"));
+    assert!(connect("beta.localhost"));
+    assert!(!connect("gamma.localhost"));
+
+    let creds = SchannelCred::builder().acquire_client().unwrap();
+    let err = tls_stream::Builder::new()
+        .domain("localhost")
+        .cache_target_name("alpha\0.localhost")
+        .connect_engine(creds)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn purge_session_cache() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let client_cred = SchannelCred::builder()
+        .enabled_protocols(&[Protocol::Tls12])
+        .acquire_client()
+        .unwrap();
+    let server_cred = SchannelCred::builder().cert(cert).acquire_server().unwrap();
+    let connect = || {
+        let (client_stream, server_stream) = mock_stream::pipe();
+        let client = tls_stream::Builder::new()
+            .domain("localhost")
+            .connect(client_cred.clone(), client_stream);
+        let server = tls_stream::Builder::new().accept(server_cred.clone(), server_stream);
+        let (client, server) = handshake_pair(client, server);
+        let mut client = client.unwrap();
+        let mut server = server.unwrap();
+        exchange(&mut client, &mut server, b"ping");
+        client.session_resumed().unwrap()
+    };
+
+    connect();
+    crate::purge_session_cache(Some("localhost")).unwrap();
+    assert!(!connect());
+    crate::purge_session_cache(None).unwrap();
+    assert!(!connect());
+}
+
+#[test]
+fn acceptor_credential_provider() {
+    use std::time::Duration;
+
+    let ecdsa = include_bytes!("../test/localhost-ecdsa.p12");
+    let ecdsa = identity::Identity::from_pkcs12(ecdsa, "mypass").unwrap();
+    let rsa = include_bytes!("../test/localhost-rsa.p12");
+    let rsa = identity::Identity::from_pkcs12(rsa, "mypass").unwrap();
+    let certs = [ecdsa.cert().clone(), rsa.cert().clone()];
+    let current = sync::Arc::new(sync::atomic::AtomicUsize::new(0));
+
+    let provided = certs.clone();
+    let selected = current.clone();
+    let acceptor = TlsAcceptor::builder(ecdsa)
+        .credential_provider(None, move || {
+            let cert = provided[selected.load(sync::atomic::Ordering::SeqCst)].clone();
+            SchannelCred::builder().cert(cert).acquire_server()
+        })
+        .build()
+        .unwrap();
+    let connector = TlsConnector::builder()
+        .enabled_protocols(&[Protocol::Tls12])
+        .verify_callback(|_| Ok(()))
+        .build()
+        .unwrap();
+    let connect = |acceptor: &TlsAcceptor| {
+        let (client_stream, server_stream) = mock_stream::pipe();
+        let client = connector.connect("localhost", client_stream);
+        let server = acceptor.accept(server_stream);
+        let (client, server) = handshake_pair(client, server);
+        (client.unwrap(), server.unwrap())
+    };
+
+    let (mut old_client, mut old_server) = connect(&acceptor);
+    assert_eq!(old_server.certificate().unwrap(), certs[0]);
+
+    current.store(1, sync::atomic::Ordering::SeqCst);
+    let (_, server) = connect(&acceptor);
+    assert_eq!(server.certificate().unwrap(), certs[0]);
+    acceptor.refresh_credentials().unwrap();
+    let (client, server) = connect(&acceptor);
+    assert_eq!(server.certificate().unwrap(), certs[1]);
+    assert_eq!(client.peer_certificate().unwrap().unwrap(), certs[1]);
+
+    // established sessions keep their credentials
+    exchange(&mut old_client, &mut old_server, b"ping");
+    assert_eq!(old_server.certificate().unwrap(), certs[0]);
+
+    // credentials older than the maximum age are replaced by `accept`
+    let provided = certs.clone();
+    let selected = current.clone();
+    let acceptor = TlsAcceptor::builder(identity::Identity::from_cert(certs[0].clone()))
+        .credential_provider(Some(Duration::from_secs(0)), move || {
+            let cert = provided[selected.load(sync::atomic::Ordering::SeqCst)].clone();
+            SchannelCred::builder().cert(cert).acquire_server()
+        })
+        .build()
+        .unwrap();
+    let (_, server) = connect(&acceptor);
+    assert_eq!(server.certificate().unwrap(), certs[1]);
+    current.store(0, sync::atomic::Ordering::SeqCst);
+    let (_, server) = connect(&acceptor);
+    assert_eq!(server.certificate().unwrap(), certs[0]);
+}
+
+#[test]
+fn acceptor_replace_credentials() {
+    let ecdsa = include_bytes!("../test/localhost-ecdsa.p12");
+    let ecdsa = identity::Identity::from_pkcs12(ecdsa, "mypass").unwrap();
+    let rsa = include_bytes!("../test/localhost-rsa.p12");
+    let rsa = identity::Identity::from_pkcs12(rsa, "mypass").unwrap();
+    let certs = [ecdsa.cert().clone(), rsa.cert().clone()];
+
+    let acceptor = TlsAcceptor::new(ecdsa).unwrap();
+    let connector = TlsConnector::builder()
+        .enabled_protocols(&[Protocol::Tls12])
+        .verify_callback(|_| Ok(()))
+        .build()
+        .unwrap();
+    let connect = |acceptor: &TlsAcceptor| {
+        let (client_stream, server_stream) = mock_stream::pipe();
+        let client = connector.connect("localhost", client_stream);
+        let server = acceptor.accept(server_stream);
+        (client, server)
+    };
+
+    let (client, server) = connect(&acceptor);
+    let (old_client, old_server) = handshake_pair(client, server);
+    let (client, server) = connect(&acceptor);
+    assert!(matches!(server, Err(HandshakeError::Interrupted(_))));
+
+    let cred = SchannelCred::builder()
+        .cert(certs[1].clone())
+        .acquire_server()
+        .unwrap();
+    // clones share the credentials
+    acceptor.clone().replace_credentials(cred);
+
+    // the handshake in progress continues with the old certificate
+    let (client, _) = handshake_pair(client, server);
+    let client = client.unwrap();
+    assert_eq!(client.peer_certificate().unwrap().unwrap(), certs[0]);
+
+    let (client, server) = connect(&acceptor);
+    let (client, server) = handshake_pair(client, server);
+    let client = client.unwrap();
+    assert_eq!(client.peer_certificate().unwrap().unwrap(), certs[1]);
+    assert_eq!(server.unwrap().certificate().unwrap(), certs[1]);
+
+    let mut old_client = old_client.unwrap();
+    let mut old_server = old_server.unwrap();
+    exchange(&mut old_client, &mut old_server, b"ping");
+    assert_eq!(old_client.peer_certificate().unwrap().unwrap(), certs[0]);
+}
+
+#[test]
+fn connect_with_fallback() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let connector = TlsConnector::builder()
+        .fallback_protocols(&[Protocol::Tls12])
+        .build()
+        .unwrap();
+    let mut servers = vec![];
+    let result = connector.connect_with_fallback("localhost", || {
+        let (mut client, server) = mock_stream::pipe();
+        if servers.is_empty() {
+            client.fail_next_read(io::ErrorKind::ConnectionReset);
+        }
+        servers.push(server);
+        Ok(client)
+    });
+    let client = match result {
+        Err(HandshakeError::Interrupted(stream)) => stream,
+        _ => panic!("expected the handshake to wait for the server"),
+    };
+    assert_eq!(servers.len(), 2);
+
+    let hellos = sync::Arc::new(sync::Mutex::new(vec![]));
+    let observed = hellos.clone();
+    let creds = SchannelCred::builder().cert(cert).acquire_server().unwrap();
+    let server = tls_stream::Builder::new()
+        .record_observer(move |record| {
+            if record.direction() == RecordDirection::Received {
+                observed.lock().unwrap().push(record.data().to_vec());
+            }
+        })
+        .accept(creds, servers.pop().unwrap());
+    let (client, server) = handshake_pair(Err(HandshakeError::Interrupted(client)), server);
+    client.unwrap();
+    server.unwrap();
+    // the ClientHello of the retry only offers TLS 1.2
+    assert_eq!(&hellos.lock().unwrap()[0][9..11], b"\x03\x03");
+}
+
+#[test]
+fn connect_with_fallback_fails() {
+    let connector = TlsConnector::builder()
+        .fallback_protocols(&[Protocol::Tls12])
+        .build()
+        .unwrap();
+
+    // every attempt fails with an EOF, which is retried until the fallback
+    // protocols are exhausted
+    let mut attempts = 0;
+    let err = connector
+        .connect_with_fallback("localhost", || {
+            attempts += 1;
+            let mut stream = MockStream::new();
+            stream.eof();
+            Ok(stream)
+        })
+        .err()
+        .unwrap();
+    assert_eq!(attempts, 2);
+    match err {
+        HandshakeError::Io(e) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+        _ => panic!("expected an EOF"),
+    }
+
+    // other errors are returned right away
+    let mut attempts = 0;
+    let err = connector
+        .connect_with_fallback("localhost", || {
+            attempts += 1;
+            let mut stream = MockStream::new();
+            stream.error(io::ErrorKind::PermissionDenied);
+            Ok(stream)
+        })
+        .err()
+        .unwrap();
+    assert_eq!(attempts, 1);
+    match err {
+        HandshakeError::Io(e) => assert_eq!(e.kind(), io::ErrorKind::PermissionDenied),
+        _ => panic!("expected the injected error"),
+    }
+
+    // as are failures to reconnect
+    let err = connector
+        .connect_with_fallback::<MockStream, _>("localhost", || {
+            Err(io::Error::new(io::ErrorKind::ConnectionRefused, "refused"))
+        })
+        .err()
+        .unwrap();
+    match err {
+        HandshakeError::Io(e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused),
+        _ => panic!("expected the reconnect error"),
+    }
+}
+
+#[test]
+fn cert_without_private_key() {
+    let cert = CertContext::new(include_bytes!("../test/cert.der")).unwrap();
+    assert!(!cert.has_private_key());
+    let err = SchannelCred::builder()
+        .cert(cert)
+        .acquire(Direction::Inbound)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    let identity = include_bytes!("../test/identity.p12");
+    let identity = identity::Identity::from_pkcs12(identity, "mypass").unwrap();
+    assert!(identity.cert().has_private_key());
+}
+
+#[test]
+fn duplex_transport() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    // one pipe for each direction, with the client reading from the first
+    let (client_reader, server_writer) = mock_stream::pipe();
+    let (server_reader, client_writer) = mock_stream::pipe();
+    let creds = SchannelCred::builder().acquire_client().unwrap();
+    let client = tls_stream::Builder::new()
+        .domain("localhost")
+        .connect(creds, Duplex::new(client_reader, client_writer));
+    let creds = SchannelCred::builder().cert(cert).acquire_server().unwrap();
+    let server =
+        tls_stream::Builder::new().accept(creds, Duplex::new(server_reader, server_writer));
+    let (client, server) = handshake_pair(client, server);
+    let mut client = client.unwrap();
+    let mut server = server.unwrap();
+
+    for &len in &[1, 1000, 40_000] {
+        let data = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+        server.write_all(&data).unwrap();
+        server.flush().unwrap();
+        let mut buf = vec![0; len];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data);
+        client.write_all(&data).unwrap();
+        client.flush().unwrap();
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data);
+    }
+    assert_eq!(client.get_ref().reader().pending(), 0);
+
+    client.shutdown().unwrap();
+    assert_eq!(server.read(&mut [0; 1]).unwrap(), 0);
+    let (reader, writer) = client.into_inner().unwrap().0.into_inner();
+    assert_eq!(reader.pending(), 0);
+    drop(writer);
+    let (mut reader, _) = server.into_inner().unwrap().0.into_inner();
+    assert_eq!(reader.read(&mut [0; 1]).unwrap(), 0);
+}
+
+#[test]
+fn offline_wrong_host_cert() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let (client, _server) = offline_pair(
+        tls_stream::Builder::new().domain("wrong.host.localhost"),
+        &mut SchannelCred::builder(),
+        cert,
+    );
+    let err = unwrap_handshake(client.err().unwrap());
+    assert_eq!(err.raw_os_error().unwrap(), Foundation::CERT_E_CN_NO_MATCH);
+}
+
+#[test]
+fn offline_wrong_host_cert_ignored() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let (client, server) = offline_pair(
+        tls_stream::Builder::new()
+            .domain("wrong.host.localhost")
+            .accept_invalid_hostnames(true),
+        &mut SchannelCred::builder(),
+        cert,
+    );
+    let mut client = client.unwrap();
+    let mut server = server.unwrap();
+    exchange(&mut server, &mut client, b"hello");
+}
+
+#[test]
+fn offline_untrusted_cert() {
+    let (client, _server) = offline_pair(
+        tls_stream::Builder::new().domain("foobar.com"),
+        &mut SchannelCred::builder(),
+        test_identity_cert(),
+    );
+    let err = match client {
+        Err(HandshakeError::Failure(err)) => err,
+        _ => panic!("expected a handshake failure"),
+    };
+    assert_eq!(err.kind(), error::ErrorKind::UntrustedRoot);
+}
+
+#[test]
+fn offline_verify_callback_success() {
+    let (client, server) = offline_pair(
+        tls_stream::Builder::new()
+            .domain("foobar.com")
+            .verify_callback(|validation_result| {
+                assert!(validation_result.result().is_err());
+                Ok(())
+            }),
+        &mut SchannelCred::builder(),
+        test_identity_cert(),
+    );
+    let mut client = client.unwrap();
+    let mut server = server.unwrap();
+    exchange(&mut client, &mut server, b"hello");
+}
+
+#[test]
+fn offline_verify_callback_error() {
+    let (client, _server) = offline_pair(
+        tls_stream::Builder::new()
+            .domain("foobar.com")
+            .verify_callback(|_| {
+                Err(io::Error::from_raw_os_error(
+                    Foundation::CERT_E_UNTRUSTEDROOT,
+                ))
+            }),
+        &mut SchannelCred::builder(),
+        test_identity_cert(),
+    );
+    let err = unwrap_handshake(client.err().unwrap());
+    assert_eq!(
+        err.raw_os_error().unwrap(),
+        Foundation::CERT_E_UNTRUSTEDROOT
+    );
+}
+
+#[test]
+fn offline_handshake_one_byte_at_a_time() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let (mut client_stream, mut server_stream) = mock_stream::pipe();
+    client_stream.read_chunk(1).write_chunk(1);
+    server_stream.read_chunk(1).write_chunk(1);
+    let creds = SchannelCred::builder()
+        .acquire(Direction::Outbound)
+        .unwrap();
+    let client = tls_stream::Builder::new()
+        .domain("localhost")
+        .connect(creds, client_stream);
+    let creds = SchannelCred::builder()
+        .cert(cert)
+        .acquire(Direction::Inbound)
+        .unwrap();
+    let server = tls_stream::Builder::new().accept(creds, server_stream);
+    let (client, server) = handshake_pair(client, server);
+    let mut client = client.unwrap();
+    let mut server = server.unwrap();
+
+    exchange(&mut client, &mut server, &[1, 2, 3, 4]);
+    exchange(&mut server, &mut client, &[5, 6, 7, 8]);
+}
+
+#[test]
+fn offline_records_one_byte_at_a_time() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let (client, server) = offline_pair(
+        tls_stream::Builder::new().domain("localhost"),
+        &mut SchannelCred::builder(),
+        cert,
+    );
+    let mut client = client.unwrap();
+    let mut server = server.unwrap();
+    client.get_mut().read_chunk(1);
+
+    for &len in &[1, 100, 20_000] {
+        let data = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+        server.write_all(&data).unwrap();
+        server.flush().unwrap();
+        let mut buf = vec![0; len];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data);
+    }
+    assert_eq!(client.get_ref().pending(), 0);
+}
+
+#[test]
+fn offline_transport_errors_mid_stream() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let (client, server) = offline_pair(
+        tls_stream::Builder::new().domain("localhost"),
+        &mut SchannelCred::builder(),
+        cert,
+    );
+    let mut client = client.unwrap();
+    let mut server = server.unwrap();
+    server.write_all(b"hello").unwrap();
+    server.flush().unwrap();
+
+    // transient failures leave the session intact
+    let mut buf = [0; 5];
+    client.get_mut().fail_next_read(io::ErrorKind::Interrupted);
+    client.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+
+    client
+        .get_mut()
+        .fail_next_read(io::ErrorKind::ConnectionReset);
+    let err = client.read(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    assert!(err.raw_os_error().is_none());
+
+    // the peer going away without a close_notify reads as EOF
+    drop(server);
+    assert_eq!(client.read(&mut buf).unwrap(), 0);
+    assert_eq!(client.session_state(), SessionState::Streaming);
+}
+
+#[test]
+fn mock_would_block_during_handshake() {
+    let mut stream = MockStream::new();
+    stream.would_block().write_chunk(1);
+    let creds = SchannelCred::builder()
+        .acquire(Direction::Outbound)
+        .unwrap();
+    let stream = match tls_stream::Builder::new()
+        .domain("localhost")
+        .connect(creds, stream)
+    {
+        Err(HandshakeError::Interrupted(stream)) => stream,
+        _ => panic!("expected an interrupted handshake"),
+    };
+    // the whole ClientHello has been written despite the short writes
+    let written = stream.get_ref().written();
+    assert_eq!(written[0], 0x16);
+    assert_eq!(
+        written.len(),
+        5 + u16::from_be_bytes([written[3], written[4]]) as usize
+    );
+
+    match stream.handshake() {
+        Err(HandshakeError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof),
+        _ => panic!("expected an EOF"),
+    }
+}
+
+#[test]
+fn mock_eof_during_handshake() {
+    for &len in &[0, 1, 4, 5, 6] {
+        let mut stream = MockStream::new();
+        // the start of a ServerHello
+        stream
+            .data(&b"\x16\x03\x03\x00\x40\x02\x00\x00\x3c"[..len])
+            .eof();
+        let creds = SchannelCred::builder()
+            .acquire(Direction::Outbound)
+            .unwrap();
+        let err = tls_stream::Builder::new()
+            .domain("localhost")
+            .connect(creds, stream)
+            .err()
+            .unwrap();
+        match err {
+            HandshakeError::Io(err) => assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof),
+            _ => panic!("expected an EOF after {} bytes", len),
+        }
+    }
+}
+
+#[test]
+fn mock_write_errors_during_handshake() {
+    let mut stream = MockStream::new();
+    stream.write_error(io::ErrorKind::BrokenPipe);
+    let creds = SchannelCred::builder()
+        .acquire(Direction::Outbound)
+        .unwrap();
+    match tls_stream::Builder::new()
+        .domain("localhost")
+        .connect(creds, stream)
+    {
+        Err(HandshakeError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::BrokenPipe),
+        _ => panic!("expected an I/O error"),
+    }
+
+    let mut stream = MockStream::new();
+    stream.write_would_block();
+    let creds = SchannelCred::builder()
+        .acquire(Direction::Outbound)
+        .unwrap();
+    let stream = match tls_stream::Builder::new()
+        .domain("localhost")
+        .connect(creds, stream)
+    {
+        Err(HandshakeError::Interrupted(stream)) => stream,
+        _ => panic!("expected an interrupted handshake"),
+    };
+    assert!(stream.get_ref().written().is_empty());
+    match stream.handshake() {
+        Err(HandshakeError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof),
+        _ => panic!("expected an EOF"),
+    }
+}
+
+#[test]
+fn pending_writes() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let (client, server) = offline_pair(
+        tls_stream::Builder::new().domain("localhost"),
+        &mut SchannelCred::builder(),
+        cert,
+    );
+    let mut client = client.unwrap();
+    let mut server = server.unwrap();
+    assert_eq!(client.pending_write_len(), 0);
+    assert_eq!(client.flush_pending().unwrap(), 0);
+
+    client.get_mut().write_limit(Some(10));
+    let err = client.write(b"hello world").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    let pending = client.pending_write_len();
+    assert!(pending > b"hello world".len() - 10, "{}", pending);
+
+    client.get_mut().write_limit(Some(5));
+    assert_eq!(client.flush_pending().unwrap(), 5);
+    assert_eq!(client.pending_write_len(), pending - 5);
+    let err = client.flush_pending().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    assert_eq!(client.pending_write_len(), pending - 5);
+
+    client.get_mut().write_limit(None);
+    assert_eq!(client.flush_pending().unwrap(), pending - 5);
+    assert_eq!(client.pending_write_len(), 0);
+    assert_eq!(client.stats().records_sent, 1);
+
+    // the repeated write completes without encrypting the data again
+    assert_eq!(client.write(b"hello world").unwrap(), 11);
+    assert_eq!(client.pending_write_len(), 0);
+    assert_eq!(client.stats().records_sent, 1);
+    let mut buf = [0; 11];
+    server.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello world");
+}
+
+#[test]
+fn write_record() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let sent = sync::Arc::new(sync::Mutex::new(vec![]));
+    let observed = sent.clone();
+    let (client, server) = offline_pair(
+        tls_stream::Builder::new()
+            .domain("localhost")
+            .record_observer(move |record| {
+                if record.direction() == RecordDirection::Sent && record.is_application_data() {
+                    observed.lock().unwrap().push(record.data().len());
+                }
+            }),
+        &mut SchannelCred::builder(),
+        cert,
+    );
+    let mut client = client.unwrap();
+    let mut server = server.unwrap();
+
+    let lens = [1, 1000, 16384];
+    for len in &lens {
+        client.write_record(&vec![0xab; *len]).unwrap();
+    }
+    let err = client.write_record(&[0; 16385]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    let sent = sent.lock().unwrap();
+    assert_eq!(sent.len(), lens.len());
+    for (record, len) in sent.iter().zip(&lens) {
+        assert!(record > len, "{} byte record for {} bytes", record, len);
+    }
+
+    let mut buf = vec![0; lens.iter().sum()];
+    server.read_exact(&mut buf).unwrap();
+    assert!(buf.iter().all(|&b| b == 0xab));
+}
+
+#[test]
+fn read_record() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let (client, server) = offline_pair(
+        tls_stream::Builder::new().domain("localhost"),
+        &mut SchannelCred::builder(),
+        cert,
+    );
+    let mut client = client.unwrap();
+    let mut server = server.unwrap();
+
+    let lens = [5, 1000, 16384, 3];
+    for (i, len) in lens.iter().enumerate() {
+        client.write_record(&vec![i as u8; *len]).unwrap();
+    }
+    for (i, len) in lens.iter().enumerate() {
+        let record = server.read_record().unwrap().unwrap();
+        assert_eq!(record, vec![i as u8; *len]);
+    }
+
+    // the rest of a partly read record comes back on its own
+    client.write_record(b"0123456789").unwrap();
+    client.write_record(b"abc").unwrap();
+    let mut buf = [0; 4];
+    server.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"0123");
+    assert_eq!(server.read_record().unwrap().unwrap(), b"456789");
+    assert_eq!(server.read_record().unwrap().unwrap(), b"abc");
+
+    client.shutdown().unwrap();
+    assert!(server.read_record().unwrap().is_none());
+    assert_eq!(server.session_state(), SessionState::Shutdown);
+}
+
+#[test]
+fn read_ahead() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let (client, server) = offline_pair(
+        tls_stream::Builder::new().domain("localhost"),
+        &mut SchannelCred::builder(),
+        cert.clone(),
+    );
+    let mut client = client.unwrap();
+    let mut server = server.unwrap();
+    exchange(&mut client, &mut server, b"hello");
+
+    // all records received in one read are decrypted by one call, up to and
+    // including the close_notify behind them
+    let lens = [100, 7, 2000, 1];
+    for (i, len) in lens.iter().enumerate() {
+        server.write_record(&vec![i as u8; *len]).unwrap();
+    }
+    server.shutdown().unwrap();
+    let records = client.stats().records_received;
+    let expected = lens
+        .iter()
+        .enumerate()
+        .flat_map(|(i, len)| vec![i as u8; *len])
+        .collect::<Vec<_>>();
+    assert_eq!(client.fill_buf().unwrap(), &expected[..]);
+    assert_eq!(client.stats().records_received, records + 5);
+    assert_eq!(client.session_state(), SessionState::Shutdown);
+
+    // records handed out by `read_record` keep their boundaries
+    let mut buf = [0; 50];
+    client.read_exact(&mut buf).unwrap();
+    assert_eq!(client.read_record().unwrap().unwrap(), vec![0; 50]);
+    assert_eq!(client.read_record().unwrap().unwrap(), vec![1; 7]);
+    let mut out = vec![];
+    client.read_to_end(&mut out).unwrap();
+    assert_eq!(out.len(), 2001);
+
+    if !crate::capabilities().tls13 {
+        return;
+    }
+
+    // With TLS 1.3, the server's NewSessionTicket is still buffered by the
+    // client when the records behind it arrive. The pass stops at it, and
+    // the records after it are decrypted together once it's been handled.
+    let (client, server) = offline_pair(
+        tls_stream::Builder::new().domain("localhost"),
+        SchannelCred::builder().enabled_protocols(&[Protocol::Tls13]),
+        cert,
+    );
+    let mut client = client.unwrap();
+    let mut server = server.unwrap();
+    for (i, len) in lens.iter().enumerate() {
+        server.write_record(&vec![i as u8; *len]).unwrap();
+    }
+    let stats = client.stats();
+    assert_eq!(client.fill_buf().unwrap(), &expected[..]);
+    let renegotiations = client.stats().renegotiations - stats.renegotiations;
+    assert_eq!(
+        client.stats().records_received - stats.records_received,
+        lens.len() as u64 + renegotiations
+    );
+    assert_eq!(client.session_state(), SessionState::Streaming);
+    client.consume(expected.len());
+    exchange(&mut server, &mut client, b"hello");
+}
+
+#[test]
+fn export_context() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let (client, server) = offline_pair(
+        tls_stream::Builder::new().domain("localhost"),
+        &mut SchannelCred::builder(),
+        cert.clone(),
+    );
+    let mut client = client.unwrap();
+    let mut server = server.unwrap();
+    exchange(&mut client, &mut server, b"hello");
+
+    client.write_record(b"abcdef").unwrap();
+    client.write_record(b"ghi").unwrap();
+    let mut buf = [0; 2];
+    server.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ab");
+
+    let (stream, exported) = match server.export_context() {
+        Ok(exported) => exported,
+        // not every version of Windows exports Schannel contexts
+        Err(e) if e.raw_os_error() == Some(Foundation::SEC_E_UNSUPPORTED_FUNCTION) => return,
+        Err(e) => panic!("{}", e),
+    };
+    assert!(exported.is_server());
+    let imported = ExportedContext::from_bytes(&exported.to_bytes()).unwrap();
+    assert!(imported.plaintext().is_empty());
+    assert!(imported.ciphertext().is_empty());
+
+    let creds = SchannelCred::builder()
+        .cert(cert)
+        .acquire(Direction::Inbound)
+        .unwrap();
+    let mut server =
+        TlsStream::from_exported(creds, stream, imported, exported.ciphertext()).unwrap();
+    assert_eq!(server.session_state(), SessionState::Streaming);
+    let mut rest = exported.plaintext().to_vec();
+    while rest.len() < 7 {
+        rest.extend_from_slice(&server.read_record().unwrap().unwrap());
+    }
+    assert_eq!(rest, b"cdefghi");
+    exchange(&mut client, &mut server, b"hello");
+    exchange(&mut server, &mut client, b"world");
+
+    assert!(ExportedContext::from_bytes(&[]).is_err());
+    assert!(ExportedContext::from_bytes(&[4, 0, 0]).is_err());
+    assert!(ExportedContext::from_bytes(&[0, 0, 1, b'a']).is_err());
+    let exported = ExportedContext::from_bytes(&[3, 0, 1, b'a', 1, 2]).unwrap();
+    assert_eq!(exported.to_bytes(), [3, 0, 1, b'a', 1, 2]);
+}
+
+#[test]
+fn send_alert() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let (client, server) = offline_pair(
+        tls_stream::Builder::new().domain("localhost"),
+        &mut SchannelCred::builder(),
+        cert,
+    );
+    let mut client = client.unwrap();
+    let mut server = server.unwrap();
+    exchange(&mut client, &mut server, b"hello");
+
+    server
+        .send_alert(AlertLevel::Fatal, AlertDescription::AccessDenied)
+        .unwrap();
+    assert_eq!(server.session_state(), SessionState::Shutdown);
+    assert!(server.write(b"more").is_err());
+    assert!(server
+        .send_alert(AlertLevel::Fatal, AlertDescription::AccessDenied)
+        .is_err());
+
+    let err = client.read(&mut [0; 16]).unwrap_err();
+    let err = error::Error::from_io_error(&err).expect("expected an Schannel error");
+    assert_ne!(err.kind(), error::ErrorKind::ContextExpired, "{:?}", err);
+}
+
+#[test]
+fn connect_default() {
+    let stream = TcpStream::connect("google.com:443").unwrap();
+    let mut stream = crate::connect("google.com", stream).unwrap();
+    stream.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+    let mut out = vec![];
+    stream.read_to_end(&mut out).unwrap();
+    assert!(out.starts_with(b"HTTP/1.0 200 OK") || out.starts_with(b"HTTP/1.0 302 Found"));
+
+    // the options of the builder still apply
+    let stream = TcpStream::connect("google.com:443").unwrap();
+    let err = tls_stream::Builder::new()
+        .domain("example.com")
+        .connect_default(stream)
+        .err()
+        .unwrap();
+    let err = error::Error::from_io_error(&err).unwrap();
+    assert_eq!(err.kind(), error::ErrorKind::CertNameMismatch);
+
+    let stream = TcpStream::connect("google.com:443").unwrap();
+    let stream = tls_stream::Builder::new()
+        .domain("google.com")
+        .request_application_protocols(&[b"h2"])
+        .connect_default(stream)
+        .unwrap();
+    assert_eq!(
+        stream.negotiated_application_protocol().unwrap(),
+        Some(b"h2".to_vec())
+    );
+}
+
+#[test]
+fn client_cert_selector() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let server_cred = SchannelCred::builder()
+        .cert(cert.clone())
+        .acquire_server()
+        .unwrap();
+    let calls = sync::Arc::new(sync::Mutex::new(0));
+    let counted = calls.clone();
+    let selected = cert.clone();
+    let (client_stream, server_stream) = mock_stream::pipe();
+    let client = tls_stream::Builder::new()
+        .domain("localhost")
+        .client_cert_selector(move |_issuers| {
+            *counted.lock().unwrap() += 1;
+            SchannelCred::builder()
+                .cert(selected.clone())
+                .acquire_client()
+                .ok()
+        })
+        .connect(
+            SchannelCred::builder().acquire_client().unwrap(),
+            client_stream,
+        );
+    let server = tls_stream::Builder::new()
+        .client_cert_policy(ClientCertPolicy::Require)
+        .accept(server_cred.clone(), server_stream);
+    let (client, server) = handshake_pair(client, server);
+    let mut client = client.unwrap();
+    let mut server = server.unwrap();
+    assert_eq!(*calls.lock().unwrap(), 1);
+    let peer = server.peer_certificate().unwrap().unwrap();
+    assert_eq!(
+        peer.fingerprint(HashAlgorithm::sha256()).unwrap(),
+        cert.fingerprint(HashAlgorithm::sha256()).unwrap()
+    );
+    exchange(&mut client, &mut server, b"hello");
+
+    // without a certificate from the selector the client continues without
+    // one
+    let (client_stream, server_stream) = mock_stream::pipe();
+    let client = tls_stream::Builder::new()
+        .domain("localhost")
+        .client_cert_selector(|_| None)
+        .connect(
+            SchannelCred::builder().acquire_client().unwrap(),
+            client_stream,
+        );
+    let server = tls_stream::Builder::new()
+        .client_cert_policy(ClientCertPolicy::Request)
+        .accept(server_cred, server_stream);
+    let (client, server) = handshake_pair(client, server);
+    client.unwrap();
+    assert!(server.unwrap().peer_certificate().unwrap().is_none());
+}
+
+#[cfg(feature = "cert-dialog")]
+#[test]
+fn cert_dialog_candidates() {
+    use crate::cert_dialog::CertificateDialog;
+
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+    let mut store = Memory::new().unwrap().into_store();
+    store.add_cert(&cert, CertAdd::Always).unwrap();
+    let fingerprints = |store: CertStore| {
+        store
+            .certs()
+            .map(|cert| cert.fingerprint(HashAlgorithm::sha256()).unwrap())
+            .collect::<Vec<_>>()
+    };
+    let expected = vec![cert.fingerprint(HashAlgorithm::sha256()).unwrap()];
+
+    let candidates = CertificateDialog::new().candidates(&store).unwrap();
+    assert_eq!(fingerprints(candidates), expected);
+
+    // the certificate is self-signed, so it is its own issuer
+    let issuer = unsafe {
+        let info = &*(*cert.as_inner()).pCertInfo;
+        std::slice::from_raw_parts(info.Issuer.pbData, info.Issuer.cbData as usize).to_vec()
+    };
+    let candidates = CertificateDialog::new()
+        .issuers(&[issuer])
+        .candidates(&store)
+        .unwrap();
+    assert_eq!(fingerprints(candidates), expected);
+
+    let other = unsafe {
+        let other = test_identity_cert();
+        let info = &*(*other.as_inner()).pCertInfo;
+        std::slice::from_raw_parts(info.Issuer.pbData, info.Issuer.cbData as usize).to_vec()
+    };
+    let candidates = CertificateDialog::new()
+        .issuers(&[other])
+        .candidates(&store)
+        .unwrap();
+    assert!(fingerprints(candidates).is_empty());
+
+    // certificates without a private key are never listed
+    let mut store = Memory::new().unwrap();
+    store.add_encoded_certificate(cert.to_der()).unwrap();
+    let candidates = CertificateDialog::new()
+        .candidates(&store.into_store())
+        .unwrap();
+    assert!(fingerprints(candidates).is_empty());
+
+    // nothing to select from, so no dialog is shown
+    let store = Memory::new().unwrap().into_store();
+    let selected = CertificateDialog::new()
+        .title("title")
+        .text("text")
+        .select(&store)
+        .unwrap();
+    assert!(selected.is_none());
+}
+
+#[test]
+fn find_private_key() {
+    let cert = CertContext::new(include_bytes!("../test/cert.der")).unwrap();
+    assert!(cert.find_private_key(true).unwrap().is_none());
+
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+    assert!(cert.find_private_key(true).unwrap().is_some());
+
+    // a cached key is shared with the certificate, which keeps owning it
+    let mut store = Memory::new().unwrap().into_store();
+    let cert = store.add_cert(&cert, CertAdd::Always).unwrap();
+    let handle = |key: &PrivateKey| match *key {
+        PrivateKey::CryptProv(ref prov) => prov.as_inner(),
+        PrivateKey::NcryptKey(ref key) => key.as_inner(),
+    };
+    let acquire = || {
+        cert.private_key()
+            .cache(true)
+            .silent(true)
+            .acquire()
+            .unwrap()
+    };
+    let first = acquire();
+    let second = acquire();
+    assert_eq!(handle(&first), handle(&second));
+    drop(first);
+    drop(second);
+    // the key stays valid after the certificate is gone
+    let third = acquire();
+    drop(cert);
+    drop(store);
+    assert_ne!(handle(&third), 0);
+}
+
+static szOID_SUBJECT_ALT_NAME2: &[u8] = null_terminate!(Cryptography::szOID_SUBJECT_ALT_NAME2);
+static szOID_BASIC_CONSTRAINTS2: &[u8] = null_terminate!(Cryptography::szOID_BASIC_CONSTRAINTS2);
+static szOID_ENHANCED_KEY_USAGE: &[u8] = null_terminate!(Cryptography::szOID_ENHANCED_KEY_USAGE);
+static szOID_PKIX_KP_SERVER_AUTH: &[u8] = null_terminate!(Cryptography::szOID_PKIX_KP_SERVER_AUTH);
+static szOID_PKIX_KP_CLIENT_AUTH: &[u8] = null_terminate!(Cryptography::szOID_PKIX_KP_CLIENT_AUTH);
+
+/// Encodes `info`, a structure of type `struct_type`, as the value of a
+/// certificate extension.
+unsafe fn encode_extension(struct_type: *const u8, info: *const c_void) -> Vec<u8> {
+    let mut encoded_len = 0;
+    let encode = |buf: *mut u8, len: &mut u32| {
+        Cryptography::CryptEncodeObjectEx(
+            Cryptography::X509_ASN_ENCODING,
+            struct_type,
+            info,
+            0,
+            ptr::null(),
+            buf as *mut _,
+            len,
+        )
+    };
+    let res = encode(ptr::null_mut(), &mut encoded_len);
+    assert!(res != 0, "{}", Error::last_os_error());
+    let mut encoded = vec![0; encoded_len as usize];
+    let res = encode(encoded.as_mut_ptr(), &mut encoded_len);
+    assert!(res != 0, "{}", Error::last_os_error());
+    encoded.truncate(encoded_len as usize);
+    encoded
+}
+
+/// Creates a self-signed certificate for `subject` with the key of the
+/// localhost certificate, which must have been created already.
+///
+/// `extensions` are pairs of OIDs and encoded values.
+fn generate_cert(subject: &str, extensions: &mut [(&[u8], Vec<u8>)]) -> CertContext {
+    generate_cert_with_key("schannel-rs test suite", subject, extensions)
+}
+
+/// Like `generate_cert`, but with the signature key of the machine key
+/// container `container`.
+fn generate_cert_with_key(
+    container: &str,
+    subject: &str,
+    extensions: &mut [(&[u8], Vec<u8>)],
+) -> CertContext {
+    unsafe {
+        let mut container = container.encode_utf16().chain(Some(0)).collect::<Vec<_>>();
+        let name = subject.encode_utf16().chain(Some(0)).collect::<Vec<_>>();
+        let mut cname_buffer: [u16; 257] = mem::zeroed();
+        let mut cname_len = cname_buffer.len() as u32;
+        let res = Cryptography::CertStrToNameW(
+            Cryptography::X509_ASN_ENCODING,
+            name.as_ptr(),
+            Cryptography::CERT_X500_NAME_STR,
+            ptr::null_mut(),
+            cname_buffer.as_mut_ptr() as *mut u8,
+            &mut cname_len,
+            ptr::null_mut(),
+        );
+        assert!(res != 0, "{}", Error::last_os_error());
+
+        let mut extensions = extensions
+            .iter_mut()
+            .map(|&mut (oid, ref mut value)| Cryptography::CERT_EXTENSION {
+                pszObjId: oid.as_ptr() as *mut _,
+                fCritical: 0,
+                Value: Cryptography::CRYPTOAPI_BLOB {
+                    cbData: value.len() as u32,
+                    pbData: value.as_mut_ptr(),
+                },
+            })
+            .collect::<Vec<_>>();
+        let extensions = Cryptography::CERT_EXTENSIONS {
+            cExtension: extensions.len() as u32,
+            rgExtension: extensions.as_mut_ptr(),
+        };
+
+        let subject_issuer = Cryptography::CRYPTOAPI_BLOB {
+            cbData: cname_len,
+            pbData: cname_buffer.as_ptr() as *mut u8,
+        };
+        let key_provider = Cryptography::CRYPT_KEY_PROV_INFO {
+            pwszContainerName: container.as_mut_ptr(),
+            pwszProvName: ptr::null_mut(),
+            dwProvType: Cryptography::PROV_RSA_FULL,
+            dwFlags: Cryptography::CRYPT_MACHINE_KEYSET,
+            cProvParam: 0,
+            rgProvParam: ptr::null_mut(),
+            dwKeySpec: Cryptography::AT_SIGNATURE,
+        };
+        let sig_algorithm = Cryptography::CRYPT_ALGORITHM_IDENTIFIER {
+            pszObjId: szOID_RSA_SHA256RSA.as_ptr() as *mut _,
+            Parameters: mem::zeroed(),
+        };
+        let cert_context = Cryptography::CertCreateSelfSignCertificate(
+            Cryptography::HCRYPTPROV_OR_NCRYPT_KEY_HANDLE::default(),
+            &subject_issuer,
+            Cryptography::CERT_CREATE_SELFSIGN_FLAGS::default(),
+            &key_provider,
+            &sig_algorithm,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            &extensions,
+        );
+        assert!(!cert_context.is_null(), "{}", Error::last_os_error());
+        CertContext::from_inner(cert_context)
+    }
+}
+
+/// Returns an extension marking certificates as certificate authorities.
+fn ca_extension() -> (&'static [u8], Vec<u8>) {
+    let info = Cryptography::CERT_BASIC_CONSTRAINTS2_INFO {
+        fCA: 1,
+        fPathLenConstraint: 0,
+        dwPathLenConstraint: 0,
+    };
+    let value = unsafe {
+        encode_extension(
+            Cryptography::X509_BASIC_CONSTRAINTS2,
+            &info as *const _ as *const _,
+        )
+    };
+    (szOID_BASIC_CONSTRAINTS2, value)
+}
+
+/// Issues a copy of `cert`, a certificate created by `generate_cert`, from
+/// `issuer`, which shares its key.
+fn reissue_cert(cert: &CertContext, issuer: &CertContext) -> CertContext {
+    reissue_cert_with_key(cert, issuer, "schannel-rs test suite")
+}
+
+/// Like `reissue_cert`, for an issuer whose key is in the machine key
+/// container `container`.
+fn reissue_cert_with_key(cert: &CertContext, issuer: &CertContext, container: &str) -> CertContext {
+    let prov = AcquireOptions::new()
+        .container(container)
+        .machine_keyset(true)
+        .acquire(ProviderType::rsa_full())
+        .unwrap();
+    let encoded = unsafe {
+        let mut info = *(*cert.as_inner()).pCertInfo;
+        info.Issuer = (*(*issuer.as_inner()).pCertInfo).Subject;
+        let sign = |buf: *mut u8, len: &mut u32| {
+            Cryptography::CryptSignAndEncodeCertificate(
+                prov.as_inner(),
+                Cryptography::AT_SIGNATURE,
+                Cryptography::X509_ASN_ENCODING,
+                Cryptography::X509_CERT_TO_BE_SIGNED,
+                &info as *const _ as *const _,
+                &info.SignatureAlgorithm,
+                ptr::null(),
+                buf,
+                len,
+            )
+        };
+        let mut len = 0;
+        let res = sign(ptr::null_mut(), &mut len);
+        assert!(res != 0, "{}", Error::last_os_error());
+        let mut encoded = vec![0; len as usize];
+        let res = sign(encoded.as_mut_ptr(), &mut len);
+        assert!(res != 0, "{}", Error::last_os_error());
+        encoded.truncate(len as usize);
+        encoded
+    };
+
+    let reissued = CertContext::new(&encoded).unwrap();
+    reissued
+        .set_key_prov_info()
+        .container("schannel-rs test suite")
+        .type_(ProviderType::rsa_full())
+        .machine_keyset(true)
+        .key_spec(KeySpec::signature())
+        .set()
+        .unwrap();
+    reissued
+}
+
+/// Creates a self-signed certificate for the loopback addresses.
+fn loopback_ip_cert() -> Option<CertContext> {
+    localhost_cert()?;
+
+    let mut v4 = [127, 0, 0, 1];
+    let mut v6 = Ipv6Addr::LOCALHOST.octets();
+    let names = unsafe {
+        let mut entries: [Cryptography::CERT_ALT_NAME_ENTRY; 2] = mem::zeroed();
+        for (entry, addr) in entries.iter_mut().zip([&mut v4[..], &mut v6[..]]) {
+            entry.dwAltNameChoice = CERT_ALT_NAME_IP_ADDRESS;
+            entry.Anonymous.IPAddress = Cryptography::CRYPTOAPI_BLOB {
+                cbData: addr.len() as u32,
+                pbData: addr.as_mut_ptr(),
+            };
+        }
+        let names = Cryptography::CERT_ALT_NAME_INFO {
+            cAltEntry: entries.len() as u32,
+            rgAltEntry: entries.as_mut_ptr(),
+        };
+        encode_extension(
+            Cryptography::X509_ALTERNATE_NAME,
+            &names as *const _ as *const _,
+        )
+    };
+    Some(generate_cert(
+        "CN=schannel-rs IP test",
+        &mut [(szOID_SUBJECT_ALT_NAME2, names)],
+    ))
+}
+
+/// Creates a self-signed certificate for localhost valid for the extended
+/// key usages `ekus`.
+fn eku_cert(ekus: &[&[u8]]) -> Option<CertContext> {
+    localhost_cert()?;
+
+    let mut identifiers = ekus
+        .iter()
+        .map(|oid| oid.as_ptr() as *mut u8)
+        .collect::<Vec<_>>();
+    let usage = Cryptography::CTL_USAGE {
+        cUsageIdentifier: identifiers.len() as u32,
+        rgpszUsageIdentifier: identifiers.as_mut_ptr(),
+    };
+    let usage = unsafe {
+        encode_extension(
+            Cryptography::X509_ENHANCED_KEY_USAGE,
+            &usage as *const _ as *const _,
+        )
+    };
+    Some(generate_cert(
+        "CN=localhost,O=schannel-rs EKU test",
+        &mut [(szOID_ENHANCED_KEY_USAGE, usage)],
+    ))
+}
+
+#[test]
+fn ip_address_names() {
+    let cert = match loopback_ip_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+    assert_eq!(
+        cert.ip_addresses().unwrap(),
+        [
+            IpAddr::from(Ipv4Addr::LOCALHOST),
+            IpAddr::from(Ipv6Addr::LOCALHOST)
+        ]
+    );
+    assert!(test_identity_cert().ip_addresses().unwrap().is_empty());
+
+    let mut store = Memory::new().unwrap().into_store();
+    store.add_cert(&cert, CertAdd::Always).unwrap();
+    let connect = |builder: &mut tls_stream::Builder| {
+        offline_pair(
+            builder.cert_store(store.clone()),
+            &mut SchannelCred::builder(),
+            cert.clone(),
+        )
+    };
+
+    for &domain in &["127.0.0.1", "::1", "[::1]", "0:0:0:0:0:0:0:1"] {
+        let (client, server) = connect(tls_stream::Builder::new().domain(domain));
+        let mut client = client.unwrap_or_else(|e| panic!("{}: {}", domain, unwrap_handshake(e)));
+        let mut server = server.unwrap();
+        exchange(&mut client, &mut server, b"ping");
+    }
+    let (client, _server) = connect(tls_stream::Builder::new().ip(Ipv6Addr::LOCALHOST.into()));
+    client.unwrap();
+
+    for &domain in &["10.0.0.5", "::2", "localhost"] {
+        let (client, _server) = connect(tls_stream::Builder::new().domain(domain));
+        let err = unwrap_handshake(client.unwrap_err());
+        assert_eq!(
+            err.raw_os_error(),
+            Some(Foundation::CERT_E_CN_NO_MATCH),
+            "{}: {}",
+            domain,
+            err
+        );
+    }
+
+    let (client, _server) = connect(
+        tls_stream::Builder::new()
+            .domain("10.0.0.5")
+            .accept_invalid_hostnames(true),
+    );
+    client.unwrap();
+}
+
+#[test]
+fn silent_credentials() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let mut builder = SchannelCred::builder();
+    builder.silent(true).cert(cert.clone());
+    assert!(format!("{:?}", builder).contains("silent: true"));
+    let server_cred = builder.acquire_server().unwrap();
+    let client_cred = builder.acquire_client().unwrap();
+
+    let (client_stream, server_stream) = mock_stream::pipe();
+    let client = tls_stream::Builder::new()
+        .domain("localhost")
+        .connect(client_cred, client_stream);
+    let server = tls_stream::Builder::new()
+        .client_cert_policy(ClientCertPolicy::Require)
+        .accept(server_cred, server_stream);
+    let (client, server) = handshake_pair(client, server);
+    let mut client = client.unwrap();
+    let mut server = server.unwrap();
+    assert!(server.peer_certificate().unwrap().is_some());
+    exchange(&mut client, &mut server, b"ping");
+}
+
+#[test]
+fn required_ekus() {
+    let server_auth = match eku_cert(&[szOID_PKIX_KP_SERVER_AUTH]) {
+        Some(cert) => cert,
+        None => return,
+    };
+    let client_auth = eku_cert(&[szOID_PKIX_KP_CLIENT_AUTH]).unwrap();
+    let both = eku_cert(&[szOID_PKIX_KP_SERVER_AUTH, szOID_PKIX_KP_CLIENT_AUTH]).unwrap();
+    // valid for every usage, but without an extension listing them
+    let no_ekus = localhost_cert().unwrap();
+    let wrong_usage = |err: HandshakeError<PipeStream>| match err {
+        HandshakeError::Failure(err) => {
+            assert_eq!(err.code(), Foundation::CERT_E_WRONG_USAGE);
+            assert_eq!(err.kind(), error::ErrorKind::CertWrongUsage);
+        }
+        err => panic!("expected a usage failure: {}", unwrap_handshake(err)),
+    };
+
+    // clients check the certificates of servers
+    let servers = [
+        (&server_auth, true),
+        (&both, true),
+        (&client_auth, false),
+        (&no_ekus, false),
+    ];
+    for &(cert, valid) in servers.iter() {
+        let mut store = Memory::new().unwrap().into_store();
+        store.add_cert(cert, CertAdd::Always).unwrap();
+        let (client, _server) = offline_pair(
+            tls_stream::Builder::new()
+                .domain("localhost")
+                .cert_store(store)
+                .required_eku(Cryptography::szOID_PKIX_KP_SERVER_AUTH),
+            &mut SchannelCred::builder(),
+            cert.clone(),
+        );
+        match client {
+            Ok(_) => assert!(valid),
+            Err(err) => {
+                assert!(!valid);
+                wrong_usage(err);
+            }
+        }
+    }
+
+    // and servers those of clients
+    let server_cred = SchannelCred::builder()
+        .cert(no_ekus.clone())
+        .acquire_server()
+        .unwrap();
+    let clients = [
+        (&client_auth, true),
+        (&both, true),
+        (&server_auth, false),
+        (&no_ekus, false),
+    ];
+    for &(cert, valid) in clients.iter() {
+        let client_cred = SchannelCred::builder()
+            .cert(cert.clone())
+            .acquire_client()
+            .unwrap();
+        let (client_stream, server_stream) = mock_stream::pipe();
+        let client = tls_stream::Builder::new()
+            .domain("localhost")
+            .connect(client_cred, client_stream);
+        let server = tls_stream::Builder::new()
+            .client_cert_policy(ClientCertPolicy::Require)
+            .required_eku(Cryptography::szOID_PKIX_KP_CLIENT_AUTH)
+            .accept(server_cred.clone(), server_stream);
+        let (_client, server) = handshake_pair(client, server);
+        match server {
+            Ok(server) => {
+                assert!(valid);
+                assert_eq!(server.peer_certificate().unwrap().as_ref(), Some(cert));
+            }
+            Err(err) => {
+                assert!(!valid);
+                wrong_usage(err);
+            }
+        }
+    }
+
+    let (client, _server) = offline_pair(
+        tls_stream::Builder::new()
+            .domain("localhost")
+            .required_eku("1.3.6.1.5.5.7.3.1\0"),
+        &mut SchannelCred::builder(),
+        no_ekus,
+    );
+    match client {
+        Err(HandshakeError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::InvalidInput),
+        _ => panic!("expected an invalid OID to be rejected"),
+    }
+}
+
+#[test]
+fn send_intermediates() {
+    if localhost_cert().is_none() {
+        return;
+    }
+    let root = generate_cert("CN=schannel-rs test root", &mut [ca_extension()]);
+    let intermediate = generate_cert("CN=schannel-rs test intermediate", &mut [ca_extension()]);
+    let intermediate = reissue_cert(&intermediate, &root);
+    let leaf = generate_cert("CN=localhost,O=schannel-rs chain test", &mut []);
+    let leaf = reissue_cert(&leaf, &intermediate);
+
+    // the client only trusts the root, and knows no intermediates
+    let connect = |cred: &mut schannel_cred::Builder| {
+        let mut roots = Memory::new().unwrap().into_store();
+        roots.add_cert(&root, CertAdd::Always).unwrap();
+        let client_cred = SchannelCred::builder().acquire_client().unwrap();
+        let server_cred = cred.acquire_server().unwrap();
+        let (client_stream, server_stream) = mock_stream::pipe();
+        let client = tls_stream::Builder::new()
+            .domain("localhost")
+            .cert_store(roots)
+            .connect(client_cred, client_stream);
+        let server = tls_stream::Builder::new().accept(server_cred, server_stream);
+        handshake_pair(client, server)
+    };
+
+    let (client, _server) = connect(SchannelCred::builder().cert(leaf.clone()));
+    match client {
+        Err(HandshakeError::Failure(err)) => {
+            assert_eq!(err.kind(), error::ErrorKind::UntrustedRoot, "{}", err)
+        }
+        _ => panic!("expected the chain to be incomplete"),
+    }
+
+    let mut cred = SchannelCred::builder();
+    cred.cert_with_chain(leaf, vec![intermediate.clone()]);
+    let (client, server) = connect(&mut cred);
+    let mut client = client.unwrap();
+    let mut server = server.unwrap();
+    let peer_cert = client.peer_certificate().unwrap().unwrap();
+    let sent = peer_cert.cert_store().unwrap();
+    assert!(sent.certs().any(|cert| cert == intermediate));
+    assert!(!sent.certs().any(|cert| cert == root));
+    exchange(&mut client, &mut server, b"ping");
+}
+
+/// The machine key container holding the RSA-1024 key of `weak_key_cert`.
+const WEAK_KEY_CONTAINER: &str = "schannel-rs test suite RSA-1024";
+
+/// Creates a self-signed certificate for localhost with an RSA-1024 key.
+fn weak_key_cert(extensions: &mut [(&[u8], Vec<u8>)]) -> Option<CertContext> {
+    localhost_cert()?;
+
+    static INIT: Once = Once::new();
+    INIT.call_once(|| unsafe {
+        let container = WEAK_KEY_CONTAINER
+            .encode_utf16()
+            .chain(Some(0))
+            .collect::<Vec<_>>();
+        let mut provider = 0;
+        let res = Cryptography::CryptAcquireContextW(
+            &mut provider,
+            container.as_ptr(),
+            ptr::null(),
+            Cryptography::PROV_RSA_FULL,
+            Cryptography::CRYPT_NEWKEYSET | Cryptography::CRYPT_MACHINE_KEYSET,
+        );
+        if res == 0 {
+            // the container and its key exist already
+            return;
+        }
+        let mut key = 0;
+        let res =
+            Cryptography::CryptGenKey(provider, Cryptography::AT_SIGNATURE, 0x0400 << 16, &mut key);
+        assert!(res != 0, "{}", Error::last_os_error());
+        Cryptography::CryptDestroyKey(key);
+        Cryptography::CryptReleaseContext(provider, 0);
+    });
+
+    Some(generate_cert_with_key(
+        WEAK_KEY_CONTAINER,
+        "CN=localhost,O=schannel-rs weak key test",
+        extensions,
+    ))
+}
+
+#[test]
+fn min_key_bits() {
+    let weak = match weak_key_cert(&mut []) {
+        Some(cert) => cert,
+        None => return,
+    };
+    assert_eq!(weak.public_key_bits().unwrap(), 1024);
+    assert_eq!(weak.public_key_algorithm(), Cryptography::szOID_RSA_RSA);
+    let strong = localhost_cert().unwrap();
+    assert_eq!(strong.public_key_bits().unwrap(), 2048);
+
+    let weak_key = |err: HandshakeError<PipeStream>| match err {
+        HandshakeError::Failure(err) => {
+            assert_eq!(err.code(), Foundation::CERTSRV_E_WEAK_SIGNATURE_OR_KEY);
+            assert_eq!(err.kind(), error::ErrorKind::WeakKey);
+        }
+        err => panic!("expected a weak key failure: {}", unwrap_handshake(err)),
+    };
+    let connect = |builder: &mut tls_stream::Builder, cred: &mut schannel_cred::Builder| {
+        let (client_stream, server_stream) = mock_stream::pipe();
+        let client_cred = SchannelCred::builder().acquire_client().unwrap();
+        let client = builder
+            .domain("localhost")
+            .connect(client_cred, client_stream);
+        let server_cred = cred.acquire_server().unwrap();
+        let server = tls_stream::Builder::new().accept(server_cred, server_stream);
+        handshake_pair(client, server).0
+    };
+    let trusting = |cert: &CertContext| {
+        let mut store = Memory::new().unwrap().into_store();
+        store.add_cert(cert, CertAdd::Always).unwrap();
+        let mut builder = tls_stream::Builder::new();
+        builder.cert_store(store);
+        builder
+    };
+
+    // clients check the key of the server
+    connect(
+        tls_stream::Builder::new().min_rsa_key_bits(2048),
+        SchannelCred::builder().cert(strong.clone()),
+    )
+    .unwrap();
+    let mut cred = SchannelCred::builder();
+    cred.cert(weak.clone());
+    connect(&mut trusting(&weak), &mut cred).unwrap();
+    let client = connect(
+        trusting(&weak).min_rsa_key_bits(2048),
+        SchannelCred::builder().cert(weak.clone()),
+    );
+    weak_key(client.unwrap_err());
+    // the minimum for ECC keys doesn't apply to RSA keys
+    connect(
+        trusting(&weak).min_ecc_key_bits(384),
+        SchannelCred::builder().cert(weak.clone()),
+    )
+    .unwrap();
+
+    // the keys of the rest of the chain are only checked on request
+    let ca = weak_key_cert(&mut [ca_extension()]).unwrap();
+    let leaf = generate_cert("CN=localhost,O=schannel-rs weak CA test", &mut []);
+    let leaf = reissue_cert_with_key(&leaf, &ca, WEAK_KEY_CONTAINER);
+    let mut cred = SchannelCred::builder();
+    cred.cert(leaf);
+    connect(trusting(&ca).min_rsa_key_bits(2048), &mut cred).unwrap();
+    let client = connect(
+        trusting(&ca)
+            .min_rsa_key_bits(2048)
+            .min_key_bits_in_chain(true),
+        &mut cred,
+    );
+    weak_key(client.unwrap_err());
+
+    // and servers check the keys of clients
+    let server_cred = SchannelCred::builder()
+        .cert(strong)
+        .acquire_server()
+        .unwrap();
+    for &(bits, valid) in [(1024, true), (2048, false)].iter() {
+        let client_cred = SchannelCred::builder()
+            .cert(weak.clone())
+            .acquire_client()
+            .unwrap();
+        let (client_stream, server_stream) = mock_stream::pipe();
+        let client = tls_stream::Builder::new()
+            .domain("localhost")
+            .connect(client_cred, client_stream);
+        let server = tls_stream::Builder::new()
+            .client_cert_policy(ClientCertPolicy::Require)
+            .min_rsa_key_bits(bits)
+            .accept(server_cred.clone(), server_stream);
+        match handshake_pair(client, server).1 {
+            Ok(_) => assert!(valid),
+            Err(err) => {
+                assert!(!valid);
+                weak_key(err);
+            }
+        }
+    }
+}
+
+#[test]
+fn negotiated_cipher_suite() {
+    let cert = match localhost_cert() {
+        Some(cert) => cert,
+        None => return,
+    };
+
+    let (client, server) = offline_pair(
+        tls_stream::Builder::new().domain("localhost"),
+        SchannelCred::builder().enabled_protocols(&[Protocol::Tls12]),
+        cert,
+    );
+    let client = client.unwrap();
+    let server = server.unwrap();
+
+    let suite = client.cipher_suite().unwrap();
+    assert_eq!(suite, server.cipher_suite().unwrap());
+    assert_eq!(suite.protocols, [Protocol::Tls12]);
+    // the test certificate has an RSA key
+    assert_eq!(suite.certificate, "RSA");
+
+    let enabled = crate::enumerate_cipher_suites().unwrap();
+    let enabled = enabled.iter().find(|s| s.name == suite.name).unwrap();
+    assert_eq!(enabled.id, suite.id);
+    assert_eq!(enabled.cipher, suite.cipher);
+    assert_eq!(enabled.cipher_bits, suite.cipher_bits);
+    assert!(enabled.protocols.contains(&Protocol::Tls12));
+
+    let mut builder = SchannelCred::builder();
+    builder.enabled_protocols(&[Protocol::Tls12]);
+    assert_eq!(builder.unsatisfiable(std::slice::from_ref(enabled)), []);
+}
+
+// Throughput benchmarks of the bulk paths of `TlsStream`, over a pipe so that
+// only the copies and the encryption are measured. They are ignored by
+// default, run them with
+// `cargo test --release bench_ -- --ignored --nocapture --test-threads 1`.
+
+const BENCH_LEN: usize = 64 * 1024 * 1024;
+const BENCH_CHUNK: usize = 1024 * 1024;
+const BENCH_RECORD: usize = 16 * 1024;
+
+fn bench_pair() -> Option<(TlsStream<PipeStream>, TlsStream<PipeStream>)> {
+    let cert = localhost_cert()?;
+    let (client, server) = offline_pair(
+        tls_stream::Builder::new().domain("localhost"),
+        &mut SchannelCred::builder(),
+        cert,
+    );
+    Some((client.unwrap(), server.unwrap()))
+}
+
+fn report(name: &str, elapsed: Duration) {
+    let rate = BENCH_LEN as f64 / elapsed.as_secs_f64() / 1_000_000.0;
+    println!("{}: {:.0} MB/s", name, rate);
+}
+
+/// Sends `BENCH_LEN` bytes in chunks with `send`, which writes
+/// `BENCH_CHUNK` bytes of the given value, and reports the time spent in it.
+fn bench_send<F>(name: &str, mut send: F)
+where
+    F: FnMut(&mut TlsStream<PipeStream>, u8),
+{
+    let (mut client, mut server) = match bench_pair() {
+        Some(pair) => pair,
+        None => return,
+    };
+    let mut buf = vec![0; BENCH_CHUNK];
+    let mut elapsed = Duration::default();
+    for i in 0..BENCH_LEN / BENCH_CHUNK {
+        let start = Instant::now();
+        send(&mut client, i as u8);
+        client.flush().unwrap();
+        elapsed += start.elapsed();
+        server.read_exact(&mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == i as u8));
+    }
+    report(name, elapsed);
+}
+
+/// Receives `BENCH_LEN` bytes in chunks with `recv`, which returns the next
+/// `BENCH_CHUNK` bytes, and reports the time spent in it.
+fn bench_recv<F>(name: &str, mut recv: F)
+where
+    F: FnMut(&mut TlsStream<PipeStream>) -> Vec<u8>,
+{
+    let (mut client, mut server) = match bench_pair() {
+        Some(pair) => pair,
+        None => return,
+    };
+    let mut elapsed = Duration::default();
+    for i in 0..BENCH_LEN / BENCH_CHUNK {
+        client.write_all(&vec![i as u8; BENCH_CHUNK]).unwrap();
+        client.flush().unwrap();
+        let start = Instant::now();
+        let buf = recv(&mut server);
+        elapsed += start.elapsed();
+        assert_eq!(buf.len(), BENCH_CHUNK);
+        assert!(buf.iter().all(|&b| b == i as u8));
+    }
+    report(name, elapsed);
+}
+
+#[test]
+#[ignore] // benchmark
+fn bench_write() {
+    let mut data = vec![0; BENCH_CHUNK];
+    bench_send("write", |stream, value| {
+        data.fill(value);
+        stream.write_all(&data).unwrap();
+    });
+}
+
+#[test]
+#[ignore] // benchmark
+fn bench_write_prepared() {
+    bench_send("write_prepared", |stream, value| {
+        for _ in 0..BENCH_CHUNK / BENCH_RECORD {
+            let (mut buf, payload) = stream.prepare_buffer(BENCH_RECORD).unwrap();
+            buf[payload.clone()].fill(value);
+            stream.write_prepared(&mut buf, payload).unwrap();
+        }
+    });
+}
+
+#[test]
+#[ignore] // benchmark
+fn bench_copy_from() {
+    bench_send("copy_from", |stream, value| {
+        let mut reader = io::repeat(value).take(BENCH_CHUNK as u64);
+        stream.copy_from(&mut reader).unwrap();
+    });
+    // the read-then-write loop `copy_from` replaces
+    bench_send("io::copy", |stream, value| {
+        let mut reader = io::repeat(value).take(BENCH_CHUNK as u64);
+        io::copy(&mut reader, stream).unwrap();
+    });
+}
+
+#[test]
+#[ignore] // benchmark
+fn bench_read() {
+    bench_recv("read_exact", |stream| {
+        let mut buf = vec![0; BENCH_CHUNK];
+        stream.read_exact(&mut buf).unwrap();
+        buf
+    });
+}
+
+#[test]
+#[ignore] // benchmark
+fn bench_read_into_spare() {
+    bench_recv("read_into_spare", |stream| {
+        let mut buf = Vec::with_capacity(BENCH_CHUNK);
+        while buf.len() < BENCH_CHUNK {
+            stream.read_into_spare(&mut buf).unwrap();
+        }
+        buf
+    });
+}
+
+#[test]
+#[ignore] // benchmark
+fn bench_read_to_end() {
+    // only implements `read`, so that std's `read_to_end` is used
+    struct ReadOnly<'a>(&'a mut TlsStream<PipeStream>);
+
+    impl Read for ReadOnly<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    fn run(name: &str, read_to_end: fn(&mut TlsStream<PipeStream>, &mut Vec<u8>)) {
+        let (mut client, mut server) = match bench_pair() {
+            Some(pair) => pair,
+            None => return,
+        };
+        for i in 0..BENCH_LEN / BENCH_CHUNK {
+            client.write_all(&vec![i as u8; BENCH_CHUNK]).unwrap();
+        }
+        client.shutdown().unwrap();
+        let mut buf = vec![];
+        let start = Instant::now();
+        read_to_end(&mut server, &mut buf);
+        let elapsed = start.elapsed();
+        assert_eq!(buf.len(), BENCH_LEN);
+        assert_eq!(server.session_state(), SessionState::Shutdown);
+        report(name, elapsed);
+    }
+
+    run("read_to_end", |stream, buf| {
+        stream.read_to_end(buf).unwrap();
+    });
+    run("std read_to_end", |stream, buf| {
+        ReadOnly(stream).read_to_end(buf).unwrap();
+    });
 }
@@ -0,0 +1,479 @@
+//! Reusable TLS client and server configurations.
+//!
+//! A `TlsConnector` or `TlsAcceptor` acquires its credentials once and shares
+//! them between all of the sessions it creates, so sessions can be resumed
+//! and the cost of acquiring credentials is only paid once. Both are cheap to
+//! clone and can be used from any number of threads.
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use windows_sys::Win32::Foundation;
+
+use crate::cert_context::CertContext;
+use crate::cert_store::CertStore;
+use crate::error::{self, ErrorKind};
+use crate::identity::Identity;
+use crate::schannel_cred::{self, Direction, Protocol, SchannelCred, ServerCred};
+use crate::tls_stream::{self, CertValidationResult, ClientCertPolicy, HandshakeError, TlsStream};
+
+/// A builder type for `TlsConnector`s.
+#[derive(Debug, Clone, Default)]
+pub struct TlsConnectorBuilder {
+    cred: schannel_cred::Builder,
+    stream: tls_stream::Builder,
+    fallback_protocols: Vec<Protocol>,
+}
+
+impl TlsConnectorBuilder {
+    /// Sets the protocols enabled for connections.
+    pub fn enabled_protocols(&mut self, protocols: &[Protocol]) -> &mut TlsConnectorBuilder {
+        self.cred.enabled_protocols(protocols);
+        self
+    }
+
+    /// Sets the protocols `TlsConnector::connect_with_fallback` falls back to
+    /// when servers fail to handle the protocols offered first.
+    ///
+    /// Each protocol is tried on its own, in the given order, so the list
+    /// should go from newer to older protocols. Credentials for each of them
+    /// are acquired by `build`. Defaults to no fallback.
+    pub fn fallback_protocols(&mut self, protocols: &[Protocol]) -> &mut TlsConnectorBuilder {
+        self.fallback_protocols = protocols.to_vec();
+        self
+    }
+
+    /// Requests one of a set of application protocols using ALPN.
+    pub fn request_application_protocols(&mut self, alpns: &[&[u8]]) -> &mut TlsConnectorBuilder {
+        self.stream.request_application_protocols(alpns);
+        self
+    }
+
+    /// Determines if the server's hostname will be checked during
+    /// certificate verification.
+    ///
+    /// Defaults to `false`.
+    pub fn accept_invalid_hostnames(&mut self, accept: bool) -> &mut TlsConnectorBuilder {
+        self.stream.accept_invalid_hostnames(accept);
+        self
+    }
+
+    /// Sets a store of certificates which are trusted when validating the
+    /// server's certificate.
+    pub fn cert_store(&mut self, cert_store: CertStore) -> &mut TlsConnectorBuilder {
+        self.stream.cert_store(cert_store);
+        self
+    }
+
+    /// Sets a callback to validate the server's certificate.
+    ///
+    /// See `tls_stream::Builder::verify_callback`.
+    pub fn verify_callback<F>(&mut self, callback: F) -> &mut TlsConnectorBuilder
+    where
+        F: Fn(CertValidationResult) -> io::Result<()> + 'static + Sync + Send,
+    {
+        self.stream.verify_callback(callback);
+        self
+    }
+
+    /// Sets the identity used to authenticate to servers requesting a client
+    /// certificate.
+    pub fn identity(&mut self, identity: Identity) -> &mut TlsConnectorBuilder {
+        self.cred.cert(identity.into_cert());
+        self
+    }
+
+    /// Returns the builder of the credentials, for settings without a method
+    /// of their own.
+    pub fn credentials(&mut self) -> &mut schannel_cred::Builder {
+        &mut self.cred
+    }
+
+    /// Returns the builder of the streams, for settings without a method of
+    /// their own.
+    ///
+    /// The domain is set by `TlsConnector::connect` for every connection.
+    pub fn streams(&mut self) -> &mut tls_stream::Builder {
+        &mut self.stream
+    }
+
+    /// Acquires the credentials and creates a `TlsConnector`.
+    pub fn build(&self) -> io::Result<TlsConnector> {
+        let mut fallback = Vec::with_capacity(self.fallback_protocols.len());
+        for &protocol in &self.fallback_protocols {
+            let cred = self
+                .cred
+                .clone()
+                .enabled_protocols(&[protocol])
+                .acquire(Direction::Outbound)?;
+            fallback.push((protocol, cred));
+        }
+        Ok(TlsConnector(Arc::new(Shared {
+            cred: RwLock::new(CurrentCred::new(self.cred.acquire(Direction::Outbound)?)),
+            stream: self.stream.clone(),
+            certs: Vec::new(),
+            provider: None,
+            fallback,
+        })))
+    }
+}
+
+/// A reusable configuration for the client side of TLS sessions.
+#[derive(Debug, Clone)]
+pub struct TlsConnector(Arc<Shared>);
+
+#[derive(Debug)]
+struct Shared {
+    cred: RwLock<CurrentCred>,
+    stream: tls_stream::Builder,
+    certs: Vec<CertContext>,
+    provider: Option<CredProvider>,
+    fallback: Vec<(Protocol, SchannelCred)>,
+}
+
+impl Shared {
+    fn cred(&self) -> SchannelCred {
+        self.cred.read().unwrap().cred.clone()
+    }
+}
+
+#[derive(Debug)]
+struct CurrentCred {
+    cred: SchannelCred,
+    acquired: Instant,
+}
+
+impl CurrentCred {
+    fn new(cred: SchannelCred) -> CurrentCred {
+        CurrentCred {
+            cred,
+            acquired: Instant::now(),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct CredProvider {
+    provide: Arc<dyn Fn() -> error::Result<ServerCred> + Sync + Send>,
+    max_age: Option<Duration>,
+}
+
+impl fmt::Debug for CredProvider {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("CredProvider")
+            .field("max_age", &self.max_age)
+            .finish()
+    }
+}
+
+impl TlsConnector {
+    /// Returns a new `TlsConnector` with the default settings.
+    pub fn new() -> io::Result<TlsConnector> {
+        TlsConnector::builder().build()
+    }
+
+    /// Returns a builder for a `TlsConnector`.
+    pub fn builder() -> TlsConnectorBuilder {
+        TlsConnectorBuilder::default()
+    }
+
+    /// Initializes a TLS session with the server `domain` over `stream`.
+    ///
+    /// See `tls_stream::Builder::connect`.
+    pub fn connect<S>(&self, domain: &str, stream: S) -> Result<TlsStream<S>, HandshakeError<S>>
+    where
+        S: Read + Write,
+    {
+        let mut builder = self.0.stream.clone();
+        builder.domain(domain).connect(self.0.cred(), stream)
+    }
+
+    /// Initializes a TLS session with the server `domain`, retrying with the
+    /// protocols set by `TlsConnectorBuilder::fallback_protocols` if the
+    /// handshake fails in a way which suggests that the server or a
+    /// middlebox can't handle the protocols offered.
+    ///
+    /// `reconnect` is called to open a new stream for every attempt, as the
+    /// server usually closes the connection after such a failure. On success
+    /// the protocol which was fallen back to is returned along with the
+    /// stream, or `None` if the first attempt succeeded.
+    ///
+    /// Only malformed or unsupported handshake messages and connections
+    /// which are reset or closed by the peer lead to a retry; certificate
+    /// validation and all other failures are returned right away. Retries
+    /// only happen while this function runs, so a stream which would block
+    /// ends the fallback with `HandshakeError::Interrupted`.
+    ///
+    /// Falling back makes downgrade attacks possible, so this should only be
+    /// used for servers which are known to need it.
+    pub fn connect_with_fallback<S, F>(
+        &self,
+        domain: &str,
+        mut reconnect: F,
+    ) -> Result<(TlsStream<S>, Option<Protocol>), HandshakeError<S>>
+    where
+        S: Read + Write,
+        F: FnMut() -> io::Result<S>,
+    {
+        let mut builder = self.0.stream.clone();
+        builder.domain(domain);
+        let attempts = Some((None, self.0.cred())).into_iter().chain(
+            self.0
+                .fallback
+                .iter()
+                .map(|&(protocol, ref cred)| (Some(protocol), cred.clone())),
+        );
+
+        let mut last_err = None;
+        for (protocol, cred) in attempts {
+            let stream = reconnect().map_err(HandshakeError::Io)?;
+            match builder.connect(cred, stream) {
+                Ok(stream) => return Ok((stream, protocol)),
+                Err(e) if can_fall_back(&e) => {
+                    debug!("handshake failed, falling back to an older protocol");
+                    last_err = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(last_err.unwrap())
+    }
+}
+
+/// Returns true if a handshake which failed with `err` may succeed with an
+/// older protocol.
+fn can_fall_back<S>(err: &HandshakeError<S>) -> bool {
+    match *err {
+        HandshakeError::Failure(ref e) => matches!(
+            e.code(),
+            Foundation::SEC_E_ILLEGAL_MESSAGE | Foundation::SEC_E_UNSUPPORTED_FUNCTION
+        ),
+        HandshakeError::Io(ref e) => matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::UnexpectedEof
+        ),
+        HandshakeError::Interrupted(_) => false,
+    }
+}
+
+/// A builder type for `TlsAcceptor`s.
+#[derive(Debug, Clone)]
+pub struct TlsAcceptorBuilder {
+    cred: schannel_cred::Builder,
+    stream: tls_stream::Builder,
+    provider: Option<CredProvider>,
+}
+
+impl TlsAcceptorBuilder {
+    /// Sets the protocols enabled for connections.
+    pub fn enabled_protocols(&mut self, protocols: &[Protocol]) -> &mut TlsAcceptorBuilder {
+        self.cred.enabled_protocols(protocols);
+        self
+    }
+
+    /// Sets whether clients are asked for a certificate.
+    ///
+    /// Defaults to `ClientCertPolicy::Ignore`.
+    pub fn client_cert_policy(&mut self, policy: ClientCertPolicy) -> &mut TlsAcceptorBuilder {
+        self.stream.client_cert_policy(policy);
+        self
+    }
+
+    /// Sets a DER-encoded OCSP response to staple for clients requesting the
+    /// status of the certificate.
+    ///
+    /// See `TlsAcceptor::set_ocsp_response` to replace it once it expires.
+    pub fn ocsp_response(&mut self, der: &[u8]) -> &mut TlsAcceptorBuilder {
+        self.cred.ocsp_response(der);
+        self
+    }
+
+    /// Sets a function which acquires the credentials of the acceptor, in
+    /// place of the identity and the builder returned by `credentials`.
+    ///
+    /// The function is called by `build`, and again to replace the
+    /// credentials for subsequent sessions once they are older than
+    /// `max_age`, or when a handshake fails because the certificate expired.
+    /// This allows long running servers to pick up renewed certificates.
+    /// Sessions which have already been established keep their credentials.
+    /// If the function fails, the previous credentials remain in use and it
+    /// is called again by the next `accept`.
+    pub fn credential_provider<F>(
+        &mut self,
+        max_age: Option<Duration>,
+        provider: F,
+    ) -> &mut TlsAcceptorBuilder
+    where
+        F: Fn() -> error::Result<ServerCred> + 'static + Sync + Send,
+    {
+        self.provider = Some(CredProvider {
+            provide: Arc::new(provider),
+            max_age,
+        });
+        self
+    }
+
+    /// Returns the builder of the credentials, for settings without a method
+    /// of their own.
+    pub fn credentials(&mut self) -> &mut schannel_cred::Builder {
+        &mut self.cred
+    }
+
+    /// Returns the builder of the streams, for settings without a method of
+    /// their own.
+    pub fn streams(&mut self) -> &mut tls_stream::Builder {
+        &mut self.stream
+    }
+
+    /// Acquires the credentials and creates a `TlsAcceptor`.
+    pub fn build(&self) -> io::Result<TlsAcceptor> {
+        let cred = match self.provider {
+            Some(ref provider) => (provider.provide)()?.into_inner(),
+            None => self.cred.acquire(Direction::Inbound)?,
+        };
+        Ok(TlsAcceptor(Arc::new(Shared {
+            cred: RwLock::new(CurrentCred::new(cred)),
+            stream: self.stream.clone(),
+            certs: self.cred.certs().to_vec(),
+            provider: self.provider.clone(),
+            fallback: Vec::new(),
+        })))
+    }
+}
+
+/// A reusable configuration for the server side of TLS sessions.
+#[derive(Debug, Clone)]
+pub struct TlsAcceptor(Arc<Shared>);
+
+impl TlsAcceptor {
+    /// Returns a new `TlsAcceptor` identifying itself with `identity`.
+    pub fn new(identity: Identity) -> io::Result<TlsAcceptor> {
+        TlsAcceptor::builder(identity).build()
+    }
+
+    /// Returns a builder for a `TlsAcceptor` identifying itself with
+    /// `identity`.
+    pub fn builder(identity: Identity) -> TlsAcceptorBuilder {
+        let mut cred = SchannelCred::builder();
+        cred.cert(identity.into_cert());
+        TlsAcceptorBuilder {
+            cred,
+            stream: tls_stream::Builder::new(),
+            provider: None,
+        }
+    }
+
+    /// Replaces the OCSP response stapled for clients requesting the status
+    /// of the certificate.
+    ///
+    /// Sessions established from then on staple the new response, including
+    /// the ones of clones of this acceptor.
+    pub fn set_ocsp_response(&self, der: &[u8]) -> io::Result<()> {
+        for cert in &self.0.certs {
+            cert.set_ocsp_response(der)?;
+        }
+        Ok(())
+    }
+
+    /// Initializes a TLS session with a client over `stream`.
+    ///
+    /// See `tls_stream::Builder::accept`.
+    pub fn accept<S>(&self, stream: S) -> Result<TlsStream<S>, HandshakeError<S>>
+    where
+        S: Read + Write,
+    {
+        if let Some(max_age) = self.0.provider.as_ref().and_then(|p| p.max_age) {
+            if self.0.cred.read().unwrap().acquired.elapsed() >= max_age {
+                self.refresh(Some(max_age));
+            }
+        }
+        let result = self.0.stream.clone().accept(self.0.cred(), stream);
+        if let Err(HandshakeError::Failure(ref e)) = result {
+            if e.kind() == ErrorKind::CertExpired {
+                self.refresh(None);
+            }
+        }
+        result
+    }
+
+    /// Replaces the credentials used for subsequent sessions with new ones
+    /// from the function set by `TlsAcceptorBuilder::credential_provider`.
+    ///
+    /// Does nothing if no function has been set.
+    pub fn refresh_credentials(&self) -> io::Result<()> {
+        match self.0.provider {
+            Some(ref provider) => {
+                self.replace_credentials((provider.provide)()?);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Replaces the credentials used for subsequent sessions, including the
+    /// ones of clones of this acceptor.
+    ///
+    /// Handshakes which are in progress and sessions which have been
+    /// established keep the credentials they started with. Responses passed
+    /// to `set_ocsp_response` are still stapled to the certificates the
+    /// acceptor was built with, and a function set by
+    /// `TlsAcceptorBuilder::credential_provider` replaces these credentials
+    /// again once they are older than its maximum age.
+    pub fn replace_credentials(&self, cred: ServerCred) {
+        *self.0.cred.write().unwrap() = CurrentCred::new(cred.into_inner());
+    }
+
+    /// Refreshes the credentials, unless another thread has already done so
+    /// since they became older than `max_age`.
+    fn refresh(&self, max_age: Option<Duration>) {
+        let provider = match self.0.provider {
+            Some(ref provider) => provider,
+            None => return,
+        };
+        let mut current = self.0.cred.write().unwrap();
+        if let Some(max_age) = max_age {
+            if current.acquired.elapsed() < max_age {
+                return;
+            }
+        }
+        match (provider.provide)() {
+            Ok(cred) => *current = CurrentCred::new(cred.into_inner()),
+            Err(_e) => {
+                debug!("failed to refresh the credentials: {}", _e);
+            }
+        }
+    }
+}
+
+/// ensures that connectors and acceptors are always Sync/Send
+fn _is_sync() {
+    fn sync<T: Sync + Send>() {}
+    sync::<TlsConnector>();
+    sync::<TlsAcceptor>();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::Error;
+
+    #[test]
+    fn fallback_errors() {
+        let failure = |code| can_fall_back(&HandshakeError::<()>::Failure(Error::from_code(code)));
+        assert!(failure(Foundation::SEC_E_ILLEGAL_MESSAGE));
+        assert!(failure(Foundation::SEC_E_UNSUPPORTED_FUNCTION));
+        assert!(!failure(Foundation::SEC_E_DOWNGRADE_DETECTED));
+        assert!(!failure(Foundation::SEC_E_UNTRUSTED_ROOT));
+        assert!(!failure(Foundation::SEC_E_WRONG_PRINCIPAL));
+        assert!(!failure(Foundation::CERT_E_EXPIRED));
+
+        let io = |kind| can_fall_back(&HandshakeError::<()>::Io(io::Error::new(kind, "io")));
+        assert!(io(io::ErrorKind::ConnectionReset));
+        assert!(io(io::ErrorKind::UnexpectedEof));
+        assert!(!io(io::ErrorKind::PermissionDenied));
+        assert!(!io(io::ErrorKind::TimedOut));
+    }
+}
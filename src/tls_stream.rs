@@ -5,50 +5,172 @@ use std::error::Error;
 use std::fmt;
 use std::io::{self, BufRead, Cursor, Read, Write};
 use std::mem;
+use std::net::{IpAddr, TcpStream};
+use std::ops::Range;
+use std::os::windows::io::{AsRawHandle, AsRawSocket, OwnedHandle, RawHandle, RawSocket};
 use std::ptr;
 use std::slice;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use windows_sys::Win32::Foundation;
 use windows_sys::Win32::Security::Authentication::Identity;
-use windows_sys::Win32::Security::Cryptography;
+use windows_sys::Win32::Security::{Credentials, Cryptography};
 
 use crate::alpn_list::AlpnList;
+use crate::capabilities;
 use crate::cert_chain::{CertChain, CertChainContext};
-use crate::cert_context::CertContext;
+use crate::cert_context::{CertContext, ValidUses};
 use crate::cert_store::{CertAdd, CertStore};
+use crate::cipher_suite::CipherSuiteInfo;
+use crate::client_hello;
 use crate::context_buffer::ContextBuffer;
-use crate::schannel_cred::SchannelCred;
+use crate::error;
+use crate::idna;
+use crate::input_buffer::InputBuffer;
+use crate::key_log::{self, KeyLogCallback, KeyLogger};
+use crate::schannel_cred::{ClientCred, SchannelCred, ServerCred};
 use crate::security_context::SecurityContext;
-use crate::{secbuf, secbuf_desc, Inner, ACCEPT_REQUESTS, INIT_REQUESTS};
+use crate::{
+    secbuf, secbuf_desc, zeroize, Inner, ACCEPT_REQUESTS, INIT_REQUESTS, MAX_SECBUFFER_LEN,
+};
 
 static szOID_PKIX_KP_SERVER_AUTH: &[u8] = null_terminate!(Cryptography::szOID_PKIX_KP_SERVER_AUTH);
 static szOID_SERVER_GATED_CRYPTO: &[u8] = null_terminate!(Cryptography::szOID_SERVER_GATED_CRYPTO);
 static szOID_SGC_NETSCAPE: &[u8] = null_terminate!(Cryptography::szOID_SGC_NETSCAPE);
 
+/// The amount of data to read at once while the record sizes are unknown.
+const HANDSHAKE_READ_SIZE: usize = 16 * 1024;
+
+/// The default limit on the size of a single handshake message.
+const DEFAULT_MAX_HANDSHAKE_MESSAGE_SIZE: usize = 256 * 1024;
+
+/// The default limit on the size of a single record, a bit above the
+/// 16 KiB + 2 KiB + header allowed by the TLS specification.
+const DEFAULT_MAX_RECORD_SIZE: usize = 20 * 1024;
+
+/// How a server handles certificates presented by clients.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ClientCertPolicy {
+    /// No certificate is requested from the client.
+    Ignore,
+    /// A certificate is requested from the client, but the handshake also
+    /// succeeds if the client doesn't present one. `peer_certificate`
+    /// returns `None` in that case.
+    Request,
+    /// A certificate is requested from the client and the handshake fails
+    /// with an error of kind `PermissionDenied` if the client doesn't present
+    /// one, before any application data is exchanged.
+    Require,
+}
+
 /// A builder type for `TlsStream`s.
+///
+/// Builders can be cloned to derive settings for a connection from a common
+/// base configuration.
+#[derive(Clone)]
 pub struct Builder {
     domain: Option<Vec<u16>>,
+    invalid_domain: bool,
+    ip: Option<IpAddr>,
     use_sni: bool,
+    cache_target_name: Option<String>,
     accept_invalid_hostnames: bool,
+    required_ekus: Vec<String>,
+    min_rsa_key_bits: u32,
+    min_ecc_key_bits: u32,
+    min_key_bits_in_chain: bool,
     verify_callback: Option<Arc<dyn Fn(CertValidationResult) -> io::Result<()> + Sync + Send>>,
     cert_store: Option<CertStore>,
     requested_application_protocols: Option<Vec<Vec<u8>>>,
+    buffer_baseline: Option<usize>,
+    initial_read_buffer_size: usize,
+    initial_write_buffer_size: usize,
+    max_handshake_message_size: usize,
+    max_record_size: usize,
+    zeroize_buffers: bool,
+    record_observer: Option<RecordObserver>,
+    key_log: Option<KeyLogCallback>,
+    client_cert_policy: ClientCertPolicy,
+    client_cert_selector: Option<ClientCertSelector>,
+    check_capabilities: bool,
 }
 
+type ClientCertSelector = Arc<dyn Fn(&[Vec<u8>]) -> Option<ClientCred> + Sync + Send>;
+
+type RecordObserver = Arc<dyn Fn(&Record) + Sync + Send>;
+
 impl Default for Builder {
     fn default() -> Builder {
         Builder {
             domain: None,
+            invalid_domain: false,
+            ip: None,
             use_sni: true,
+            cache_target_name: None,
             accept_invalid_hostnames: false,
+            required_ekus: Vec::new(),
+            min_rsa_key_bits: 0,
+            min_ecc_key_bits: 0,
+            min_key_bits_in_chain: false,
             verify_callback: None,
             cert_store: None,
             requested_application_protocols: None,
+            buffer_baseline: None,
+            initial_read_buffer_size: 0,
+            initial_write_buffer_size: 0,
+            max_handshake_message_size: DEFAULT_MAX_HANDSHAKE_MESSAGE_SIZE,
+            max_record_size: DEFAULT_MAX_RECORD_SIZE,
+            zeroize_buffers: false,
+            record_observer: None,
+            key_log: None,
+            client_cert_policy: ClientCertPolicy::Ignore,
+            client_cert_selector: None,
+            check_capabilities: false,
         }
     }
 }
 
+impl fmt::Debug for Builder {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Builder")
+            .field("domain", &domain_name(&self.domain))
+            .field("ip", &self.ip)
+            .field("use_sni", &self.use_sni)
+            .field("cache_target_name", &self.cache_target_name)
+            .field("accept_invalid_hostnames", &self.accept_invalid_hostnames)
+            .field("required_ekus", &self.required_ekus)
+            .field("min_rsa_key_bits", &self.min_rsa_key_bits)
+            .field("min_ecc_key_bits", &self.min_ecc_key_bits)
+            .field("min_key_bits_in_chain", &self.min_key_bits_in_chain)
+            .field("verify_callback", &self.verify_callback.is_some())
+            .field("cert_store", &self.cert_store.is_some())
+            .field(
+                "requested_application_protocols",
+                &self.requested_application_protocols.as_ref().map(|p| p.len()),
+            )
+            .field("buffer_baseline", &self.buffer_baseline)
+            .field("initial_read_buffer_size", &self.initial_read_buffer_size)
+            .field("initial_write_buffer_size", &self.initial_write_buffer_size)
+            .field("max_handshake_message_size", &self.max_handshake_message_size)
+            .field("max_record_size", &self.max_record_size)
+            .field("zeroize_buffers", &self.zeroize_buffers)
+            .field("record_observer", &self.record_observer.is_some())
+            .field("key_log", &self.key_log.is_some())
+            .field("client_cert_policy", &self.client_cert_policy)
+            .field("client_cert_selector", &self.client_cert_selector.is_some())
+            .field("check_capabilities", &self.check_capabilities)
+            .finish()
+    }
+}
+
+/// Decodes a NUL terminated domain for display.
+fn domain_name(domain: &Option<Vec<u16>>) -> Option<String> {
+    domain
+        .as_ref()
+        .map(|d| String::from_utf16_lossy(&d[..d.len() - 1]))
+}
+
 impl Builder {
     /// Returns a new `Builder`.
     pub fn new() -> Builder {
@@ -58,12 +180,69 @@ impl Builder {
     /// Sets the domain associated with connections created with this `Builder`.
     ///
     /// The domain will be used for Server Name Indication as well as
-    /// certificate validation.
+    /// certificate validation. Internationalized domain names are converted
+    /// to their ASCII form, with labels encoded as Punycode.
+    ///
+    /// A domain containing a NUL character would be truncated by Schannel, so
+    /// connecting with it fails with an `InvalidInput` error.
+    ///
+    /// IPv4 and IPv6 addresses, the latter optionally in brackets, are
+    /// matched against the IP addresses in the certificate's subject
+    /// alternative names rather than its DNS names.
     pub fn domain(&mut self, domain: &str) -> &mut Builder {
+        self.invalid_domain = domain.contains('\0');
+        let literal = domain
+            .strip_prefix('[')
+            .and_then(|d| d.strip_suffix(']'))
+            .unwrap_or(domain);
+        self.ip = literal.parse().ok();
+        let domain = if self.invalid_domain {
+            String::new()
+        } else {
+            idna::to_ascii(domain)
+        };
         self.domain = Some(domain.encode_utf16().chain(Some(0)).collect());
         self
     }
 
+    /// Sets the IP address of the server for connections created with this
+    /// `Builder`.
+    ///
+    /// This is equivalent to passing the address to `domain`.
+    pub fn ip(&mut self, addr: IpAddr) -> &mut Builder {
+        self.domain(&addr.to_string())
+    }
+
+    /// Sets how servers handle certificates presented by clients.
+    ///
+    /// Clients which are asked for a certificate present the one of their
+    /// credentials, if any. Defaults to `ClientCertPolicy::Ignore`, and has
+    /// no effect on clients.
+    pub fn client_cert_policy(&mut self, policy: ClientCertPolicy) -> &mut Builder {
+        self.client_cert_policy = policy;
+        self
+    }
+
+    /// Sets a callback choosing the credentials of clients which are asked
+    /// for a certificate their credentials don't have.
+    ///
+    /// The callback is passed the DER encoded names of the certificate
+    /// authorities accepted by the server, which may be empty, and returns
+    /// credentials with the certificate to present. If it returns `None`
+    /// the handshake continues without a certificate, which is also what
+    /// happens without a callback. It is only called once per handshake, and
+    /// has no effect on servers.
+    ///
+    /// With the `cert-dialog` feature the certificate can be chosen by the
+    /// user, see `cert_dialog::CertificateDialog`.
+    pub fn client_cert_selector<F>(&mut self, selector: F) -> &mut Builder
+    where
+        F: Fn(&[Vec<u8>]) -> Option<ClientCred> + 'static + Sync + Send,
+    {
+        self.client_cert_selector = Some(Arc::new(selector));
+        self
+    }
+
     /// Determines if Server Name Indication (SNI) will be used.
     ///
     /// Defaults to `true`.
@@ -72,6 +251,38 @@ impl Builder {
         self
     }
 
+    /// Sets the target name passed to Schannel in place of the domain, which
+    /// its client session cache is keyed on.
+    ///
+    /// Clients only resume sessions cached under the same name, so
+    /// connections sharing a name share their sessions, while connections
+    /// under different names don't. This is also the name to pass to
+    /// `purge_session_cache`.
+    ///
+    /// This overrides the Server Name Indication host name as well: Schannel
+    /// sends the target name, which can't be set apart from the cache key, so
+    /// the name has to be one the server recognizes if it selects its
+    /// certificate by name. Certificates are still validated against the
+    /// domain.
+    ///
+    /// A name containing a NUL character fails the connection with an
+    /// `InvalidInput` error. Defaults to none, which keys the cache on the
+    /// domain if `use_sni` is enabled, and has no effect on servers.
+    pub fn cache_target_name(&mut self, name: &str) -> &mut Builder {
+        self.cache_target_name = Some(name.to_owned());
+        self
+    }
+
+    /// Returns the NUL terminated name passed to
+    /// `InitializeSecurityContextW`.
+    fn target_name(&self) -> Option<Vec<u16>> {
+        match self.cache_target_name {
+            Some(ref name) => Some(name.encode_utf16().chain(Some(0)).collect()),
+            None if self.use_sni => self.domain.clone(),
+            None => None,
+        }
+    }
+
     /// Determines if the server's hostname will be checked during certificate verification.
     ///
     /// Defaults to `false`.
@@ -80,6 +291,57 @@ impl Builder {
         self
     }
 
+    /// Requires the peer's certificate to be valid for the extended key
+    /// usage `oid`, such as `"1.3.6.1.5.5.7.3.1"` for server authentication.
+    ///
+    /// This can be called several times to require more than one usage. The
+    /// certificate must list each of them in its extended key usage
+    /// extension, and so must any certificate of its chain which restricts
+    /// its usages. Otherwise the handshake fails with a `CERT_E_WRONG_USAGE`
+    /// error, of kind `schannel::error::ErrorKind::CertWrongUsage`.
+    ///
+    /// Clients check the usages of servers in place of the default, which
+    /// accepts certificates for server authentication or server gated
+    /// cryptography. Servers only check the certificates presented by
+    /// clients, whether a certificate is needed at all is set by
+    /// `client_cert_policy`. An OID containing a NUL character fails the
+    /// connection with an `InvalidInput` error.
+    pub fn required_eku(&mut self, oid: &str) -> &mut Builder {
+        self.required_ekus.push(oid.to_string());
+        self
+    }
+
+    /// Sets the minimum length in bits of the RSA key of the peer's
+    /// certificate.
+    ///
+    /// A shorter key fails the handshake with a
+    /// `CERTSRV_E_WEAK_SIGNATURE_OR_KEY` error, of kind
+    /// `schannel::error::ErrorKind::WeakKey`. As with `required_eku`,
+    /// servers only check the certificates presented by clients. Defaults to
+    /// 0, which accepts keys of any length allowed by Windows.
+    pub fn min_rsa_key_bits(&mut self, bits: u32) -> &mut Builder {
+        self.min_rsa_key_bits = bits;
+        self
+    }
+
+    /// Sets the minimum length in bits of the ECC key of the peer's
+    /// certificate, which is the size of its curve.
+    ///
+    /// See `min_rsa_key_bits`.
+    pub fn min_ecc_key_bits(&mut self, bits: u32) -> &mut Builder {
+        self.min_ecc_key_bits = bits;
+        self
+    }
+
+    /// Sets whether the minimum key lengths apply to every certificate of
+    /// the peer's chain rather than only to the peer's certificate.
+    ///
+    /// Defaults to `false`.
+    pub fn min_key_bits_in_chain(&mut self, in_chain: bool) -> &mut Builder {
+        self.min_key_bits_in_chain = in_chain;
+        self
+    }
+
     /// Set a verification callback to be used for connections created with this `Builder`.
     ///
     /// The callback is provided with an io::Result indicating if the (pre)validation was
@@ -94,6 +356,65 @@ impl Builder {
         self
     }
 
+    /// Set a callback which is invoked for every TLS record sent or received
+    /// by connections created with this `Builder`.
+    ///
+    /// This is meant for debugging and testing. The callback is provided with
+    /// a description of the record along with its raw bytes, as they appear
+    /// on the wire.
+    pub fn record_observer<F>(&mut self, observer: F) -> &mut Builder
+    where
+        F: Fn(&Record) + 'static + Sync + Send,
+    {
+        self.record_observer = Some(Arc::new(observer));
+        self
+    }
+
+    /// Set a callback which is passed the secrets of connections created with
+    /// this `Builder`, formatted as lines of an NSS key log file.
+    ///
+    /// This is meant for debugging: tools such as Wireshark can decrypt
+    /// captured traffic with the logged secrets, so they must be handled with
+    /// the same care as private keys.
+    ///
+    /// Schannel only exports the traffic secrets of TLS 1.3 sessions, and only
+    /// on Windows builds supporting `SECBUFFER_TRAFFIC_SECRETS`. Handshakes
+    /// which complete without the secrets of both directions having been
+    /// logged fail with `io::ErrorKind::Unsupported`, rather than silently
+    /// leaving the log incomplete.
+    pub fn key_log<F>(&mut self, callback: F) -> &mut Builder
+    where
+        F: Fn(&str) + 'static + Sync + Send,
+    {
+        self.key_log = Some(Arc::new(callback));
+        self
+    }
+
+    /// Logs the secrets of connections to the file named by the
+    /// `SSLKEYLOGFILE` environment variable, if it is set.
+    ///
+    /// Lines are appended to the file, which is created if needed. The
+    /// variable has no effect unless this is called. See `key_log`.
+    pub fn key_log_from_env(&mut self) -> &mut Builder {
+        if let Some(callback) = key_log::file_from_env() {
+            self.key_log = Some(callback);
+        }
+        self
+    }
+
+    /// Determines if connections check that the running system supports the
+    /// requested features before starting the handshake.
+    ///
+    /// With the check, requesting application protocols without ALPN support
+    /// or setting a `key_log` without support for exporting traffic secrets
+    /// fails with `io::ErrorKind::Unsupported`, rather than with an error
+    /// after the handshake. See `schannel::capabilities`. Defaults to
+    /// `false`.
+    pub fn check_capabilities(&mut self, check: bool) -> &mut Builder {
+        self.check_capabilities = check;
+        self
+    }
+
     /// Specifies a custom certificate store which is later used when validating
     /// a server's certificate.
     ///
@@ -115,6 +436,85 @@ impl Builder {
         self
     }
 
+    /// Sets the capacity, in bytes, to which internal buffers are shrunk once
+    /// they are empty.
+    ///
+    /// Buffers grow as needed to hold complete TLS records and by default
+    /// keep their peak capacity for the lifetime of the stream. If a baseline
+    /// is set, buffers which have grown beyond it are released again as soon
+    /// as they don't contain any data, which reduces the memory held by idle
+    /// streams at the cost of reallocating on the next large record.
+    ///
+    /// Defaults to `None`.
+    pub fn shrink_buffers(&mut self, baseline: Option<usize>) -> &mut Builder {
+        self.buffer_baseline = baseline;
+        self
+    }
+
+    /// Sets the capacity, in bytes, allocated up front for the buffer of data
+    /// received from the peer.
+    ///
+    /// The buffer still grows as needed, but a large enough initial capacity
+    /// avoids reallocating it while the first records arrive. Buffers are
+    /// never shrunk below their initial capacity by `shrink_buffers`.
+    ///
+    /// Defaults to 0, leaving the buffer unallocated until data is read.
+    pub fn initial_read_buffer_size(&mut self, size: usize) -> &mut Builder {
+        self.initial_read_buffer_size = size;
+        self
+    }
+
+    /// Sets the capacity, in bytes, allocated up front for the buffer of
+    /// encrypted data waiting to be written.
+    ///
+    /// See `initial_read_buffer_size` for details.
+    ///
+    /// Defaults to 0, allocating only what the first handshake message needs.
+    pub fn initial_write_buffer_size(&mut self, size: usize) -> &mut Builder {
+        self.initial_write_buffer_size = size;
+        self
+    }
+
+    /// Sets the maximum size, in bytes, of a single message which will be
+    /// buffered while the handshake is in progress.
+    ///
+    /// The handshake fails with an `InvalidData` error once the peer has sent
+    /// more than this without completing a message, so that a misbehaving
+    /// peer cannot make the stream buffer an unbounded amount of data.
+    ///
+    /// Defaults to 256 KiB.
+    pub fn max_handshake_message_size(&mut self, size: usize) -> &mut Builder {
+        self.max_handshake_message_size = size;
+        self
+    }
+
+    /// Sets the maximum size, in bytes, of a single record which will be
+    /// buffered once the handshake has completed.
+    ///
+    /// Reads fail with an `InvalidData` error once the peer has sent more
+    /// than this without completing a record. Complete records waiting to be
+    /// decrypted don't count towards the limit.
+    ///
+    /// Defaults to 20 KiB, a bit above the largest record allowed by the TLS
+    /// specification.
+    pub fn max_record_size(&mut self, size: usize) -> &mut Builder {
+        self.max_record_size = size;
+        self
+    }
+
+    /// Determines if decrypted data is wiped from memory once it has been
+    /// read.
+    ///
+    /// When enabled, plaintext is overwritten with zeros as it is consumed
+    /// and the buffer it was decrypted into is wiped before its memory is
+    /// released, so that sensitive data doesn't linger in freed memory.
+    ///
+    /// Defaults to `false`.
+    pub fn zeroize_buffers(&mut self, zeroize_buffers: bool) -> &mut Builder {
+        self.zeroize_buffers = zeroize_buffers;
+        self
+    }
+
     /// Initialize a new TLS session where the stream provided will be
     /// connecting to a remote TLS server.
     ///
@@ -123,146 +523,673 @@ impl Builder {
     /// then a `HandshakeError::Interrupted` variant may be returned. This
     /// type can then be extracted to later call
     /// `MidHandshakeTlsStream::handshake` when data becomes available.
-    pub fn connect<S>(
-        &mut self,
-        cred: SchannelCred,
-        stream: S,
-    ) -> Result<TlsStream<S>, HandshakeError<S>>
+    ///
+    /// `cred` must be a `ClientCred`, or an untyped `SchannelCred` acquired
+    /// for outbound connections.
+    pub fn connect<C, S>(&mut self, cred: C, stream: S) -> Result<TlsStream<S>, HandshakeError<S>>
+    where
+        C: Into<ClientCred>,
+        S: Read + Write,
+    {
+        self.initialize(cred.into().into_inner(), false, stream)
+    }
+
+    /// Connects to a TLS server with credentials acquired with default
+    /// settings, completing the handshake before returning.
+    ///
+    /// This is a shortcut for acquiring a `ClientCred` without a client
+    /// certificate and passing it to `connect`. The options of this builder
+    /// apply as usual, so at least `domain` should have been set.
+    ///
+    /// The stream has to be blocking, an interrupted handshake is reported
+    /// as an error of kind `WouldBlock`.
+    ///
+    /// ```no_run
+    /// use std::io::{Read, Write};
+    /// use std::net::TcpStream;
+    ///
+    /// use schannel::tls_stream;
+    ///
+    /// let stream = TcpStream::connect("example.com:443").unwrap();
+    /// let mut stream = tls_stream::Builder::new()
+    ///     .domain("example.com")
+    ///     .connect_default(stream)
+    ///     .unwrap();
+    /// stream.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+    /// let mut response = vec![];
+    /// stream.read_to_end(&mut response).unwrap();
+    /// ```
+    pub fn connect_default<S>(&mut self, stream: S) -> io::Result<TlsStream<S>>
     where
         S: Read + Write,
     {
-        self.initialize(cred, false, stream)
+        let cred = SchannelCred::builder().acquire_client()?;
+        match self.connect(cred, stream) {
+            Ok(stream) => Ok(stream),
+            Err(HandshakeError::Failure(e)) => Err(e.into()),
+            Err(HandshakeError::Io(e)) => Err(e),
+            Err(HandshakeError::Interrupted(_)) => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "the handshake would block",
+            )),
+        }
     }
 
     /// Initialize a new TLS session where the stream provided will be
     /// accepting a connection.
     ///
-    /// This method will tweak the protocol for "who talks first" and also
-    /// currently disables validation of the client that's connecting to us.
+    /// This method will tweak the protocol for "who talks first". Whether the
+    /// client is asked for a certificate is controlled by
+    /// `client_cert_policy`.
     ///
     /// If the stream provided is a blocking stream then the entire handshake
     /// will be performed if possible, but if the stream is in nonblocking mode
     /// then a `HandshakeError::Interrupted` variant may be returned. This
     /// type can then be extracted to later call
     /// `MidHandshakeTlsStream::handshake` when data becomes available.
-    pub fn accept<S>(
-        &mut self,
-        cred: SchannelCred,
-        stream: S,
-    ) -> Result<TlsStream<S>, HandshakeError<S>>
+    ///
+    /// `cred` must be a `ServerCred`, or an untyped `SchannelCred` acquired
+    /// for inbound connections.
+    pub fn accept<C, S>(&mut self, cred: C, stream: S) -> Result<TlsStream<S>, HandshakeError<S>>
     where
+        C: Into<ServerCred>,
         S: Read + Write,
     {
-        self.initialize(cred, true, stream)
+        self.initialize(cred.into().into_inner(), true, stream)
+    }
+
+    /// Creates a `TlsEngine` for the client side of a TLS session.
+    ///
+    /// The engine performs the same handshake and record processing as a
+    /// `TlsStream` created by `connect`, but leaves transferring the data to
+    /// and from the peer to the caller.
+    pub fn connect_engine<C>(&mut self, cred: C) -> io::Result<TlsEngine>
+    where
+        C: Into<ClientCred>,
+    {
+        self.engine(cred.into().into_inner(), false)
+    }
+
+    /// Creates a `TlsEngine` for the server side of a TLS session.
+    ///
+    /// The engine performs the same handshake and record processing as a
+    /// `TlsStream` created by `accept`, but leaves transferring the data to
+    /// and from the peer to the caller.
+    pub fn accept_engine<C>(&mut self, cred: C) -> io::Result<TlsEngine>
+    where
+        C: Into<ServerCred>,
+    {
+        self.engine(cred.into().into_inner(), true)
     }
 
     fn initialize<S>(
         &mut self,
-        mut cred: SchannelCred,
+        cred: SchannelCred,
         server: bool,
         stream: S,
     ) -> Result<TlsStream<S>, HandshakeError<S>>
     where
         S: Read + Write,
     {
-        let domain = match self.domain {
-            Some(ref domain) if self.use_sni => Some(&domain[..]),
-            _ => None,
+        let engine = match self.engine(cred, server) {
+            Ok(engine) => engine,
+            Err(e) => return Err(HandshakeError::from_io(e)),
         };
-        let (ctxt, buf) = match SecurityContext::initialize(
+        MidHandshakeTlsStream {
+            inner: Box::new(TlsStream::from_engine(engine, stream)),
+        }
+        .handshake()
+    }
+
+    fn engine(&mut self, mut cred: SchannelCred, server: bool) -> io::Result<TlsEngine> {
+        if self.invalid_domain {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "domain contains a NUL character",
+            ));
+        }
+        if self.required_ekus.iter().any(|oid| oid.contains('\0')) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "required EKU contains a NUL character",
+            ));
+        }
+        if self
+            .cache_target_name
+            .iter()
+            .any(|name| name.contains('\0'))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cache target name contains a NUL character",
+            ));
+        }
+        if self.check_capabilities {
+            let capabilities = capabilities::capabilities();
+            let unsupported =
+                if self.requested_application_protocols.is_some() && !capabilities.alpn {
+                    Some("ALPN requested but not supported on this OS")
+                } else if self.key_log.is_some() && !capabilities.traffic_secrets {
+                    Some("key logging requested but not supported on this OS")
+                } else {
+                    None
+                };
+            if let Some(msg) = unsupported {
+                return Err(io::Error::new(io::ErrorKind::Unsupported, msg));
+            }
+        }
+        let target_name = self.target_name();
+        let (ctxt, buf, more_calls) = SecurityContext::initialize(
             &mut cred,
             server,
-            domain,
+            target_name.as_deref(),
             &self.requested_application_protocols,
-        ) {
-            Ok(pair) => pair,
-            Err(e) => return Err(HandshakeError::Failure(e)),
-        };
+        )?;
+        self.engine_with_context(cred, server, ctxt, buf.as_deref(), more_calls)
+    }
+
+    /// Creates an engine for a context after the first call to
+    /// `InitializeSecurityContextW`, which returned `token` to send.
+    ///
+    /// If the call completed the handshake, the engine goes straight to
+    /// streaming and only has the token left to send.
+    fn engine_with_context(
+        &self,
+        cred: SchannelCred,
+        server: bool,
+        context: SecurityContext,
+        token: Option<&[u8]>,
+        more_calls: bool,
+    ) -> io::Result<TlsEngine> {
+        let mut out_buf = Vec::with_capacity(self.initial_write_buffer_size);
+        if let Some(token) = token {
+            out_buf.extend_from_slice(token);
+        }
+        let mut key_log = self.key_log.clone().map(KeyLogger::new);
+        if let Some(ref mut key_log) = key_log {
+            key_log.observe_hello(&out_buf);
+        }
 
-        let stream = TlsStream {
+        let mut engine = TlsEngine {
             cred,
-            context: ctxt,
+            context,
             cert_store: self.cert_store.clone(),
             domain: self.domain.clone(),
-            use_sni: self.use_sni,
+            ip: self.ip,
+            target_name: self.target_name(),
             accept_invalid_hostnames: self.accept_invalid_hostnames,
+            required_ekus: self
+                .required_ekus
+                .iter()
+                .map(|oid| oid.bytes().chain(Some(0)).collect())
+                .collect(),
+            min_rsa_key_bits: self.min_rsa_key_bits,
+            min_ecc_key_bits: self.min_ecc_key_bits,
+            min_key_bits_in_chain: self.min_key_bits_in_chain,
             verify_callback: self.verify_callback.clone(),
-            stream,
             server,
             accept_first: true,
+            client_cert_policy: self.client_cert_policy,
+            client_cert_selector: self.client_cert_selector.clone(),
+            incomplete_creds_retried: false,
+            close_notify_received: false,
             state: State::Initializing {
-                needs_flush: false,
-                more_calls: true,
+                more_calls,
                 shutting_down: false,
+                half_close: false,
                 validated: false,
             },
-            needs_read: 1,
-            dec_in: Cursor::new(Vec::new()),
-            enc_in: Cursor::new(Vec::new()),
-            out_buf: Cursor::new(buf.map(|b| b.to_owned()).unwrap_or_else(Vec::new)),
-            last_write_len: 0,
+            needs_read: more_calls as usize,
+            input: InputBuffer::with_capacity(self.initial_read_buffer_size, self.zeroize_buffers),
+            out_buf: Cursor::new(out_buf),
             requested_application_protocols: self.requested_application_protocols.clone(),
+            buffer_baseline: self.buffer_baseline,
+            initial_read_buffer_size: self.initial_read_buffer_size,
+            initial_write_buffer_size: self.initial_write_buffer_size,
+            max_handshake_message_size: self.max_handshake_message_size,
+            max_record_size: self.max_record_size,
+            created: Instant::now(),
+            stats: Stats::default(),
+            record_observer: self.record_observer.clone(),
+            key_log,
+            client_hello_sni: None,
         };
-
-        MidHandshakeTlsStream { inner: stream }.handshake()
+        if !more_calls {
+            debug!("the handshake completed in the first call");
+            engine.advance()?;
+        }
+        Ok(engine)
     }
 }
 
 enum State {
     Initializing {
-        needs_flush: bool,
         more_calls: bool,
         shutting_down: bool,
+        // whether to continue in `WriteShutdown` rather than `Streaming` or
+        // `Shutdown` once done
+        half_close: bool,
         validated: bool,
     },
     Streaming {
         sizes: Identity::SecPkgContext_StreamSizes,
     },
+    WriteShutdown,
+    Shutdown,
+}
+
+/// The state of the TLS session of a `TlsStream`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SessionState {
+    /// A handshake or renegotiation is in progress.
+    Handshaking,
+    /// The handshake has completed and application data can be exchanged.
+    Streaming,
+    /// The session is being shut down but the close_notify alert has not been
+    /// sent yet.
+    ShuttingDown,
+    /// The close_notify alert has been sent by `shutdown_write`, but data
+    /// from the peer can still be read.
+    WriteShutdown,
+    /// The session has been shut down, either locally or by the peer.
     Shutdown,
 }
 
+/// The capacities of the internal buffers of a `TlsStream`, in bytes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct BufferCapacities {
+    /// The buffer of received data, which holds both the data which has not
+    /// been decrypted yet and the decrypted data which has not been read yet.
+    pub input: usize,
+    /// The buffer of encrypted data which has not been written yet.
+    pub output: usize,
+}
+
+/// The level of a TLS alert.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum AlertLevel {
+    /// The session may continue, the alert is for information only.
+    Warning,
+    /// The session is terminated by the alert.
+    Fatal,
+}
+
+/// The description of a TLS alert, see section 6 of RFC 8446.
+///
+/// close_notify is missing since it is sent by `shutdown`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum AlertDescription {
+    /// An inappropriate message was received.
+    UnexpectedMessage,
+    /// A record could not be deprotected.
+    BadRecordMac,
+    /// A record exceeded the maximum length.
+    RecordOverflow,
+    /// No acceptable set of security parameters could be negotiated.
+    HandshakeFailure,
+    /// A certificate was corrupt or its signature didn't verify.
+    BadCertificate,
+    /// A certificate was of an unsupported type.
+    UnsupportedCertificate,
+    /// A certificate was revoked by its signer.
+    CertificateRevoked,
+    /// A certificate has expired or is not yet valid.
+    CertificateExpired,
+    /// A certificate was rejected for another reason.
+    CertificateUnknown,
+    /// A field of the handshake was out of range or inconsistent.
+    IllegalParameter,
+    /// The issuer of a certificate was not found or not trusted.
+    UnknownCa,
+    /// The peer is not allowed to proceed, although its identity was
+    /// verified.
+    AccessDenied,
+    /// A message could not be decoded.
+    DecodeError,
+    /// A signature or the verification of a Finished message failed.
+    DecryptError,
+    /// The protocol version offered by the peer is not supported.
+    ProtocolVersion,
+    /// The parameters offered by the peer are not secure enough.
+    InsufficientSecurity,
+    /// An error unrelated to the peer or the protocol occurred.
+    InternalError,
+    /// The session is being canceled for a reason unrelated to the protocol.
+    UserCanceled,
+    /// A renegotiation request was refused.
+    NoRenegotiation,
+    /// An extension was received which wasn't requested.
+    UnsupportedExtension,
+    /// None of the application protocols offered by the client is supported.
+    NoApplicationProtocol,
+}
+
+impl AlertDescription {
+    fn code(self) -> u32 {
+        match self {
+            AlertDescription::UnexpectedMessage => Identity::TLS1_ALERT_UNEXPECTED_MESSAGE,
+            AlertDescription::BadRecordMac => Identity::TLS1_ALERT_BAD_RECORD_MAC,
+            AlertDescription::RecordOverflow => Identity::TLS1_ALERT_RECORD_OVERFLOW,
+            AlertDescription::HandshakeFailure => Identity::TLS1_ALERT_HANDSHAKE_FAILURE,
+            AlertDescription::BadCertificate => Identity::TLS1_ALERT_BAD_CERTIFICATE,
+            AlertDescription::UnsupportedCertificate => Identity::TLS1_ALERT_UNSUPPORTED_CERT,
+            AlertDescription::CertificateRevoked => Identity::TLS1_ALERT_CERTIFICATE_REVOKED,
+            AlertDescription::CertificateExpired => Identity::TLS1_ALERT_CERTIFICATE_EXPIRED,
+            AlertDescription::CertificateUnknown => Identity::TLS1_ALERT_CERTIFICATE_UNKNOWN,
+            AlertDescription::IllegalParameter => Identity::TLS1_ALERT_ILLEGAL_PARAMETER,
+            AlertDescription::UnknownCa => Identity::TLS1_ALERT_UNKNOWN_CA,
+            AlertDescription::AccessDenied => Identity::TLS1_ALERT_ACCESS_DENIED,
+            AlertDescription::DecodeError => Identity::TLS1_ALERT_DECODE_ERROR,
+            AlertDescription::DecryptError => Identity::TLS1_ALERT_DECRYPT_ERROR,
+            AlertDescription::ProtocolVersion => Identity::TLS1_ALERT_PROTOCOL_VERSION,
+            AlertDescription::InsufficientSecurity => Identity::TLS1_ALERT_INSUFFIENT_SECURITY,
+            AlertDescription::InternalError => Identity::TLS1_ALERT_INTERNAL_ERROR,
+            AlertDescription::UserCanceled => Identity::TLS1_ALERT_USER_CANCELED,
+            AlertDescription::NoRenegotiation => Identity::TLS1_ALERT_NO_RENEGOTIATION,
+            AlertDescription::UnsupportedExtension => Identity::TLS1_ALERT_UNSUPPORTED_EXT,
+            AlertDescription::NoApplicationProtocol => Identity::TLS1_ALERT_NO_APP_PROTOCOL,
+        }
+    }
+}
+
+/// The direction of a TLS record passed to a record observer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RecordDirection {
+    /// The record was sent to the peer.
+    Sent,
+    /// The record was received from the peer.
+    Received,
+}
+
+/// A TLS record passed to a record observer.
+#[derive(Debug)]
+pub struct Record<'a> {
+    direction: RecordDirection,
+    data: &'a [u8],
+    status: i32,
+}
+
+impl<'a> Record<'a> {
+    /// Returns whether the record was sent or received.
+    pub fn direction(&self) -> RecordDirection {
+        self.direction
+    }
+
+    /// Returns the content type from the header of the record.
+    pub fn content_type(&self) -> Option<u8> {
+        self.data.first().cloned()
+    }
+
+    /// Determines if the record holds handshake messages.
+    pub fn is_handshake(&self) -> bool {
+        self.content_type() == Some(22)
+    }
+
+    /// Determines if the record holds application data.
+    ///
+    /// With TLS 1.3 the later handshake messages are sent as encrypted
+    /// application data records as well.
+    pub fn is_application_data(&self) -> bool {
+        self.content_type() == Some(23)
+    }
+
+    /// Returns the raw bytes of the record, including its header.
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Returns the status Schannel reported for the record.
+    ///
+    /// For records sent this is the status of the call which produced them,
+    /// for records received that of the call which processed them.
+    pub fn status(&self) -> i32 {
+        self.status
+    }
+}
+
+/// Returns the length of the TLS record at the start of `data`, limited to the
+/// length of `data`.
+fn record_len(data: &[u8]) -> usize {
+    if data.len() < 5 {
+        return data.len();
+    }
+    let len = 5 + u16::from_be_bytes([data[3], data[4]]) as usize;
+    cmp::min(len, data.len())
+}
+
+/// Counters describing the traffic of a TLS session.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct Stats {
+    /// The number of bytes of application data received from the peer.
+    pub bytes_read: u64,
+    /// The number of bytes of application data sent to the peer.
+    pub bytes_written: u64,
+    /// The number of records received from the peer after the handshake.
+    pub records_received: u64,
+    /// The number of records of application data sent to the peer.
+    pub records_sent: u64,
+    /// The number of renegotiations and, with TLS 1.3, post-handshake
+    /// messages such as session tickets and key updates.
+    pub renegotiations: u64,
+    /// The time the initial handshake took, once it has completed.
+    pub handshake_duration: Option<Duration>,
+}
+
+/// A TLS session exported by `TlsStream::export_context`, which can be
+/// continued by `TlsStream::from_exported`.
+///
+/// The context holds the keys of the session, so it is wiped once dropped.
+pub struct ExportedContext {
+    server: bool,
+    domain: Option<String>,
+    context: Vec<u8>,
+    plaintext: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+impl Drop for ExportedContext {
+    fn drop(&mut self) {
+        zeroize(&mut self.context);
+        zeroize(&mut self.plaintext);
+    }
+}
+
+impl fmt::Debug for ExportedContext {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("ExportedContext")
+            .field("server", &self.server)
+            .field("domain", &self.domain)
+            .field("plaintext", &self.plaintext.len())
+            .field("ciphertext", &self.ciphertext.len())
+            .finish()
+    }
+}
+
+impl ExportedContext {
+    /// Returns whether the session was exported by a server.
+    pub fn is_server(&self) -> bool {
+        self.server
+    }
+
+    /// Returns the data which had been decrypted but not read when the
+    /// session was exported.
+    ///
+    /// It isn't part of the serialized context, and isn't handed out by the
+    /// stream continuing the session either: it comes before the data read
+    /// from that stream, and has to be passed on separately.
+    pub fn plaintext(&self) -> &[u8] {
+        &self.plaintext
+    }
+
+    /// Returns the data which had been received from the peer but not
+    /// decrypted when the session was exported.
+    ///
+    /// It isn't part of the serialized context, and has to be passed to
+    /// `TlsStream::from_exported` as buffered data.
+    pub fn ciphertext(&self) -> &[u8] {
+        &self.ciphertext
+    }
+
+    /// Serializes the session, for example to send it to another process.
+    ///
+    /// The serialized session includes its keys, and neither `plaintext` nor
+    /// `ciphertext`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let domain = self.domain.as_deref().unwrap_or("");
+        let mut data = Vec::with_capacity(3 + domain.len() + self.context.len());
+        data.push(self.server as u8 | (self.domain.is_some() as u8) << 1);
+        // `TlsStream::export_context` rejects longer domains and `from_bytes`
+        // can't produce them
+        data.extend_from_slice(&(domain.len() as u16).to_be_bytes());
+        data.extend_from_slice(domain.as_bytes());
+        data.extend_from_slice(&self.context);
+        data
+    }
+
+    /// Deserializes a session serialized by `to_bytes`.
+    pub fn from_bytes(data: &[u8]) -> io::Result<ExportedContext> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid exported context");
+        if data.len() < 3 || data[0] & !3 != 0 {
+            return Err(invalid());
+        }
+        let domain_len = u16::from_be_bytes([data[1], data[2]]) as usize;
+        let rest = &data[3..];
+        if rest.len() < domain_len {
+            return Err(invalid());
+        }
+        let domain = match data[0] & 2 {
+            0 if domain_len > 0 => return Err(invalid()),
+            0 => None,
+            _ => {
+                let domain = std::str::from_utf8(&rest[..domain_len]).map_err(|_| invalid())?;
+                Some(domain.to_owned())
+            }
+        };
+        Ok(ExportedContext {
+            server: data[0] & 1 != 0,
+            domain,
+            context: rest[domain_len..].to_vec(),
+            plaintext: Vec::new(),
+            ciphertext: Vec::new(),
+        })
+    }
+}
+
 /// An Schannel TLS stream.
 pub struct TlsStream<S> {
+    engine: TlsEngine,
+    stream: S,
+    /// whether output was written which the wrapped stream hasn't flushed yet
+    needs_flush: bool,
+    /// the (unencrypted) length of the last write call used to track writes
+    last_write_len: usize,
+    /// whether the last write call was encrypted but not reported as written
+    write_pending: bool,
+    /// whether the last write_record call was encrypted but not fully sent
+    record_pending: bool,
+}
+
+/// The TLS state machine behind a `TlsStream`, without any I/O.
+///
+/// This can be used to run a TLS session over transports which don't
+/// implement `Read` and `Write`. Data received from the peer is passed to
+/// `handshake_input` during the handshake and to `unwrap` afterwards, while
+/// the data to send to the peer is collected by `take_output` and `wrap`.
+pub struct TlsEngine {
     cred: SchannelCred,
     context: SecurityContext,
     cert_store: Option<CertStore>,
     domain: Option<Vec<u16>>,
-    use_sni: bool,
+    ip: Option<IpAddr>,
+    // NUL terminated, passed to InitializeSecurityContextW
+    target_name: Option<Vec<u16>>,
     accept_invalid_hostnames: bool,
+    // NUL terminated OIDs
+    required_ekus: Vec<Vec<u8>>,
+    min_rsa_key_bits: u32,
+    min_ecc_key_bits: u32,
+    min_key_bits_in_chain: bool,
     verify_callback: Option<Arc<dyn Fn(CertValidationResult) -> io::Result<()> + Sync + Send>>,
-    stream: S,
     state: State,
     server: bool,
     accept_first: bool,
+    client_cert_policy: ClientCertPolicy,
+    client_cert_selector: Option<ClientCertSelector>,
+    // whether the client has already passed the handshake message again
+    // after Schannel found no certificate to present
+    incomplete_creds_retried: bool,
+    // whether the peer has sent a close_notify
+    close_notify_received: bool,
     needs_read: usize,
-    // valid from position() to len()
-    dec_in: Cursor<Vec<u8>>,
-    // valid from 0 to position()
-    enc_in: Cursor<Vec<u8>>,
+    input: InputBuffer,
     // valid from position() to len()
     out_buf: Cursor<Vec<u8>>,
-    /// the (unencrypted) length of the last write call used to track writes
-    last_write_len: usize,
     requested_application_protocols: Option<Vec<Vec<u8>>>,
+    buffer_baseline: Option<usize>,
+    initial_read_buffer_size: usize,
+    initial_write_buffer_size: usize,
+    max_handshake_message_size: usize,
+    max_record_size: usize,
+    created: Instant,
+    stats: Stats,
+    record_observer: Option<RecordObserver>,
+    key_log: Option<KeyLogger>,
+    // the server name of the ClientHello, for systems which don't report it
+    client_hello_sni: Option<String>,
 }
 
 /// ensures that a TlsStream is always Sync/Send
+///
+/// The security context is a plain handle which SSPI allows to be used from
+/// any thread as long as calls on it are not made concurrently. Every call
+/// which mutates the context goes through `&mut self` (or the mutex shared by
+/// the split halves), so no additional `unsafe impl`s are required and the
+/// auto traits are derived from the wrapped stream.
 fn _is_sync() {
     fn sync<T: Sync + Send>() {}
     sync::<TlsStream<()>>();
+    sync::<MidHandshakeTlsStream<()>>();
+    sync::<TlsEngine>();
+    sync::<ReadHalf<()>>();
+    sync::<WriteHalf<()>>();
+    sync::<Builder>();
 }
 
-/// A failure which can happen during the `Builder::initialize` phase, either an
-/// I/O error or an intermediate stream which has not completed its handshake.
+/// A failure which can happen during the `Builder::initialize` phase, either a
+/// TLS or I/O error or an intermediate stream which has not completed its
+/// handshake.
 #[derive(Debug)]
 pub enum HandshakeError<S> {
+    /// The handshake was rejected by Schannel, for example because the
+    /// peer's certificate failed validation.
+    ///
+    /// Retrying the handshake with the same settings is unlikely to succeed.
+    Failure(error::Error),
     /// A fatal I/O error occurred
-    Failure(io::Error),
+    ///
+    /// Errors of the underlying stream are passed on unchanged, keeping their
+    /// `ErrorKind`. This also covers other errors which don't carry a
+    /// `SECURITY_STATUS` code, such as a handshake message exceeding
+    /// `Builder::max_handshake_message_size`.
+    Io(io::Error),
     /// The stream connection is in progress, but the handshake is not completed
     /// yet.
     Interrupted(MidHandshakeTlsStream<S>),
 }
 
+impl<S> HandshakeError<S> {
+    fn from_io(e: io::Error) -> HandshakeError<S> {
+        match error::Error::from_io_error(&e) {
+            Some(e) => HandshakeError::Failure(e),
+            None => HandshakeError::Io(e),
+        }
+    }
+}
+
 /// A struct used to wrap various cert chain validation results for callback processing.
 pub struct CertValidationResult {
     chain: CertChainContext,
@@ -299,6 +1226,7 @@ impl<S: fmt::Debug + Any> Error for HandshakeError<S> {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match *self {
             HandshakeError::Failure(ref e) => Some(e),
+            HandshakeError::Io(ref e) => Some(e),
             HandshakeError::Interrupted(_) => None,
         }
     }
@@ -307,7 +1235,7 @@ impl<S: fmt::Debug + Any> Error for HandshakeError<S> {
 impl<S: fmt::Debug + Any> fmt::Display for HandshakeError<S> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let desc = match *self {
-            HandshakeError::Failure(_) => "failed to perform handshake",
+            HandshakeError::Failure(_) | HandshakeError::Io(_) => "failed to perform handshake",
             HandshakeError::Interrupted(_) => "interrupted performing handshake",
         };
         write!(f, "{}", desc)?;
@@ -318,10 +1246,58 @@ impl<S: fmt::Debug + Any> fmt::Display for HandshakeError<S> {
     }
 }
 
+/// The failure of a `TlsStream::copy_from` call.
+///
+/// It is wrapped in the `io::Error` returned by the call, which has the kind
+/// of the underlying error, and can be recovered by downcasting
+/// `io::Error::get_ref`.
+#[derive(Debug)]
+pub struct CopyError {
+    sent: u64,
+    pending: u64,
+    error: io::Error,
+}
+
+impl CopyError {
+    /// Returns the number of bytes read from the reader whose records have
+    /// been written to the wrapped stream in full.
+    pub fn sent(&self) -> u64 {
+        self.sent
+    }
+
+    /// Returns the number of bytes read from the reader which have been
+    /// encrypted, but whose record hasn't been written to the wrapped stream
+    /// in full.
+    ///
+    /// If the error is a `WouldBlock` error, the rest of the record is sent
+    /// by the next call to `flush`, `flush_pending` or one of the write
+    /// methods, see `TlsStream::copy_from`.
+    pub fn pending(&self) -> u64 {
+        self.pending
+    }
+
+    /// Returns the error of the reader or the wrapped stream.
+    pub fn error(&self) -> &io::Error {
+        &self.error
+    }
+}
+
+impl Error for CopyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl fmt::Display for CopyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} after sending {} bytes", self.error, self.sent)
+    }
+}
+
 /// A stream which has not yet completed its handshake.
 #[derive(Debug)]
 pub struct MidHandshakeTlsStream<S> {
-    inner: TlsStream<S>,
+    inner: Box<TlsStream<S>>,
 }
 
 impl<S> fmt::Debug for TlsStream<S>
@@ -330,25 +1306,194 @@ where
 {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         fmt.debug_struct("TlsStream")
+            .field("engine", &self.engine)
             .field("stream", &self.stream)
             .finish()
     }
 }
 
+impl fmt::Debug for TlsEngine {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        // Only the amount of buffered data is shown, as it may be plaintext.
+        fmt.debug_struct("TlsEngine")
+            .field("server", &self.server)
+            .field("state", &self.session_state())
+            .field("domain", &domain_name(&self.domain))
+            .field("buffered_plaintext", &self.input.plaintext().len())
+            .field("buffered_ciphertext", &self.input.ciphertext().len())
+            .field(
+                "pending_output",
+                &(self.out_buf.get_ref().len() - self.out_buf.position() as usize),
+            )
+            .finish()
+    }
+}
+
 impl<S> TlsStream<S> {
+    /// Continues a TLS session exported by `export_context` over `stream`.
+    ///
+    /// `buffered` is the data received from the peer which the new stream
+    /// starts with, the `ciphertext` of the exported context followed by
+    /// anything read from `stream` since. Data which had been decrypted
+    /// already isn't handed out by the new stream, see
+    /// `ExportedContext::plaintext`.
+    ///
+    /// The credentials are used if the peer renegotiates. The session
+    /// continues with the default settings of `Builder`, validating the
+    /// certificates presented in renegotiations against the domain of the
+    /// exported session.
+    pub fn from_exported(
+        cred: SchannelCred,
+        stream: S,
+        exported: ExportedContext,
+        buffered: &[u8],
+    ) -> io::Result<TlsStream<S>> {
+        let context = SecurityContext::import(&exported.context)?;
+        let mut builder = Builder::new();
+        if let Some(ref domain) = exported.domain {
+            builder.domain(domain);
+        }
+        let mut engine = builder.engine_with_context(cred, exported.server, context, None, true)?;
+        // The exporting process validated the peer already.
+        engine.state = State::Initializing {
+            more_calls: false,
+            shutting_down: false,
+            half_close: false,
+            validated: true,
+        };
+        engine.needs_read = 0;
+        engine.accept_first = false;
+        engine.advance()?;
+        engine.input.extend_from_slice(buffered);
+        Ok(TlsStream::from_engine(engine, stream))
+    }
+
+    fn from_engine(engine: TlsEngine, stream: S) -> TlsStream<S> {
+        TlsStream {
+            engine,
+            stream,
+            needs_flush: false,
+            last_write_len: 0,
+            write_pending: false,
+            record_pending: false,
+        }
+    }
+
     /// Returns a reference to the wrapped stream.
+    ///
+    /// It is fine to query or change properties of the wrapped stream, such
+    /// as socket options or timeouts, but reading from or writing to it
+    /// directly will corrupt the TLS session.
     pub fn get_ref(&self) -> &S {
         &self.stream
     }
 
     /// Returns a mutable reference to the wrapped stream.
+    ///
+    /// The same restrictions as for `get_ref` apply: any data read from or
+    /// written to the wrapped stream bypasses the TLS session and will
+    /// corrupt it.
     pub fn get_mut(&mut self) -> &mut S {
         &mut self.stream
     }
 
     /// Indicates if this stream is the server- or client-side of a TLS session.
     pub fn is_server(&self) -> bool {
-        self.server
+        self.engine.is_server()
+    }
+
+    /// Returns the number of decrypted bytes which can be read without
+    /// reading from the wrapped stream.
+    pub fn buffered_read_len(&self) -> usize {
+        self.engine.input.plaintext().len()
+    }
+
+    /// Returns the number of encrypted bytes which haven't been written to
+    /// the wrapped stream yet.
+    ///
+    /// This is the part of a record left over by a write which failed with
+    /// `WouldBlock`, as well as handshake messages queued by a renegotiation.
+    /// Event loops should wait for the wrapped stream to become writable
+    /// while this isn't 0, rather than encrypting more data.
+    pub fn pending_write_len(&self) -> usize {
+        let out_buf = &self.engine.out_buf;
+        out_buf.get_ref().len() - out_buf.position() as usize
+    }
+
+    /// Indicates if data can be read without waiting for the wrapped stream
+    /// to become readable.
+    ///
+    /// This is the case if decrypted data is buffered or if a complete record
+    /// has been received but not decrypted yet. Event loops should check this
+    /// before waiting for readiness of the underlying socket, since all of
+    /// the available data may already have been read from it.
+    pub fn has_buffered_read_data(&self) -> bool {
+        self.buffered_read_len() > 0 || self.engine.input.has_complete_record()
+    }
+
+    /// Returns the traffic counters of this session.
+    pub fn stats(&self) -> Stats {
+        self.engine.stats
+    }
+
+    /// Returns the current capacities of the internal buffers.
+    pub fn buffer_capacities(&self) -> BufferCapacities {
+        BufferCapacities {
+            input: self.engine.input.capacity(),
+            output: self.engine.out_buf.get_ref().capacity(),
+        }
+    }
+
+    /// Splits this stream into a read half and a write half which can be
+    /// used independently, for example from two different threads.
+    ///
+    /// The halves share the security context, access to which is serialized
+    /// internally, and the wrapped stream, which must therefore be usable
+    /// through a shared reference (as `TcpStream` is).
+    ///
+    /// Renegotiation cannot be coordinated between the two halves, so if the
+    /// peer requests one the read half will return an error. TLS 1.3
+    /// post-handshake messages, such as session tickets, are processed by
+    /// the read half, and any response to them is sent by the write half
+    /// ahead of its next record or on `flush`. The stream should be split
+    /// once the handshake has completed; if a renegotiation was in progress
+    /// at the time of the split both halves return errors.
+    pub fn split(self) -> (ReadHalf<S>, WriteHalf<S>) {
+        let engine = self.engine;
+        let state = match engine.state {
+            State::Streaming { sizes } => SplitState::Streaming(sizes),
+            State::WriteShutdown | State::Shutdown => SplitState::Shutdown,
+            State::Initializing { .. } => SplitState::Renegotiating,
+        };
+        let requests = engine.context_requests();
+        let shared = Arc::new(SplitShared {
+            cred: engine.cred,
+            server: engine.server,
+            target_name: engine.target_name,
+            requests,
+            context: Mutex::new(SplitContext {
+                context: engine.context,
+                output: Vec::new(),
+            }),
+            stream: self.stream,
+        });
+
+        let read = ReadHalf {
+            shared: shared.clone(),
+            state,
+            post_handshake: false,
+            needs_read: engine.needs_read,
+            input: engine.input,
+            max_record_size: engine.max_record_size,
+        };
+        let write = WriteHalf {
+            shared,
+            state,
+            out_buf: engine.out_buf,
+            last_write_len: self.last_write_len,
+            write_pending: self.write_pending,
+        };
+        (read, write)
     }
 }
 
@@ -356,6 +1501,629 @@ impl<S> TlsStream<S>
 where
     S: Read + Write,
 {
+    /// Returns the certificate used to identify this side of the TLS session.
+    ///
+    /// Its associated cert store contains any intermediate certificates sent
+    /// along with the leaf.
+    pub fn certificate(&self) -> io::Result<CertContext> {
+        self.engine.certificate()
+    }
+
+    /// Returns the peer's certificate, if available.
+    ///
+    /// See `TlsEngine::peer_certificate`.
+    pub fn peer_certificate(&self) -> io::Result<Option<CertContext>> {
+        self.engine.peer_certificate()
+    }
+
+    /// Returns the negotiated application protocol for this tls stream, if one exists
+    pub fn negotiated_application_protocol(&self) -> io::Result<Option<Vec<u8>>> {
+        self.engine.negotiated_application_protocol()
+    }
+
+    /// Returns whether or not the session was resumed.
+    pub fn session_resumed(&self) -> io::Result<bool> {
+        self.engine.session_resumed()
+    }
+
+    /// Returns the host name the client asked for with Server Name
+    /// Indication.
+    ///
+    /// See `TlsEngine::sni_hostname`.
+    pub fn sni_hostname(&self) -> io::Result<Option<String>> {
+        self.engine.sni_hostname()
+    }
+
+    /// Returns the cipher suite negotiated for the session.
+    ///
+    /// See `TlsEngine::cipher_suite`.
+    pub fn cipher_suite(&self) -> io::Result<CipherSuiteInfo> {
+        self.engine.cipher_suite()
+    }
+
+    /// Returns the Windows access token of the account the client's
+    /// certificate was mapped to.
+    ///
+    /// See `TlsEngine::client_token`.
+    pub fn client_token(&self) -> io::Result<OwnedHandle> {
+        self.engine.client_token()
+    }
+
+    /// Sets whether the session of this connection may be resumed by later
+    /// connections.
+    ///
+    /// See `TlsEngine::set_session_resumption`.
+    pub fn set_session_resumption(&mut self, enabled: bool) -> io::Result<()> {
+        self.engine.set_session_resumption(enabled)
+    }
+
+    /// Queries an attribute of the security context of the session.
+    ///
+    /// See `TlsEngine::query_context_attribute`.
+    ///
+    /// # Safety
+    ///
+    /// `T` must be the type `QueryContextAttributesW` writes for `attr`.
+    pub unsafe fn query_context_attribute<T>(&self, attr: u32) -> io::Result<T> {
+        self.engine.query_context_attribute(attr)
+    }
+
+    /// Returns the handle of the security context of the session.
+    ///
+    /// See `TlsEngine::context_handle`.
+    ///
+    /// # Safety
+    ///
+    /// The handle must not be deleted, and the context must not be changed in
+    /// ways the session doesn't expect, such as by calling
+    /// `ApplyControlToken` or `DecryptMessage` on it.
+    pub unsafe fn context_handle(&mut self) -> &mut Credentials::SecHandle {
+        self.engine.context_handle()
+    }
+
+    /// Returns a reference to the buffer of pending data.
+    ///
+    /// Like `BufRead::fill_buf` except that it will return an empty slice
+    /// rather than reading from the wrapped stream if there is no buffered
+    /// data.
+    pub fn get_buf(&self) -> &[u8] {
+        self.engine.input.plaintext()
+    }
+
+    /// Reads decrypted data into `buf` without consuming it.
+    ///
+    /// Like `read`, this blocks until at least one byte is available unless
+    /// the session has been closed, in which case `Ok(0)` is returned. A
+    /// subsequent `read` will return the same bytes.
+    pub fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read_buf = self.fill_buf()?;
+        let nread = cmp::min(buf.len(), read_buf.len());
+        buf[..nread].copy_from_slice(&read_buf[..nread]);
+        Ok(nread)
+    }
+
+    /// Consumes this stream, returning the wrapped stream along with any
+    /// decrypted data which has not been read yet.
+    ///
+    /// Pending encrypted output is written to the wrapped stream before it is
+    /// returned. The TLS session itself is not shut down by this method, so
+    /// `shutdown` should be called beforehand if the peer is expecting a
+    /// close_notify alert. Either way the session cannot be used anymore
+    /// afterwards and the wrapped stream is free to be used for other
+    /// purposes.
+    pub fn into_inner(mut self) -> io::Result<(S, Vec<u8>)> {
+        self.write_out()?;
+        self.stream.flush()?;
+
+        Ok((self.stream, self.engine.input.into_plaintext()))
+    }
+
+    /// Exports the TLS session so that it can be continued with
+    /// `from_exported`, usually by another process to which the wrapped
+    /// stream is handed over as well.
+    ///
+    /// Pending encrypted output is written to the wrapped stream before it is
+    /// returned. The data which has been received from the peer but not read
+    /// yet is returned along with the context, see
+    /// `ExportedContext::plaintext` and `ExportedContext::ciphertext`. The
+    /// session cannot be used by this process anymore afterwards.
+    ///
+    /// Sessions can only be exported once the handshake has completed, and
+    /// are serialized with `ExportSecurityContext`. Schannel only imports
+    /// them on the same computer, and may require the importing process to
+    /// run in the same logon session as the exporting one. Sessions with a
+    /// domain of more than 65535 bytes can't be exported.
+    pub fn export_context(mut self) -> io::Result<(S, ExportedContext)> {
+        let domain = self.engine.domain.as_ref().map(|domain| {
+            let domain = domain.strip_suffix(&[0]).unwrap_or(domain);
+            String::from_utf16_lossy(domain)
+        });
+        // `ExportedContext::to_bytes` stores the length in two bytes
+        if domain.as_ref().map_or(0, |domain| domain.len()) > u16::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the domain is too long to be exported",
+            ));
+        }
+        match self.engine.state {
+            State::Streaming { .. } => {}
+            State::Initializing {
+                shutting_down: false,
+                ..
+            } => return Err(io::Error::other("the TLS handshake has not completed")),
+            _ => {
+                return Err(io::Error::from_raw_os_error(
+                    Foundation::SEC_E_CONTEXT_EXPIRED,
+                ))
+            }
+        }
+        self.write_out()?;
+        self.stream.flush()?;
+
+        let context = self
+            .engine
+            .context
+            .export()
+            .map_err(|e| self.engine.with_context(e, "ExportSecurityContext"))?;
+        let exported = ExportedContext {
+            server: self.engine.server,
+            domain,
+            context,
+            plaintext: self.engine.input.plaintext().to_vec(),
+            ciphertext: self.engine.input.ciphertext().to_vec(),
+        };
+        Ok((self.stream, exported))
+    }
+
+    /// Shuts the TLS session down.
+    ///
+    /// This sends a close_notify alert to the peer. If the wrapped stream is
+    /// nonblocking and can't accept the alert right away a `WouldBlock` error
+    /// is returned; the session is then in the `SessionState::ShuttingDown`
+    /// state and `shutdown` should be called again once the stream is
+    /// writable, which picks up where the previous call left off. Calling
+    /// `shutdown` on a session which has already been shut down does nothing.
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        self.engine.start_shutdown(false)?;
+        self.initialize().map(|_| ())
+    }
+
+    /// Shuts down the writing side of the TLS session.
+    ///
+    /// Like `shutdown` this sends a close_notify alert to the peer, but data
+    /// sent by the peer afterwards can still be read until it closes its side
+    /// of the session as well, at which point `read` returns `Ok(0)`. Writing
+    /// fails once this has been called.
+    ///
+    /// Interruptions by `WouldBlock` errors are handled as for `shutdown`.
+    pub fn shutdown_write(&mut self) -> io::Result<()> {
+        self.engine.start_shutdown(true)?;
+        self.initialize().map(|_| ())
+    }
+
+    /// Sends an alert to the peer and closes the TLS session.
+    ///
+    /// This tells the peer why the session ends, for example that it isn't
+    /// allowed to proceed with `AlertDescription::AccessDenied`, where
+    /// `shutdown` would only send a close_notify alert. The session is
+    /// closed even for warnings, reading and writing fail afterwards.
+    ///
+    /// If this is interrupted by a `WouldBlock` error, `shutdown` has to be
+    /// called to finish sending the alert.
+    pub fn send_alert(
+        &mut self,
+        level: AlertLevel,
+        description: AlertDescription,
+    ) -> io::Result<()> {
+        self.engine.begin_alert(level, description)?;
+        self.initialize().map(|_| ())
+    }
+
+    /// Returns the current state of the TLS session.
+    ///
+    /// This can be used to tell a clean closure of the session by the peer
+    /// apart from a truncated stream: once `read` returns `Ok(0)` the state is
+    /// `SessionState::Shutdown` if the peer sent a close_notify alert, while
+    /// it stays `SessionState::Streaming` if the wrapped stream simply
+    /// reached EOF.
+    pub fn session_state(&self) -> SessionState {
+        self.engine.session_state()
+    }
+
+    /// Returns a buffer to pass to `write_prepared` with room for a payload of
+    /// `payload_len` bytes, along with the range the payload has to be
+    /// placed in.
+    ///
+    /// `payload_len` may be at most the maximum message size of the session.
+    pub fn prepare_buffer(&mut self, payload_len: usize) -> io::Result<(Vec<u8>, Range<usize>)> {
+        let sizes = match self.initialize()? {
+            Some(sizes) => sizes,
+            None => {
+                return Err(io::Error::from_raw_os_error(
+                    Foundation::SEC_E_CONTEXT_EXPIRED,
+                ))
+            }
+        };
+        if payload_len > sizes.cbMaximumMessage as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "message exceeds the maximum record size",
+            ));
+        }
+
+        let header = sizes.cbHeader as usize;
+        let buf = vec![0; header + payload_len + sizes.cbTrailer as usize];
+        Ok((buf, header..header + payload_len))
+    }
+
+    /// Encrypts the payload in `buf[payload]` in place and writes the
+    /// resulting record to the stream.
+    ///
+    /// This avoids the copy of the data made by `write`. The payload has to be
+    /// preceded by room for the record header and followed by room for the
+    /// trailer, as set up by `prepare_buffer`. The record takes over the
+    /// memory of `buf`, which is replaced by an empty buffer.
+    ///
+    /// If writing the record runs into a `WouldBlock` error once it has been
+    /// encrypted, the rest of it is sent by the next call to `flush` or to
+    /// one of the write methods. The payload must not be passed again.
+    pub fn write_prepared(&mut self, buf: &mut Vec<u8>, payload: Range<usize>) -> io::Result<()> {
+        let sizes = match self.initialize()? {
+            Some(sizes) => sizes,
+            None => {
+                return Err(io::Error::from_raw_os_error(
+                    Foundation::SEC_E_CONTEXT_EXPIRED,
+                ))
+            }
+        };
+
+        // The record is moved into the output buffer, so anything still in
+        // there has to be sent first.
+        self.write_out()?;
+        self.engine.encrypt_prepared(buf, payload, &sizes)?;
+        match self.write_out() {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+        self.engine.shrink_buffers();
+        Ok(())
+    }
+
+    /// Encrypts the data of `reader` and writes it to the stream until the
+    /// reader reaches EOF, returning the number of bytes copied.
+    ///
+    /// Like `write_prepared`, this avoids the copy of the data made by
+    /// `write`: the reader reads straight into the space between the header
+    /// and the trailer of a record, which is encrypted in place. Each read
+    /// asks for a full record of data. The wrapped stream isn't flushed.
+    ///
+    /// If the reader or the wrapped stream fail, the returned error wraps a
+    /// `CopyError` telling how much of the data has been sent. After a
+    /// `WouldBlock` error the record of the pending data is kept: `flush` or
+    /// `flush_pending` should be called once the wrapped stream is writable
+    /// again, until `pending_write_len` is 0, before writing anything else.
+    /// The pending data must not be passed again.
+    pub fn copy_from<R>(&mut self, reader: &mut R) -> io::Result<u64>
+    where
+        R: Read + ?Sized,
+    {
+        let mut sent = 0;
+        let mut pending = 0;
+        match self.copy_records(reader, &mut sent, &mut pending) {
+            Ok(()) => Ok(sent),
+            Err(error) => {
+                let kind = error.kind();
+                let error = CopyError {
+                    sent,
+                    pending,
+                    error,
+                };
+                Err(io::Error::new(kind, error))
+            }
+        }
+    }
+
+    fn copy_records<R>(
+        &mut self,
+        reader: &mut R,
+        sent: &mut u64,
+        pending: &mut u64,
+    ) -> io::Result<()>
+    where
+        R: Read + ?Sized,
+    {
+        let sizes = match self.initialize()? {
+            Some(sizes) => sizes,
+            None => {
+                return Err(io::Error::from_raw_os_error(
+                    Foundation::SEC_E_CONTEXT_EXPIRED,
+                ))
+            }
+        };
+        let header = sizes.cbHeader as usize;
+        let max_message = sizes.cbMaximumMessage as usize;
+        let record = header + max_message + sizes.cbTrailer as usize;
+
+        // Each record is built in the memory of the previous one, which is
+        // taken out of the output buffer once it has been sent.
+        self.write_out()?;
+        loop {
+            let mut buf = mem::take(self.engine.out_buf.get_mut());
+            self.engine.out_buf.set_position(0);
+            buf.resize(record, 0);
+            let len = loop {
+                match reader.read(&mut buf[header..header + max_message]) {
+                    Ok(len) => break len,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        zeroize(&mut buf);
+                        return Err(e);
+                    }
+                }
+            };
+            if len == 0 {
+                buf.clear();
+                *self.engine.out_buf.get_mut() = buf;
+                break;
+            }
+
+            if let Err(e) = self
+                .engine
+                .encrypt_prepared(&mut buf, header..header + len, &sizes)
+            {
+                zeroize(&mut buf);
+                return Err(e);
+            }
+            *pending = len as u64;
+            self.write_out()?;
+            *sent += len as u64;
+            *pending = 0;
+        }
+        self.engine.shrink_buffers();
+        Ok(())
+    }
+
+    /// Reads decrypted data into the spare capacity of `buf`, returning the
+    /// number of bytes appended.
+    ///
+    /// This behaves like `read`, but doesn't need an initialized buffer: the
+    /// data is copied from the internal buffer to the end of `buf`, so
+    /// reserving capacity is all the vector needs. At most the spare capacity
+    /// is filled, unless there is none, in which case all of the buffered
+    /// data is appended. Returns 0 once the session has been closed.
+    pub fn read_into_spare(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let len = {
+            let data = self.fill_buf()?;
+            let len = match buf.capacity() - buf.len() {
+                0 => data.len(),
+                spare => cmp::min(spare, data.len()),
+            };
+            buf.extend_from_slice(&data[..len]);
+            len
+        };
+        self.consume(len);
+        Ok(len)
+    }
+
+    /// Sends `buf` as exactly one record and flushes the wrapped stream.
+    ///
+    /// This is meant for protocols which rely on record boundaries, as a
+    /// `write` may split data across records or leave it buffered. `buf` may
+    /// be at most the maximum message size of the session.
+    ///
+    /// Only once this returns `Ok` has the whole record been handed to the
+    /// wrapped stream. If the record runs into a `WouldBlock` error once it
+    /// has been encrypted, the call has to be repeated with the same data to
+    /// send the rest of it, which will not encrypt the data a second time.
+    pub fn write_record(&mut self, buf: &[u8]) -> io::Result<()> {
+        let sizes = match self.initialize()? {
+            Some(sizes) => sizes,
+            None => {
+                return Err(io::Error::from_raw_os_error(
+                    Foundation::SEC_E_CONTEXT_EXPIRED,
+                ))
+            }
+        };
+
+        if !self.record_pending {
+            // Output of an earlier call must not end up in front of the
+            // record's data in the same buffer.
+            self.write_out()?;
+            self.engine.encrypt(buf, &sizes)?;
+            self.record_pending = true;
+        }
+        self.write_out()?;
+        self.record_pending = false;
+        self.engine.shrink_buffers();
+        self.stream.flush()
+    }
+
+    fn initialize(&mut self) -> io::Result<Option<Identity::SecPkgContext_StreamSizes>> {
+        loop {
+            match self.engine.state {
+                State::Initializing { .. } => {}
+                State::Streaming { sizes } => return Ok(Some(sizes)),
+                State::WriteShutdown | State::Shutdown => return Ok(None),
+            }
+
+            if self.write_out()? > 0 {
+                self.needs_flush = true;
+            }
+            if self.needs_flush {
+                self.stream.flush()?;
+                self.needs_flush = false;
+            }
+
+            if self.engine.advance()? && self.read_in()? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "unexpected EOF during handshake",
+                ));
+            }
+        }
+    }
+
+    /// Reads the plaintext of exactly one record.
+    ///
+    /// `read` hands out the data of a record in as many parts as the caller
+    /// asks for, while this returns the whole of the next application data
+    /// record, which may be empty. Handshake records, such as those of a
+    /// renegotiation, are processed along the way. If a record has been
+    /// partly read with `read` or `fill_buf`, the rest of it is returned
+    /// first.
+    ///
+    /// Returns `None` once the peer has shut down the session, as well as
+    /// when the wrapped stream reaches EOF. As for `read`, `session_state`
+    /// tells the two apart.
+    pub fn read_record(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            if !self.get_buf().is_empty() {
+                break;
+            }
+            let records = self.engine.stats.records_received;
+            let renegotiations = self.engine.stats.renegotiations;
+            if !self.read_step(false)? {
+                return Ok(None);
+            }
+            // Handshake records and close_notify alerts don't produce any
+            // data either, but only records of application data count.
+            if self.engine.stats.records_received > records
+                && self.engine.stats.renegotiations == renegotiations
+                && matches!(
+                    self.engine.state,
+                    State::Streaming { .. } | State::WriteShutdown
+                )
+            {
+                break;
+            }
+        }
+
+        let data = self.engine.input.first_record().to_vec();
+        self.consume(data.len());
+        Ok(Some(data))
+    }
+
+    /// Makes progress on reading from the peer, which may or may not have
+    /// produced data, and returns false once nothing more can be read.
+    ///
+    /// With `read_ahead`, all complete records which have been received are
+    /// decrypted rather than just the first one.
+    fn read_step(&mut self, read_ahead: bool) -> io::Result<bool> {
+        match self.initialize() {
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                if let State::Shutdown = self.engine.state {
+                    return Ok(false);
+                }
+            }
+            // Answering the peer's close_notify is best-effort; the peer may
+            // well have closed its side of the connection already. Failures
+            // of Schannel itself, and anything going wrong with a shutdown
+            // started on this side, are still reported.
+            Err(ref e)
+                if self.engine.close_notify_received
+                    && self.session_state() == SessionState::ShuttingDown
+                    && error::Error::from_io_error(e).is_none()
+                    && e.kind() != io::ErrorKind::WouldBlock
+                    && e.kind() != io::ErrorKind::Interrupted =>
+            {
+                self.engine.state = State::Shutdown;
+                return Ok(false);
+            }
+            Err(e) => return Err(e),
+        }
+
+        if self.engine.needs_read > 0 {
+            if self.read_in()? == 0 {
+                return Ok(false);
+            }
+            self.engine.needs_read = 0;
+        }
+
+        let closed = if read_ahead {
+            self.engine.decrypt_buffered()?
+        } else {
+            self.engine.decrypt()?
+        };
+        if closed {
+            // The peer sent a close_notify, respond with our own before
+            // reporting EOF.
+            self.engine.peer_closed()?;
+        }
+        Ok(true)
+    }
+
+    fn write_out(&mut self) -> io::Result<usize> {
+        write_out(&mut self.stream, &mut self.engine.out_buf)
+    }
+
+    /// Writes the encrypted data counted by `pending_write_len` to the wrapped
+    /// stream, without encrypting anything new.
+    ///
+    /// The wrapped stream is flushed once all of the data has been written.
+    /// Returns the number of bytes written, which is less than what was
+    /// pending if a write failed after making progress. Without progress the
+    /// error, such as `WouldBlock`, is returned.
+    ///
+    /// A `write` call which previously failed with `WouldBlock` must still be
+    /// repeated with the same data afterwards, but it won't encrypt the data
+    /// again.
+    pub fn flush_pending(&mut self) -> io::Result<usize> {
+        let pending = self.pending_write_len();
+        if let Err(e) = self.write_out() {
+            let written = pending - self.pending_write_len();
+            return if written > 0 { Ok(written) } else { Err(e) };
+        }
+        self.engine.shrink_buffers();
+        self.stream.flush()?;
+        Ok(pending)
+    }
+
+    /// Writes out and flushes any pending output, which is left over by a
+    /// handshake Schannel completed in the first call.
+    fn flush_output(&mut self) -> io::Result<()> {
+        if self.write_out()? > 0 {
+            self.needs_flush = true;
+        }
+        if self.needs_flush {
+            self.stream.flush()?;
+            self.needs_flush = false;
+        }
+        Ok(())
+    }
+
+    fn read_in(&mut self) -> io::Result<usize> {
+        self.engine.read_in(&mut self.stream)
+    }
+}
+
+impl TlsEngine {
+    /// Indicates if this engine is the server- or client-side of a TLS session.
+    pub fn is_server(&self) -> bool {
+        self.server
+    }
+
+    /// Returns the current state of the TLS session.
+    ///
+    /// This can be used to tell a clean closure of the session by the peer
+    /// apart from a truncated stream: once `read` returns `Ok(0)` the state is
+    /// `SessionState::Shutdown` if the peer sent a close_notify alert, while
+    /// it stays `SessionState::Streaming` if the wrapped stream simply
+    /// reached EOF.
+    pub fn session_state(&self) -> SessionState {
+        match self.state {
+            State::Initializing {
+                shutting_down: true,
+                ..
+            } => SessionState::ShuttingDown,
+            State::Initializing { .. } => SessionState::Handshaking,
+            State::Streaming { .. } => SessionState::Streaming,
+            State::WriteShutdown => SessionState::WriteShutdown,
+            State::Shutdown => SessionState::Shutdown,
+        }
+    }
+
     /// Returns the certificate used to identify this side of the TLS session.
     ///
     /// Its associated cert store contains any intermediate certificates sent
@@ -367,9 +2135,14 @@ where
     /// Returns the peer's certificate, if available.
     ///
     /// Its associated cert store contains any intermediate certificates sent
-    /// by the server.
-    pub fn peer_certificate(&self) -> io::Result<CertContext> {
-        self.context.remote_cert()
+    /// by the peer. On the server side this is `None` if the client didn't
+    /// present a certificate, see `Builder::client_cert_policy`.
+    pub fn peer_certificate(&self) -> io::Result<Option<CertContext>> {
+        match self.context.remote_cert() {
+            Ok(cert) => Ok(Some(cert)),
+            Err(ref e) if self.server && error::Error::from_io_error(e).is_some() => Ok(None),
+            Err(e) => Err(e),
+        }
     }
 
     /// Returns the negotiated application protocol for this tls stream, if one exists
@@ -391,78 +2164,524 @@ where
         Ok(session_info.dwFlags & Identity::SSL_SESSION_RECONNECT > 0)
     }
 
-    /// Returns a reference to the buffer of pending data.
+    /// Returns the host name the client asked for with Server Name
+    /// Indication, or `None` if it sent none.
     ///
-    /// Like `BufRead::fill_buf` except that it will return an empty slice
-    /// rather than reading from the wrapped stream if there is no buffered
-    /// data.
-    pub fn get_buf(&self) -> &[u8] {
-        &self.dec_in.get_ref()[self.dec_in.position() as usize..]
+    /// This lets servers route or log connections by name, whichever
+    /// certificate they presented. The name is queried with
+    /// `SECPKG_ATTR_CLIENT_SPECIFIED_TARGET`. Systems which don't support
+    /// the attribute get the name of the ClientHello received during the
+    /// handshake instead, which is only found if the ClientHello fits in its
+    /// first record, and isn't available for sessions created with
+    /// `TlsStream::from_exported`.
+    ///
+    /// Always returns `None` for clients.
+    pub fn sni_hostname(&self) -> io::Result<Option<String>> {
+        if !self.server {
+            return Ok(None);
+        }
+        match self.context.client_specified_target() {
+            Err(ref e) if e.raw_os_error() == Some(Foundation::SEC_E_UNSUPPORTED_FUNCTION) => {
+                Ok(self.client_hello_sni.clone())
+            }
+            result => result,
+        }
+    }
+
+    /// Returns the cipher suite negotiated for the session.
+    ///
+    /// It is described like the cipher suites returned by
+    /// `schannel::enumerate_cipher_suites`, with the negotiated protocol as
+    /// its only protocol.
+    pub fn cipher_suite(&self) -> io::Result<CipherSuiteInfo> {
+        self.context
+            .cipher_info()
+            .map(|info| CipherSuiteInfo::from_cipher_info(&info))
+    }
+
+    /// Sets whether the session of this connection may be resumed by later
+    /// connections.
+    ///
+    /// Disabling resumption removes the session from Schannel's cache, so the
+    /// next connection with the same credentials performs a full handshake.
+    /// This works on both sides of a session, for example to force a full
+    /// handshake after the peer behaved suspiciously.
+    pub fn set_session_resumption(&mut self, enabled: bool) -> io::Result<()> {
+        let mut token = Identity::SCHANNEL_SESSION_TOKEN {
+            dwTokenType: Identity::SCHANNEL_SESSION,
+            dwFlags: if enabled {
+                Identity::SSL_SESSION_ENABLE_RECONNECTS
+            } else {
+                Identity::SSL_SESSION_DISABLE_RECONNECTS
+            },
+        };
+        self.apply_control_token(&mut token)
+    }
+
+    /// Returns the Windows access token of the account the client's
+    /// certificate was mapped to, for example to impersonate the client with
+    /// `ImpersonateLoggedOnUser`. The token is closed when the returned
+    /// handle is dropped.
+    ///
+    /// Schannel only maps a certificate to an account if
+    ///
+    /// * the server requested a certificate from the client with the
+    ///   `ASC_REQ_MUTUAL_AUTH` flag, which is set unless the
+    ///   `ClientCertPolicy` is `Ignore`,
+    /// * the client sent a certificate which chains to a trusted root,
+    /// * the server's credentials were acquired without
+    ///   `SCH_CRED_NO_SYSTEM_MAPPER`, which this crate never sets, and
+    /// * the certificate is mapped to an account, by Active Directory or by a
+    ///   mapping configured for the server.
+    ///
+    /// Otherwise `QuerySecurityContextToken` fails, usually with
+    /// `SEC_E_NO_IMPERSONATION`, which is returned as an `error::Error`.
+    /// Calling this on the client side of a session fails with
+    /// `io::ErrorKind::InvalidInput`.
+    pub fn client_token(&self) -> io::Result<OwnedHandle> {
+        if !self.server {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only servers can query the access token of the client",
+            ));
+        }
+        self.context
+            .token()
+            .map_err(|e| self.with_context(e, "QuerySecurityContextToken"))
+    }
+
+    /// Returns the traffic counters of this session.
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Queries an attribute of the security context of the session.
+    ///
+    /// This gives access to the `SECPKG_ATTR_*` attributes which have no
+    /// accessor of their own. Attributes which contain pointers to memory
+    /// allocated by Schannel, such as `SECPKG_ATTR_NAMES`, have to be released
+    /// by the caller with `FreeContextBuffer`, or with `CertFreeCertificateContext`
+    /// for the certificate attributes.
+    ///
+    /// # Safety
+    ///
+    /// `T` must be the type `QueryContextAttributesW` writes for `attr`.
+    pub unsafe fn query_context_attribute<T>(&self, attr: u32) -> io::Result<T> {
+        self.context.attribute(attr)
+    }
+
+    /// Returns the handle of the security context of the session.
+    ///
+    /// This allows calling SSPI functions which have no wrapper, such as
+    /// `SetContextAttributesW`.
+    ///
+    /// # Safety
+    ///
+    /// The handle must not be deleted, and the context must not be changed in
+    /// ways the session doesn't expect, such as by calling
+    /// `ApplyControlToken` or `DecryptMessage` on it.
+    pub unsafe fn context_handle(&mut self) -> &mut Credentials::SecHandle {
+        self.context.get_mut()
+    }
+
+    /// Processes data received from the peer during the handshake.
+    ///
+    /// The handshake is advanced as far as the data allows, after which any
+    /// messages to send in response can be retrieved with `take_output`. All
+    /// of `data` is consumed: anything following the end of the handshake is
+    /// kept and decrypted by the next `unwrap` call.
+    ///
+    /// Returns the number of bytes consumed.
+    pub fn handshake_input(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.input.extend_from_slice(data);
+        if !data.is_empty() {
+            self.needs_read = 0;
+        }
+
+        while let State::Initializing { .. } = self.state {
+            if self.advance()? {
+                break;
+            }
+        }
+        Ok(data.len())
+    }
+
+    /// Moves the data which has to be sent to the peer into `out`.
+    pub fn take_output(&mut self, out: &mut Vec<u8>) {
+        let position = self.out_buf.position() as usize;
+        out.extend_from_slice(&self.out_buf.get_ref()[position..]);
+        self.out_buf.get_mut().clear();
+        self.out_buf.set_position(0);
+        self.shrink_buffers();
+    }
+
+    /// Encrypts `plaintext`, appending the resulting records to `out`.
+    ///
+    /// Any output which is still pending is moved to `out` first, so that
+    /// everything is sent to the peer in the right order. Fails if the
+    /// handshake hasn't completed yet or the session has been shut down.
+    ///
+    /// Returns the number of bytes of `plaintext` consumed, which is all of
+    /// them.
+    pub fn wrap(&mut self, plaintext: &[u8], out: &mut Vec<u8>) -> io::Result<usize> {
+        let sizes = match self.state {
+            State::Streaming { sizes } => sizes,
+            State::Initializing {
+                shutting_down: false,
+                ..
+            } => return Err(io::Error::other("the TLS handshake has not completed")),
+            _ => {
+                return Err(io::Error::from_raw_os_error(
+                    Foundation::SEC_E_CONTEXT_EXPIRED,
+                ))
+            }
+        };
+
+        self.take_output(out);
+        for chunk in plaintext.chunks(sizes.cbMaximumMessage as usize) {
+            self.encrypt(chunk, &sizes)?;
+            self.take_output(out);
+        }
+        Ok(plaintext.len())
+    }
+
+    /// Decrypts the records in `ciphertext`, appending the plaintext to `out`.
+    ///
+    /// An incomplete record at the end of `ciphertext` is kept until the rest
+    /// of it is passed to a later call. Handshake messages, such as those of
+    /// a renegotiation, are processed along the way and any responses to
+    /// them can be retrieved with `take_output`. The same goes for the
+    /// close_notify alert sent in response once the peer shuts the session
+    /// down.
+    ///
+    /// Returns the number of bytes of `ciphertext` consumed, which is all of
+    /// them.
+    pub fn unwrap(&mut self, ciphertext: &[u8], out: &mut Vec<u8>) -> io::Result<usize> {
+        self.input.extend_from_slice(ciphertext);
+        if !ciphertext.is_empty() {
+            self.needs_read = 0;
+        }
+
+        loop {
+            let len = self.input.plaintext().len();
+            out.extend_from_slice(self.input.plaintext());
+            self.input.consume_plaintext(len);
+
+            match self.state {
+                State::Initializing { .. } => {
+                    if self.advance()? {
+                        break;
+                    }
+                }
+                State::Streaming { .. } | State::WriteShutdown => {
+                    if self.needs_read > 0 {
+                        break;
+                    }
+                    if self.decrypt()? {
+                        self.peer_closed()?;
+                    }
+                }
+                State::Shutdown => break,
+            }
+        }
+
+        self.shrink_buffers();
+        Ok(ciphertext.len())
     }
 
     /// Shuts the TLS session down.
+    ///
+    /// The close_notify alert to send to the peer can then be retrieved with
+    /// `take_output`.
     pub fn shutdown(&mut self) -> io::Result<()> {
+        self.start_shutdown(false)?;
+        while let State::Initializing { .. } = self.state {
+            if self.advance()? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends an alert and closes the TLS session, see
+    /// `TlsStream::send_alert`.
+    ///
+    /// The alert to send to the peer can then be retrieved with
+    /// `take_output`.
+    pub fn send_alert(
+        &mut self,
+        level: AlertLevel,
+        description: AlertDescription,
+    ) -> io::Result<()> {
+        self.begin_alert(level, description)?;
+        while let State::Initializing { .. } = self.state {
+            if self.advance()? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies an alert control token, after which `initialize` will
+    /// generate and send the alert.
+    fn begin_alert(&mut self, level: AlertLevel, description: AlertDescription) -> io::Result<()> {
         match self.state {
-            State::Shutdown => return Ok(()),
             State::Initializing {
                 shutting_down: true,
                 ..
-            } => {}
-            _ => {
-                unsafe {
-                    let mut token = Identity::SCHANNEL_SHUTDOWN;
-                    let ptr = &mut token as *mut _ as *mut u8;
-                    let size = mem::size_of_val(&token);
-                    let token = slice::from_raw_parts_mut(ptr, size);
-                    let mut buf = [secbuf(Identity::SECBUFFER_TOKEN, Some(token))];
-                    let desc = secbuf_desc(&mut buf);
-
-                    match Identity::ApplyControlToken(self.context.get_mut(), &desc) {
-                        Foundation::SEC_E_OK => {}
-                        err => return Err(io::Error::from_raw_os_error(err)),
-                    }
+            }
+            | State::WriteShutdown
+            | State::Shutdown => {
+                return Err(io::Error::from_raw_os_error(
+                    Foundation::SEC_E_CONTEXT_EXPIRED,
+                ))
+            }
+            _ => {}
+        }
+
+        debug!("sending a {:?} alert: {:?}", level, description);
+        let mut token = Identity::SCHANNEL_ALERT_TOKEN {
+            dwTokenType: Identity::SCHANNEL_ALERT,
+            dwAlertType: match level {
+                AlertLevel::Warning => Identity::TLS1_ALERT_WARNING,
+                AlertLevel::Fatal => Identity::TLS1_ALERT_FATAL,
+            },
+            dwAlertNumber: description.code(),
+        };
+        self.apply_control_token(&mut token)?;
+
+        // The next call to InitializeSecurityContext or AcceptSecurityContext
+        // generates the alert, after which the session is over.
+        self.state = State::Initializing {
+            more_calls: true,
+            shutting_down: true,
+            half_close: false,
+            validated: false,
+        };
+        self.needs_read = 0;
+        Ok(())
+    }
+
+    fn apply_control_token<T>(&mut self, token: &mut T) -> io::Result<()> {
+        unsafe {
+            let ptr = token as *mut T as *mut u8;
+            let token = slice::from_raw_parts_mut(ptr, mem::size_of::<T>());
+            let mut buf = [secbuf(Identity::SECBUFFER_TOKEN, Some(token))?];
+            let desc = secbuf_desc(&mut buf);
+
+            match Identity::ApplyControlToken(self.context.get_mut(), &desc) {
+                Foundation::SEC_E_OK => Ok(()),
+                err => Err(self.sspi_error(err, "ApplyControlToken")),
+            }
+        }
+    }
+
+    /// Releases the memory of empty buffers which have grown beyond the
+    /// configured baseline.
+    fn shrink_buffers(&mut self) {
+        let baseline = match self.buffer_baseline {
+            Some(baseline) => baseline,
+            None => return,
+        };
+
+        self.input
+            .shrink(cmp::max(baseline, self.initial_read_buffer_size));
+        let baseline = cmp::max(baseline, self.initial_write_buffer_size);
+        if self.out_buf.position() as usize == self.out_buf.get_ref().len()
+            && self.out_buf.get_ref().capacity() > baseline
+        {
+            self.out_buf = Cursor::new(Vec::with_capacity(baseline));
+        }
+    }
+
+    /// Moves the session into the state of `shutdown` or, with `half_close`,
+    /// `shutdown_write` unless it is there already.
+    fn start_shutdown(&mut self, half_close: bool) -> io::Result<()> {
+        match self.state {
+            State::Shutdown => {}
+            State::WriteShutdown => {
+                if !half_close {
+                    self.state = State::Shutdown;
                 }
+            }
+            State::Initializing {
+                shutting_down: true,
+                half_close: ref mut state_half_close,
+                ..
+            } => {
+                if !half_close {
+                    *state_half_close = false;
+                }
+            }
+            _ => self.begin_shutdown(half_close)?,
+        }
+        Ok(())
+    }
 
-                self.state = State::Initializing {
-                    needs_flush: false,
-                    more_calls: true,
-                    shutting_down: true,
-                    validated: false,
-                };
-                self.needs_read = 0;
+    /// Applies the shutdown control token, after which `initialize` will
+    /// generate and send a close_notify alert.
+    ///
+    /// With `half_close` reading continues once the alert has been sent.
+    fn begin_shutdown(&mut self, half_close: bool) -> io::Result<()> {
+        debug!(
+            "shutting down the TLS session{}",
+            if half_close { " for writing" } else { "" }
+        );
+        let mut token = Identity::SCHANNEL_SHUTDOWN;
+        self.apply_control_token(&mut token)?;
+
+        self.state = State::Initializing {
+            more_calls: true,
+            shutting_down: true,
+            half_close,
+            validated: false,
+        };
+        self.needs_read = 0;
+        Ok(())
+    }
+
+    /// Handles a close_notify alert from the peer by replying with our own,
+    /// unless it has been sent already.
+    fn peer_closed(&mut self) -> io::Result<()> {
+        self.close_notify_received = true;
+        match self.state {
+            State::WriteShutdown => {
+                self.state = State::Shutdown;
+                Ok(())
             }
+            _ => self.begin_shutdown(false),
         }
+    }
 
-        self.initialize().map(|_| ())
+    /// Advances the handshake or shutdown in progress by one step.
+    ///
+    /// Returns `true` if more data from the peer is needed to continue.
+    fn advance(&mut self) -> io::Result<bool> {
+        let (more_calls, shutting_down, half_close, validated) = match self.state {
+            State::Initializing {
+                more_calls,
+                shutting_down,
+                half_close,
+                validated,
+            } => (more_calls, shutting_down, half_close, validated),
+            _ => return Ok(false),
+        };
+
+        if !shutting_down && !validated {
+            // on the last call, we require a valid certificate
+            if self.validate(!more_calls)? {
+                if let State::Initializing {
+                    ref mut validated, ..
+                } = self.state
+                {
+                    *validated = true;
+                }
+            }
+        }
+
+        if !more_calls {
+            if !shutting_down
+                && self.server
+                && self.client_cert_policy == ClientCertPolicy::Require
+                && self.peer_certificate()?.is_none()
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "the client didn't present a certificate",
+                ));
+            }
+            if !shutting_down && matches!(self.key_log, Some(ref k) if !k.is_complete()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Schannel didn't export the traffic secrets to log",
+                ));
+            }
+            if !shutting_down && self.stats.handshake_duration.is_none() {
+                self.stats.handshake_duration = Some(self.created.elapsed());
+            }
+            self.state = if half_close {
+                State::WriteShutdown
+            } else if shutting_down {
+                State::Shutdown
+            } else {
+                State::Streaming {
+                    sizes: self
+                        .context
+                        .stream_sizes()
+                        .map_err(|e| self.with_context(e, "QueryContextAttributesW"))?,
+                }
+            };
+            debug!("TLS session state is now {:?}", self.session_state());
+            self.shrink_buffers();
+            return Ok(false);
+        }
+
+        if self.needs_read > 0 {
+            return Ok(true);
+        }
+
+        self.step_initialize()?;
+        Ok(false)
+    }
+
+    /// Returns the requirements passed to AcceptSecurityContext or
+    /// InitializeSecurityContextW.
+    fn context_requests(&self) -> u32 {
+        if !self.server {
+            return INIT_REQUESTS;
+        }
+        match self.client_cert_policy {
+            ClientCertPolicy::Ignore => ACCEPT_REQUESTS,
+            ClientCertPolicy::Request | ClientCertPolicy::Require => {
+                ACCEPT_REQUESTS | Identity::ASC_REQ_MUTUAL_AUTH
+            }
+        }
     }
 
     fn step_initialize(&mut self) -> io::Result<()> {
         unsafe {
-            let pos = self.enc_in.position() as usize;
+            // Data received while shutting down belongs to the session rather
+            // than to the shutdown exchange, so it is left for reading.
+            let shutting_down = match self.state {
+                State::Initializing { shutting_down, .. } => shutting_down,
+                _ => false,
+            };
+            let len = if shutting_down {
+                0
+            } else {
+                cmp::min(self.input.ciphertext().len(), MAX_SECBUFFER_LEN)
+            };
             let mut inbufs = vec![
                 secbuf(
                     Identity::SECBUFFER_TOKEN,
-                    Some(&mut self.enc_in.get_mut()[..pos]),
-                ),
-                secbuf(Identity::SECBUFFER_EMPTY, None),
+                    Some(&mut self.input.ciphertext_mut()[..len]),
+                )?,
+                secbuf(Identity::SECBUFFER_EMPTY, None)?,
             ];
             // Make sure `AlpnList` is kept alive for the duration of this function.
             let mut alpns = self
                 .requested_application_protocols
                 .as_ref()
-                .map(|alpn| AlpnList::new(alpn));
+                .map(|alpn| AlpnList::new(alpn))
+                .transpose()?;
             if let Some(ref mut alpns) = alpns {
                 inbufs.push(secbuf(
                     Identity::SECBUFFER_APPLICATION_PROTOCOLS,
                     Some(&mut alpns[..]),
-                ));
+                )?);
             };
             let inbuf_desc = secbuf_desc(&mut inbufs[..]);
+            if let Some(ref mut key_log) = self.key_log {
+                key_log.observe_hello(&self.input.ciphertext()[..len]);
+            }
+            if self.server && self.accept_first && self.client_hello_sni.is_none() {
+                self.client_hello_sni = client_hello::server_name(&self.input.ciphertext()[..len]);
+            }
 
             let mut outbufs = [
-                secbuf(Identity::SECBUFFER_TOKEN, None),
-                secbuf(Identity::SECBUFFER_ALERT, None),
-                secbuf(Identity::SECBUFFER_EMPTY, None),
+                secbuf(Identity::SECBUFFER_TOKEN, None)?,
+                secbuf(Identity::SECBUFFER_ALERT, None)?,
+                secbuf(Identity::SECBUFFER_EMPTY, None)?,
             ];
             let mut outbuf_desc = secbuf_desc(&mut outbufs);
 
@@ -478,7 +2697,7 @@ where
                     &self.cred.as_inner(),
                     ptr,
                     &inbuf_desc,
-                    ACCEPT_REQUESTS,
+                    self.context_requests(),
                     0,
                     self.context.get_mut(),
                     &mut outbuf_desc,
@@ -486,16 +2705,16 @@ where
                     ptr::null_mut(),
                 )
             } else {
-                let domain = match self.domain {
-                    Some(ref domain) if self.use_sni => domain.as_ptr() as *mut u16,
-                    _ => ptr::null_mut(),
+                let target_name = match self.target_name {
+                    Some(ref name) => name.as_ptr() as *mut u16,
+                    None => ptr::null_mut(),
                 };
 
                 Identity::InitializeSecurityContextW(
                     &self.cred.as_inner(),
                     self.context.get_mut(),
-                    domain,
-                    INIT_REQUESTS,
+                    target_name,
+                    self.context_requests(),
                     0,
                     0,
                     &inbuf_desc,
@@ -509,31 +2728,53 @@ where
 
             for buf in &outbufs[1..] {
                 if !buf.pvBuffer.is_null() {
+                    if buf.BufferType == Identity::SECBUFFER_TRAFFIC_SECRETS {
+                        if let Some(ref mut key_log) = self.key_log {
+                            key_log.traffic_secrets(buf);
+                        }
+                    }
                     Identity::FreeContextBuffer(buf.pvBuffer);
                 }
             }
 
+            trace!(
+                "{} returned {:#x} for {} bytes of input, {} bytes of output",
+                if self.server {
+                    "AcceptSecurityContext"
+                } else {
+                    "InitializeSecurityContextW"
+                },
+                status,
+                len,
+                outbufs[0].cbBuffer
+            );
+            if inbufs[1].BufferType == Identity::SECBUFFER_EXTRA {
+                trace!("{} bytes of the input were left over", inbufs[1].cbBuffer);
+            }
+
             match status {
                 Foundation::SEC_E_OK => {
-                    let nread = if inbufs[1].BufferType == Identity::SECBUFFER_EXTRA {
-                        self.enc_in.position() as usize - inbufs[1].cbBuffer as usize
-                    } else {
-                        self.enc_in.position() as usize
-                    };
+                    let nread = consumed(len, &inbufs[1])?;
                     let to_write = if outbufs[0].pvBuffer.is_null() {
                         None
                     } else {
                         Some(ContextBuffer(outbufs[0]))
                     };
 
-                    self.consume_enc_in(nread);
-                    self.needs_read = (self.enc_in.position() == 0) as usize;
+                    self.observe(
+                        RecordDirection::Received,
+                        &self.input.ciphertext()[..nread],
+                        status,
+                    );
+                    // Any records following the handshake are decrypted
+                    // once streaming, where a close_notify among them is
+                    // handled.
+                    self.input.consume_ciphertext(nread);
+                    self.needs_read = self.input.ciphertext().is_empty() as usize;
                     if let Some(to_write) = to_write {
+                        self.observe(RecordDirection::Sent, &to_write, status);
                         self.out_buf.get_mut().extend_from_slice(&to_write);
                     }
-                    if self.enc_in.position() != 0 {
-                        self.decrypt()?;
-                    }
                     if let State::Initializing {
                         ref mut more_calls, ..
                     } = self.state
@@ -556,96 +2797,65 @@ where
                     // once we've actually successfully received the full
                     // "token" from the client.
                     self.accept_first = false;
-                    let nread = if inbufs[1].BufferType == Identity::SECBUFFER_EXTRA {
-                        self.enc_in.position() as usize - inbufs[1].cbBuffer as usize
-                    } else {
-                        self.enc_in.position() as usize
-                    };
+                    let nread = consumed(len, &inbufs[1])?;
                     let to_write = ContextBuffer(outbufs[0]);
 
-                    self.consume_enc_in(nread);
-                    self.needs_read = (self.enc_in.position() == 0) as usize;
+                    self.observe(
+                        RecordDirection::Received,
+                        &self.input.ciphertext()[..nread],
+                        status,
+                    );
+                    self.input.consume_ciphertext(nread);
+                    self.needs_read = self.input.ciphertext().is_empty() as usize;
+                    self.observe(RecordDirection::Sent, &to_write, status);
                     self.out_buf.get_mut().extend_from_slice(&to_write);
                 }
+                Foundation::SEC_I_INCOMPLETE_CREDENTIALS
+                    if !self.server && !self.incomplete_creds_retried =>
+                {
+                    // The server asked for a certificate and the credentials
+                    // don't have one. The same input is passed again to
+                    // continue with the credentials of the selector, if any,
+                    // or without a certificate.
+                    let cred = match self.client_cert_selector.clone() {
+                        Some(selector) => selector(&self.accepted_issuers()),
+                        None => None,
+                    };
+                    if let Some(cred) = cred {
+                        debug!("presenting the certificate of the selected credentials");
+                        self.cred = cred.into_inner();
+                    } else {
+                        debug!("no client certificate to present to the server");
+                    }
+                    self.incomplete_creds_retried = true;
+                    self.needs_read = 0;
+                }
                 Foundation::SEC_E_INCOMPLETE_MESSAGE => {
                     self.needs_read = if inbufs[1].BufferType == Identity::SECBUFFER_MISSING {
                         inbufs[1].cbBuffer as usize
                     } else {
                         1
                     };
+                    trace!(
+                        "handshake message incomplete, {} more bytes needed",
+                        self.needs_read
+                    );
                 }
-                err => return Err(io::Error::from_raw_os_error(err)),
-            }
-            Ok(())
-        }
-    }
-
-    fn initialize(&mut self) -> io::Result<Option<Identity::SecPkgContext_StreamSizes>> {
-        loop {
-            match self.state {
-                State::Initializing {
-                    mut needs_flush,
-                    more_calls,
-                    shutting_down,
-                    validated,
-                } => {
-                    if self.write_out()? > 0 {
-                        needs_flush = true;
-                        if let State::Initializing {
-                            ref mut needs_flush,
-                            ..
-                        } = self.state
-                        {
-                            *needs_flush = true;
-                        }
-                    }
-
-                    if needs_flush {
-                        self.stream.flush()?;
-                        if let State::Initializing {
-                            ref mut needs_flush,
-                            ..
-                        } = self.state
-                        {
-                            *needs_flush = false;
-                        }
-                    }
-
-                    if !shutting_down && !validated {
-                        // on the last call, we require a valid certificate
-                        if self.validate(!more_calls)? {
-                            if let State::Initializing {
-                                ref mut validated, ..
-                            } = self.state
-                            {
-                                *validated = true;
-                            }
-                        }
-                    }
-
-                    if !more_calls {
-                        self.state = if shutting_down {
-                            State::Shutdown
-                        } else {
-                            State::Streaming {
-                                sizes: self.context.stream_sizes()?,
-                            }
-                        };
-                        continue;
-                    }
-
-                    if self.needs_read > 0 && self.read_in()? == 0 {
-                        return Err(io::Error::new(
-                            io::ErrorKind::UnexpectedEof,
-                            "unexpected EOF during handshake",
-                        ));
-                    }
-
-                    self.step_initialize()?;
+                err => {
+                    self.observe(
+                        RecordDirection::Received,
+                        &self.input.ciphertext()[..len],
+                        err,
+                    );
+                    let operation = if self.server {
+                        "AcceptSecurityContext"
+                    } else {
+                        "InitializeSecurityContextW"
+                    };
+                    return Err(self.sspi_error(err, operation));
                 }
-                State::Streaming { sizes } => return Ok(Some(sizes)),
-                State::Shutdown => return Ok(None),
             }
+            Ok(())
         }
     }
 
@@ -654,9 +2864,10 @@ where
     /// Returns an error when the verification failed
     fn validate(&mut self, require_cert: bool) -> io::Result<bool> {
         // If we're accepting connections then we don't perform any validation
-        // for the remote certificate, that's what they're doing!
+        // for the remote certificate, that's what they're doing! Only the
+        // usages and key lengths required of clients are checked.
         if self.server {
-            return Ok(false);
+            return self.validate_client_cert();
         }
 
         let cert_context = match self.context.remote_cert() {
@@ -664,54 +2875,7 @@ where
             ret => ret?,
         };
 
-        let cert_chain = unsafe {
-            let cert_store = match (cert_context.cert_store(), &self.cert_store) {
-                (Some(ref mut chain_certs), &Some(ref extra_certs)) => {
-                    for extra_cert in extra_certs.certs() {
-                        chain_certs.add_cert(&extra_cert, CertAdd::ReplaceExisting)?;
-                    }
-                    chain_certs.as_inner()
-                }
-                (Some(chain_certs), &None) => chain_certs.as_inner(),
-                (None, &Some(ref extra_certs)) => extra_certs.as_inner(),
-                (None, &None) => ptr::null_mut(),
-            };
-
-            let flags = Cryptography::CERT_CHAIN_CACHE_END_CERT
-                | Cryptography::CERT_CHAIN_REVOCATION_CHECK_CACHE_ONLY
-                | Cryptography::CERT_CHAIN_REVOCATION_CHECK_CHAIN_EXCLUDE_ROOT;
-
-            let mut para: Cryptography::CERT_CHAIN_PARA = mem::zeroed();
-            para.cbSize = mem::size_of_val(&para) as u32;
-            para.RequestedUsage.dwType = Cryptography::USAGE_MATCH_TYPE_OR;
-
-            let mut identifiers = [
-                szOID_PKIX_KP_SERVER_AUTH.as_ptr() as _,
-                szOID_SERVER_GATED_CRYPTO.as_ptr() as _,
-                szOID_SGC_NETSCAPE.as_ptr() as _,
-            ];
-            para.RequestedUsage.Usage.cUsageIdentifier = identifiers.len() as u32;
-            para.RequestedUsage.Usage.rgpszUsageIdentifier = identifiers.as_mut_ptr();
-
-            let mut cert_chain = mem::zeroed();
-
-            let res = Cryptography::CertGetCertificateChain(
-                Cryptography::HCERTCHAINENGINE::default(),
-                cert_context.as_inner(),
-                ptr::null_mut(),
-                cert_store,
-                &para,
-                flags,
-                ptr::null_mut(),
-                &mut cert_chain,
-            );
-
-            if res != 0 {
-                CertChainContext(cert_chain)
-            } else {
-                return Err(io::Error::last_os_error());
-            }
-        };
+        let cert_chain = self.peer_chain(&cert_context)?;
 
         unsafe {
             // check if we trust the root-CA explicitly
@@ -731,8 +2895,10 @@ where
             let mut extra_para: Cryptography::HTTPSPolicyCallbackData = mem::zeroed();
             extra_para.Anonymous.cbSize = mem::size_of_val(&extra_para) as u32;
             extra_para.dwAuthType = Cryptography::AUTHTYPE_SERVER;
+            // Schannel's matching of IP addresses is inconsistent across
+            // versions, so they are matched below instead
             match self.domain {
-                Some(ref mut domain) if !self.accept_invalid_hostnames => {
+                Some(ref mut domain) if !self.accept_invalid_hostnames && self.ip.is_none() => {
                     extra_para.pwszServerName = domain.as_mut_ptr();
                 }
                 _ => {}
@@ -757,8 +2923,34 @@ where
                 return Err(io::Error::last_os_error());
             }
 
-            let mut verify_result = if status.dwError != Foundation::ERROR_SUCCESS {
-                Err(io::Error::from_raw_os_error(status.dwError as i32))
+            let mut res = status.dwError as i32;
+            let mut chain_index = status.lChainIndex;
+            let mut element_index = status.lElementIndex;
+            if let Some(ip) = self.ip {
+                if res == 0
+                    && !self.accept_invalid_hostnames
+                    && !cert_context.ip_addresses()?.contains(&ip)
+                {
+                    res = Foundation::CERT_E_CN_NO_MATCH;
+                    chain_index = 0;
+                    element_index = 0;
+                }
+            }
+            if res == 0 && !self.has_required_ekus(&cert_context)? {
+                res = Foundation::CERT_E_WRONG_USAGE;
+                chain_index = 0;
+                element_index = 0;
+            }
+            if res == 0 {
+                if let Some((chain, element)) = self.weak_key(&cert_context, &cert_chain)? {
+                    res = Foundation::CERTSRV_E_WEAK_SIGNATURE_OR_KEY;
+                    chain_index = chain;
+                    element_index = element;
+                }
+            }
+
+            let mut verify_result = if res != 0 {
+                Err(self.sspi_error(res, "CertVerifyCertificateChainPolicy"))
             } else {
                 Ok(())
             };
@@ -767,9 +2959,9 @@ where
             if let Some(ref callback) = self.verify_callback {
                 verify_result = callback(CertValidationResult {
                     chain: cert_chain,
-                    res: status.dwError as i32,
-                    chain_index: status.lChainIndex,
-                    element_index: status.lElementIndex,
+                    res,
+                    chain_index,
+                    element_index,
                 });
             }
             verify_result?;
@@ -777,169 +2969,640 @@ where
         Ok(true)
     }
 
-    fn write_out(&mut self) -> io::Result<usize> {
-        let mut out = 0;
-        while self.out_buf.position() as usize != self.out_buf.get_ref().len() {
-            let position = self.out_buf.position() as usize;
-            let nwritten = self.stream.write(&self.out_buf.get_ref()[position..])?;
-            out += nwritten;
-            self.out_buf.set_position((position + nwritten) as u64);
+    /// Checks the certificate presented by a client for the usages passed to
+    /// `Builder::required_eku` and the minimum key lengths, returning false
+    /// if there is nothing to check or the client hasn't presented a
+    /// certificate (yet).
+    fn validate_client_cert(&mut self) -> io::Result<bool> {
+        let checks_key_bits = self.min_rsa_key_bits > 0 || self.min_ecc_key_bits > 0;
+        if self.required_ekus.is_empty() && !checks_key_bits {
+            return Ok(false);
+        }
+        let cert_context = match self.context.remote_cert() {
+            Ok(cert_context) => cert_context,
+            Err(_) => return Ok(false),
+        };
+
+        let cert_chain = self.peer_chain(&cert_context)?;
+        if !self.required_ekus.is_empty() {
+            let status = unsafe { (*cert_chain.0).TrustStatus.dwErrorStatus };
+            if status & Cryptography::CERT_TRUST_IS_NOT_VALID_FOR_USAGE != 0
+                || !self.has_required_ekus(&cert_context)?
+            {
+                return Err(
+                    self.sspi_error(Foundation::CERT_E_WRONG_USAGE, "CertGetCertificateChain")
+                );
+            }
+        }
+        if self.weak_key(&cert_context, &cert_chain)?.is_some() {
+            return Err(self.sspi_error(
+                Foundation::CERTSRV_E_WEAK_SIGNATURE_OR_KEY,
+                "CertGetPublicKeyLength",
+            ));
+        }
+        Ok(true)
+    }
+
+    /// Returns the chain and element index of the first certificate whose
+    /// key is shorter than `Builder::min_rsa_key_bits` or
+    /// `Builder::min_ecc_key_bits` allow, looking beyond the peer's
+    /// certificate only if `Builder::min_key_bits_in_chain` is set.
+    fn weak_key(
+        &self,
+        cert_context: &CertContext,
+        cert_chain: &CertChainContext,
+    ) -> io::Result<Option<(i32, i32)>> {
+        if self.min_rsa_key_bits == 0 && self.min_ecc_key_bits == 0 {
+            return Ok(None);
+        }
+        if !self.min_key_bits_in_chain {
+            return Ok(self.is_weak_key(cert_context)?.then_some((0, 0)));
         }
+        for (i, chain) in cert_chain.chains().enumerate() {
+            for (j, cert) in chain.certificates().enumerate() {
+                if self.is_weak_key(&cert)? {
+                    return Ok(Some((i as i32, j as i32)));
+                }
+            }
+        }
+        Ok(None)
+    }
 
-        Ok(out)
+    fn is_weak_key(&self, cert: &CertContext) -> io::Result<bool> {
+        let algorithm = cert.public_key_algorithm();
+        let min_bits = match &algorithm[..] {
+            Cryptography::szOID_RSA_RSA | Cryptography::szOID_RSA_SSA_PSS => self.min_rsa_key_bits,
+            Cryptography::szOID_ECC_PUBLIC_KEY => self.min_ecc_key_bits,
+            _ => return Ok(false),
+        };
+        Ok(min_bits > 0 && cert.public_key_bits()? < min_bits)
     }
 
-    fn read_in(&mut self) -> io::Result<usize> {
-        let mut sum_nread = 0;
+    /// Builds the chain of the peer's certificate, requesting the usages
+    /// passed to `Builder::required_eku` or else those of servers.
+    fn peer_chain(&self, cert_context: &CertContext) -> io::Result<CertChainContext> {
+        unsafe {
+            let cert_store = match (cert_context.cert_store(), &self.cert_store) {
+                (Some(ref mut chain_certs), &Some(ref extra_certs)) => {
+                    for extra_cert in extra_certs.certs() {
+                        chain_certs.add_cert(&extra_cert, CertAdd::ReplaceExisting)?;
+                    }
+                    chain_certs.as_inner()
+                }
+                (Some(chain_certs), &None) => chain_certs.as_inner(),
+                (None, &Some(ref extra_certs)) => extra_certs.as_inner(),
+                (None, &None) => ptr::null_mut(),
+            };
+
+            let flags = Cryptography::CERT_CHAIN_CACHE_END_CERT
+                | Cryptography::CERT_CHAIN_REVOCATION_CHECK_CACHE_ONLY
+                | Cryptography::CERT_CHAIN_REVOCATION_CHECK_CHAIN_EXCLUDE_ROOT;
+
+            let mut para: Cryptography::CERT_CHAIN_PARA = mem::zeroed();
+            para.cbSize = mem::size_of_val(&para) as u32;
+
+            let mut identifiers: Vec<*mut u8> = if self.required_ekus.is_empty() {
+                para.RequestedUsage.dwType = Cryptography::USAGE_MATCH_TYPE_OR;
+                vec![
+                    szOID_PKIX_KP_SERVER_AUTH.as_ptr() as _,
+                    szOID_SERVER_GATED_CRYPTO.as_ptr() as _,
+                    szOID_SGC_NETSCAPE.as_ptr() as _,
+                ]
+            } else {
+                para.RequestedUsage.dwType = Cryptography::USAGE_MATCH_TYPE_AND;
+                self.required_ekus
+                    .iter()
+                    .map(|oid| oid.as_ptr() as _)
+                    .collect()
+            };
+            para.RequestedUsage.Usage.cUsageIdentifier = identifiers.len() as u32;
+            para.RequestedUsage.Usage.rgpszUsageIdentifier = identifiers.as_mut_ptr();
+
+            let mut cert_chain = ptr::null_mut();
+
+            let res = Cryptography::CertGetCertificateChain(
+                Cryptography::HCERTCHAINENGINE::default(),
+                cert_context.as_inner(),
+                ptr::null_mut(),
+                cert_store,
+                &para,
+                flags,
+                ptr::null_mut(),
+                &mut cert_chain,
+            );
+
+            if res != 0 {
+                Ok(CertChainContext(cert_chain))
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        }
+    }
+
+    /// Returns true if the extended key usages of `cert_context` include all
+    /// of those passed to `Builder::required_eku`.
+    ///
+    /// Certificates without any are valid for every usage as far as chains
+    /// are concerned, but don't carry the required ones.
+    fn has_required_ekus(&self, cert_context: &CertContext) -> io::Result<bool> {
+        if self.required_ekus.is_empty() {
+            return Ok(true);
+        }
+        let oids = match cert_context.valid_uses()? {
+            ValidUses::All => return Ok(false),
+            ValidUses::Oids(oids) => oids,
+        };
+        let has = |eku: &Vec<u8>| {
+            oids.iter()
+                .any(|oid| oid.as_bytes() == &eku[..eku.len() - 1])
+        };
+        Ok(self.required_ekus.iter().all(has))
+    }
+
+    fn read_in<R: Read + ?Sized>(&mut self, stream: &mut R) -> io::Result<usize> {
+        let (read_size, max_message_size) = match self.state {
+            State::Streaming { ref sizes } => (read_size(sizes), self.max_record_size),
+            State::WriteShutdown => (HANDSHAKE_READ_SIZE, self.max_record_size),
+            _ => (HANDSHAKE_READ_SIZE, self.max_handshake_message_size),
+        };
+        self.input
+            .read_from(stream, &mut self.needs_read, read_size, max_message_size)
+    }
+
+    /// Returns the names of the certificate authorities accepted by the
+    /// server for client certificates.
+    fn accepted_issuers(&self) -> Vec<Vec<u8>> {
+        self.context.issuer_list().unwrap_or_else(|_e| {
+            debug!("failed to query the accepted issuers: {}", _e);
+            Vec::new()
+        })
+    }
+
+    /// Returns the phase of the session reported along with Schannel errors.
+    fn phase(&self) -> &'static str {
+        match self.state {
+            State::Initializing {
+                shutting_down: true,
+                ..
+            }
+            | State::WriteShutdown
+            | State::Shutdown => "shutdown",
+            State::Initializing { .. } if self.stats.handshake_duration.is_some() => {
+                "renegotiation"
+            }
+            State::Initializing { .. } => "handshake",
+            State::Streaming { .. } => "data transfer",
+        }
+    }
+
+    /// Creates the error for a call to `operation` which failed with `code`.
+    fn sspi_error(&self, code: i32, operation: &'static str) -> io::Error {
+        error::Error::from_code(code)
+            .with_context(operation, self.phase())
+            .into()
+    }
+
+    /// Adds the name of the failing function and the phase of the session to
+    /// `err` if it is a Schannel error.
+    fn with_context(&self, err: io::Error, operation: &'static str) -> io::Error {
+        with_context(err, operation, self.phase())
+    }
+
+    /// Passes the records in `data` to the record observer, if any.
+    fn observe(&self, direction: RecordDirection, mut data: &[u8], status: i32) {
+        if let Some(ref observer) = self.record_observer {
+            while !data.is_empty() {
+                let len = record_len(data);
+                observer(&Record {
+                    direction,
+                    data: &data[..len],
+                    status,
+                });
+                data = &data[len..];
+            }
+        }
+    }
+
+    fn decrypt(&mut self) -> io::Result<bool> {
+        let ciphertext_len = self.input.ciphertext().len();
+        let plaintext_len = self.input.plaintext().len();
+        // The record is decrypted in place, so the observer gets a copy.
+        let record = match self.record_observer {
+            Some(_) => {
+                let ciphertext = self.input.ciphertext();
+                ciphertext[..record_len(ciphertext)].to_vec()
+            }
+            None => Vec::new(),
+        };
+        let decrypted = match decrypt(&mut self.context, &mut self.input, &mut self.needs_read) {
+            Ok(decrypted) => decrypted,
+            Err(e) => {
+                let status = e.raw_os_error().unwrap_or(Foundation::SEC_E_INTERNAL_ERROR);
+                self.observe(RecordDirection::Received, &record, status);
+                return Err(self.with_context(e, "DecryptMessage"));
+            }
+        };
+        if self.input.ciphertext().len() < ciphertext_len {
+            self.stats.records_received += 1;
+            let status = match decrypted {
+                Decrypted::Data => Foundation::SEC_E_OK,
+                Decrypted::Expired => Foundation::SEC_I_CONTEXT_EXPIRED,
+                Decrypted::Renegotiate => Foundation::SEC_I_RENEGOTIATE,
+            };
+            self.observe(RecordDirection::Received, &record, status);
+        }
+        match decrypted {
+            Decrypted::Data => {
+                self.stats.bytes_read += (self.input.plaintext().len() - plaintext_len) as u64;
+                Ok(false)
+            }
+            Decrypted::Expired => Ok(true),
+            Decrypted::Renegotiate => {
+                self.stats.renegotiations += 1;
+                // TLS 1.3 has no renegotiation, this is how Schannel hands
+                // over post-handshake messages such as NewSessionTicket and
+                // KeyUpdate. A single call with the buffered message handles
+                // them, and as the peer's certificate can't change it isn't
+                // validated again.
+                let post_handshake = is_tls13(&self.context);
+                debug!(
+                    "{} received from the peer",
+                    if post_handshake {
+                        "post-handshake message"
+                    } else {
+                        "renegotiation request"
+                    }
+                );
+                // The call is passed what follows the message, which
+                // DecryptMessage leaves as ciphertext. Without anything
+                // following it, one would only ask for more data, while what
+                // comes next is application data to decrypt, so the session
+                // goes on streaming as engine_handshake_messages_alone checks.
+                if post_handshake && self.input.ciphertext().is_empty() {
+                    return Ok(false);
+                }
+                let half_close = matches!(self.state, State::WriteShutdown);
+                self.state = State::Initializing {
+                    more_calls: true,
+                    shutting_down: false,
+                    half_close,
+                    validated: post_handshake,
+                };
+                Ok(false)
+            }
+        }
+    }
+
+    /// Decrypts all complete records buffered in the input, appending their
+    /// data to the plaintext which hasn't been read yet.
+    ///
+    /// Like `decrypt`, returns true once the peer's close_notify has been
+    /// received. The pass also stops at handshake messages, which have to be
+    /// processed before the records behind them can be decrypted.
+    fn decrypt_buffered(&mut self) -> io::Result<bool> {
+        loop {
+            if self.decrypt()? {
+                return Ok(true);
+            }
+            let streaming = matches!(self.state, State::Streaming { .. } | State::WriteShutdown);
+            if !streaming || self.needs_read > 0 || !self.input.has_complete_record() {
+                return Ok(false);
+            }
+        }
+    }
+
+    fn encrypt(
+        &mut self,
+        buf: &[u8],
+        sizes: &Identity::SecPkgContext_StreamSizes,
+    ) -> io::Result<()> {
+        encrypt(&mut self.context, &mut self.out_buf, buf, sizes)
+            .map_err(|e| self.with_context(e, "EncryptMessage"))?;
+        self.observe(
+            RecordDirection::Sent,
+            self.out_buf.get_ref(),
+            Foundation::SEC_E_OK,
+        );
+        self.stats.bytes_written += buf.len() as u64;
+        self.stats.records_sent += 1;
+        Ok(())
+    }
+
+    /// Encrypts `buf[payload]` in place and moves the resulting record into
+    /// the output buffer, which must be empty.
+    fn encrypt_prepared(
+        &mut self,
+        buf: &mut Vec<u8>,
+        payload: Range<usize>,
+        sizes: &Identity::SecPkgContext_StreamSizes,
+    ) -> io::Result<()> {
+        let header = sizes.cbHeader as usize;
+        let trailer = sizes.cbTrailer as usize;
+        let fits = payload.start <= payload.end
+            && payload.start >= header
+            && payload.end - payload.start <= sizes.cbMaximumMessage as usize
+            && payload
+                .end
+                .checked_add(trailer)
+                .is_some_and(|end| end <= buf.len());
+        if !fits {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the payload doesn't leave room for the record header and trailer",
+            ));
+        }
+
+        let start = payload.start - header;
+        let message_len = payload.end - payload.start;
+        let len = encrypt_in_place(
+            &mut self.context,
+            &mut buf[start..payload.end + trailer],
+            header,
+            message_len,
+        )
+        .map_err(|e| self.with_context(e, "EncryptMessage"))?;
+
+        // Take over the caller's buffer rather than copying the record.
+        mem::swap(self.out_buf.get_mut(), buf);
+        buf.clear();
+        self.out_buf.get_mut().truncate(start + len);
+        self.out_buf.set_position(start as u64);
+
+        self.observe(
+            RecordDirection::Sent,
+            &self.out_buf.get_ref()[start..],
+            Foundation::SEC_E_OK,
+        );
+        self.stats.bytes_written += message_len as u64;
+        self.stats.records_sent += 1;
+        Ok(())
+    }
+}
+
+fn write_out<W: Write + ?Sized>(
+    stream: &mut W,
+    out_buf: &mut Cursor<Vec<u8>>,
+) -> io::Result<usize> {
+    let mut out = 0;
+    while out_buf.position() as usize != out_buf.get_ref().len() {
+        let position = out_buf.position() as usize;
+        let nwritten = stream.write(&out_buf.get_ref()[position..])?;
+        if nwritten == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "failed to write whole buffer",
+            ));
+        }
+        out += nwritten;
+        out_buf.set_position((position + nwritten) as u64);
+    }
+
+    Ok(out)
+}
+
+/// Returns the amount of data to read at once, which is enough for a couple
+/// of full-sized records.
+fn read_size(sizes: &Identity::SecPkgContext_StreamSizes) -> usize {
+    let record = sizes.cbHeader + sizes.cbMaximumMessage + sizes.cbTrailer;
+    2 * record as usize
+}
+
+/// The outcome of a successful `decrypt` call.
+enum Decrypted {
+    /// Application data (possibly none yet) was decrypted in place.
+    Data,
+    /// The peer has closed the session.
+    Expired,
+    /// The peer requested a renegotiation; the handshake data is left at the
+    /// start of the ciphertext.
+    Renegotiate,
+}
+
+/// Returns the number of bytes consumed by a call which was passed `len` bytes
+/// of input, given the buffer indicating any bytes left over.
+fn consumed(len: usize, extra: &Identity::SecBuffer) -> io::Result<usize> {
+    if extra.BufferType != Identity::SECBUFFER_EXTRA {
+        return Ok(len);
+    }
+    len.checked_sub(extra.cbBuffer as usize)
+        .ok_or_else(invalid_buffer)
+}
+
+/// Wraps the Schannel error carried as the raw OS error of `err`, if any,
+/// along with the name of the failing function and the phase of the session.
+fn with_context(err: io::Error, operation: &'static str, phase: &'static str) -> io::Error {
+    if err.get_ref().is_some() {
+        return err;
+    }
+    match error::Error::from_io_error(&err) {
+        Some(e) => e.with_context(operation, phase).into(),
+        None => err,
+    }
+}
+
+fn is_tls13(context: &SecurityContext) -> bool {
+    match context.connection_info() {
+        Ok(info) => {
+            info.dwProtocol & (Identity::SP_PROT_TLS1_3_CLIENT | Identity::SP_PROT_TLS1_3_SERVER)
+                != 0
+        }
+        Err(_) => false,
+    }
+}
+
+fn invalid_buffer() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Schannel returned a buffer outside of the input",
+    )
+}
 
-        while self.needs_read > 0 {
-            let existing_len = self.enc_in.position() as usize;
-            let min_len = cmp::max(cmp::max(1024, 2 * existing_len), self.needs_read);
-            if self.enc_in.get_ref().len() < min_len {
-                self.enc_in.get_mut().resize(min_len, 0);
+fn decrypt(
+    context: &mut SecurityContext,
+    input: &mut InputBuffer,
+    needs_read: &mut usize,
+) -> io::Result<Decrypted> {
+    unsafe {
+        // Anything beyond the first 4 GiB is simply left for the next call.
+        let len = cmp::min(input.ciphertext().len(), MAX_SECBUFFER_LEN);
+        let mut bufs = [
+            secbuf(
+                Identity::SECBUFFER_DATA,
+                Some(&mut input.ciphertext_mut()[..len]),
+            )?,
+            secbuf(Identity::SECBUFFER_EMPTY, None)?,
+            secbuf(Identity::SECBUFFER_EMPTY, None)?,
+            secbuf(Identity::SECBUFFER_EMPTY, None)?,
+        ];
+        let bufdesc = secbuf_desc(&mut bufs);
+
+        match Identity::DecryptMessage(context.get_mut(), &bufdesc, 0, ptr::null_mut()) {
+            Foundation::SEC_E_OK => {
+                let nread = consumed(len, &bufs[3])?;
+                // The data is decrypted in place, so it can be handed out
+                // straight from the input buffer.
+                let data = if bufs[1].cbBuffer == 0 {
+                    0..0
+                } else {
+                    let start = (bufs[1].pvBuffer as usize)
+                        .checked_sub(input.ciphertext().as_ptr() as usize)
+                        .ok_or_else(invalid_buffer)?;
+                    start..start + bufs[1].cbBuffer as usize
+                };
+                trace!(
+                    "decrypted a record of {} bytes into {} bytes of data",
+                    nread,
+                    data.len()
+                );
+                input.decrypted(data, nread)?;
+                *needs_read = input.ciphertext().is_empty() as usize;
+                Ok(Decrypted::Data)
+            }
+            Foundation::SEC_E_INCOMPLETE_MESSAGE => {
+                *needs_read = if bufs[1].BufferType == Identity::SECBUFFER_MISSING {
+                    bufs[1].cbBuffer as usize
+                } else {
+                    1
+                };
+                trace!("record incomplete, {} more bytes needed", *needs_read);
+                Ok(Decrypted::Data)
+            }
+            Foundation::SEC_I_CONTEXT_EXPIRED => {
+                // Nothing after a close_notify can be decrypted anymore.
+                debug!("received close_notify from the peer");
+                input.clear_ciphertext();
+                *needs_read = 0;
+                Ok(Decrypted::Expired)
             }
-            let nread = {
-                let buf = &mut self.enc_in.get_mut()[existing_len..];
-                self.stream.read(buf)?
-            };
-            self.enc_in.set_position((existing_len + nread) as u64);
-            self.needs_read = self.needs_read.saturating_sub(nread);
-            if nread == 0 {
-                break;
+            Foundation::SEC_I_RENEGOTIATE => {
+                let nread = consumed(len, &bufs[3])?;
+                trace!(
+                    "handshake record of {} bytes, {} bytes left over",
+                    nread,
+                    len - nread
+                );
+                input.consume_ciphertext(nread);
+                *needs_read = 0;
+                Ok(Decrypted::Renegotiate)
+            }
+            err => {
+                trace!("DecryptMessage returned {:#x}", err);
+                Err(io::Error::from_raw_os_error(err))
             }
-            sum_nread += nread;
         }
-
-        Ok(sum_nread)
     }
+}
 
-    fn consume_enc_in(&mut self, nread: usize) {
-        let size = self.enc_in.position() as usize;
-        assert!(size >= nread);
-        let count = size - nread;
-
-        if count > 0 {
-            self.enc_in.get_mut().drain(..nread);
-        }
-
-        self.enc_in.set_position(count as u64);
+fn encrypt(
+    context: &mut SecurityContext,
+    out_buf: &mut Cursor<Vec<u8>>,
+    buf: &[u8],
+    sizes: &Identity::SecPkgContext_StreamSizes,
+) -> io::Result<()> {
+    if buf.len() > sizes.cbMaximumMessage as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "message exceeds the maximum record size",
+        ));
     }
 
-    fn decrypt(&mut self) -> io::Result<bool> {
-        unsafe {
-            let position = self.enc_in.position() as usize;
-            let mut bufs = [
-                secbuf(
-                    Identity::SECBUFFER_DATA,
-                    Some(&mut self.enc_in.get_mut()[..position]),
-                ),
-                secbuf(Identity::SECBUFFER_EMPTY, None),
-                secbuf(Identity::SECBUFFER_EMPTY, None),
-                secbuf(Identity::SECBUFFER_EMPTY, None),
-            ];
-            let bufdesc = secbuf_desc(&mut bufs);
+    let header = sizes.cbHeader as usize;
+    let len = header + buf.len() + sizes.cbTrailer as usize;
 
-            match Identity::DecryptMessage(self.context.get_mut(), &bufdesc, 0, ptr::null_mut()) {
-                Foundation::SEC_E_OK => {
-                    let start = bufs[1].pvBuffer as usize - self.enc_in.get_ref().as_ptr() as usize;
-                    let end = start + bufs[1].cbBuffer as usize;
-                    self.dec_in.get_mut().clear();
-                    self.dec_in
-                        .get_mut()
-                        .extend_from_slice(&self.enc_in.get_ref()[start..end]);
-                    self.dec_in.set_position(0);
-
-                    let nread = if bufs[3].BufferType == Identity::SECBUFFER_EXTRA {
-                        self.enc_in.position() as usize - bufs[3].cbBuffer as usize
-                    } else {
-                        self.enc_in.position() as usize
-                    };
-                    self.consume_enc_in(nread);
-                    self.needs_read = (self.enc_in.position() == 0) as usize;
-                    Ok(false)
-                }
-                Foundation::SEC_E_INCOMPLETE_MESSAGE => {
-                    self.needs_read = if bufs[1].BufferType == Identity::SECBUFFER_MISSING {
-                        bufs[1].cbBuffer as usize
-                    } else {
-                        1
-                    };
-                    Ok(false)
-                }
-                Foundation::SEC_I_CONTEXT_EXPIRED => Ok(true),
-                Foundation::SEC_I_RENEGOTIATE => {
-                    self.state = State::Initializing {
-                        needs_flush: false,
-                        more_calls: true,
-                        shutting_down: false,
-                        validated: false,
-                    };
+    // Output which hasn't been written out yet, such as the response to a
+    // post-handshake message queued by the read half of a split stream,
+    // stays in front of the record. Otherwise the buffer still holds the
+    // previous record, which has been written out already. Its header and
+    // trailer space is simply overwritten, so only bytes beyond its length
+    // need to be initialized.
+    let start = if out_buf.position() == out_buf.get_ref().len() as u64 {
+        out_buf.set_position(0);
+        0
+    } else {
+        out_buf.get_ref().len()
+    };
+    {
+        let out_buf = out_buf.get_mut();
+        out_buf.resize(start + len, 0);
+        out_buf[start + header..start + header + buf.len()].copy_from_slice(buf);
+    }
 
-                    let nread = if bufs[3].BufferType == Identity::SECBUFFER_EXTRA {
-                        self.enc_in.position() as usize - bufs[3].cbBuffer as usize
-                    } else {
-                        self.enc_in.position() as usize
-                    };
-                    self.consume_enc_in(nread);
-                    self.needs_read = 0;
-                    Ok(false)
-                }
-                err => Err(io::Error::from_raw_os_error(err)),
-            }
+    match encrypt_in_place(context, &mut out_buf.get_mut()[start..], header, buf.len()) {
+        Ok(len) => {
+            out_buf.get_mut().truncate(start + len);
+            Ok(())
+        }
+        Err(e) => {
+            // Don't leave the plaintext behind.
+            zeroize(&mut out_buf.get_mut()[start..]);
+            out_buf.get_mut().truncate(start);
+            Err(e)
         }
     }
+}
 
-    fn encrypt(
-        &mut self,
-        buf: &[u8],
-        sizes: &Identity::SecPkgContext_StreamSizes,
-    ) -> io::Result<()> {
-        assert!(buf.len() <= sizes.cbMaximumMessage as usize);
-
-        unsafe {
-            let len = sizes.cbHeader as usize + buf.len() + sizes.cbTrailer as usize;
-
-            if self.out_buf.get_ref().len() < len {
-                self.out_buf.get_mut().resize(len, 0);
-            }
-
-            let message_start = sizes.cbHeader as usize;
-            self.out_buf.get_mut()[message_start..message_start + buf.len()].clone_from_slice(buf);
-
-            let mut bufs = {
-                let out_buf = self.out_buf.get_mut();
-                let size = sizes.cbHeader as usize;
+/// Encrypts the message of `message_len` bytes following the first
+/// `header_len` bytes of `record` in place, with the rest of `record` as room
+/// for the trailer.
+///
+/// Returns the length of the record.
+fn encrypt_in_place(
+    context: &mut SecurityContext,
+    record: &mut [u8],
+    header_len: usize,
+    message_len: usize,
+) -> io::Result<usize> {
+    unsafe {
+        let mut bufs = {
+            let (header, rest) = record.split_at_mut(header_len);
+            let (data, trailer) = rest.split_at_mut(message_len);
+            [
+                secbuf(Identity::SECBUFFER_STREAM_HEADER, Some(header))?,
+                secbuf(Identity::SECBUFFER_DATA, Some(data))?,
+                secbuf(Identity::SECBUFFER_STREAM_TRAILER, Some(trailer))?,
+                secbuf(Identity::SECBUFFER_EMPTY, None)?,
+            ]
+        };
+        let bufdesc = secbuf_desc(&mut bufs);
 
-                let header = secbuf(
-                    Identity::SECBUFFER_STREAM_HEADER,
-                    Some(&mut out_buf[..size]),
-                );
-                let data = secbuf(
-                    Identity::SECBUFFER_DATA,
-                    Some(&mut out_buf[size..size + buf.len()]),
+        match Identity::EncryptMessage(context.get_mut(), 0, &bufdesc, 0) {
+            Foundation::SEC_E_OK => {
+                let len = bufs[0].cbBuffer as usize
+                    + bufs[1].cbBuffer as usize
+                    + bufs[2].cbBuffer as usize;
+                trace!(
+                    "encrypted {} bytes into a record of {} bytes",
+                    message_len,
+                    len
                 );
-                let trailer = secbuf(
-                    Identity::SECBUFFER_STREAM_TRAILER,
-                    Some(&mut out_buf[size + buf.len()..]),
-                );
-                let empty = secbuf(Identity::SECBUFFER_EMPTY, None);
-                [header, data, trailer, empty]
-            };
-            let bufdesc = secbuf_desc(&mut bufs);
-
-            match Identity::EncryptMessage(self.context.get_mut(), 0, &bufdesc, 0) {
-                Foundation::SEC_E_OK => {
-                    let len = bufs[0].cbBuffer + bufs[1].cbBuffer + bufs[2].cbBuffer;
-                    self.out_buf.get_mut().truncate(len as usize);
-                    self.out_buf.set_position(0);
-                    Ok(())
-                }
-                err => Err(io::Error::from_raw_os_error(err)),
+                Ok(len)
+            }
+            err => {
+                trace!("EncryptMessage returned {:#x}", err);
+                Err(io::Error::from_raw_os_error(err))
             }
         }
     }
 }
 
+/// The raw socket of the wrapped stream.
+///
+/// See the implementation for `TlsStream`.
+impl<S: AsRawSocket> AsRawSocket for MidHandshakeTlsStream<S> {
+    fn as_raw_socket(&self) -> RawSocket {
+        self.inner.as_raw_socket()
+    }
+}
+
+/// The raw handle of the wrapped stream.
+///
+/// See the implementation for `TlsStream`.
+impl<S: AsRawHandle> AsRawHandle for MidHandshakeTlsStream<S> {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.inner.as_raw_handle()
+    }
+}
+
 impl<S> MidHandshakeTlsStream<S> {
     /// Returns a shared reference to the inner stream.
     pub fn get_ref(&self) -> &S {
@@ -958,12 +3621,16 @@ where
 {
     /// Restarts the handshake process.
     pub fn handshake(mut self) -> Result<TlsStream<S>, HandshakeError<S>> {
-        match self.inner.initialize() {
-            Ok(_) => Ok(self.inner),
+        match self
+            .inner
+            .initialize()
+            .and_then(|_| self.inner.flush_output())
+        {
+            Ok(_) => Ok(*self.inner),
             Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                 Err(HandshakeError::Interrupted(self))
             }
-            Err(e) => Err(HandshakeError::Failure(e)),
+            Err(e) => Err(HandshakeError::from_io(e)),
         }
     }
 }
@@ -980,26 +3647,42 @@ where
             Some(sizes) => sizes,
             None => {
                 return Err(io::Error::from_raw_os_error(
-                    Foundation::SEC_E_CONTEXT_EXPIRED as i32,
+                    Foundation::SEC_E_CONTEXT_EXPIRED,
                 ))
             }
         };
 
-        // if we have pending output data, it must have been because a previous
-        // attempt to send this part of the data ran into an error.
-        if self.out_buf.position() == self.out_buf.get_ref().len() as u64 {
+        // if a write is pending, it must have been because a previous attempt
+        // to send this part of the data ran into an error. Its output may have
+        // been sent by `flush` in the meantime.
+        if !self.write_pending {
+            // The record is encrypted in the output buffer, so anything left
+            // in there, such as the rest of a record of `write_prepared`, has
+            // to be sent first.
+            self.write_out()?;
             let len = cmp::min(buf.len(), sizes.cbMaximumMessage as usize);
-            self.encrypt(&buf[..len], &sizes)?;
+            self.engine.encrypt(&buf[..len], &sizes)?;
             self.last_write_len = len;
+            self.write_pending = true;
         }
         self.write_out()?;
+        self.write_pending = false;
+        self.engine.shrink_buffers();
 
         Ok(self.last_write_len)
     }
 
+    /// Writes out all pending encrypted data, including handshake messages of
+    /// a renegotiation in progress, before flushing the wrapped stream.
+    ///
+    /// If this is interrupted by a `WouldBlock` error it can be called again
+    /// to resume. A `write` call which previously failed with `WouldBlock`
+    /// still has to be repeated with the same data, but will not send that
+    /// data a second time.
     fn flush(&mut self) -> io::Result<()> {
         // Make sure the write buffer is emptied
         self.write_out()?;
+        self.engine.shrink_buffers();
         self.stream.flush()
     }
 }
@@ -1018,6 +3701,24 @@ where
         self.consume(nread);
         Ok(nread)
     }
+
+    /// Appends the decrypted records to `buf` straight from the internal
+    /// buffer, without going through an intermediate buffer.
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let start = buf.len();
+        loop {
+            let len = match self.fill_buf() {
+                Ok([]) => return Ok(buf.len() - start),
+                Ok(data) => {
+                    buf.extend_from_slice(data);
+                    data.len()
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            self.consume(len);
+        }
+    }
 }
 
 impl<S> BufRead for TlsStream<S>
@@ -1026,20 +3727,259 @@ where
 {
     fn fill_buf(&mut self) -> io::Result<&[u8]> {
         while self.get_buf().is_empty() {
-            if self.initialize()?.is_none() {
+            if !self.read_step(true)? {
                 break;
             }
+        }
+
+        Ok(self.get_buf())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.engine.input.consume_plaintext(amt);
+        self.engine.shrink_buffers();
+    }
+}
+
+/// The raw socket of the wrapped stream.
+///
+/// As with `get_ref`, the socket may be used to change socket options or to
+/// register it with an event loop, but not to send or receive data. Calling
+/// `shutdown` on the socket from another thread is a way to abort a hung
+/// handshake or read: the pending call then fails with the I/O error
+/// reported by the socket. Switching the socket to nonblocking mode during a
+/// blocking handshake makes the handshake return
+/// `HandshakeError::Interrupted` the next time it would block.
+impl<S: AsRawSocket> AsRawSocket for TlsStream<S> {
+    fn as_raw_socket(&self) -> RawSocket {
+        self.stream.as_raw_socket()
+    }
+}
+
+/// The raw handle of the wrapped stream.
+///
+/// The same restrictions as for `AsRawSocket` apply.
+impl<S: AsRawHandle> AsRawHandle for TlsStream<S> {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.stream.as_raw_handle()
+    }
+}
+
+impl TlsStream<TcpStream> {
+    /// Sets the read timeout of the wrapped socket.
+    ///
+    /// Reads which time out fail with an error of kind `TimedOut` and leave
+    /// the session intact, so they can be retried.
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(dur)
+    }
+
+    /// Sets the write timeout of the wrapped socket.
+    ///
+    /// After a write times out, the next write must be made with the same
+    /// data, as after a `WouldBlock` error.
+    pub fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.stream.set_write_timeout(dur)
+    }
+
+    /// Returns the read timeout of the wrapped socket.
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        self.stream.read_timeout()
+    }
+
+    /// Returns the write timeout of the wrapped socket.
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        self.stream.write_timeout()
+    }
+
+    /// Sets the `TCP_NODELAY` option of the wrapped socket.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.stream.set_nodelay(nodelay)
+    }
+}
+
+#[derive(Copy, Clone)]
+enum SplitState {
+    Streaming(Identity::SecPkgContext_StreamSizes),
+    Renegotiating,
+    Shutdown,
+}
+
+impl SplitState {
+    fn renegotiation_error() -> io::Error {
+        io::Error::other("renegotiation is not supported on a split TLS stream")
+    }
+}
+
+struct SplitShared<S> {
+    cred: SchannelCred,
+    server: bool,
+    target_name: Option<Vec<u16>>,
+    requests: u32,
+    context: Mutex<SplitContext>,
+    stream: S,
+}
+
+struct SplitContext {
+    context: SecurityContext,
+    /// responses to post-handshake messages received by the read half, sent
+    /// by the write half ahead of its next record
+    output: Vec<u8>,
+}
+
+/// The read half of a `TlsStream`, created by `TlsStream::split`.
+pub struct ReadHalf<S> {
+    shared: Arc<SplitShared<S>>,
+    state: SplitState,
+    /// whether a TLS 1.3 post-handshake message is waiting to be continued
+    post_handshake: bool,
+    needs_read: usize,
+    input: InputBuffer,
+    max_record_size: usize,
+}
+
+/// The write half of a `TlsStream`, created by `TlsStream::split`.
+pub struct WriteHalf<S> {
+    shared: Arc<SplitShared<S>>,
+    state: SplitState,
+    // valid from position() to len()
+    out_buf: Cursor<Vec<u8>>,
+    /// the (unencrypted) length of the last write call used to track writes
+    last_write_len: usize,
+    /// whether the last write call was encrypted but not reported as written
+    write_pending: bool,
+}
+
+impl<S> fmt::Debug for ReadHalf<S>
+where
+    S: fmt::Debug,
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("ReadHalf")
+            .field("stream", &self.shared.stream)
+            .finish()
+    }
+}
+
+impl<S> fmt::Debug for WriteHalf<S>
+where
+    S: fmt::Debug,
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("WriteHalf")
+            .field("stream", &self.shared.stream)
+            .finish()
+    }
+}
+
+impl<S> ReadHalf<S> {
+    /// Returns a reference to the wrapped stream.
+    ///
+    /// The same restrictions as for `TlsStream::get_ref` apply.
+    pub fn get_ref(&self) -> &S {
+        &self.shared.stream
+    }
+
+    /// Returns a reference to the buffer of pending data.
+    ///
+    /// Like `BufRead::fill_buf` except that it will return an empty slice
+    /// rather than reading from the wrapped stream if there is no buffered
+    /// data.
+    pub fn get_buf(&self) -> &[u8] {
+        self.input.plaintext()
+    }
+}
+
+impl<S> WriteHalf<S> {
+    /// Returns a reference to the wrapped stream.
+    ///
+    /// The same restrictions as for `TlsStream::get_ref` apply.
+    pub fn get_ref(&self) -> &S {
+        &self.shared.stream
+    }
+}
+
+impl<S> Read for ReadHalf<S>
+where
+    for<'a> &'a S: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let nread = {
+            let read_buf = self.fill_buf()?;
+            let nread = cmp::min(buf.len(), read_buf.len());
+            buf[..nread].copy_from_slice(&read_buf[..nread]);
+            nread
+        };
+        self.consume(nread);
+        Ok(nread)
+    }
+}
+
+impl<S> BufRead for ReadHalf<S>
+where
+    for<'a> &'a S: Read,
+{
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.get_buf().is_empty() {
+            let sizes = match self.state {
+                SplitState::Streaming(sizes) => sizes,
+                SplitState::Renegotiating => return Err(SplitState::renegotiation_error()),
+                SplitState::Shutdown => break,
+            };
 
             if self.needs_read > 0 {
-                if self.read_in()? == 0 {
+                let mut stream = &self.shared.stream;
+                let read_size = read_size(&sizes);
+                let nread = self.input.read_from(
+                    &mut stream,
+                    &mut self.needs_read,
+                    read_size,
+                    self.max_record_size,
+                )?;
+                if nread == 0 {
                     break;
                 }
                 self.needs_read = 0;
             }
 
-            let eof = self.decrypt()?;
-            if eof {
-                break;
+            // All complete records are decrypted while the context is
+            // locked. The state set by a close_notify or handshake message
+            // takes effect once the data before it has been read.
+            let mut context = self.shared.context.lock().unwrap();
+            if self.post_handshake {
+                self.post_handshake = post_handshake(
+                    &self.shared,
+                    &mut context,
+                    &mut self.input,
+                    &mut self.needs_read,
+                )?;
+                if self.post_handshake || self.needs_read > 0 {
+                    continue;
+                }
+            }
+            loop {
+                let decrypted =
+                    decrypt(&mut context.context, &mut self.input, &mut self.needs_read)
+                        .map_err(|e| with_context(e, "DecryptMessage", "data transfer"))?;
+                match decrypted {
+                    Decrypted::Data => {}
+                    Decrypted::Expired => {
+                        self.state = SplitState::Shutdown;
+                        break;
+                    }
+                    // As for a `TlsStream`, the message is continued with
+                    // what follows it, if anything.
+                    Decrypted::Renegotiate if is_tls13(&context.context) => {
+                        self.post_handshake = !self.input.ciphertext().is_empty();
+                    }
+                    Decrypted::Renegotiate => {
+                        self.state = SplitState::Renegotiating;
+                        break;
+                    }
+                }
+                if self.post_handshake || self.needs_read > 0 || !self.input.has_complete_record() {
+                    break;
+                }
             }
         }
 
@@ -1047,8 +3987,315 @@ where
     }
 
     fn consume(&mut self, amt: usize) {
-        let pos = self.dec_in.position() + amt as u64;
-        assert!(pos <= self.dec_in.get_ref().len() as u64);
-        self.dec_in.set_position(pos);
+        self.input.consume_plaintext(amt);
+    }
+}
+
+impl<S> Write for WriteHalf<S>
+where
+    for<'a> &'a S: Write,
+{
+    /// In the case of a WouldBlock error, we expect another call
+    /// starting with the same input data, just like for `TlsStream`.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let sizes = match self.state {
+            SplitState::Streaming(sizes) => sizes,
+            SplitState::Renegotiating => return Err(SplitState::renegotiation_error()),
+            SplitState::Shutdown => {
+                return Err(io::Error::from_raw_os_error(
+                    Foundation::SEC_E_CONTEXT_EXPIRED,
+                ))
+            }
+        };
+
+        if !self.write_pending {
+            let len = cmp::min(buf.len(), sizes.cbMaximumMessage as usize);
+            let mut context = self.shared.context.lock().unwrap();
+            queue_output(&mut self.out_buf, &mut context.output);
+            encrypt(&mut context.context, &mut self.out_buf, &buf[..len], &sizes)
+                .map_err(|e| with_context(e, "EncryptMessage", "data transfer"))?;
+            self.last_write_len = len;
+            self.write_pending = true;
+        }
+        let mut stream = &self.shared.stream;
+        write_out(&mut stream, &mut self.out_buf)?;
+        self.write_pending = false;
+
+        Ok(self.last_write_len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        {
+            let mut context = self.shared.context.lock().unwrap();
+            queue_output(&mut self.out_buf, &mut context.output);
+        }
+        let mut stream = &self.shared.stream;
+        write_out(&mut stream, &mut self.out_buf)?;
+        stream.flush()
+    }
+}
+
+/// Moves the responses to post-handshake messages queued by the read half of
+/// a split stream into the output buffer of the write half, behind anything
+/// which hasn't been written out yet.
+fn queue_output(out_buf: &mut Cursor<Vec<u8>>, output: &mut Vec<u8>) {
+    if output.is_empty() {
+        return;
+    }
+    if out_buf.position() == out_buf.get_ref().len() as u64 {
+        out_buf.get_mut().clear();
+        out_buf.set_position(0);
+    }
+    out_buf.get_mut().append(output);
+}
+
+/// Continues a TLS 1.3 post-handshake message received by the read half of a
+/// split stream with the ciphertext following it, like `TlsEngine` does with
+/// a single call to AcceptSecurityContext or InitializeSecurityContextW.
+///
+/// Any response is queued for the write half. Returns true if the call has
+/// to be made again, once more data has been read if `needs_read` is set.
+fn post_handshake<S>(
+    shared: &SplitShared<S>,
+    context: &mut SplitContext,
+    input: &mut InputBuffer,
+    needs_read: &mut usize,
+) -> io::Result<bool> {
+    unsafe {
+        let len = cmp::min(input.ciphertext().len(), MAX_SECBUFFER_LEN);
+        let mut inbufs = [
+            secbuf(
+                Identity::SECBUFFER_TOKEN,
+                Some(&mut input.ciphertext_mut()[..len]),
+            )?,
+            secbuf(Identity::SECBUFFER_EMPTY, None)?,
+        ];
+        let inbuf_desc = secbuf_desc(&mut inbufs);
+        let mut outbufs = [
+            secbuf(Identity::SECBUFFER_TOKEN, None)?,
+            secbuf(Identity::SECBUFFER_ALERT, None)?,
+            secbuf(Identity::SECBUFFER_EMPTY, None)?,
+        ];
+        let mut outbuf_desc = secbuf_desc(&mut outbufs);
+        let mut attributes = 0;
+
+        let handle: *mut Credentials::SecHandle = context.context.get_mut();
+        let (status, operation) = if shared.server {
+            let status = Identity::AcceptSecurityContext(
+                &shared.cred.as_inner(),
+                handle,
+                &inbuf_desc,
+                shared.requests,
+                0,
+                handle,
+                &mut outbuf_desc,
+                &mut attributes,
+                ptr::null_mut(),
+            );
+            (status, "AcceptSecurityContext")
+        } else {
+            let target_name = match shared.target_name {
+                Some(ref name) => name.as_ptr() as *mut u16,
+                None => ptr::null_mut(),
+            };
+            let status = Identity::InitializeSecurityContextW(
+                &shared.cred.as_inner(),
+                handle,
+                target_name,
+                shared.requests,
+                0,
+                0,
+                &inbuf_desc,
+                0,
+                ptr::null_mut(),
+                &mut outbuf_desc,
+                &mut attributes,
+                ptr::null_mut(),
+            );
+            (status, "InitializeSecurityContextW")
+        };
+        for buf in &outbufs[1..] {
+            if !buf.pvBuffer.is_null() {
+                Identity::FreeContextBuffer(buf.pvBuffer);
+            }
+        }
+        trace!(
+            "{} returned {:#x} for {} bytes of input, {} bytes of output",
+            operation,
+            status,
+            len,
+            outbufs[0].cbBuffer
+        );
+
+        match status {
+            Foundation::SEC_E_OK | Foundation::SEC_I_CONTINUE_NEEDED => {
+                let nread = consumed(len, &inbufs[1])?;
+                if !outbufs[0].pvBuffer.is_null() {
+                    let to_write = ContextBuffer(outbufs[0]);
+                    context.output.extend_from_slice(&to_write);
+                }
+                input.consume_ciphertext(nread);
+                *needs_read = input.ciphertext().is_empty() as usize;
+                Ok(status == Foundation::SEC_I_CONTINUE_NEEDED)
+            }
+            Foundation::SEC_E_INCOMPLETE_MESSAGE => {
+                *needs_read = if inbufs[1].BufferType == Identity::SECBUFFER_MISSING {
+                    inbufs[1].cbBuffer as usize
+                } else {
+                    1
+                };
+                Ok(true)
+            }
+            err => Err(with_context(
+                io::Error::from_raw_os_error(err),
+                operation,
+                "data transfer",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+
+    use crate::mock_stream::MockStream;
+    use crate::schannel_cred::Direction;
+    use crate::test::localhost_cert;
+
+    #[test]
+    fn handshake_completed_by_first_call() {
+        let cert = match localhost_cert() {
+            Some(cert) => cert,
+            None => return,
+        };
+
+        let creds = SchannelCred::builder()
+            .cert(cert)
+            .acquire(Direction::Inbound)
+            .unwrap();
+        let mut server = Builder::new().accept_engine(creds).unwrap();
+        let creds = SchannelCred::builder()
+            .acquire(Direction::Outbound)
+            .unwrap();
+        let mut builder = Builder::new();
+        builder.domain("localhost");
+        let mut client = builder.connect_engine(creds).unwrap();
+
+        let mut to_server = vec![];
+        let mut to_client = vec![];
+        while client.session_state() == SessionState::Handshaking {
+            client.take_output(&mut to_server);
+            server.handshake_input(&to_server).unwrap();
+            to_server.clear();
+            server.take_output(&mut to_client);
+            client.handshake_input(&to_client).unwrap();
+            to_client.clear();
+        }
+        client.take_output(&mut to_server);
+        server.handshake_input(&to_server).unwrap();
+        to_server.clear();
+        assert_eq!(server.session_state(), SessionState::Streaming);
+
+        // A completed context stands in for one which Schannel finished in
+        // the first call to InitializeSecurityContextW, along with a token.
+        let TlsEngine { cred, context, .. } = client;
+        let engine = builder
+            .engine_with_context(cred, false, context, Some(b"token"), false)
+            .unwrap();
+        assert_eq!(engine.session_state(), SessionState::Streaming);
+        assert!(engine.stats().handshake_duration.is_some());
+
+        let mut mock = MockStream::new();
+        mock.write_would_block();
+        let stream = TlsStream::from_engine(engine, mock);
+        let stream = match (MidHandshakeTlsStream {
+            inner: Box::new(stream),
+        })
+        .handshake()
+        {
+            Err(HandshakeError::Interrupted(stream)) => stream,
+            _ => panic!("expected the handshake to be interrupted"),
+        };
+        assert!(stream.get_ref().written().is_empty());
+
+        let mut stream = stream.handshake().unwrap();
+        assert_eq!(stream.get_ref().written(), b"token");
+        stream.write_all(b"hello").unwrap();
+
+        let mut plaintext = vec![];
+        server
+            .unwrap(&stream.get_ref().written()[5..], &mut plaintext)
+            .unwrap();
+        assert_eq!(plaintext, b"hello");
+    }
+
+    #[test]
+    fn split_sends_queued_output_first() {
+        let cert = match localhost_cert() {
+            Some(cert) => cert,
+            None => return,
+        };
+
+        let creds = SchannelCred::builder()
+            .cert(cert)
+            .acquire(Direction::Inbound)
+            .unwrap();
+        let mut server = Builder::new().accept_engine(creds).unwrap();
+        let creds = SchannelCred::builder()
+            .acquire(Direction::Outbound)
+            .unwrap();
+        let mut client = Builder::new()
+            .domain("localhost")
+            .connect_engine(creds)
+            .unwrap();
+        let mut to_server = vec![];
+        let mut to_client = vec![];
+        for _ in 0..10 {
+            client.take_output(&mut to_server);
+            server.handshake_input(&to_server).unwrap();
+            to_server.clear();
+            server.take_output(&mut to_client);
+            client.handshake_input(&to_client).unwrap();
+            to_client.clear();
+            if client.session_state() == SessionState::Streaming
+                && server.session_state() == SessionState::Streaming
+            {
+                break;
+            }
+        }
+        assert_eq!(client.session_state(), SessionState::Streaming);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut peer = listener.accept().unwrap().0;
+        let mut stream = TlsStream::from_engine(client, stream);
+
+        // Records of the session itself stand in for responses to
+        // post-handshake messages, queued as the read half would.
+        let mut first = vec![];
+        stream.engine.wrap(b"first", &mut first).unwrap();
+        let mut second = vec![];
+        stream.engine.wrap(b"second", &mut second).unwrap();
+        let (_reader, mut writer) = stream.split();
+
+        writer.shared.context.lock().unwrap().output = first.clone();
+        writer.write_all(b"data").unwrap();
+        writer.shared.context.lock().unwrap().output = second.clone();
+        writer.flush().unwrap();
+
+        let mut wire = vec![];
+        let mut plaintext = vec![];
+        while plaintext.len() < 15 {
+            let mut buf = [0; 1024];
+            let len = peer.read(&mut buf).unwrap();
+            assert!(len > 0);
+            wire.extend_from_slice(&buf[..len]);
+            server.unwrap(&buf[..len], &mut plaintext).unwrap();
+        }
+        assert_eq!(wire[..first.len()], first[..]);
+        assert_eq!(wire[wire.len() - second.len()..], second[..]);
+        assert_eq!(plaintext, b"firstdatasecond");
     }
 }